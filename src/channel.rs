@@ -1,5 +1,7 @@
 //! RPC channel related components.
+use crate::flow_control::INITIAL_WINDOW_SIZE;
 use crate::packet::MAX_PACKET_LEN;
+use rand::Rng;
 use std::time::Duration;
 
 /// Options for a RPC channel.
@@ -9,6 +11,8 @@ pub struct ChannelOptions {
     pub read_buffer_size: usize,
 
     /// The byte size of the application level write buffer.
+    ///
+    /// Values which cannot hold a packet header and a byte of its payload are rounded up.
     pub write_buffer_size: usize,
 
     /// The maximum length of the transmit queue.
@@ -22,10 +26,54 @@ pub struct ChannelOptions {
     pub yield_threshold: usize,
 
     /// TCP connect timeout duration.
+    ///
+    /// Servers also use this as the timeout for accepting a connection (e.g., the TLS handshake).
     pub tcp_connect_timeout: Duration,
 
     /// Timeout duration of a write operation.
     pub tcp_write_timeout: Duration,
+
+    /// Interval of heartbeat pings.
+    ///
+    /// If nothing is received from the peer during this interval, a ping is sent to it.
+    ///
+    /// If `None`, heartbeat pings are never sent.
+    pub heartbeat_interval: Option<Duration>,
+
+    /// The number of consecutive unanswered heartbeat pings allowed.
+    ///
+    /// If it exceeds this value, the peer is considered to be down and the channel will be disconnected.
+    pub heartbeat_miss_threshold: usize,
+
+    /// The byte size of the receive window of the channel.
+    ///
+    /// The peer can start sending a new message only if the payload bytes that have not been decoded
+    /// by this side yet are less than this value
+    /// (messages that have already been started are allowed to be sent to the end, though).
+    ///
+    /// Values smaller than `INITIAL_WINDOW_SIZE` (64 KiB) are treated as `INITIAL_WINDOW_SIZE`.
+    pub channel_window_size: u32,
+
+    /// The byte size of the receive window of each incoming stream.
+    ///
+    /// The peer can send the next item of a stream only if the payload bytes of the items
+    /// that have not been consumed by the application are less than this value.
+    ///
+    /// Values smaller than `INITIAL_WINDOW_SIZE` (64 KiB) are treated as `INITIAL_WINDOW_SIZE`.
+    pub stream_window_size: u32,
+
+    /// The maximum byte size of an incoming message (i.e., the total payload bytes of its packets).
+    ///
+    /// Messages exceeding this value are discarded without being buffered,
+    /// and the error is replied to the peer (server side) or returned to the caller (client side).
+    ///
+    /// If `None`, the size is unlimited.
+    pub max_incoming_message_size: Option<usize>,
+
+    /// The policy for reconnecting to the server after the connection is lost or cannot be established.
+    ///
+    /// This is only used by client side channels.
+    pub reconnect_policy: ReconnectPolicy,
 }
 impl ChannelOptions {
     /// The default value of `read_buffer_size` field.
@@ -45,6 +93,24 @@ impl ChannelOptions {
 
     /// The default duration of `tcp_write_timeout` field.
     pub const DEFAULT_TCP_WRITE_TIMEOUT_SECONDS: u64 = 5;
+
+    /// The default duration of `heartbeat_interval` field.
+    pub const DEFAULT_HEARTBEAT_INTERVAL_SECONDS: u64 = 30;
+
+    /// The default value of `heartbeat_miss_threshold` field.
+    pub const DEFAULT_HEARTBEAT_MISS_THRESHOLD: usize = 3;
+
+    /// The default value of `channel_window_size` field.
+    pub const DEFAULT_CHANNEL_WINDOW_SIZE: u32 = 8 * 1024 * 1024;
+
+    /// The default value of `stream_window_size` field.
+    pub const DEFAULT_STREAM_WINDOW_SIZE: u32 = 1024 * 1024;
+
+    /// The default value of `max_incoming_message_size` field.
+    pub const DEFAULT_MAX_INCOMING_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+    /// The window size that both sides of a channel assume before receiving any window updates.
+    pub const INITIAL_WINDOW_SIZE: u32 = INITIAL_WINDOW_SIZE;
 }
 impl Default for ChannelOptions {
     fn default() -> Self {
@@ -55,6 +121,148 @@ impl Default for ChannelOptions {
             yield_threshold: Self::DEFAULT_YIELD_THRESHOLD,
             tcp_connect_timeout: Duration::from_secs(Self::DEFAULT_TCP_CONNECT_TIMEOUT_SECONDS),
             tcp_write_timeout: Duration::from_secs(Self::DEFAULT_TCP_WRITE_TIMEOUT_SECONDS),
+            heartbeat_interval: Some(Duration::from_secs(
+                Self::DEFAULT_HEARTBEAT_INTERVAL_SECONDS,
+            )),
+            heartbeat_miss_threshold: Self::DEFAULT_HEARTBEAT_MISS_THRESHOLD,
+            channel_window_size: Self::DEFAULT_CHANNEL_WINDOW_SIZE,
+            stream_window_size: Self::DEFAULT_STREAM_WINDOW_SIZE,
+            max_incoming_message_size: Some(Self::DEFAULT_MAX_INCOMING_MESSAGE_SIZE),
+            reconnect_policy: ReconnectPolicy::default(),
         }
     }
 }
+
+/// Policy for reconnecting a client side channel to the server.
+///
+/// When a channel fails to connect (or loses the connection), it immediately tries to reconnect once.
+/// If that also fails, the `n`-th subsequent attempt is delayed by
+/// `min(base_delay * multiplier^(n - 1), max_delay)` with random jitter applied.
+///
+/// While a channel is waiting for reconnecting, messages sent to the server are discarded.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// The delay before the first delayed reconnection attempt.
+    pub base_delay: Duration,
+
+    /// The factor by which the delay is multiplied for each subsequent attempt.
+    pub multiplier: f64,
+
+    /// The upper bound of the delay (before applying jitter).
+    pub max_delay: Duration,
+
+    /// The ratio of randomization applied to delays.
+    ///
+    /// Each delay is chosen uniformly from `[delay * (1 - jitter), delay * (1 + jitter)]`.
+    /// The value is clamped to `[0.0, 1.0]` (a non-finite value is regarded as `0.0`).
+    pub jitter: f64,
+
+    /// The maximum number of consecutive reconnection attempts.
+    ///
+    /// If all of the attempts fail, the channel gives up reconnecting and is closed
+    /// (a new channel will be created when a message is sent to the server next time).
+    ///
+    /// If `None`, the channel keeps reconnecting forever.
+    pub max_attempts: Option<usize>,
+}
+impl ReconnectPolicy {
+    /// The default duration of `base_delay` field.
+    pub const DEFAULT_BASE_DELAY_SECONDS: u64 = 1;
+
+    /// The default value of `multiplier` field.
+    pub const DEFAULT_MULTIPLIER: f64 = 2.0;
+
+    /// The default duration of `max_delay` field.
+    pub const DEFAULT_MAX_DELAY_SECONDS: u64 = 60;
+
+    /// The default value of `jitter` field.
+    pub const DEFAULT_JITTER: f64 = 0.2;
+
+    /// Returns the delay before the `attempt`-th delayed reconnection attempt (1-origin).
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let max = self.max_delay.as_secs_f64();
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exp);
+        let delay = if delay.is_finite() {
+            delay.min(max)
+        } else {
+            max
+        };
+        let jitter = if self.jitter.is_finite() {
+            self.jitter.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let ratio = if jitter == 0.0 {
+            1.0
+        } else {
+            rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        };
+
+        // The delay may exceed the range of `Duration` if `max_delay` is close to `Duration::MAX`
+        Duration::try_from_secs_f64(delay * ratio).unwrap_or(Duration::MAX)
+    }
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            base_delay: Duration::from_secs(Self::DEFAULT_BASE_DELAY_SECONDS),
+            multiplier: Self::DEFAULT_MULTIPLIER,
+            max_delay: Duration::from_secs(Self::DEFAULT_MAX_DELAY_SECONDS),
+            jitter: Self::DEFAULT_JITTER,
+            max_attempts: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay_works() {
+        let policy = ReconnectPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(7), Duration::from_secs(60));
+        assert_eq!(policy.delay(usize::MAX), Duration::from_secs(60));
+
+        let policy = ReconnectPolicy::default();
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(800));
+            assert!(delay <= Duration::from_millis(1200));
+        }
+
+        // Extreme values
+        let policy = ReconnectPolicy {
+            max_delay: Duration::MAX,
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(usize::MAX), Duration::MAX);
+
+        let policy = ReconnectPolicy {
+            max_delay: Duration::MAX,
+            ..Default::default()
+        };
+        assert!(policy.delay(usize::MAX) > Duration::from_secs(60));
+
+        let policy = ReconnectPolicy {
+            jitter: f64::NAN,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+
+        let policy = ReconnectPolicy {
+            multiplier: f64::NAN,
+            jitter: f64::INFINITY,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(3), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,397 @@
+use crate::metrics::ClientMetrics;
+use crate::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Policy of the circuit breakers of a client service.
+///
+/// A circuit breaker is associated with each server and has the following three states:
+/// - `Closed`: requests are sent to the server as usual
+/// - `Open`: requests are rejected immediately with `ErrorKind::Unavailable` errors
+/// - `HalfOpen`: a limited number of requests are sent to the server for probing it
+///
+/// A closed breaker becomes open if the number of consecutive failures reaches `consecutive_failures`,
+/// or if the failure rate in the current window exceeds `failure_rate`.
+/// After `open_duration` elapses, it becomes half-open.
+/// A half-open breaker becomes closed if all of the probing requests succeed,
+/// and becomes open again if one of them fails.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerPolicy {
+    /// The number of consecutive failures that opens the breaker.
+    ///
+    /// The default value is `5`.
+    pub consecutive_failures: usize,
+
+    /// The ratio of failures (in `0.0..=1.0`) that opens the breaker.
+    ///
+    /// The default value is `0.5`.
+    pub failure_rate: f64,
+
+    /// The minimum number of requests in a window required for evaluating `failure_rate`.
+    ///
+    /// The default value is `20`.
+    pub minimum_requests: usize,
+
+    /// The length of the window for calculating failure rates.
+    ///
+    /// The default value is `10s`.
+    pub window: Duration,
+
+    /// How long the breaker remains open before it allows probing requests.
+    ///
+    /// The default value is `30s`.
+    pub open_duration: Duration,
+
+    /// The number of probing requests allowed in the half-open state.
+    ///
+    /// The default value is `1`.
+    pub half_open_requests: usize,
+
+    /// The kinds of errors regarded as failures.
+    ///
+    /// Other errors (e.g., `ErrorKind::InvalidInput` replied by a server) are regarded as successes,
+    /// because the server is responding.
+    ///
+    /// The default value contains `ErrorKind::Timeout`, `ErrorKind::Unavailable`,
+    /// `ErrorKind::ServerError`, `ErrorKind::ConnectionLost` and `ErrorKind::Other`.
+    pub failure_errors: Vec<ErrorKind>,
+}
+impl CircuitBreakerPolicy {
+    fn is_failure(&self, result: Result<(), &Error>) -> bool {
+        result.is_err_and(|e| self.failure_errors.contains(e.kind()))
+    }
+}
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        CircuitBreakerPolicy {
+            consecutive_failures: 5,
+            failure_rate: 0.5,
+            minimum_requests: 20,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(30),
+            half_open_requests: 1,
+            failure_errors: vec![
+                ErrorKind::Timeout,
+                ErrorKind::Unavailable,
+                ErrorKind::ServerError,
+                ErrorKind::ConnectionLost {
+                    request_sent: false,
+                },
+                ErrorKind::ConnectionLost { request_sent: true },
+                ErrorKind::Other,
+            ],
+        }
+    }
+}
+
+/// State of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CircuitState {
+    /// Requests are sent as usual.
+    Closed,
+
+    /// Requests are rejected.
+    Open,
+
+    /// Probing requests are being sent.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    policy: Arc<CircuitBreakerPolicy>,
+    metrics: ClientMetrics,
+    inner: Mutex<Inner>,
+}
+impl CircuitBreaker {
+    pub fn new(policy: Arc<CircuitBreakerPolicy>, metrics: ClientMetrics) -> Self {
+        CircuitBreaker {
+            policy,
+            metrics,
+            inner: Mutex::new(Inner::new()),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let inner = self.lock();
+        match inner.phase {
+            Phase::Closed => CircuitState::Closed,
+            Phase::Open { until } if until > Instant::now() => CircuitState::Open,
+            Phase::Open { .. } | Phase::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Tries to acquire a permit for sending a request.
+    ///
+    /// If the breaker is open (or enough probing requests are already in flight), this returns `None`.
+    pub fn try_acquire(breaker: &Arc<Self>) -> Option<Permit> {
+        let mut inner = breaker.lock();
+        let is_probe = match inner.phase {
+            Phase::Closed => false,
+            Phase::Open { until } => {
+                if until > Instant::now() {
+                    return None;
+                }
+                inner.phase = Phase::HalfOpen {
+                    in_flight: 1,
+                    successes: 0,
+                };
+                true
+            }
+            Phase::HalfOpen {
+                ref mut in_flight, ..
+            } => {
+                if *in_flight >= breaker.policy.half_open_requests {
+                    return None;
+                }
+                *in_flight += 1;
+                true
+            }
+        };
+        Some(Permit {
+            breaker: Arc::clone(breaker),
+            is_probe,
+            done: false,
+        })
+    }
+
+    fn record(&self, is_probe: bool, is_failure: bool) {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        match inner.phase {
+            Phase::Closed => {
+                if now.duration_since(inner.window_start) >= self.policy.window {
+                    inner.window_start = now;
+                    inner.window_requests = 0;
+                    inner.window_failures = 0;
+                }
+                inner.window_requests += 1;
+                if !is_failure {
+                    inner.consecutive_failures = 0;
+                    return;
+                }
+                inner.window_failures += 1;
+                inner.consecutive_failures += 1;
+
+                let rate = inner.window_failures as f64 / inner.window_requests as f64;
+                if inner.consecutive_failures >= self.policy.consecutive_failures
+                    || (inner.window_requests >= self.policy.minimum_requests
+                        && rate >= self.policy.failure_rate)
+                {
+                    self.open(&mut inner, now);
+                    self.metrics.open_circuit_breakers.increment();
+                }
+            }
+            Phase::Open { .. } => {}
+            Phase::HalfOpen {
+                in_flight,
+                successes,
+            } => {
+                if !is_probe {
+                    // A response of a request sent before the breaker was opened
+                    return;
+                }
+                if is_failure {
+                    self.open(&mut inner, now);
+                } else if successes + 1 >= self.policy.half_open_requests {
+                    *inner = Inner::new();
+                    self.metrics.open_circuit_breakers.decrement();
+                } else {
+                    inner.phase = Phase::HalfOpen {
+                        in_flight: in_flight.saturating_sub(1),
+                        successes: successes + 1,
+                    };
+                }
+            }
+        }
+    }
+
+    fn release(&self, is_probe: bool) {
+        let mut inner = self.lock();
+        if let Phase::HalfOpen {
+            ref mut in_flight, ..
+        } = inner.phase
+        {
+            if is_probe {
+                *in_flight = in_flight.saturating_sub(1);
+            }
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, now: Instant) {
+        inner.phase = Phase::Open {
+            until: now + self.policy.open_duration,
+        };
+        self.metrics.circuit_breaker_opened.increment();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Drop for CircuitBreaker {
+    fn drop(&mut self) {
+        if self.lock().phase != Phase::Closed {
+            self.metrics.open_circuit_breakers.decrement();
+        }
+    }
+}
+
+/// A permit for sending a request.
+///
+/// The result of the request should be reported by `complete` method.
+/// If the permit is dropped without that (e.g., the request is cancelled), the result is not recorded.
+#[derive(Debug)]
+pub(crate) struct Permit {
+    breaker: Arc<CircuitBreaker>,
+    is_probe: bool,
+    done: bool,
+}
+impl Permit {
+    pub fn complete(mut self, result: Result<(), &Error>) {
+        let is_failure = self.breaker.policy.is_failure(result);
+        self.breaker.record(self.is_probe, is_failure);
+        self.done = true;
+    }
+}
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.done {
+            self.breaker.release(self.is_probe);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    phase: Phase,
+    consecutive_failures: usize,
+    window_start: Instant,
+    window_requests: usize,
+    window_failures: usize,
+}
+impl Inner {
+    fn new() -> Self {
+        Inner {
+            phase: Phase::Closed,
+            consecutive_failures: 0,
+            window_start: Instant::now(),
+            window_requests: 0,
+            window_failures: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: usize, successes: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometrics::metrics::MetricBuilder;
+
+    fn breaker(policy: CircuitBreakerPolicy) -> Arc<CircuitBreaker> {
+        let metrics = ClientMetrics::new(MetricBuilder::without_registry());
+        Arc::new(CircuitBreaker::new(Arc::new(policy), metrics))
+    }
+
+    fn failure() -> Error {
+        ErrorKind::Timeout.into()
+    }
+
+    #[test]
+    fn consecutive_failures_open_breaker() {
+        let breaker = breaker(CircuitBreakerPolicy {
+            consecutive_failures: 3,
+            open_duration: Duration::from_secs(60),
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            CircuitBreaker::try_acquire(&breaker)
+                .unwrap()
+                .complete(Err(&failure()));
+        }
+        CircuitBreaker::try_acquire(&breaker)
+            .unwrap()
+            .complete(Ok(()));
+        for _ in 0..2 {
+            CircuitBreaker::try_acquire(&breaker)
+                .unwrap()
+                .complete(Err(&failure()));
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        // Errors not regarded as failures
+        let e = ErrorKind::InvalidInput.into();
+        CircuitBreaker::try_acquire(&breaker)
+            .unwrap()
+            .complete(Err(&e));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        for _ in 0..3 {
+            CircuitBreaker::try_acquire(&breaker)
+                .unwrap()
+                .complete(Err(&failure()));
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(CircuitBreaker::try_acquire(&breaker).is_none());
+        assert_eq!(breaker.metrics.open_circuit_breakers(), 1);
+    }
+
+    #[test]
+    fn failure_rate_opens_breaker() {
+        let breaker = breaker(CircuitBreakerPolicy {
+            failure_rate: 0.5,
+            minimum_requests: 10,
+            ..Default::default()
+        });
+        for i in 0..9 {
+            let result = if i % 2 == 0 { Err(failure()) } else { Ok(()) };
+            CircuitBreaker::try_acquire(&breaker)
+                .unwrap()
+                .complete(result.as_ref().map(|_| ()));
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        CircuitBreaker::try_acquire(&breaker)
+            .unwrap()
+            .complete(Err(&failure()));
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn half_open_breaker_works() {
+        let breaker = breaker(CircuitBreakerPolicy {
+            consecutive_failures: 1,
+            open_duration: Duration::from_secs(0),
+            ..Default::default()
+        });
+        CircuitBreaker::try_acquire(&breaker)
+            .unwrap()
+            .complete(Err(&failure()));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        // Only one probing request is allowed
+        let probe = CircuitBreaker::try_acquire(&breaker).unwrap();
+        assert!(CircuitBreaker::try_acquire(&breaker).is_none());
+
+        // Cancelled probe
+        drop(probe);
+        let probe = CircuitBreaker::try_acquire(&breaker).unwrap();
+
+        // Failed probe
+        probe.complete(Err(&failure()));
+        assert_ne!(breaker.state(), CircuitState::Closed);
+
+        // Succeeded probe
+        let probe = CircuitBreaker::try_acquire(&breaker).unwrap();
+        probe.complete(Ok(()));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.metrics.open_circuit_breakers(), 0);
+        assert_eq!(breaker.metrics.circuit_breaker_opened(), 2);
+    }
+}
@@ -1,21 +1,26 @@
-use crate::channel::ChannelOptions;
+use crate::channel::{ChannelOptions, ReconnectPolicy};
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerPolicy, CircuitState};
 use crate::client_side_channel::{ClientSideChannel, DEFAULT_KEEP_ALIVE_TIMEOUT_SECS};
 use crate::client_side_handlers::BoxResponseHandler;
-use crate::message::OutgoingMessage;
+use crate::message::{MessageId, OutgoingMessage};
 use crate::metrics::ClientMetrics;
-use crate::Error;
+use crate::resolver::Resolver;
+use crate::server_set::{BalanceStrategy, Members, ServerSet};
+use crate::transport::Connector;
+use crate::{Error, ErrorKind, TransportAddr};
 use atomic_immut::AtomicImmut;
 use fibers::sync::mpsc;
+use fibers::time::timer::{self, Timeout};
 use fibers::{BoxSpawn, Spawn};
 use futures::{Async, Future, Poll, Stream};
 use prometrics::metrics::MetricBuilder;
 use slog::{Discard, Logger};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::net::SocketAddr;
-use std::sync::atomic::{self, AtomicBool};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
 
 /// `ClientService` builder.
 #[derive(Debug)]
@@ -23,6 +28,8 @@ pub struct ClientServiceBuilder {
     logger: Logger,
     keep_alive_timeout: Duration,
     channel_options: ChannelOptions,
+    connector: Connector,
+    circuit_breaker: Option<CircuitBreakerPolicy>,
     metrics: MetricBuilder,
 }
 impl ClientServiceBuilder {
@@ -32,6 +39,8 @@ impl ClientServiceBuilder {
             logger: Logger::root(Discard, o!()),
             keep_alive_timeout: Duration::from_secs(DEFAULT_KEEP_ALIVE_TIMEOUT_SECS),
             channel_options: ChannelOptions::default(),
+            connector: Connector::default(),
+            circuit_breaker: None,
             metrics: MetricBuilder::new(),
         }
     }
@@ -63,6 +72,24 @@ impl ClientServiceBuilder {
         self
     }
 
+    /// Sets the policy for reconnecting to servers.
+    ///
+    /// This overwrites `ChannelOptions::reconnect_policy` of the channel options.
+    ///
+    /// The default value is `ReconnectPolicy::default()`.
+    pub fn reconnect_policy(&mut self, policy: ReconnectPolicy) -> &mut Self {
+        self.channel_options.reconnect_policy = policy;
+        self
+    }
+
+    /// Enables the circuit breakers which are associated with each server.
+    ///
+    /// By default, circuit breakers are disabled.
+    pub fn circuit_breaker(&mut self, policy: CircuitBreakerPolicy) -> &mut Self {
+        self.circuit_breaker = Some(policy);
+        self
+    }
+
     /// Sets `MetricBuilder` used by the service.
     ///
     /// The default value is `MetricBuilder::new()`.
@@ -71,6 +98,15 @@ impl ClientServiceBuilder {
         self
     }
 
+    /// Enables TLS on the connections to servers.
+    ///
+    /// This fails if the certificates or the private key specified by `options` cannot be loaded.
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, options: crate::tls::ClientTlsOptions) -> crate::Result<&mut Self> {
+        self.connector.tls = Some(track!(crate::tls::TlsConnector::new(&options))?);
+        Ok(self)
+    }
+
     /// Builds a new `ClientService` instance.
     pub fn finish<S>(&self, spawner: S) -> ClientService
     where
@@ -85,8 +121,17 @@ impl ClientServiceBuilder {
             command_rx,
             command_tx,
             channels: channels.clone(),
+            services: Arc::new(Mutex::new(HashMap::new())),
+            resolvers: Vec::new(),
+            circuit_breaker_policy: self.circuit_breaker.clone().map(Arc::new),
+            circuit_breakers: Arc::new(AtomicImmut::default()),
+            next_message_id: Arc::new(AtomicU64::new(0)),
+            is_shutting_down: Arc::new(AtomicBool::new(false)),
+            shutdown_deadline: None,
+            shutdown_timeout: None,
             keep_alive_timeout: self.keep_alive_timeout,
             channel_options: self.channel_options.clone(),
+            connector: self.connector.clone(),
             metrics,
         }
     }
@@ -107,9 +152,18 @@ pub struct ClientService {
     spawner: BoxSpawn,
     command_rx: mpsc::Receiver<Command>,
     command_tx: mpsc::Sender<Command>,
-    channels: Arc<AtomicImmut<HashMap<SocketAddr, ChannelHandle>>>,
+    channels: Arc<AtomicImmut<HashMap<TransportAddr, ChannelHandle>>>,
+    services: Arc<Mutex<Services>>,
+    resolvers: Vec<ResolverEntry>,
+    circuit_breaker_policy: Option<Arc<CircuitBreakerPolicy>>,
+    circuit_breakers: Arc<AtomicImmut<CircuitBreakers>>,
+    next_message_id: Arc<AtomicU64>,
+    is_shutting_down: Arc<AtomicBool>,
+    shutdown_deadline: Option<Instant>,
+    shutdown_timeout: Option<Timeout>,
     keep_alive_timeout: Duration,
     channel_options: ChannelOptions,
+    connector: Connector,
     metrics: ClientMetrics,
 }
 impl ClientService {
@@ -128,6 +182,11 @@ impl ClientService {
         ClientServiceHandle {
             command_tx: self.command_tx.clone(),
             channels: Arc::clone(&self.channels),
+            services: Arc::clone(&self.services),
+            circuit_breaker_policy: self.circuit_breaker_policy.clone(),
+            circuit_breakers: Arc::clone(&self.circuit_breakers),
+            next_message_id: Arc::clone(&self.next_message_id),
+            is_shutting_down: Arc::clone(&self.is_shutting_down),
             metrics: Arc::new(self.metrics.clone()),
         }
     }
@@ -135,6 +194,7 @@ impl ClientService {
     fn handle_command(&mut self, command: Command) {
         match command {
             Command::CreateChannel { server, message } => {
+                let shutdown_deadline = self.shutdown_deadline;
                 if !self.channels.load().contains_key(&server) {
                     self.channels.update(|channels| {
                         let logger = self.logger.new(o!("server" => server.to_string()));
@@ -144,7 +204,8 @@ impl ClientService {
                         let mut channels = channels.clone();
                         let (mut channel, handle) = Channel::new(
                             logger.clone(),
-                            server,
+                            server.clone(),
+                            self.connector.clone(),
                             self.channel_options.clone(),
                             self.metrics.clone(),
                         );
@@ -152,17 +213,22 @@ impl ClientService {
                             .inner
                             .set_keep_alive_timeout(self.keep_alive_timeout);
 
+                        let removed = server.clone();
                         self.spawner.spawn(channel.then(move |result| {
                             if let Err(e) = result {
                                 error!(logger, "A client-side RPC channel aborted: {}", e);
                             } else {
                                 info!(logger, "A client-side RPC channel was closed");
                             }
-                            let command = Command::RemoveChannel { server };
+                            let command = Command::RemoveChannel { server: removed };
                             let _ = command_tx.send(command);
                             Ok(())
-                        }));;
-                        channels.insert(server, handle);
+                        }));
+                        if let Some(deadline) = shutdown_deadline {
+                            // The message was sent before the shutdown started
+                            handle.shutdown(deadline.saturating_duration_since(Instant::now()));
+                        }
+                        channels.insert(server.clone(), handle);
                         channels
                     });
                 }
@@ -170,6 +236,36 @@ impl ClientService {
                     self.channels.load()[&server].send_message(message);
                 }
             }
+            Command::Shutdown { timeout } => {
+                if self.shutdown_timeout.is_some() {
+                    return;
+                }
+                info!(
+                    self.logger,
+                    "Starts shutting down: timeout={:?}, channels={}",
+                    timeout,
+                    self.channels.load().len()
+                );
+                self.is_shutting_down.store(true, atomic::Ordering::SeqCst);
+                self.shutdown_deadline = Some(Instant::now() + timeout);
+                self.shutdown_timeout = Some(timer::timeout(timeout));
+                for channel in self.channels.load().values() {
+                    channel.shutdown(timeout);
+                }
+            }
+            Command::CancelMessage { server, message_id } => {
+                if let Some(channel) = self.channels.load().get(&server) {
+                    channel.cancel_message(message_id);
+                }
+            }
+            Command::AddResolver { name, resolver } => {
+                info!(self.logger, "New resolver is added: {:?}", resolver; "service" => name.clone());
+                self.resolvers.push(ResolverEntry {
+                    name,
+                    resolver,
+                    retry: None,
+                });
+            }
             Command::RemoveChannel { server } => {
                 self.channels.update(|channels| {
                     info!(self.logger, "A client-side RPC channel was deleted";
@@ -178,6 +274,98 @@ impl ClientService {
                     channels.remove(&server);
                     channels
                 });
+                if self.circuit_breakers.load().contains_key(&server) {
+                    self.circuit_breakers.update(|breakers| {
+                        let mut breakers = breakers.clone();
+                        breakers.remove(&server);
+                        breakers
+                    });
+                }
+            }
+        }
+    }
+}
+impl ClientService {
+    fn poll_resolvers(&mut self) {
+        let mut i = 0;
+        while i < self.resolvers.len() {
+            let entry = &mut self.resolvers[i];
+            if let Some(mut retry) = entry.retry.take() {
+                // A broken timer is regarded as expired
+                if let Ok(Async::NotReady) = retry.poll() {
+                    entry.retry = Some(retry);
+                    i += 1;
+                    continue;
+                }
+            }
+            match track!(entry.resolver.poll_resolve()) {
+                Err(e) => {
+                    warn!(self.logger, "Failed to resolve: {}", e; "service" => entry.name.clone());
+                    let mut retry = timer::timeout(Duration::from_secs(RESOLVER_RETRY_DELAY_SECS));
+                    let _ = retry.poll(); // Registers the current task to be notified
+                    entry.retry = Some(retry);
+                    i += 1;
+                }
+                Ok(Async::NotReady) => {
+                    i += 1;
+                }
+                Ok(Async::Ready(None)) => {
+                    let entry = self.resolvers.swap_remove(i);
+                    debug!(self.logger, "A resolver finished"; "service" => entry.name);
+                }
+                Ok(Async::Ready(Some(servers))) => {
+                    let name = entry.name.clone();
+                    self.update_members(&name, servers);
+                }
+            }
+        }
+    }
+
+    fn update_members(&mut self, name: &str, servers: Vec<TransportAddr>) {
+        let members = Members::new(servers);
+        info!(self.logger, "The servers are resolved: {:?}", members.servers(); "service" => name);
+
+        let (added, removed) = {
+            let mut services = self.services.lock().unwrap_or_else(|e| e.into_inner());
+            let old = services.entry(name.to_owned()).or_default().load();
+            let added = members
+                .servers()
+                .iter()
+                .filter(|s| !old.servers().contains(s))
+                .cloned()
+                .collect::<Vec<_>>();
+            let removed = old
+                .servers()
+                .iter()
+                .filter(|s| !members.servers().contains(s))
+                .cloned()
+                .collect::<Vec<_>>();
+            services[name].store(members);
+
+            // Servers which are still members of other services should be kept connected
+            let in_use = services
+                .values()
+                .flat_map(|m| m.load().servers().to_vec())
+                .collect::<HashSet<_>>();
+            let removed = removed
+                .into_iter()
+                .filter(|s| !in_use.contains(s))
+                .collect::<Vec<_>>();
+            (added, removed)
+        };
+
+        if !self.is_shutting_down.load(atomic::Ordering::SeqCst) {
+            for server in added {
+                self.handle_command(Command::CreateChannel {
+                    server,
+                    message: None,
+                });
+            }
+        }
+        for server in removed {
+            if let Some(channel) = self.channels.load().get(&server) {
+                info!(self.logger, "Closes the channel to the removed server"; "server" => server.to_string());
+                channel.shutdown(Duration::from_secs(REMOVED_SERVER_SHUTDOWN_TIMEOUT_SECS));
             }
         }
     }
@@ -191,6 +379,28 @@ impl Future for ClientService {
             let command = command.expect("Infinite stream");
             self.handle_command(command);
         }
+        self.poll_resolvers();
+        if self.shutdown_timeout.is_some() {
+            if self.channels.load().is_empty() {
+                info!(
+                    self.logger,
+                    "Client service stopped (all channels were drained)"
+                );
+                return Ok(Async::Ready(()));
+            }
+            let is_expired = track!(self
+                .shutdown_timeout
+                .poll()
+                .map_err(|_| Error::from(ErrorKind::Other.cause("Broken timer"))))?;
+            if let Async::Ready(Some(())) = is_expired {
+                warn!(
+                    self.logger,
+                    "Client service stopped (shutdown timeout expired): remaining_channels={}",
+                    self.channels.load().len()
+                );
+                return Ok(Async::Ready(()));
+            }
+        }
         Ok(Async::NotReady)
     }
 }
@@ -199,7 +409,12 @@ impl Future for ClientService {
 #[derive(Debug, Clone)]
 pub struct ClientServiceHandle {
     command_tx: mpsc::Sender<Command>,
-    channels: Arc<AtomicImmut<HashMap<SocketAddr, ChannelHandle>>>,
+    channels: Arc<AtomicImmut<HashMap<TransportAddr, ChannelHandle>>>,
+    services: Arc<Mutex<Services>>,
+    circuit_breaker_policy: Option<Arc<CircuitBreakerPolicy>>,
+    circuit_breakers: Arc<AtomicImmut<CircuitBreakers>>,
+    next_message_id: Arc<AtomicU64>,
+    is_shutting_down: Arc<AtomicBool>,
     pub(crate) metrics: Arc<ClientMetrics>,
 }
 impl ClientServiceHandle {
@@ -208,7 +423,96 @@ impl ClientServiceHandle {
         &self.metrics
     }
 
-    pub(crate) fn send_message(&self, server: SocketAddr, message: Message) -> bool {
+    /// Returns `true` if the channel to `server` is waiting for reconnecting
+    /// or the circuit breaker of `server` is open (i.e., the server is considered to be down).
+    ///
+    /// If there is no channel to `server`, this returns `false`.
+    pub fn is_server_down(&self, server: &TransportAddr) -> bool {
+        let is_down = self
+            .channels
+            .load()
+            .get(server)
+            .is_some_and(|c| c.is_server_down.load(atomic::Ordering::SeqCst));
+        is_down || self.circuit_state(server) == Some(CircuitState::Open)
+    }
+
+    /// Returns the state of the circuit breaker of `server`.
+    ///
+    /// If circuit breakers are disabled, this returns `None`.
+    pub fn circuit_state(&self, server: &TransportAddr) -> Option<CircuitState> {
+        self.circuit_breaker_policy.as_ref()?;
+        let state = self
+            .circuit_breakers
+            .load()
+            .get(server)
+            .map_or(CircuitState::Closed, |b| b.state());
+        Some(state)
+    }
+
+    /// Returns the circuit breaker of `server` (it is created if it does not exist).
+    ///
+    /// If circuit breakers are disabled, this returns `None`.
+    pub(crate) fn circuit_breaker(&self, server: &TransportAddr) -> Option<Arc<CircuitBreaker>> {
+        let policy = self.circuit_breaker_policy.as_ref()?;
+        if let Some(breaker) = self.circuit_breakers.load().get(server) {
+            return Some(Arc::clone(breaker));
+        }
+        self.circuit_breakers.update(|breakers| {
+            let mut breakers = breakers.clone();
+            breakers.entry(server.clone()).or_insert_with(|| {
+                let metrics = (*self.metrics).clone();
+                Arc::new(CircuitBreaker::new(Arc::clone(policy), metrics))
+            });
+            breakers
+        });
+        self.circuit_breakers.load().get(server).cloned()
+    }
+
+    /// Registers `resolver` which resolves the servers of the service named `name`.
+    ///
+    /// The client service opens channels to the servers added by the resolver,
+    /// and closes the channels to the servers removed by it.
+    ///
+    /// If a resolver for `name` already exists, both resolvers update the same set of servers.
+    pub fn add_resolver<R: Resolver>(&self, name: &str, resolver: R) {
+        let command = Command::AddResolver {
+            name: name.to_owned(),
+            resolver: Box::new(resolver),
+        };
+        let _ = self.command_tx.send(command);
+    }
+
+    /// Returns the `ServerSet` of the service named `name`.
+    ///
+    /// The members of the set are updated by the resolver registered by `add_resolver`.
+    /// If there is no such resolver yet, the set is empty until it is registered.
+    pub fn server_set(&self, name: &str, strategy: BalanceStrategy) -> ServerSet {
+        let members = Arc::clone(
+            self.services
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(name.to_owned())
+                .or_default(),
+        );
+        ServerSet::with_members(self.clone(), strategy, members)
+    }
+
+    /// Starts shutting down the client service.
+    ///
+    /// After calling this, new RPC invocations (i.e., `CallClient::call` and `CastClient::cast`)
+    /// will fail with `ErrorKind::Unavailable` errors.
+    ///
+    /// The `ClientService` future completes when all the queued messages have been sent and
+    /// all the outstanding responses have been received, or when `timeout` elapses.
+    pub fn shutdown(&self, timeout: Duration) {
+        self.is_shutting_down.store(true, atomic::Ordering::SeqCst);
+        let _ = self.command_tx.send(Command::Shutdown { timeout });
+    }
+
+    pub(crate) fn send_message(&self, server: TransportAddr, message: Message) -> bool {
+        if self.is_shutting_down.load(atomic::Ordering::SeqCst) {
+            return false;
+        }
         if let Some(channel) = self.channels.load().get(&server) {
             channel.send_message(message)
         } else {
@@ -219,43 +523,90 @@ impl ClientServiceHandle {
             self.command_tx.send(command).is_ok()
         }
     }
+
+    pub(crate) fn next_message_id(&self) -> MessageId {
+        MessageId(self.next_message_id.fetch_add(1, atomic::Ordering::Relaxed))
+    }
+
+    pub(crate) fn cancel_message(&self, server: &TransportAddr, message_id: MessageId) {
+        if let Some(channel) = self.channels.load().get(server) {
+            channel.cancel_message(message_id);
+        } else {
+            // The channel may be being created
+            let command = Command::CancelMessage {
+                server: server.clone(),
+                message_id,
+            };
+            let _ = self.command_tx.send(command);
+        }
+    }
 }
 
+/// The timeout for closing the channel to a server removed by a resolver.
+const REMOVED_SERVER_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// The delay before polling a resolver again after it returned an error.
+const RESOLVER_RETRY_DELAY_SECS: u64 = 1;
+
+#[derive(Debug)]
+struct ResolverEntry {
+    name: String,
+    resolver: Box<dyn Resolver>,
+    retry: Option<Timeout>,
+}
+
+type Services = HashMap<String, Arc<AtomicImmut<Members>>>;
+
+type CircuitBreakers = HashMap<TransportAddr, Arc<CircuitBreaker>>;
+
 #[derive(Debug)]
 enum Command {
     CreateChannel {
-        server: SocketAddr,
+        server: TransportAddr,
         message: Option<Message>,
     },
+    CancelMessage {
+        server: TransportAddr,
+        message_id: MessageId,
+    },
+    Shutdown {
+        timeout: Duration,
+    },
     RemoveChannel {
-        server: SocketAddr,
+        server: TransportAddr,
+    },
+    AddResolver {
+        name: String,
+        resolver: Box<dyn Resolver>,
     },
 }
 
 #[derive(Debug)]
 struct Channel {
     inner: ClientSideChannel,
-    message_rx: mpsc::Receiver<Message>,
+    command_rx: mpsc::Receiver<ChannelCommand>,
 }
 impl Channel {
     fn new(
         logger: Logger,
-        server: SocketAddr,
+        server: TransportAddr,
+        connector: Connector,
         options: ChannelOptions,
         metrics: ClientMetrics,
     ) -> (Self, ChannelHandle) {
-        let (message_tx, message_rx) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
         let is_server_down = Arc::new(AtomicBool::new(false));
         let inner = ClientSideChannel::new(
             logger,
             server,
             Arc::clone(&is_server_down),
+            connector,
             options,
             metrics,
         );
-        let channel = Channel { inner, message_rx };
+        let channel = Channel { inner, command_rx };
         let handle = ChannelHandle {
-            message_tx,
+            command_tx,
             is_server_down,
         };
         (channel, handle)
@@ -266,14 +617,21 @@ impl Future for Channel {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while let Async::Ready(message) = self.message_rx.poll().expect("Never fails") {
-            if let Some(m) = message {
-                if m.force_wakeup {
-                    self.inner.force_wakeup();
+        while let Async::Ready(command) = self.command_rx.poll().expect("Never fails") {
+            match command {
+                Some(ChannelCommand::Send(m)) => {
+                    if m.force_wakeup {
+                        self.inner.force_wakeup();
+                    }
+                    self.inner.send_message(m.message, m.response_handler);
                 }
-                self.inner.send_message(m.message, m.response_handler);
-            } else {
-                return Ok(Async::Ready(()));
+                Some(ChannelCommand::Cancel(message_id)) => {
+                    self.inner.cancel_message(message_id);
+                }
+                Some(ChannelCommand::Shutdown(timeout)) => {
+                    self.inner.shutdown(timeout);
+                }
+                None => return Ok(Async::Ready(())),
             }
         }
         track!(self.inner.poll())
@@ -282,7 +640,7 @@ impl Future for Channel {
 
 #[derive(Debug, Clone)]
 struct ChannelHandle {
-    message_tx: mpsc::Sender<Message>,
+    command_tx: mpsc::Sender<ChannelCommand>,
     is_server_down: Arc<AtomicBool>,
 }
 impl ChannelHandle {
@@ -290,9 +648,24 @@ impl ChannelHandle {
         if !message.force_wakeup && self.is_server_down.load(atomic::Ordering::SeqCst) {
             false
         } else {
-            self.message_tx.send(message).is_ok()
+            self.command_tx.send(ChannelCommand::Send(message)).is_ok()
         }
     }
+
+    pub fn cancel_message(&self, message_id: MessageId) {
+        let _ = self.command_tx.send(ChannelCommand::Cancel(message_id));
+    }
+
+    pub fn shutdown(&self, timeout: Duration) {
+        let _ = self.command_tx.send(ChannelCommand::Shutdown(timeout));
+    }
+}
+
+#[derive(Debug)]
+enum ChannelCommand {
+    Send(Message),
+    Cancel(MessageId),
+    Shutdown(Duration),
 }
 
 pub struct Message {
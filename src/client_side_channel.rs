@@ -1,16 +1,14 @@
-use crate::channel::ChannelOptions;
+use crate::channel::{ChannelOptions, ReconnectPolicy};
 use crate::client_side_handlers::{Assigner, BoxResponseHandler};
 use crate::message::{MessageId, OutgoingMessage};
-use crate::message_stream::MessageStream;
+use crate::message_stream::{MessageEvent, MessageStream};
 use crate::metrics::{ChannelMetrics, ClientMetrics};
+use crate::transport::{Connector, TransportAddr};
 use crate::{Error, ErrorKind, Result};
-use fibers;
-use fibers::net::TcpStream;
-use fibers::time::timer::{self, Timeout, TimerExt};
+use fibers::time::timer::{self, Timeout};
 use futures::{Async, Future, Poll, Stream};
 use slog::Logger;
 use std::fmt;
-use std::net::SocketAddr;
 use std::sync::atomic::{self, AtomicBool};
 use std::sync::mpsc::RecvError;
 use std::sync::Arc;
@@ -22,31 +20,35 @@ pub const DEFAULT_KEEP_ALIVE_TIMEOUT_SECS: u64 = 60 * 10;
 #[derive(Debug)]
 pub struct ClientSideChannel {
     logger: Logger,
-    server: SocketAddr,
+    server: TransportAddr,
     is_server_down: Arc<AtomicBool>,
     keep_alive: KeepAlive,
-    next_message_id: MessageId,
+    shutdown_timeout: Option<Timeout>,
     message_stream: MessageStreamState,
     exponential_backoff: ExponentialBackoff,
+    connector: Connector,
     options: ChannelOptions,
     metrics: ClientMetrics,
 }
 impl ClientSideChannel {
     pub fn new(
         logger: Logger,
-        server: SocketAddr,
+        server: TransportAddr,
         is_server_down: Arc<AtomicBool>,
+        connector: Connector,
         options: ChannelOptions,
         metrics: ClientMetrics,
     ) -> Self {
+        let message_stream = MessageStreamState::new(&server, &connector, &options);
         ClientSideChannel {
             logger,
             server,
             is_server_down,
             keep_alive: KeepAlive::new(Duration::from_secs(DEFAULT_KEEP_ALIVE_TIMEOUT_SECS)),
-            next_message_id: MessageId(0),
-            message_stream: MessageStreamState::new(server, &options),
-            exponential_backoff: ExponentialBackoff::new(),
+            shutdown_timeout: None,
+            message_stream,
+            exponential_backoff: ExponentialBackoff::new(options.reconnect_policy.clone()),
+            connector,
             options,
             metrics,
         }
@@ -58,22 +60,56 @@ impl ClientSideChannel {
 
     pub fn send_message(
         &mut self,
-        mut message: OutgoingMessage,
+        message: OutgoingMessage,
         response_handler: Option<BoxResponseHandler>,
     ) {
-        message.header.id = self.next_message_id.next();
         if !self.message_stream.send_message(message, response_handler) {
             self.metrics.discarded_outgoing_messages.increment();
         }
     }
 
+    pub fn cancel_message(&mut self, message_id: MessageId) {
+        self.message_stream.cancel_message(message_id);
+    }
+
+    /// Starts shutting down the channel.
+    ///
+    /// The channel will be closed when all the queued messages have been sent and
+    /// all the outstanding responses have been received, or when `timeout` elapses.
+    pub fn shutdown(&mut self, timeout: Duration) {
+        if self.shutdown_timeout.is_none() {
+            info!(self.logger, "Starts shutting down: timeout={:?}", timeout);
+            self.shutdown_timeout = Some(timer::timeout(timeout));
+        }
+    }
+
+    fn poll_shutdown(&mut self) -> Result<bool> {
+        if self.shutdown_timeout.is_none() {
+            return Ok(false);
+        }
+        if self.message_stream.is_drained() {
+            info!(self.logger, "The channel was drained");
+            return Ok(true);
+        }
+        let is_expired = track!(self.shutdown_timeout.poll().map_err(from_timeout_error))?;
+        if let Async::Ready(Some(())) = is_expired {
+            warn!(
+                self.logger,
+                "Shutdown timeout expired: {:?}", self.message_stream
+            );
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
     pub fn force_wakeup(&mut self) {
         if let MessageStreamState::Wait { .. } = self.message_stream {
             info!(self.logger, "Waked up");
             self.exponential_backoff.next();
+            self.metrics.reconnects.increment();
             let next = MessageStreamState::Connecting {
                 buffer: Vec::new(),
-                future: tcp_connect(self.server, &self.options),
+                future: self.connector.connect(&self.server, &self.options),
             };
             self.update_message_stream_state(next);
         }
@@ -89,20 +125,30 @@ impl ClientSideChannel {
     }
 
     fn wait_or_reconnect(
-        server: SocketAddr,
+        server: &TransportAddr,
         backoff: &mut ExponentialBackoff,
         metrics: &ClientMetrics,
+        connector: &Connector,
         options: &ChannelOptions,
-    ) -> MessageStreamState {
+    ) -> Result<MessageStreamState> {
         metrics.channels().remove_channel_metrics(server);
+        if backoff.is_exhausted() {
+            metrics.reconnect_give_ups.increment();
+            track_panic!(
+                ErrorKind::Unavailable,
+                "Gave up reconnecting: attempts={}",
+                backoff.retried_count
+            );
+        }
         if let Some(timeout) = backoff.timeout() {
-            MessageStreamState::Wait { timeout }
+            Ok(MessageStreamState::Wait { timeout })
         } else {
             backoff.next();
-            MessageStreamState::Connecting {
+            metrics.reconnects.increment();
+            Ok(MessageStreamState::Connecting {
                 buffer: Vec::new(),
-                future: tcp_connect(server, options),
-            }
+                future: connector.connect(server, options),
+            })
         }
     }
 
@@ -116,9 +162,10 @@ impl ClientSideChannel {
                         "Reconnecting timeout expired; starts reconnecting"
                     );
                     self.exponential_backoff.next();
+                    self.metrics.reconnects.increment();
                     let next = MessageStreamState::Connecting {
                         buffer: Vec::new(),
-                        future: tcp_connect(self.server, &self.options),
+                        future: self.connector.connect(&self.server, &self.options),
                     };
                     Ok(Async::Ready(Some(next)))
                 } else {
@@ -128,66 +175,128 @@ impl ClientSideChannel {
             MessageStreamState::Connecting {
                 ref mut future,
                 ref mut buffer,
-            } => match track!(future.poll().map_err(Error::from)) {
+            } => match track!(future.poll()) {
                 Err(e) => {
-                    warn!(self.logger, "Failed to TCP connect: {}", e);
+                    warn!(self.logger, "Failed to connect: {}", e);
                     self.metrics
                         .discarded_outgoing_messages
                         .add_u64(buffer.len() as u64);
-                    let next = Self::wait_or_reconnect(
-                        self.server,
+                    for m in buffer.drain(..) {
+                        if let Some(mut handler) = m.handler {
+                            let e = ErrorKind::ConnectionLost {
+                                request_sent: false,
+                            }
+                            .cause("Failed to connect");
+                            handler.handle_error(track!(e).into());
+                        }
+                    }
+                    let next = track!(Self::wait_or_reconnect(
+                        &self.server,
                         &mut self.exponential_backoff,
                         &self.metrics,
+                        &self.connector,
                         &self.options,
-                    );
+                    ))?;
                     Ok(Async::Ready(Some(next)))
                 }
                 Ok(Async::NotReady) => Ok(Async::NotReady),
                 Ok(Async::Ready(stream)) => {
                     info!(
                         self.logger,
-                        "TCP connected: stream={:?}, buffered_messages={}",
+                        "Connected: stream={:?}, buffered_messages={}",
                         stream,
                         buffer.len()
                     );
                     let stream = MessageStream::new(
                         stream,
-                        Assigner::new(),
+                        Assigner::new(self.logger.clone(), self.metrics.clone()),
                         self.options.clone(),
-                        self.metrics.channels().create_channel_metrics(self.server),
+                        self.metrics.channels().create_channel_metrics(&self.server),
                     );
-                    let mut connected = MessageStreamState::Connected { stream };
+                    let mut connected = MessageStreamState::Connected {
+                        stream,
+                        is_going_away: false,
+                    };
                     for m in buffer.drain(..) {
                         connected.send_message(m.message, m.handler);
                     }
                     Ok(Async::Ready(Some(connected)))
                 }
             },
-            MessageStreamState::Connected { ref mut stream } => match track!(stream.poll()) {
+            MessageStreamState::Connected {
+                is_going_away: true,
+                ..
+            } if self.message_stream.is_drained() => {
+                info!(
+                    self.logger,
+                    "All outstanding requests are completed; closes the going away stream"
+                );
+                let next = track!(Self::wait_or_reconnect(
+                    &self.server,
+                    &mut self.exponential_backoff,
+                    &self.metrics,
+                    &self.connector,
+                    &self.options,
+                ))?;
+                Ok(Async::Ready(Some(next)))
+            }
+            MessageStreamState::Connected {
+                ref mut stream,
+                ref mut is_going_away,
+            } => match track!(stream.poll()) {
                 Err(e) => {
                     error!(self.logger, "Message stream aborted: {}", e);
-                    let next = Self::wait_or_reconnect(
-                        self.server,
+                    fail_pending_responses(stream, "Message stream aborted");
+                    let next = track!(Self::wait_or_reconnect(
+                        &self.server,
                         &mut self.exponential_backoff,
                         &self.metrics,
+                        &self.connector,
                         &self.options,
-                    );
+                    ))?;
                     Ok(Async::Ready(Some(next)))
                 }
                 Ok(Async::NotReady) => Ok(Async::NotReady),
                 Ok(Async::Ready(None)) => {
                     warn!(self.logger, "Message stream terminated");
-                    let next = Self::wait_or_reconnect(
-                        self.server,
+                    fail_pending_responses(stream, "Message stream terminated");
+                    let next = track!(Self::wait_or_reconnect(
+                        &self.server,
                         &mut self.exponential_backoff,
                         &self.metrics,
+                        &self.connector,
                         &self.options,
-                    );
+                    ))?;
                     Ok(Async::Ready(Some(next)))
                 }
-                Ok(Async::Ready(Some(_event))) => {
+                Ok(Async::Ready(Some(event))) => {
+                    // Only application messages keep the channel alive
+                    // (e.g., heartbeat pings exchanged on an idle channel do not)
+                    let mut is_active = false;
+                    match event {
+                        MessageEvent::Handshaked { agreed } => {
+                            debug!(self.logger, "Handshake completed: {:?}", agreed);
+                        }
+                        MessageEvent::Sent {
+                            message_id: Some(message_id),
+                        } => {
+                            stream.assigner_mut().mark_as_sent(message_id);
+                            is_active = true;
+                        }
+                        MessageEvent::Received { .. } => {
+                            is_active = true;
+                        }
+                        MessageEvent::GoAway => {
+                            info!(self.logger, "The server is going away");
+                            *is_going_away = true;
+                            self.is_server_down.store(true, atomic::Ordering::SeqCst);
+                        }
+                        _ => {}
+                    }
                     self.exponential_backoff.reset();
-                    self.keep_alive.extend_period();
+                    if is_active {
+                        self.keep_alive.extend_period();
+                    }
                     Ok(Async::Ready(None))
                 }
             },
@@ -208,6 +317,9 @@ impl Future for ClientSideChannel {
             if let Some(next) = next {
                 self.update_message_stream_state(next);
             }
+            if track!(self.poll_shutdown())? {
+                return Ok(Async::Ready(()));
+            }
 
             count += 1;
             if count > self.options.yield_threshold {
@@ -217,12 +329,18 @@ impl Future for ClientSideChannel {
                 return fibers::fiber::yield_poll();
             }
         }
+        if track!(self.poll_shutdown())? {
+            return Ok(Async::Ready(()));
+        }
         Ok(Async::NotReady)
     }
 }
 impl Drop for ClientSideChannel {
     fn drop(&mut self) {
-        self.metrics.channels().remove_channel_metrics(self.server);
+        if let MessageStreamState::Connected { ref mut stream, .. } = self.message_stream {
+            fail_pending_responses(stream, "Channel closed");
+        }
+        self.metrics.channels().remove_channel_metrics(&self.server);
     }
 }
 
@@ -233,24 +351,27 @@ enum MessageStreamState {
     },
     Connecting {
         buffer: Vec<BufferedMessage>,
-        future: Box<dyn Future<Item = TcpStream, Error = Error> + Send + 'static>,
+        future: crate::transport::Connect,
     },
     Connected {
         stream: MessageStream<Assigner>,
+
+        // If `true`, the server is shutting down and new messages should not be sent to it
+        is_going_away: bool,
     },
 }
 impl MessageStreamState {
-    fn new(addr: SocketAddr, options: &ChannelOptions) -> Self {
+    fn new(addr: &TransportAddr, connector: &Connector, options: &ChannelOptions) -> Self {
         MessageStreamState::Connecting {
             buffer: Vec::new(),
-            future: tcp_connect(addr, options),
+            future: connector.connect(addr, options),
         }
     }
 
     fn metrics(&self) -> Option<&ChannelMetrics> {
         match *self {
             MessageStreamState::Wait { .. } | MessageStreamState::Connecting { .. } => None,
-            MessageStreamState::Connected { ref stream } => Some(stream.metrics()),
+            MessageStreamState::Connected { ref stream, .. } => Some(stream.metrics()),
         }
     }
 
@@ -263,7 +384,7 @@ impl MessageStreamState {
             MessageStreamState::Wait { .. } => {
                 if let Some(mut handler) = handler {
                     let e = ErrorKind::Unavailable
-                        .cause("TCP stream disconnected (waiting for reconnecting)");
+                        .cause("Stream disconnected (waiting for reconnecting)");
                     handler.handle_error(track!(e).into());
                 }
                 false
@@ -272,7 +393,17 @@ impl MessageStreamState {
                 buffer.push(BufferedMessage { message, handler });
                 true
             }
-            MessageStreamState::Connected { ref mut stream } => {
+            MessageStreamState::Connected {
+                is_going_away: true,
+                ..
+            } => {
+                if let Some(mut handler) = handler {
+                    let e = ErrorKind::Unavailable.cause("The server is going away");
+                    handler.handle_error(track!(e).into());
+                }
+                false
+            }
+            MessageStreamState::Connected { ref mut stream, .. } => {
                 let message_id = message.header.id;
                 stream.send_message(message);
                 if let Some(handler) = handler {
@@ -284,6 +415,35 @@ impl MessageStreamState {
             }
         }
     }
+
+    /// Returns `true` if there are no messages to be sent and no responses to be received.
+    fn is_drained(&self) -> bool {
+        match *self {
+            MessageStreamState::Wait { .. } => true,
+            MessageStreamState::Connecting { ref buffer, .. } => buffer.is_empty(),
+            MessageStreamState::Connected { ref stream, .. } => {
+                !stream.is_sending() && !stream.assigner().has_pending_responses()
+            }
+        }
+    }
+
+    fn cancel_message(&mut self, message_id: MessageId) {
+        match *self {
+            MessageStreamState::Wait { .. } => {}
+            MessageStreamState::Connecting { ref mut buffer, .. } => {
+                buffer.retain(|m| m.message.header.id != message_id);
+            }
+            MessageStreamState::Connected { ref mut stream, .. } => {
+                let is_waiting_response = stream
+                    .assigner_mut()
+                    .unregister_response_handler(message_id)
+                    .is_some();
+                if is_waiting_response {
+                    stream.cancel_message(message_id);
+                }
+            }
+        }
+    }
 }
 impl fmt::Debug for MessageStreamState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -292,9 +452,14 @@ impl fmt::Debug for MessageStreamState {
             MessageStreamState::Connecting { buffer, .. } => {
                 write!(f, "Connecting {{ buffer: {:?}, .. }}", buffer)
             }
-            MessageStreamState::Connected { stream } => {
-                write!(f, "Connected {{ stream: {:?} }}", stream)
-            }
+            MessageStreamState::Connected {
+                stream,
+                is_going_away,
+            } => write!(
+                f,
+                "Connected {{ stream: {:?}, is_going_away: {} }}",
+                stream, is_going_away
+            ),
         }
     }
 }
@@ -357,11 +522,15 @@ impl Future for KeepAlive {
 
 #[derive(Debug)]
 struct ExponentialBackoff {
+    policy: ReconnectPolicy,
     retried_count: usize,
 }
 impl ExponentialBackoff {
-    fn new() -> Self {
-        ExponentialBackoff { retried_count: 0 }
+    fn new(policy: ReconnectPolicy) -> Self {
+        ExponentialBackoff {
+            policy,
+            retried_count: 0,
+        }
     }
     fn next(&mut self) {
         self.retried_count += 1;
@@ -370,28 +539,30 @@ impl ExponentialBackoff {
         if self.retried_count == 0 {
             None
         } else {
-            let duration = Duration::from_secs(2u64.pow(self.retried_count as u32 - 1));
+            let duration = self.policy.delay(self.retried_count);
             Some(timer::timeout(duration))
         }
     }
+    fn is_exhausted(&self) -> bool {
+        self.policy
+            .max_attempts
+            .is_some_and(|n| self.retried_count >= n)
+    }
     fn reset(&mut self) {
         self.retried_count = 0;
     }
 }
 
-fn from_timeout_error(_: RecvError) -> Error {
-    ErrorKind::Other.cause("Broken timer").into()
+/// Fails the outstanding requests of the stream with `ErrorKind::ConnectionLost`.
+fn fail_pending_responses(stream: &mut MessageStream<Assigner>, reason: &str) {
+    for handler in stream.receiving_handlers_mut() {
+        // The response is being received, so the request has been sent completely
+        let e = ErrorKind::ConnectionLost { request_sent: true }.cause(reason.to_owned());
+        handler.handle_error(track!(e).into());
+    }
+    stream.assigner_mut().fail_pending_responses(reason);
 }
 
-fn tcp_connect(
-    server: SocketAddr,
-    options: &ChannelOptions,
-) -> Box<dyn Future<Item = TcpStream, Error = Error> + Send + 'static> {
-    let future = TcpStream::connect(server)
-        .timeout_after(options.tcp_connect_timeout)
-        .map_err(|e| {
-            e.map(Error::from)
-                .unwrap_or_else(|| ErrorKind::Timeout.error().into())
-        });
-    Box::new(track_err!(future))
+fn from_timeout_error(_: RecvError) -> Error {
+    ErrorKind::Other.cause("Broken timer").into()
 }
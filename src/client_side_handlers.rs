@@ -1,21 +1,36 @@
-use crate::message::{AssignIncomingMessageHandler, MessageHeader, MessageId};
+use crate::circuit_breaker::Permit;
+use crate::client_service::ClientServiceHandle;
+use crate::error_reply::ErrorReplyDecoder;
+use crate::flow_control::Credit;
+use crate::item_stream::{self, ItemReceiver, ItemSender, ItemSlot};
+use crate::message::{AssignIncomingMessageHandler, IsolatedDecoder, MessageHeader, MessageId};
 use crate::metrics::ClientMetrics;
-use crate::{Error, ErrorKind, Result};
+use crate::rpc_client::RetryPolicy;
+use crate::{Error, ErrorKind, Result, TransportAddr};
+use bytecodec::padding::PaddingDecoder;
 use bytecodec::{self, ByteCount, Decode, Eos};
 use fibers::sync::oneshot;
 use fibers::time::timer::{self, Timeout};
-use futures::{Async, Future, Poll};
-use std::collections::HashMap;
+use futures::{Async, Future, Poll, Stream};
+use slog::Logger;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::mem;
 use std::sync::Arc;
 use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
 /// `Future` that represents a response from a RPC server.
+///
+/// If this future is dropped or timed out before the response arrives,
+/// the request will be cancelled (i.e., the server will be notified of the cancellation).
 #[derive(Debug)]
 pub struct Response<T> {
     reply_rx: oneshot::Monitor<T, Error>,
     timeout: Option<Timeout>,
+    canceller: Option<Canceller>,
+    permit: Option<Permit>,
+    retry: Option<Retry<T>>,
 }
 impl<T> Response<T> {
     pub(crate) fn error(e: Error) -> Self {
@@ -24,14 +39,46 @@ impl<T> Response<T> {
         Response {
             reply_rx: rx,
             timeout: None,
+            canceller: None,
+            permit: None,
+            retry: None,
         }
     }
-}
-impl<T> Future for Response<T> {
-    type Item = T;
-    type Error = Error;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    pub(crate) fn with_permit(mut self, permit: Option<Permit>) -> Self {
+        self.permit = permit;
+        self
+    }
+
+    pub(crate) fn with_retry(mut self, retry: Retry<T>) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Waits for the backoff of the retry (if any) and then starts the next attempt.
+    fn poll_backoff(&mut self) -> Poll<(), Error> {
+        let retry = match self.retry {
+            Some(ref mut retry) if retry.backoff.is_some() => retry,
+            _ => return Ok(Async::Ready(())),
+        };
+        let expired = track!(retry
+            .backoff
+            .poll()
+            .map_err(|_| Error::from(ErrorKind::Other.cause("Broken timer"))))?;
+        if expired.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+
+        retry.backoff = None;
+        let mut next = (retry.next_attempt)();
+        mem::swap(&mut self.reply_rx, &mut next.reply_rx);
+        mem::swap(&mut self.timeout, &mut next.timeout);
+        mem::swap(&mut self.canceller, &mut next.canceller);
+        mem::swap(&mut self.permit, &mut next.permit);
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_reply(&mut self) -> Poll<T, Error> {
         let item = self.reply_rx.poll().map_err(|e| {
             track!(e.unwrap_or_else(|| ErrorKind::Other
                 .cause("RPC response monitoring channel disconnected")
@@ -45,20 +92,220 @@ impl<T> Future for Response<T> {
                 .poll()
                 .map_err(|_| track!(ErrorKind::Other.cause("Broken timer")))?;
             if let Async::Ready(Some(())) = expired {
+                if let Some(canceller) = self.canceller.take() {
+                    canceller.cancel();
+                }
                 track_panic!(ErrorKind::Timeout);
             }
             Ok(Async::NotReady)
         }
     }
 }
+impl<T> Future for Response<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if track!(self.poll_backoff())?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+
+            let result = self.poll_reply();
+            if !matches!(result, Ok(Async::NotReady)) {
+                self.canceller = None;
+                if let Some(permit) = self.permit.take() {
+                    permit.complete(result.as_ref().map(|_| ()));
+                }
+            }
+            if let Err(ref e) = result {
+                if let Some(ref mut retry) = self.retry {
+                    if retry.start_backoff(e) {
+                        continue;
+                    }
+                }
+            }
+            return result;
+        }
+    }
+}
+
+/// The state of retrying a request.
+pub(crate) struct Retry<T> {
+    policy: RetryPolicy,
+    idempotent: bool,
+    attempts: usize,
+    backoff: Option<Timeout>,
+    next_attempt: Box<dyn FnMut() -> Response<T> + Send + 'static>,
+}
+impl<T> Retry<T> {
+    pub fn new(
+        policy: RetryPolicy,
+        idempotent: bool,
+        next_attempt: Box<dyn FnMut() -> Response<T> + Send + 'static>,
+    ) -> Self {
+        Retry {
+            policy,
+            idempotent,
+            attempts: 1,
+            backoff: None,
+            next_attempt,
+        }
+    }
+
+    /// Returns `true` if the failed request will be retried after a backoff.
+    fn start_backoff(&mut self, error: &Error) -> bool {
+        if self.attempts >= self.policy.max_attempts
+            || !self.policy.is_retryable(error.kind(), self.idempotent)
+        {
+            return false;
+        }
+        let backoff = self.policy.backoff(self.attempts);
+        self.attempts += 1;
+        self.backoff = Some(timer::timeout(backoff));
+        true
+    }
+}
+impl<T> fmt::Debug for Retry<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Retry {{ policy: {:?}, idempotent: {}, attempts: {}, .. }}",
+            self.policy, self.idempotent, self.attempts
+        )
+    }
+}
+impl<T> Drop for Response<T> {
+    fn drop(&mut self) {
+        if let Some(canceller) = self.canceller.take() {
+            canceller.cancel();
+        }
+    }
+}
+
+/// `Stream` that represents the items replied from a RPC server.
+///
+/// If this stream is dropped or timed out before the end of the stream arrives,
+/// the request will be cancelled (i.e., the server will be notified of the cancellation).
+#[derive(Debug)]
+pub struct ResponseStream<T> {
+    items: ItemReceiver<T>,
+    timeout: Option<Timeout>,
+    canceller: Option<Canceller>,
+    permit: Option<Permit>,
+    is_terminated: bool,
+}
+impl<T> ResponseStream<T> {
+    pub(crate) fn error(e: Error) -> Self {
+        ResponseStream {
+            items: ItemReceiver::error(e),
+            timeout: None,
+            canceller: None,
+            permit: None,
+            is_terminated: false,
+        }
+    }
+
+    pub(crate) fn with_permit(mut self, permit: Option<Permit>) -> Self {
+        self.permit = permit;
+        self
+    }
+
+    fn poll_item(&mut self) -> Poll<Option<T>, Error> {
+        if let Async::Ready(item) = track!(self.items.poll())? {
+            return Ok(Async::Ready(item));
+        }
+
+        let expired = self
+            .timeout
+            .poll()
+            .map_err(|_| track!(ErrorKind::Other.cause("Broken timer")))?;
+        if let Async::Ready(Some(())) = expired {
+            if let Some(canceller) = self.canceller.take() {
+                canceller.cancel();
+            }
+            track_panic!(ErrorKind::Timeout);
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<T> Stream for ResponseStream<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.is_terminated {
+            return Ok(Async::Ready(None));
+        }
+
+        let result = self.poll_item();
+        match result {
+            Ok(Async::NotReady) | Ok(Async::Ready(Some(_))) => {}
+            Ok(Async::Ready(None)) | Err(_) => {
+                self.is_terminated = true;
+                self.canceller = None;
+                if let Some(permit) = self.permit.take() {
+                    permit.complete(result.as_ref().map(|_| ()));
+                }
+            }
+        }
+        result
+    }
+}
+impl<T> Drop for ResponseStream<T> {
+    fn drop(&mut self) {
+        if let Some(canceller) = self.canceller.take() {
+            canceller.cancel();
+        }
+    }
+}
+
+/// Cancels an outstanding request.
+#[derive(Debug)]
+pub(crate) struct Canceller {
+    service: ClientServiceHandle,
+    server: TransportAddr,
+    message_id: MessageId,
+}
+impl Canceller {
+    pub fn new(service: ClientServiceHandle, server: TransportAddr, message_id: MessageId) -> Self {
+        Canceller {
+            service,
+            server,
+            message_id,
+        }
+    }
+
+    fn cancel(self) {
+        self.service.cancel_message(&self.server, self.message_id);
+    }
+}
 
-#[derive(Default)]
 pub struct Assigner {
     handlers: HashMap<MessageId, BoxResponseHandler>,
+    sent: HashSet<MessageId>,
+    logger: Logger,
+    metrics: ClientMetrics,
 }
 impl Assigner {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(logger: Logger, metrics: ClientMetrics) -> Self {
+        Assigner {
+            handlers: HashMap::new(),
+            sent: HashSet::new(),
+            logger,
+            metrics,
+        }
+    }
+
+    fn discard_unknown_response(&self, header: &MessageHeader) -> BoxResponseHandler {
+        debug!(
+            self.logger,
+            "Discards a response that no request is waiting for: id={:?}, stream_item={}",
+            header.id,
+            header.is_stream_item
+        );
+        self.metrics.unknown_responses.increment();
+        Box::new(DiscardResponseHandler::default())
     }
 
     pub fn register_response_handler(
@@ -68,18 +315,87 @@ impl Assigner {
     ) {
         self.handlers.insert(message_id, handler);
     }
+
+    /// Marks the request identified by `message_id` as completely sent.
+    pub fn mark_as_sent(&mut self, message_id: MessageId) {
+        if self.handlers.contains_key(&message_id) {
+            self.sent.insert(message_id);
+        }
+    }
+
+    /// Fails all the pending responses with `ErrorKind::ConnectionLost`.
+    pub fn fail_pending_responses(&mut self, reason: &str) {
+        for (message_id, mut handler) in self.handlers.drain() {
+            let request_sent = self.sent.contains(&message_id);
+            let e = ErrorKind::ConnectionLost { request_sent }.cause(reason.to_owned());
+            handler.handle_error(track!(e).into());
+        }
+        self.sent.clear();
+    }
+
+    pub fn has_pending_responses(&self) -> bool {
+        !self.handlers.is_empty()
+    }
+
+    pub fn unregister_response_handler(
+        &mut self,
+        message_id: MessageId,
+    ) -> Option<BoxResponseHandler> {
+        self.sent.remove(&message_id);
+        self.handlers.remove(&message_id)
+    }
 }
 impl AssignIncomingMessageHandler for Assigner {
     type Handler = BoxResponseHandler;
 
-    fn assign_incoming_message_handler(&mut self, header: &MessageHeader) -> Result<Self::Handler> {
-        let handler = track_assert_some!(
-            self.handlers.remove(&header.id),
-            ErrorKind::InvalidInput,
-            "header={:?}",
-            header
-        );
-        Ok(handler)
+    fn assign_incoming_message_handler(
+        &mut self,
+        header: &MessageHeader,
+        credit: Credit,
+    ) -> Result<Self::Handler> {
+        if header.is_stream_item {
+            // The handler of the stream remains registered until the end of the stream arrives
+            let handler = self
+                .handlers
+                .get_mut(&header.id)
+                .and_then(|h| h.item_handler(credit));
+            return Ok(handler.unwrap_or_else(|| self.discard_unknown_response(header)));
+        }
+
+        self.sent.remove(&header.id);
+        let handler = if let Some(handler) = self.handlers.remove(&header.id) {
+            handler
+        } else {
+            // e.g., a response to a timed out request
+            return Ok(self.discard_unknown_response(header));
+        };
+        if header.is_error {
+            Ok(Box::new(ErrorReplyHandler::new(handler)))
+        } else {
+            Ok(handler)
+        }
+    }
+
+    fn reject_incoming_message(
+        &mut self,
+        header: &MessageHeader,
+        handler: Option<Self::Handler>,
+        error: Error,
+    ) -> Self::Handler {
+        if let Some(mut handler) = handler {
+            handler.handle_error(error);
+        } else if header.is_stream_item {
+            // The stream fails with the error
+            if let Some(handler) = self.handlers.get_mut(&header.id) {
+                handler.handle_error(error);
+            }
+        } else {
+            self.sent.remove(&header.id);
+            if let Some(mut handler) = self.handlers.remove(&header.id) {
+                handler.handle_error(error);
+            }
+        }
+        Box::new(DiscardResponseHandler::default())
     }
 }
 impl fmt::Debug for Assigner {
@@ -92,6 +408,15 @@ pub type BoxResponseHandler = Box<dyn HandleResponse<Item = ()> + Send + 'static
 
 pub trait HandleResponse: Decode<Item = ()> {
     fn handle_error(&mut self, error: Error);
+
+    /// Returns a handler for decoding an item of the response stream.
+    ///
+    /// `credit` should be held until the item is consumed by the application.
+    ///
+    /// If the handler does not receive a stream, this returns `None`.
+    fn item_handler(&mut self, _credit: Credit) -> Option<BoxResponseHandler> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -105,6 +430,7 @@ impl<D: Decode> ResponseHandler<D> {
     pub fn new(
         decoder: D,
         timeout: Option<Duration>,
+        canceller: Canceller,
         metrics: Arc<ClientMetrics>,
         rpc_name: &'static str,
     ) -> (Self, Response<D::Item>) {
@@ -117,7 +443,13 @@ impl<D: Decode> ResponseHandler<D> {
         };
 
         let timeout = timeout.map(timer::timeout);
-        let response = Response { reply_rx, timeout };
+        let response = Response {
+            reply_rx,
+            timeout,
+            canceller: Some(canceller),
+            permit: None,
+            retry: None,
+        };
         (handler, response)
     }
 }
@@ -151,3 +483,208 @@ impl<D: Decode> HandleResponse for ResponseHandler<D> {
         self.metrics.error_responses.increment();
     }
 }
+
+/// Response handler for streaming RPC.
+///
+/// Each item of the stream is decoded by a handler made by `item_handler` method,
+/// and this handler itself decodes the (empty) message that ends the stream.
+pub struct StreamResponseHandler<D: Decode> {
+    decoder_factory: fn() -> D,
+    padding: PaddingDecoder,
+    items: ItemSender<D::Item>,
+    metrics: Arc<ClientMetrics>,
+    rpc_name: &'static str,
+}
+impl<D: Decode> StreamResponseHandler<D> {
+    pub fn new(
+        decoder_factory: fn() -> D,
+        timeout: Option<Duration>,
+        canceller: Canceller,
+        metrics: Arc<ClientMetrics>,
+        rpc_name: &'static str,
+    ) -> (Self, ResponseStream<D::Item>) {
+        let (items, item_rx) = item_stream::channel();
+        let handler = StreamResponseHandler {
+            decoder_factory,
+            padding: PaddingDecoder::default(),
+            items,
+            metrics,
+            rpc_name,
+        };
+
+        let timeout = timeout.map(timer::timeout);
+        let stream = ResponseStream {
+            items: item_rx,
+            timeout,
+            canceller: Some(canceller),
+            permit: None,
+            is_terminated: false,
+        };
+        (handler, stream)
+    }
+}
+impl<D: Decode> Decode for StreamResponseHandler<D> {
+    type Item = ();
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.padding.decode(buf, eos); self.rpc_name)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.padding.finish_decoding(); self.rpc_name)?;
+        self.items.reserve().send(Ok(None));
+        self.metrics.ok_responses.increment();
+        Ok(())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.padding.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.padding.is_idle()
+    }
+}
+impl<D> HandleResponse for StreamResponseHandler<D>
+where
+    D: Decode + Send + 'static,
+    D::Item: Send + 'static,
+{
+    fn handle_error(&mut self, error: Error) {
+        self.items.reserve().send(Err(error));
+        self.metrics.error_responses.increment();
+    }
+
+    fn item_handler(&mut self, credit: Credit) -> Option<BoxResponseHandler> {
+        let handler = StreamItemHandler {
+            decoder: IsolatedDecoder::new((self.decoder_factory)()),
+            slot: Some(self.items.reserve().with_credit(credit)),
+            rpc_name: self.rpc_name,
+        };
+        Some(Box::new(handler))
+    }
+}
+impl<D: Decode> fmt::Debug for StreamResponseHandler<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "StreamResponseHandler {{ items.reserved: {}, rpc_name: {:?}, .. }}",
+            self.items.reserved(),
+            self.rpc_name
+        )
+    }
+}
+
+/// Handler for decoding an item of a response stream.
+///
+/// If decoding fails, the stream fails with an `ErrorKind::DecodeFailed` error
+/// (the rest of the item is discarded and the other messages of the channel are not affected).
+struct StreamItemHandler<D: Decode> {
+    decoder: IsolatedDecoder<D>,
+    slot: Option<ItemSlot<D::Item>>,
+    rpc_name: &'static str,
+}
+impl<D: Decode> Decode for StreamItemHandler<D> {
+    type Item = ();
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.decoder.decode(buf, eos); self.rpc_name)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let item = self.decoder.finish_decoding().map_err(|e| {
+            let e = ErrorKind::DecodeFailed.cause(format!(
+                "Cannot decode an item of {:?}: {}",
+                self.rpc_name, e
+            ));
+            track!(Error::from(e))
+        });
+        if let Some(slot) = self.slot.take() {
+            slot.send(item.map(Some));
+        }
+        Ok(())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.decoder.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.decoder.is_idle()
+    }
+}
+impl<D: Decode> HandleResponse for StreamItemHandler<D> {
+    fn handle_error(&mut self, error: Error) {
+        if let Some(slot) = self.slot.take() {
+            slot.send(Err(error));
+        }
+    }
+}
+
+/// Response handler used when the server replied an error instead of a response message.
+struct ErrorReplyHandler {
+    decoder: ErrorReplyDecoder,
+    inner: BoxResponseHandler,
+}
+impl ErrorReplyHandler {
+    fn new(inner: BoxResponseHandler) -> Self {
+        ErrorReplyHandler {
+            decoder: ErrorReplyDecoder::default(),
+            inner,
+        }
+    }
+}
+impl Decode for ErrorReplyHandler {
+    type Item = ();
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.decoder.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let reply = track!(self.decoder.finish_decoding())?;
+        self.inner.handle_error(track!(reply.into_error()));
+        Ok(())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.decoder.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.decoder.is_idle()
+    }
+}
+impl HandleResponse for ErrorReplyHandler {
+    fn handle_error(&mut self, error: Error) {
+        self.inner.handle_error(error);
+    }
+}
+
+/// Response handler used when there is no handler waiting for the response.
+#[derive(Debug, Default)]
+struct DiscardResponseHandler {
+    padding: PaddingDecoder,
+}
+impl Decode for DiscardResponseHandler {
+    type Item = ();
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.padding.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.padding.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.padding.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.padding.is_idle()
+    }
+}
+impl HandleResponse for DiscardResponseHandler {
+    fn handle_error(&mut self, _error: Error) {}
+}
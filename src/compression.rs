@@ -0,0 +1,139 @@
+use crate::handshake::Capabilities;
+use crate::{ErrorKind, Result};
+
+const CODE_DEFLATE: u8 = 1;
+
+/// Compression algorithm applied to the payload of a message.
+///
+/// The algorithm is used only if both the sender and the receiver support it,
+/// otherwise the message is sent uncompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Compression {
+    /// No compression.
+    #[default]
+    None,
+
+    /// [DEFLATE] compression.
+    ///
+    /// This requires the `deflate` feature.
+    ///
+    /// [DEFLATE]: https://tools.ietf.org/html/rfc1951
+    Deflate,
+}
+impl Compression {
+    /// Returns `true` if this crate was built with the support of the algorithm.
+    pub fn is_supported(self) -> bool {
+        Capabilities::SUPPORTED.contains(self.capability())
+    }
+
+    /// Returns the capability required for using the algorithm in a channel.
+    pub(crate) fn capability(self) -> Capabilities {
+        match self {
+            Compression::None => Capabilities::default(),
+            Compression::Deflate => Capabilities::DEFLATE,
+        }
+    }
+}
+
+/// Compresses `payload` and prepends the code of the algorithm to it.
+pub fn compress(compression: Compression, payload: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => track_panic!(ErrorKind::InvalidInput, "No compression algorithm"),
+        Compression::Deflate => {
+            let mut buf = vec![CODE_DEFLATE];
+            track!(deflate::compress(payload, &mut buf))?;
+            Ok(buf)
+        }
+    }
+}
+
+/// Decompresses `buf` made by `compress` function.
+///
+/// If the decompressed payload is larger than `limit`,
+/// an `ErrorKind::InvalidInput` error is returned without decompressing the rest.
+pub fn decompress(buf: &[u8], limit: Option<usize>) -> Result<Vec<u8>> {
+    track_assert!(!buf.is_empty(), ErrorKind::InvalidInput, "Empty payload");
+    let mut payload = Vec::new();
+    let max_len = limit.map_or(u64::MAX, |n| (n as u64).saturating_add(1));
+    match buf[0] {
+        CODE_DEFLATE => track!(deflate::decompress(&buf[1..], max_len, &mut payload))?,
+        code => track_panic!(
+            ErrorKind::InvalidInput,
+            "Unknown compression algorithm: {}",
+            code
+        ),
+    }
+    if let Some(limit) = limit {
+        track_assert!(
+            payload.len() <= limit,
+            ErrorKind::InvalidInput,
+            "Too large message: limit={} bytes (decompressed)",
+            limit
+        );
+    }
+    Ok(payload)
+}
+
+#[cfg(feature = "deflate")]
+mod deflate {
+    use crate::{Error, ErrorKind, Result};
+    use libflate::deflate::{Decoder, Encoder};
+    use std::io::{Read, Write};
+    use trackable::error::ErrorKindExt;
+
+    pub fn compress(payload: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+        let mut encoder = Encoder::new(buf);
+        track!(encoder.write_all(payload).map_err(Error::from))?;
+        track!(encoder.finish().into_result().map_err(Error::from))?;
+        Ok(())
+    }
+
+    pub fn decompress(buf: &[u8], max_len: u64, payload: &mut Vec<u8>) -> Result<()> {
+        let mut decoder = Decoder::new(buf).take(max_len);
+        track!(decoder
+            .read_to_end(payload)
+            .map_err(|e| ErrorKind::DecodeFailed.cause(e)))?;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "deflate"))]
+mod deflate {
+    use crate::{ErrorKind, Result};
+
+    pub fn compress(_payload: &[u8], _buf: &mut Vec<u8>) -> Result<()> {
+        track_panic!(ErrorKind::InvalidInput, "The `deflate` feature is disabled")
+    }
+
+    pub fn decompress(_buf: &[u8], _max_len: u64, _payload: &mut Vec<u8>) -> Result<()> {
+        track_panic!(ErrorKind::InvalidInput, "The `deflate` feature is disabled")
+    }
+}
+
+#[cfg(all(test, feature = "deflate"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deflate_works() {
+        let payload = vec![b'a'; 1024];
+        let buf = compress(Compression::Deflate, &payload).unwrap();
+        assert_eq!(buf[0], CODE_DEFLATE);
+        assert!(buf.len() < payload.len());
+        assert_eq!(decompress(&buf, None).unwrap(), payload);
+        assert_eq!(decompress(&buf, Some(1024)).unwrap(), payload);
+
+        assert!(decompress(&[], None).is_err());
+        assert!(decompress(&[255], None).is_err());
+    }
+
+    #[test]
+    fn decompression_is_limited() {
+        let payload = vec![0; 16 * 1024 * 1024];
+        let buf = compress(Compression::Deflate, &payload).unwrap();
+        assert!(buf.len() < 64 * 1024);
+
+        let e = decompress(&buf, Some(1024 * 1024)).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,329 @@
+use crate::error_reply::ErrorReply;
+use crate::metrics::HandlerMetrics;
+use crate::{ErrorKind, ProcedureId};
+use fibers::sync::oneshot;
+use futures::{Async, Future, Poll};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Limit on the number of requests handled concurrently by an RPC server.
+///
+/// Requests exceeding `max_in_flight` wait in a queue until one of the in-flight requests completes.
+/// If the queue is full, they are rejected with `ErrorKind::Overloaded` errors.
+///
+/// The limits apply to the fibers spawned for requests, i.e., the replies made by `Reply::future`
+/// (or `Reply::try_future`), reply streams and the notification tasks made by `NoReply::future`.
+/// Notifications exceeding the limits are discarded because they cannot be replied.
+///
+/// Note that handlers themselves are invoked synchronously in the fiber of the connection,
+/// so replies made by `Reply::done` (and notifications made by `NoReply::done`) are not limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConcurrencyLimit {
+    /// The maximum number of requests being handled concurrently.
+    pub max_in_flight: usize,
+
+    /// The maximum number of requests waiting for being handled.
+    ///
+    /// If it is `0`, excess requests are rejected immediately.
+    pub max_queued: usize,
+}
+impl ConcurrencyLimit {
+    /// Makes a new `ConcurrencyLimit` instance which has no queue.
+    pub fn new(max_in_flight: usize) -> Self {
+        ConcurrencyLimit {
+            max_in_flight,
+            max_queued: 0,
+        }
+    }
+}
+
+/// The limiters shared by all channels of a server.
+#[derive(Debug, Default)]
+pub(crate) struct Limiters {
+    server: Option<Arc<Limiter>>,
+    procedures: HashMap<ProcedureId, Arc<Limiter>>,
+}
+impl Limiters {
+    pub fn new(
+        server: Option<ConcurrencyLimit>,
+        procedures: &HashMap<ProcedureId, ConcurrencyLimit>,
+    ) -> Self {
+        Limiters {
+            server: server.map(|limit| Arc::new(Limiter::new(limit))),
+            procedures: procedures
+                .iter()
+                .map(|(&id, &limit)| (id, Arc::new(Limiter::new(limit))))
+                .collect(),
+        }
+    }
+
+    /// Starts acquiring the permits needed for handling a request of `procedure`.
+    ///
+    /// The per-procedure permit is acquired before the per-server one,
+    /// so that requests waiting for the former do not occupy the latter.
+    pub fn acquire(&self, procedure: ProcedureId, metrics: HandlerMetrics) -> Acquire {
+        Acquire {
+            procedure: AcquireOne::Start(self.procedures.get(&procedure).cloned()),
+            server: AcquireOne::Start(self.server.clone()),
+            metrics,
+            is_queued: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Limiter {
+    limit: ConcurrencyLimit,
+    state: Mutex<LimiterState>,
+}
+impl Limiter {
+    fn new(limit: ConcurrencyLimit) -> Self {
+        Limiter {
+            limit,
+            state: Mutex::new(LimiterState {
+                in_flight: 0,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn try_acquire(this: &Arc<Self>) -> Option<AcquireOne> {
+        let mut state = this.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.in_flight < this.limit.max_in_flight {
+            state.in_flight += 1;
+            Some(AcquireOne::Acquired(Some(Permit {
+                limiter: Some(Arc::clone(this)),
+            })))
+        } else if state.waiters.len() < this.limit.max_queued {
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+            Some(AcquireOne::Waiting(rx))
+        } else {
+            None
+        }
+    }
+
+    /// Hands over the released slot to the next waiter (if any).
+    fn release(this: &Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut state = this.state.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(waiter) = state.waiters.pop_front() {
+                    waiter
+                } else {
+                    state.in_flight -= 1;
+                    return;
+                }
+            };
+            let permit = Permit {
+                limiter: Some(Arc::clone(this)),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                Err(mut e) => {
+                    // The waiter has been cancelled
+                    e.0.limiter = None;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    in_flight: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+#[derive(Debug)]
+struct Permit {
+    limiter: Option<Arc<Limiter>>,
+}
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            Limiter::release(&limiter);
+        }
+    }
+}
+
+/// The permits for handling a request.
+///
+/// The request is regarded as in-flight until this is dropped.
+#[derive(Debug)]
+pub(crate) struct Permits {
+    _procedure: Option<Permit>,
+    _server: Option<Permit>,
+    metrics: HandlerMetrics,
+}
+impl Drop for Permits {
+    fn drop(&mut self) {
+        self.metrics.in_flight.decrement();
+    }
+}
+
+#[derive(Debug)]
+enum AcquireOne {
+    Start(Option<Arc<Limiter>>),
+    Waiting(oneshot::Receiver<Permit>),
+    Acquired(Option<Permit>),
+}
+impl AcquireOne {
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let next = match self {
+                AcquireOne::Start(None) => AcquireOne::Acquired(None),
+                AcquireOne::Start(Some(limiter)) => Limiter::try_acquire(limiter).ok_or(())?,
+                AcquireOne::Waiting(rx) => {
+                    // `rx` never fails because the limiter outlives its waiters
+                    if let Async::Ready(permit) = rx.poll().map_err(|_| ())? {
+                        AcquireOne::Acquired(Some(permit))
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                AcquireOne::Acquired(_) => return Ok(Async::Ready(())),
+            };
+            *self = next;
+        }
+    }
+
+    fn is_waiting(&self) -> bool {
+        matches!(self, AcquireOne::Waiting(_))
+    }
+
+    fn take(&mut self) -> Option<Permit> {
+        if let AcquireOne::Acquired(permit) = self {
+            permit.take()
+        } else {
+            None
+        }
+    }
+}
+
+/// Future that acquires the permits for handling a request.
+///
+/// If one of the limits is exceeded and its queue is full, this fails with an `ErrorKind::Overloaded` error.
+#[derive(Debug)]
+pub(crate) struct Acquire {
+    procedure: AcquireOne,
+    server: AcquireOne,
+    metrics: HandlerMetrics,
+    is_queued: bool,
+}
+impl Acquire {
+    fn poll_permits(&mut self) -> Poll<(), ()> {
+        if self.procedure.poll()?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        self.server.poll()
+    }
+
+    fn set_queued(&mut self, is_queued: bool) {
+        if self.is_queued != is_queued {
+            self.is_queued = is_queued;
+            if is_queued {
+                self.metrics.queued.increment();
+            } else {
+                self.metrics.queued.decrement();
+            }
+        }
+    }
+}
+impl Future for Acquire {
+    type Item = Permits;
+    type Error = ErrorReply;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_permits();
+        let is_queued = self.procedure.is_waiting() || self.server.is_waiting();
+        self.set_queued(is_queued);
+        match result {
+            Err(()) => {
+                self.metrics.overloaded.increment();
+                Err(ErrorReply::new(
+                    ErrorKind::Overloaded,
+                    "Too many requests are being handled by the server",
+                ))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {
+                self.metrics.in_flight.increment();
+                Ok(Async::Ready(Permits {
+                    _procedure: self.procedure.take(),
+                    _server: self.server.take(),
+                    metrics: self.metrics.clone(),
+                }))
+            }
+        }
+    }
+}
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        self.set_queued(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometrics::metrics::MetricBuilder;
+
+    fn metrics() -> HandlerMetrics {
+        HandlerMetrics::new(
+            MetricBuilder::without_registry(),
+            ProcedureId(0),
+            "foo",
+            "call",
+        )
+    }
+
+    #[test]
+    fn concurrency_limit_works() {
+        let mut limits = HashMap::new();
+        limits.insert(
+            ProcedureId(0),
+            ConcurrencyLimit {
+                max_in_flight: 1,
+                max_queued: 1,
+            },
+        );
+        let limiters = Limiters::new(Some(ConcurrencyLimit::new(2)), &limits);
+        let metrics = metrics();
+
+        let mut first = limiters.acquire(ProcedureId(0), metrics.clone());
+        let permits = match first.poll() {
+            Ok(Async::Ready(permits)) => permits,
+            _ => panic!(),
+        };
+        assert_eq!(metrics.in_flight(), 1);
+
+        // Queued
+        let mut second = limiters.acquire(ProcedureId(0), metrics.clone());
+        assert!(second.poll().unwrap().is_not_ready());
+        assert_eq!(metrics.queued(), 1);
+
+        // Rejected (the queue is full)
+        let mut third = limiters.acquire(ProcedureId(0), metrics.clone());
+        assert!(third.poll().is_err());
+        assert_eq!(metrics.overloaded(), 1);
+
+        // The other procedure is limited only by the server limit
+        let other_metrics = metrics.clone();
+        let mut other = limiters.acquire(ProcedureId(1), other_metrics.clone());
+        let other_permits = match other.poll() {
+            Ok(Async::Ready(permits)) => permits,
+            _ => panic!(),
+        };
+        let mut another = limiters.acquire(ProcedureId(1), other_metrics);
+        assert!(another.poll().is_err());
+        drop(other_permits);
+
+        // The released slot is handed over to the queued request
+        drop(permits);
+        assert_eq!(metrics.in_flight(), 0);
+        let _permits = fibers_global::execute(second).unwrap();
+        assert_eq!(metrics.in_flight(), 1);
+        assert_eq!(metrics.queued(), 0);
+    }
+}
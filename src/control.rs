@@ -0,0 +1,253 @@
+use crate::compression::Compression;
+use crate::message::{MessageHeader, MessageId, OutgoingMessage, OutgoingMessagePayload};
+use crate::ProcedureId;
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::{self, ByteCount, Decode, Eos, ErrorKind};
+use byteorder::{BigEndian, ByteOrder};
+
+const TYPE_CANCEL: u8 = 0;
+const TYPE_GO_AWAY: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_PONG: u8 = 3;
+const TYPE_CHANNEL_WINDOW_UPDATE: u8 = 4;
+const TYPE_STREAM_WINDOW_UPDATE: u8 = 5;
+
+/// The byte size of the largest control message (i.e., `WindowUpdate` of a stream).
+const MAX_CONTROL_MESSAGE_LEN: usize = 1 + 8 + 4;
+
+/// A message used for controlling a channel.
+///
+/// Control messages are handled by `MessageStream` itself and never passed to RPC handlers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Requests the peer to cancel handling of the message (i.e., RPC request).
+    Cancel { message_id: MessageId },
+
+    /// Notifies the peer that the sender is shutting down and will not accept new messages.
+    GoAway,
+
+    /// Requests the peer to reply a `Pong` message which has the same nonce.
+    Ping { nonce: u64 },
+
+    /// The reply to a `Ping` message.
+    Pong { nonce: u64 },
+
+    /// Grants the peer `increment` more bytes for sending payloads.
+    ///
+    /// If `message_id` is `None`, the window of the channel is updated,
+    /// otherwise the one of the stream identified by `message_id` is updated.
+    WindowUpdate {
+        message_id: Option<MessageId>,
+        increment: u32,
+    },
+}
+impl ControlMessage {
+    /// Converts to an outgoing message.
+    ///
+    /// `id` is used for distinguishing control messages being transmitted at the same time,
+    /// so it does not need to be unique among ordinary messages.
+    pub fn into_outgoing_message(self, id: MessageId) -> OutgoingMessage {
+        let header = MessageHeader {
+            id,
+            procedure: ProcedureId(0),
+            priority: 0,
+            is_async: false,
+            is_error: false,
+            is_control: true,
+            has_extension: false,
+            compression: Compression::None,
+            is_stream_item: false,
+            is_notification: false,
+        };
+        let payload = OutgoingMessagePayload::with_item(BytesEncoder::new(), self.to_bytes());
+        OutgoingMessage { header, payload }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            ControlMessage::Cancel { message_id } => {
+                let mut buf = vec![TYPE_CANCEL; 1 + 8];
+                BigEndian::write_u64(&mut buf[1..], message_id.0);
+                buf
+            }
+            ControlMessage::GoAway => vec![TYPE_GO_AWAY],
+            ControlMessage::Ping { nonce } => {
+                let mut buf = vec![TYPE_PING; 1 + 8];
+                BigEndian::write_u64(&mut buf[1..], nonce);
+                buf
+            }
+            ControlMessage::Pong { nonce } => {
+                let mut buf = vec![TYPE_PONG; 1 + 8];
+                BigEndian::write_u64(&mut buf[1..], nonce);
+                buf
+            }
+            ControlMessage::WindowUpdate {
+                message_id: None,
+                increment,
+            } => {
+                let mut buf = vec![TYPE_CHANNEL_WINDOW_UPDATE; 1 + 4];
+                BigEndian::write_u32(&mut buf[1..], increment);
+                buf
+            }
+            ControlMessage::WindowUpdate {
+                message_id: Some(message_id),
+                increment,
+            } => {
+                let mut buf = vec![TYPE_STREAM_WINDOW_UPDATE; 1 + 8 + 4];
+                BigEndian::write_u64(&mut buf[1..], message_id.0);
+                BigEndian::write_u32(&mut buf[9..], increment);
+                buf
+            }
+        }
+    }
+
+    fn from_bytes(buf: &[u8]) -> bytecodec::Result<Self> {
+        track_assert!(!buf.is_empty(), ErrorKind::InvalidInput);
+        match buf[0] {
+            TYPE_CANCEL => {
+                track_assert_eq!(buf.len(), 1 + 8, ErrorKind::InvalidInput);
+                let message_id = MessageId(BigEndian::read_u64(&buf[1..]));
+                Ok(ControlMessage::Cancel { message_id })
+            }
+            TYPE_GO_AWAY => {
+                track_assert_eq!(buf.len(), 1, ErrorKind::InvalidInput);
+                Ok(ControlMessage::GoAway)
+            }
+            TYPE_PING => {
+                track_assert_eq!(buf.len(), 1 + 8, ErrorKind::InvalidInput);
+                let nonce = BigEndian::read_u64(&buf[1..]);
+                Ok(ControlMessage::Ping { nonce })
+            }
+            TYPE_PONG => {
+                track_assert_eq!(buf.len(), 1 + 8, ErrorKind::InvalidInput);
+                let nonce = BigEndian::read_u64(&buf[1..]);
+                Ok(ControlMessage::Pong { nonce })
+            }
+            TYPE_CHANNEL_WINDOW_UPDATE => {
+                track_assert_eq!(buf.len(), 1 + 4, ErrorKind::InvalidInput);
+                let increment = BigEndian::read_u32(&buf[1..]);
+                Ok(ControlMessage::WindowUpdate {
+                    message_id: None,
+                    increment,
+                })
+            }
+            TYPE_STREAM_WINDOW_UPDATE => {
+                track_assert_eq!(buf.len(), 1 + 8 + 4, ErrorKind::InvalidInput);
+                let message_id = MessageId(BigEndian::read_u64(&buf[1..]));
+                let increment = BigEndian::read_u32(&buf[9..]);
+                Ok(ControlMessage::WindowUpdate {
+                    message_id: Some(message_id),
+                    increment,
+                })
+            }
+            t => track_panic!(
+                ErrorKind::InvalidInput,
+                "Unknown control message type: {}",
+                t
+            ),
+        }
+    }
+}
+
+/// Decoder for control messages.
+///
+/// Payloads larger than any control message are rejected before being buffered.
+#[derive(Debug, Default)]
+pub struct ControlMessageDecoder {
+    bytes: RemainingBytesDecoder,
+    len: usize,
+}
+impl Decode for ControlMessageDecoder {
+    type Item = ControlMessage;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track_assert!(
+            self.len + buf.len() <= MAX_CONTROL_MESSAGE_LEN,
+            ErrorKind::InvalidInput,
+            "Too large control message: {} bytes",
+            self.len + buf.len()
+        );
+        let size = track!(self.bytes.decode(buf, eos))?;
+        self.len += size;
+        Ok(size)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.len = 0;
+        let buf = track!(self.bytes.finish_decoding())?;
+        track!(ControlMessage::from_bytes(&buf))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.bytes.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.bytes.is_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_message_bytes_works() {
+        let message = ControlMessage::Cancel {
+            message_id: MessageId(123),
+        };
+        let bytes = message.to_bytes();
+        assert_eq!(ControlMessage::from_bytes(&bytes).unwrap(), message);
+
+        let bytes = ControlMessage::GoAway.to_bytes();
+        assert_eq!(
+            ControlMessage::from_bytes(&bytes).unwrap(),
+            ControlMessage::GoAway
+        );
+
+        let message = ControlMessage::Ping { nonce: 10 };
+        let bytes = message.to_bytes();
+        assert_eq!(ControlMessage::from_bytes(&bytes).unwrap(), message);
+
+        let message = ControlMessage::Pong { nonce: 10 };
+        let bytes = message.to_bytes();
+        assert_eq!(ControlMessage::from_bytes(&bytes).unwrap(), message);
+
+        let message = ControlMessage::WindowUpdate {
+            message_id: None,
+            increment: 1024,
+        };
+        let bytes = message.to_bytes();
+        assert_eq!(bytes.len(), 1 + 4);
+        assert_eq!(ControlMessage::from_bytes(&bytes).unwrap(), message);
+
+        let message = ControlMessage::WindowUpdate {
+            message_id: Some(MessageId(3)),
+            increment: 1024,
+        };
+        let bytes = message.to_bytes();
+        assert_eq!(bytes.len(), 1 + 8 + 4);
+        assert_eq!(ControlMessage::from_bytes(&bytes).unwrap(), message);
+        assert!(ControlMessage::from_bytes(&[255]).is_err());
+    }
+
+    #[test]
+    fn too_large_control_message_is_rejected() {
+        let mut decoder = ControlMessageDecoder::default();
+        let bytes = ControlMessage::WindowUpdate {
+            message_id: Some(MessageId(3)),
+            increment: 1024,
+        }
+        .to_bytes();
+        decoder.decode(&bytes[..4], Eos::new(false)).unwrap();
+        decoder.decode(&bytes[4..], Eos::new(false)).unwrap();
+        decoder.decode(&[], Eos::new(true)).unwrap();
+        assert!(decoder.finish_decoding().is_ok());
+
+        decoder.decode(&bytes, Eos::new(false)).unwrap();
+        assert!(decoder.decode(&[0], Eos::new(false)).is_err());
+
+        let mut decoder = ControlMessageDecoder::default();
+        assert!(decoder.decode(&[0; 1024], Eos::new(false)).is_err());
+    }
+}
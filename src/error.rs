@@ -1,11 +1,8 @@
-use bytecodec;
-use std;
 use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt, Failure, TrackableError};
 
 /// This crate specific `Error` type.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, TrackableError)]
 pub struct Error(TrackableError<ErrorKind>);
-derive_traits_for_trackable_error_newtype!(Error, ErrorKind);
 impl From<Failure> for Error {
     fn from(f: Failure) -> Self {
         ErrorKind::Other.takes_over(f).into()
@@ -27,7 +24,10 @@ impl From<bytecodec::Error> for Error {
 }
 
 /// Possible error kinds.
+///
+/// New kinds may be added in future versions, so matches against this enum need a wildcard arm.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum ErrorKind {
     /// Input is invalid.
     InvalidInput,
@@ -38,6 +38,29 @@ pub enum ErrorKind {
     /// Request timed out.
     Timeout,
 
+    /// RPC server replied an error instead of a response message.
+    ServerError,
+
+    /// The requested procedure is not registered in the RPC server.
+    UnknownProcedure,
+
+    /// RPC server failed to decode the request message.
+    DecodeFailed,
+
+    /// The connection to the RPC server was lost (or could not be established) before the response arrived.
+    ///
+    /// If `request_sent` is `false`, the request had not been completely sent,
+    /// so the server has never handled it (i.e., retrying is safe).
+    ConnectionLost {
+        /// Whether the request message had been completely sent.
+        request_sent: bool,
+    },
+
+    /// RPC server rejected the request because too many requests were being handled.
+    ///
+    /// The request has never been handled by the server, so retrying is safe.
+    Overloaded,
+
     /// Other errors.
     Other,
 }
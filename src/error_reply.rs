@@ -0,0 +1,127 @@
+use crate::{Error, ErrorKind};
+use bytecodec::bytes::{Utf8Decoder, Utf8Encoder};
+use bytecodec::fixnum::{U8Decoder, U8Encoder};
+use bytecodec::tuple::{TupleDecoder, TupleEncoder};
+use bytecodec::{self, ByteCount, Decode, Encode, Eos};
+use trackable::error::ErrorKindExt;
+
+/// An error replied from an RPC server instead of a response message.
+#[derive(Debug, Clone)]
+pub struct ErrorReply {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+impl ErrorReply {
+    pub fn new<T: Into<String>>(kind: ErrorKind, message: T) -> Self {
+        ErrorReply {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn into_error(self) -> Error {
+        self.kind.cause(self.message).into()
+    }
+}
+
+fn kind_to_code(kind: ErrorKind) -> u8 {
+    match kind {
+        ErrorKind::Other => 0,
+        ErrorKind::InvalidInput => 1,
+        ErrorKind::Unavailable => 2,
+        ErrorKind::Timeout => 3,
+        ErrorKind::ServerError => 4,
+        ErrorKind::UnknownProcedure => 5,
+        ErrorKind::DecodeFailed => 6,
+        ErrorKind::Overloaded => 7,
+
+        // This is a client side error and is relayed as a temporary failure
+        ErrorKind::ConnectionLost { .. } => 2,
+    }
+}
+
+fn code_to_kind(code: u8) -> ErrorKind {
+    match code {
+        1 => ErrorKind::InvalidInput,
+        2 => ErrorKind::Unavailable,
+        3 => ErrorKind::Timeout,
+        4 => ErrorKind::ServerError,
+        5 => ErrorKind::UnknownProcedure,
+        6 => ErrorKind::DecodeFailed,
+        7 => ErrorKind::Overloaded,
+        _ => ErrorKind::Other,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ErrorReplyEncoder {
+    inner: TupleEncoder<(U8Encoder, Utf8Encoder)>,
+}
+impl Encode for ErrorReplyEncoder {
+    type Item = ErrorReply;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.inner.encode(buf, eos))
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        track!(self
+            .inner
+            .start_encoding((kind_to_code(item.kind), item.message)))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ErrorReplyDecoder {
+    inner: TupleDecoder<(U8Decoder, Utf8Decoder)>,
+}
+impl Decode for ErrorReplyDecoder {
+    type Item = ErrorReply;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.inner.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let (code, message) = track!(self.inner.finish_decoding())?;
+        Ok(ErrorReply::new(code_to_kind(code), message))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecodec::EncodeExt;
+
+    #[test]
+    fn error_reply_codec_works() {
+        let reply = ErrorReply::new(ErrorKind::ServerError, "foo");
+        let mut encoder = ErrorReplyEncoder::with_item(reply).unwrap();
+        let mut buf = [0; 16];
+        let size = encoder.encode(&mut buf[..], Eos::new(true)).unwrap();
+        assert!(encoder.is_idle());
+        assert_eq!(&buf[..size], b"\x04foo");
+
+        let mut decoder = ErrorReplyDecoder::default();
+        decoder.decode(&buf[..size], Eos::new(true)).unwrap();
+        let reply = decoder.finish_decoding().unwrap();
+        assert_eq!(reply.kind, ErrorKind::ServerError);
+        assert_eq!(reply.message, "foo");
+    }
+}
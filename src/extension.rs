@@ -0,0 +1,344 @@
+use crate::Result;
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::combinator::{AndThen, Length};
+use bytecodec::fixnum::U32beDecoder;
+use bytecodec::tuple::TupleEncoder;
+use bytecodec::{self, ByteCount, Decode, DecodeExt, Encode, Eos, ErrorKind};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::str;
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
+
+const TYPE_DEADLINE: u8 = 0;
+const TYPE_METADATA: u8 = 1;
+
+/// The maximum size of the value of an entry.
+const MAX_ENTRY_VALUE_LEN: usize = 0xFFFF;
+
+/// Additional information of a message which is placed in front of the payload.
+///
+/// This is transmitted only if the message header has the `has_extension` flag.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderExtension {
+    /// The time when the caller gives up the request.
+    ///
+    /// This is transmitted as the remaining time at the moment the extension is encoded,
+    /// and converted back to an instant (on the receiver's clock) when it is decoded.
+    pub deadline: Option<Instant>,
+
+    /// Key/value pairs attached to the message by the caller.
+    pub metadata: HashMap<String, Vec<u8>>,
+}
+impl HeaderExtension {
+    pub fn is_empty(&self) -> bool {
+        self.deadline.is_none() && self.metadata.is_empty()
+    }
+
+    /// Checks whether this extension can be encoded.
+    pub fn validate(&self) -> Result<()> {
+        for (key, value) in &self.metadata {
+            track_assert!(
+                2 + key.len() + value.len() <= MAX_ENTRY_VALUE_LEN,
+                crate::ErrorKind::InvalidInput,
+                "Too large metadata entry: key={:?}, value_len={}",
+                key,
+                value.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let micros = remaining.as_secs() * 1_000_000 + u64::from(remaining.subsec_micros());
+            let mut value = [0; 8];
+            BigEndian::write_u64(&mut value, micros);
+            put_entry(&mut buf, TYPE_DEADLINE, &value);
+        }
+        for (key, value) in &self.metadata {
+            let mut entry = vec![0; 2];
+            BigEndian::write_u16(&mut entry, key.len() as u16);
+            entry.extend_from_slice(key.as_bytes());
+            entry.extend_from_slice(value);
+            put_entry(&mut buf, TYPE_METADATA, &entry);
+        }
+        let len = buf.len() as u32 - 4;
+        BigEndian::write_u32(&mut buf, len);
+        buf
+    }
+
+    fn from_bytes(mut buf: &[u8]) -> bytecodec::Result<Self> {
+        let mut extension = HeaderExtension::default();
+        while !buf.is_empty() {
+            track_assert!(buf.len() >= 3, ErrorKind::InvalidInput);
+            let ty = buf[0];
+            let len = BigEndian::read_u16(&buf[1..]) as usize;
+            track_assert!(buf.len() >= 3 + len, ErrorKind::InvalidInput);
+            let value = &buf[3..][..len];
+            match ty {
+                TYPE_DEADLINE => {
+                    track_assert_eq!(value.len(), 8, ErrorKind::InvalidInput);
+                    let micros = BigEndian::read_u64(value);
+
+                    // Deadlines which cannot be represented as an instant are regarded as no deadline
+                    extension.deadline = Instant::now().checked_add(Duration::from_micros(micros));
+                }
+                TYPE_METADATA => {
+                    track_assert!(value.len() >= 2, ErrorKind::InvalidInput);
+                    let key_len = BigEndian::read_u16(value) as usize;
+                    track_assert!(value.len() >= 2 + key_len, ErrorKind::InvalidInput);
+                    let key = track!(str::from_utf8(&value[2..][..key_len])
+                        .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+                    let value = value[2 + key_len..].to_vec();
+                    extension.metadata.insert(key.to_owned(), value);
+                }
+                _ => {
+                    // Unknown entries are ignored for forward compatibility
+                }
+            }
+            buf = &buf[3 + len..];
+        }
+        Ok(extension)
+    }
+}
+
+fn put_entry(buf: &mut Vec<u8>, ty: u8, value: &[u8]) {
+    let mut entry_header = [ty, 0, 0];
+    BigEndian::write_u16(&mut entry_header[1..], value.len() as u16);
+    buf.extend_from_slice(&entry_header);
+    buf.extend_from_slice(value);
+}
+
+#[derive(Debug, Default)]
+pub struct HeaderExtensionEncoder {
+    bytes: BytesEncoder<Vec<u8>>,
+}
+impl Encode for HeaderExtensionEncoder {
+    type Item = HeaderExtension;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.bytes.encode(buf, eos))
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        track!(self.bytes.start_encoding(item.to_bytes()))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.bytes.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.bytes.is_idle()
+    }
+}
+
+type LengthPrefixedBytesDecoder =
+    AndThen<U32beDecoder, Length<RemainingBytesDecoder>, fn(u32) -> Length<RemainingBytesDecoder>>;
+
+#[derive(Debug)]
+pub struct HeaderExtensionDecoder {
+    bytes: LengthPrefixedBytesDecoder,
+}
+impl Default for HeaderExtensionDecoder {
+    fn default() -> Self {
+        let f: fn(u32) -> Length<RemainingBytesDecoder> =
+            |len| RemainingBytesDecoder::new().length(u64::from(len));
+        HeaderExtensionDecoder {
+            bytes: U32beDecoder::new().and_then(f),
+        }
+    }
+}
+impl Decode for HeaderExtensionDecoder {
+    type Item = HeaderExtension;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.bytes.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let bytes = track!(self.bytes.finish_decoding())?;
+        track!(HeaderExtension::from_bytes(&bytes))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.bytes.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.bytes.is_idle()
+    }
+}
+
+/// Encoder for messages which have header extensions.
+#[derive(Debug, Default)]
+pub struct ExtendedEncoder<E> {
+    inner: TupleEncoder<(HeaderExtensionEncoder, E)>,
+}
+impl<E: Encode> ExtendedEncoder<E> {
+    pub fn new(inner: E) -> Self {
+        ExtendedEncoder {
+            inner: TupleEncoder::new((HeaderExtensionEncoder::default(), inner)),
+        }
+    }
+}
+impl<E: Encode> Encode for ExtendedEncoder<E> {
+    type Item = (HeaderExtension, E::Item);
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.inner.encode(buf, eos))
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        track!(self.inner.start_encoding(item))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+/// Decoder for messages which may have header extensions.
+///
+/// If the message has no extension, `HeaderExtension::default()` is returned as the extension.
+#[derive(Debug)]
+pub struct ExtendedDecoder<D> {
+    extension: Option<HeaderExtensionDecoder>,
+    decoded_extension: Option<HeaderExtension>,
+    inner: D,
+}
+impl<D: Decode> ExtendedDecoder<D> {
+    pub fn new(inner: D, has_extension: bool) -> Self {
+        let (extension, decoded_extension) = if has_extension {
+            (Some(HeaderExtensionDecoder::default()), None)
+        } else {
+            (None, Some(HeaderExtension::default()))
+        };
+        ExtendedDecoder {
+            extension,
+            decoded_extension,
+            inner,
+        }
+    }
+}
+impl<D: Decode> Decode for ExtendedDecoder<D> {
+    type Item = (HeaderExtension, D::Item);
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let mut offset = 0;
+        if let Some(mut decoder) = self.extension.take() {
+            offset += track!(decoder.decode(buf, eos))?;
+            if decoder.is_idle() {
+                self.decoded_extension = Some(track!(decoder.finish_decoding())?);
+            } else {
+                self.extension = Some(decoder);
+                return Ok(offset);
+            }
+        }
+        offset += track!(self.inner.decode(&buf[offset..], eos))?;
+        Ok(offset)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let extension =
+            track_assert_some!(self.decoded_extension.take(), ErrorKind::IncompleteDecoding);
+        let item = track!(self.inner.finish_decoding())?;
+        Ok((extension, item))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if let Some(ref decoder) = self.extension {
+            decoder.requiring_bytes()
+        } else {
+            self.inner.requiring_bytes()
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.extension.is_none() && self.inner.is_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecodec::bytes::Utf8Decoder;
+    use bytecodec::io::IoDecodeExt;
+
+    #[test]
+    fn header_extension_bytes_works() {
+        let mut extension = HeaderExtension::default();
+        extension
+            .metadata
+            .insert("trace_id".to_owned(), b"123".to_vec());
+        extension.metadata.insert("empty".to_owned(), Vec::new());
+        let bytes = extension.to_bytes();
+        assert_eq!(BigEndian::read_u32(&bytes) as usize, bytes.len() - 4);
+        assert_eq!(HeaderExtension::from_bytes(&bytes[4..]).unwrap(), extension);
+
+        // Unknown entries are ignored
+        let mut bytes = bytes[4..].to_vec();
+        put_entry(&mut bytes, 255, b"foo");
+        assert_eq!(HeaderExtension::from_bytes(&bytes).unwrap(), extension);
+
+        // Too large metadata
+        assert!(extension.validate().is_ok());
+        extension
+            .metadata
+            .insert("large".to_owned(), vec![0; MAX_ENTRY_VALUE_LEN]);
+        assert!(extension.validate().is_err());
+    }
+
+    #[test]
+    fn deadline_bytes_works() {
+        let started_at = Instant::now();
+        let extension = HeaderExtension {
+            deadline: Some(started_at + Duration::from_secs(3)),
+            metadata: HashMap::new(),
+        };
+        let bytes = extension.to_bytes();
+        let decoded = HeaderExtension::from_bytes(&bytes[4..]).unwrap();
+        let deadline = decoded.deadline.unwrap();
+        assert!(deadline <= Instant::now() + Duration::from_secs(3));
+        assert!(deadline >= started_at + Duration::from_secs(2));
+
+        // Passed deadlines are sent as zero
+        let extension = HeaderExtension {
+            deadline: Some(started_at),
+            metadata: HashMap::new(),
+        };
+        let bytes = extension.to_bytes();
+        let decoded = HeaderExtension::from_bytes(&bytes[4..]).unwrap();
+        assert!(decoded.deadline.unwrap() <= Instant::now());
+
+        // Too far deadlines do not cause overflows
+        let mut bytes = Vec::new();
+        put_entry(&mut bytes, TYPE_DEADLINE, &[0xFF; 8]);
+        assert!(HeaderExtension::from_bytes(&bytes).is_ok());
+    }
+
+    #[test]
+    fn extended_decoder_works() {
+        let mut extension = HeaderExtension::default();
+        extension
+            .metadata
+            .insert("trace_id".to_owned(), b"123".to_vec());
+        let mut bytes = extension.to_bytes();
+        bytes.extend_from_slice(b"hello");
+
+        let mut decoder = ExtendedDecoder::new(Utf8Decoder::new(), true);
+        let item = decoder.decode_exact(&bytes[..]).unwrap();
+        assert_eq!(item, (extension, "hello".to_owned()));
+
+        let mut decoder = ExtendedDecoder::new(Utf8Decoder::new(), false);
+        let item = decoder.decode_exact(&b"hello"[..]).unwrap();
+        assert_eq!(item, (HeaderExtension::default(), "hello".to_owned()));
+    }
+}
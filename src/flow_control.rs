@@ -0,0 +1,453 @@
+use crate::channel::ChannelOptions;
+use crate::control::ControlMessage;
+use crate::message::{MessageHeader, MessageId};
+use crate::{ErrorKind, Result};
+use fibers::sync::mpsc;
+use futures::{Async, Stream};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The window size that both sides of a channel assume before receiving any window updates.
+pub const INITIAL_WINDOW_SIZE: u32 = 64 * 1024;
+
+/// The credit for the payload bytes of an incoming message.
+///
+/// The bytes are released (i.e., granted to the peer again) when all the clones of the credit are dropped.
+#[derive(Debug, Clone)]
+pub struct Credit(Arc<CreditInner>);
+impl Credit {
+    fn new(message_id: Option<MessageId>, tx: mpsc::Sender<(Option<MessageId>, u64)>) -> Self {
+        Credit(Arc::new(CreditInner {
+            message_id,
+            bytes: AtomicU64::new(0),
+            tx,
+        }))
+    }
+
+    /// Adds the payload bytes of a received packet.
+    pub fn add(&self, bytes: u64) {
+        self.0.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct CreditInner {
+    message_id: Option<MessageId>,
+    bytes: AtomicU64,
+    tx: mpsc::Sender<(Option<MessageId>, u64)>,
+}
+impl Drop for CreditInner {
+    fn drop(&mut self) {
+        let _ = self.tx.send((self.message_id, *self.bytes.get_mut()));
+    }
+}
+
+/// The credits for an incoming message.
+///
+/// The channel level credit is released when the message has been decoded.
+/// The stream level one is passed to the handler of the message and, in the case of a stream item,
+/// it is held until the item is consumed by the application,
+/// so that a slow consumer applies back-pressure to the peer without blocking the other streams.
+#[derive(Debug)]
+pub struct MessageCredits {
+    pub channel: Credit,
+    pub stream: Credit,
+}
+impl MessageCredits {
+    /// Adds the payload bytes of a received packet.
+    pub fn add(&self, bytes: u64) {
+        self.channel.add(bytes);
+        self.stream.add(bytes);
+    }
+}
+
+/// The state of the credit-based flow control of a channel.
+///
+/// There are two levels of windows: the channel and each stream (i.e., the items sharing a message identifier).
+/// A sender can start sending a new message only if the windows are positive.
+/// Once a message has been started, it is sent to the end regardless of the windows,
+/// because the receiver may not be able to consume any bytes until the whole message arrives.
+///
+/// Control messages are not subject to the flow control.
+///
+/// The receiver tracks the bytes it has granted, and regards a peer which starts a message
+/// while the windows are exhausted as misbehaving.
+#[derive(Debug)]
+pub struct FlowControl {
+    send_window: i64,
+    stream_send_windows: HashMap<MessageId, i64>,
+    receive_window: ReceiveWindow,
+    stream_receive_windows: HashMap<MessageId, ReceiveWindow>,
+    channel_window_size: u32,
+    stream_window_size: u32,
+    credit_tx: mpsc::Sender<(Option<MessageId>, u64)>,
+    credit_rx: mpsc::Receiver<(Option<MessageId>, u64)>,
+}
+impl FlowControl {
+    pub fn new(options: &ChannelOptions) -> Self {
+        let channel_window_size = options.channel_window_size.max(INITIAL_WINDOW_SIZE);
+        let stream_window_size = options.stream_window_size.max(INITIAL_WINDOW_SIZE);
+        let (credit_tx, credit_rx) = mpsc::channel();
+        FlowControl {
+            send_window: i64::from(INITIAL_WINDOW_SIZE),
+            stream_send_windows: HashMap::new(),
+            receive_window: ReceiveWindow::new(channel_window_size),
+            stream_receive_windows: HashMap::new(),
+            channel_window_size,
+            stream_window_size,
+            credit_tx,
+            credit_rx,
+        }
+    }
+
+    /// Returns the window update that enlarges the channel window from the initial size to the configured one.
+    pub fn initial_window_update(&mut self) -> Option<ControlMessage> {
+        let increment = self.channel_window_size - INITIAL_WINDOW_SIZE;
+        self.receive_window.grant(increment);
+        window_update(None, increment)
+    }
+
+    /// Returns `true` if the windows allow for starting to send the message.
+    pub fn can_start(&self, header: &MessageHeader) -> bool {
+        if header.is_control {
+            return true;
+        }
+        if self.send_window <= 0 {
+            return false;
+        }
+        match self.stream_send_windows.get(&header.id) {
+            Some(&w) if header.is_stream_item => w > 0,
+            _ => true,
+        }
+    }
+
+    /// Consumes the windows by the payload bytes of a sent packet.
+    pub fn consume(&mut self, header: &MessageHeader, bytes: u64) {
+        if header.is_control {
+            return;
+        }
+        let bytes = bytes as i64;
+        self.send_window -= bytes;
+        if header.is_stream_item {
+            *self
+                .stream_send_windows
+                .entry(header.id)
+                .or_insert_with(|| i64::from(INITIAL_WINDOW_SIZE)) -= bytes;
+        }
+    }
+
+    /// Forgets the stream window of the message (e.g., the stream has been ended or cancelled).
+    pub fn finish_sending(&mut self, message_id: MessageId) {
+        self.stream_send_windows.remove(&message_id);
+    }
+
+    pub fn handle_window_update(&mut self, message_id: Option<MessageId>, increment: u32) {
+        if let Some(message_id) = message_id {
+            // Updates for finished streams are ignored
+            if let Some(w) = self.stream_send_windows.get_mut(&message_id) {
+                *w += i64::from(increment);
+            }
+        } else {
+            self.send_window += i64::from(increment);
+        }
+    }
+
+    /// Makes the credits for the payload bytes of an incoming message.
+    pub fn credits(&self, message_id: MessageId) -> MessageCredits {
+        MessageCredits {
+            channel: Credit::new(None, self.credit_tx.clone()),
+            stream: Credit::new(Some(message_id), self.credit_tx.clone()),
+        }
+    }
+
+    /// Starts receiving a packet of the message.
+    ///
+    /// If it is the first item of a stream, this returns the window update for the stream.
+    pub fn start_receiving(&mut self, header: &MessageHeader) -> Option<ControlMessage> {
+        if !header.is_stream_item {
+            // The end of the stream (if any)
+            self.stream_receive_windows.remove(&header.id);
+            return None;
+        }
+        if self.stream_receive_windows.contains_key(&header.id) {
+            return None;
+        }
+        let increment = self.stream_window_size - INITIAL_WINDOW_SIZE;
+        let mut window = ReceiveWindow::new(self.stream_window_size);
+        window.grant(increment);
+        self.stream_receive_windows.insert(header.id, window);
+        window_update(Some(header.id), increment)
+    }
+
+    /// Consumes the receive windows by the payload bytes of a received packet.
+    ///
+    /// If the packet starts a new message (i.e., `is_first_packet` is `true`) even though
+    /// the windows granted to the peer have been exhausted, this returns an error.
+    pub fn receive(
+        &mut self,
+        header: &MessageHeader,
+        bytes: u64,
+        is_first_packet: bool,
+    ) -> Result<()> {
+        if is_first_packet {
+            track_assert!(
+                self.receive_window.available > 0,
+                ErrorKind::InvalidInput,
+                "The peer exceeded the channel window: message_id={:?}",
+                header.id
+            );
+        }
+        self.receive_window.consume(bytes);
+
+        if header.is_stream_item {
+            if let Some(w) = self.stream_receive_windows.get_mut(&header.id) {
+                if is_first_packet {
+                    track_assert!(
+                        w.available > 0,
+                        ErrorKind::InvalidInput,
+                        "The peer exceeded the stream window: message_id={:?}",
+                        header.id
+                    );
+                }
+                w.consume(bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the stream window of the cancelled message.
+    pub fn cancel_receiving(&mut self, message_id: MessageId) {
+        self.stream_receive_windows.remove(&message_id);
+    }
+
+    /// Returns the window updates for the bytes released since the last call.
+    pub fn poll_window_updates(&mut self) -> Vec<ControlMessage> {
+        let mut updates = Vec::new();
+        while let Async::Ready(Some((message_id, bytes))) =
+            self.credit_rx.poll().expect("Never fails")
+        {
+            if let Some(message_id) = message_id {
+                // Bytes of the messages which are not stream items are ignored
+                if let Some(w) = self.stream_receive_windows.get_mut(&message_id) {
+                    w.release(bytes);
+                    while let Some(increment) = w.take_increment() {
+                        updates.extend(window_update(Some(message_id), increment));
+                    }
+                }
+            } else {
+                self.receive_window.release(bytes);
+            }
+        }
+        while let Some(increment) = self.receive_window.take_increment() {
+            updates.extend(window_update(None, increment));
+        }
+        updates
+    }
+}
+
+fn window_update(message_id: Option<MessageId>, increment: u32) -> Option<ControlMessage> {
+    if increment == 0 {
+        None
+    } else {
+        Some(ControlMessage::WindowUpdate {
+            message_id,
+            increment,
+        })
+    }
+}
+
+/// The receiving side of a window.
+///
+/// Released bytes are granted to the peer in batches, so as not to send too many window updates.
+#[derive(Debug)]
+struct ReceiveWindow {
+    threshold: u64,
+    released: u64,
+
+    /// The granted bytes which have not been received yet (i.e., the window seen by the peer at most).
+    available: i64,
+}
+impl ReceiveWindow {
+    fn new(size: u32) -> Self {
+        ReceiveWindow {
+            threshold: u64::from(size / 4).max(1),
+            released: 0,
+            available: i64::from(INITIAL_WINDOW_SIZE),
+        }
+    }
+
+    fn grant(&mut self, increment: u32) {
+        self.available += i64::from(increment);
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        self.available -= bytes as i64;
+    }
+
+    fn release(&mut self, bytes: u64) {
+        self.released += bytes;
+    }
+
+    fn take_increment(&mut self) -> Option<u32> {
+        if self.released < self.threshold {
+            return None;
+        }
+        let increment = self.released.min(u64::from(u32::MAX));
+        self.released -= increment;
+        self.grant(increment as u32);
+        Some(increment as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::ProcedureId;
+
+    fn header(id: u64, is_stream_item: bool) -> MessageHeader {
+        MessageHeader {
+            id: MessageId(id),
+            procedure: ProcedureId(0),
+            priority: 0,
+            is_async: false,
+            is_error: false,
+            is_control: false,
+            has_extension: false,
+            compression: Compression::None,
+            is_stream_item,
+            is_notification: false,
+        }
+    }
+
+    #[test]
+    fn send_windows_work() {
+        let mut flow = FlowControl::new(&ChannelOptions::default());
+        let item = header(1, true);
+        assert!(flow.can_start(&item));
+
+        // The stream window is exhausted
+        flow.consume(&item, u64::from(INITIAL_WINDOW_SIZE));
+        assert!(!flow.can_start(&item));
+        assert!(!flow.can_start(&header(2, true)));
+        assert!(!flow.can_start(&header(3, false)));
+
+        // The channel window is enlarged
+        flow.handle_window_update(None, 100);
+        assert!(!flow.can_start(&item));
+        assert!(flow.can_start(&header(2, true)));
+        assert!(flow.can_start(&header(3, false)));
+
+        // The stream window is enlarged
+        flow.handle_window_update(Some(MessageId(1)), 100);
+        assert!(flow.can_start(&item));
+
+        // Updates for unknown streams are ignored
+        flow.finish_sending(MessageId(1));
+        flow.handle_window_update(Some(MessageId(1)), 100);
+        assert!(flow.can_start(&item));
+    }
+
+    #[test]
+    fn receive_windows_work() {
+        let options = ChannelOptions {
+            channel_window_size: 0,
+            stream_window_size: INITIAL_WINDOW_SIZE * 2,
+            ..Default::default()
+        };
+        let mut flow = FlowControl::new(&options);
+        assert_eq!(flow.initial_window_update(), None);
+
+        let item = header(1, true);
+        assert_eq!(
+            flow.start_receiving(&item),
+            Some(ControlMessage::WindowUpdate {
+                message_id: Some(MessageId(1)),
+                increment: INITIAL_WINDOW_SIZE
+            })
+        );
+        assert_eq!(flow.start_receiving(&item), None);
+
+        let credits = flow.credits(MessageId(1));
+        credits.add(1000);
+        let item = credits.stream.clone();
+        drop(credits);
+
+        // Released, but too few to be granted
+        assert_eq!(flow.poll_window_updates(), Vec::new());
+
+        let credits = flow.credits(MessageId(1));
+        credits.add(u64::from(INITIAL_WINDOW_SIZE / 2));
+        let next_item = credits.stream.clone();
+        drop(credits);
+        assert_eq!(
+            flow.poll_window_updates(),
+            vec![ControlMessage::WindowUpdate {
+                message_id: None,
+                increment: INITIAL_WINDOW_SIZE / 2 + 1000
+            }]
+        );
+
+        // The items are consumed
+        drop(item);
+        drop(next_item);
+        assert_eq!(
+            flow.poll_window_updates(),
+            vec![ControlMessage::WindowUpdate {
+                message_id: Some(MessageId(1)),
+                increment: INITIAL_WINDOW_SIZE / 2 + 1000
+            }]
+        );
+    }
+
+    #[test]
+    fn receive_window_violations_are_detected() {
+        // The smallest windows
+        let options = ChannelOptions {
+            channel_window_size: 0,
+            stream_window_size: 0,
+            ..Default::default()
+        };
+        let mut flow = FlowControl::new(&options);
+        let window = u64::from(INITIAL_WINDOW_SIZE);
+
+        // A message started within the window can exceed it
+        let message = header(1, false);
+        assert_eq!(flow.start_receiving(&message), None);
+        assert!(flow.receive(&message, window - 1, true).is_ok());
+        assert!(flow.receive(&message, 100, false).is_ok());
+
+        // But no more messages can be started
+        assert!(flow.receive(&header(2, false), 1, true).is_err());
+
+        // Until the bytes are granted again
+        let credits = flow.credits(MessageId(1));
+        credits.add(window + 99);
+        drop(credits);
+        assert_eq!(flow.poll_window_updates().len(), 1);
+        assert!(flow.receive(&header(2, false), 1, true).is_ok());
+    }
+
+    #[test]
+    fn stream_window_violations_are_detected() {
+        let options = ChannelOptions {
+            channel_window_size: INITIAL_WINDOW_SIZE * 4,
+            stream_window_size: 0,
+            ..Default::default()
+        };
+        let mut flow = FlowControl::new(&options);
+        assert!(flow.initial_window_update().is_some());
+
+        let item = header(1, true);
+        assert_eq!(flow.start_receiving(&item), None);
+        assert!(flow
+            .receive(&item, u64::from(INITIAL_WINDOW_SIZE), true)
+            .is_ok());
+
+        // The channel window remains, but the stream window is exhausted
+        assert_eq!(flow.start_receiving(&header(2, true)), None);
+        assert!(flow.receive(&header(2, true), 1, true).is_ok());
+        assert!(flow.receive(&item, 1, true).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use bytecodec::bytes::CopyableBytesDecoder;
+use bytecodec::{self, ByteCount, Decode, Eos, ErrorKind};
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+
+/// The magic number placed at the beginning of every connection.
+pub const MAGIC: [u8; 4] = *b"FRPC";
+
+/// The protocol version implemented by this crate.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The oldest protocol version this crate can talk with.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// A set of optional protocol features.
+///
+/// Error replies and header extensions are mandatory parts of the protocol version `1`,
+/// so the bits `0` and `1` are reserved and never checked.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities(u32);
+impl Capabilities {
+    /// `CANCEL` control messages.
+    pub const CANCEL: Self = Capabilities(1 << 2);
+
+    /// `GOAWAY` control messages.
+    pub const GO_AWAY: Self = Capabilities(1 << 3);
+
+    /// `PING` and `PONG` control messages.
+    pub const HEARTBEAT: Self = Capabilities(1 << 4);
+
+    /// DEFLATE compressed payloads (i.e., `COMPRESSED_FLAG`).
+    pub const DEFLATE: Self = Capabilities(1 << 5);
+
+    /// Credit-based flow control (i.e., `WINDOW_UPDATE` control messages).
+    pub const FLOW_CONTROL: Self = Capabilities(1 << 6);
+
+    /// The capabilities supported by this crate.
+    pub const SUPPORTED: Self = Capabilities(
+        Self::CANCEL.0
+            | Self::GO_AWAY.0
+            | Self::HEARTBEAT.0
+            | Self::FLOW_CONTROL.0
+            | if cfg!(feature = "deflate") {
+                Self::DEFLATE.0
+            } else {
+                0
+            },
+    );
+
+    /// Returns `true` if `self` contains all of the capabilities in `other`.
+    pub fn contains(self, other: Self) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    /// Returns the capabilities contained in both `self` and `other`.
+    pub fn intersection(self, other: Self) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+}
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Capabilities(0b{:b})", self.0)
+    }
+}
+
+/// The first message sent by both sides of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+impl Hello {
+    pub const SIZE: usize = 4 + 2 + 4;
+
+    /// Returns the hello message of this crate.
+    pub fn local() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+
+    /// Agrees on the protocol version and the capabilities used in the connection.
+    ///
+    /// If the peer is incompatible with this crate, an `ErrorKind::InvalidInput` error is returned.
+    pub fn negotiate(&self, peer: &Hello) -> crate::Result<Hello> {
+        track_assert!(
+            peer.version >= MIN_PROTOCOL_VERSION,
+            crate::ErrorKind::InvalidInput,
+            "Incompatible peer: unsupported protocol version (local={}, peer={})",
+            self.version,
+            peer.version
+        );
+        Ok(Hello {
+            version: self.version.min(peer.version),
+            capabilities: self.capabilities.intersection(peer.capabilities),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Hello::SIZE] {
+        let mut buf = [0; Hello::SIZE];
+        buf[..4].copy_from_slice(&MAGIC);
+        BigEndian::write_u16(&mut buf[4..], self.version);
+        BigEndian::write_u32(&mut buf[6..], self.capabilities.0);
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; Hello::SIZE]) -> bytecodec::Result<Self> {
+        track_assert_eq!(
+            buf[..4],
+            MAGIC,
+            ErrorKind::InvalidInput,
+            "Incompatible peer: not a fibers_rpc connection"
+        );
+        let version = BigEndian::read_u16(&buf[4..]);
+        let capabilities = Capabilities(BigEndian::read_u32(&buf[6..]));
+        Ok(Hello {
+            version,
+            capabilities,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HelloDecoder {
+    bytes: CopyableBytesDecoder<[u8; Hello::SIZE]>,
+}
+impl Decode for HelloDecoder {
+    type Item = Hello;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.bytes.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let bytes = track!(self.bytes.finish_decoding())?;
+        track!(Hello::from_bytes(&bytes))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.bytes.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.bytes.is_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_bytes_works() {
+        let hello = Hello::local();
+        let bytes = hello.to_bytes();
+        assert_eq!(Hello::from_bytes(&bytes).unwrap(), hello);
+
+        let mut bytes = bytes;
+        bytes[0] = 0;
+        assert!(Hello::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn negotiation_works() {
+        let local = Hello::local();
+        let peer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::HEARTBEAT,
+        };
+        let agreed = local.negotiate(&peer).unwrap();
+        assert_eq!(agreed.version, PROTOCOL_VERSION);
+        assert!(agreed.capabilities.contains(Capabilities::HEARTBEAT));
+        assert!(!agreed.capabilities.contains(Capabilities::CANCEL));
+
+        let peer = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        assert!(local.negotiate(&peer).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::flow_control::Credit;
+use crate::{Error, ErrorKind, Result};
+use fibers::sync::mpsc;
+use futures::{Async, Poll, Stream};
+use std::collections::BTreeMap;
+use trackable::error::ErrorKindExt;
+
+/// An item of a stream, its sequence number and the credit for the bytes of the item.
+///
+/// `Ok(None)` means the end of the stream.
+type StreamEntry<T> = (u64, Result<Option<T>>, Option<Credit>);
+
+/// Makes a channel for delivering the items of a stream received as individual messages.
+pub fn channel<T>() -> (ItemSender<T>, ItemReceiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    let sender = ItemSender { tx, next_seqno: 0 };
+    let receiver = ItemReceiver {
+        rx,
+        items: BTreeMap::new(),
+        next_seqno: 0,
+        is_terminated: false,
+    };
+    (sender, receiver)
+}
+
+/// Sending half of an item channel.
+///
+/// A slot is reserved for each message in the order of arrival,
+/// because the messages may be decoded out of order (e.g., asynchronously decoded ones).
+#[derive(Debug)]
+pub struct ItemSender<T> {
+    tx: mpsc::Sender<StreamEntry<T>>,
+    next_seqno: u64,
+}
+impl<T> ItemSender<T> {
+    /// Reserves the slot for the next item (or the end of the stream).
+    pub fn reserve(&mut self) -> ItemSlot<T> {
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+        ItemSlot {
+            tx: Some(self.tx.clone()),
+            seqno,
+            credit: None,
+        }
+    }
+
+    /// Returns the number of the reserved slots.
+    pub fn reserved(&self) -> u64 {
+        self.next_seqno
+    }
+}
+
+/// A slot of an item channel.
+///
+/// If this is dropped without sending anything, an error is sent instead,
+/// so that the receiver does not wait for the slot forever.
+#[derive(Debug)]
+pub struct ItemSlot<T> {
+    tx: Option<mpsc::Sender<StreamEntry<T>>>,
+    seqno: u64,
+    credit: Option<Credit>,
+}
+impl<T> ItemSlot<T> {
+    /// Attaches the credit for the bytes of the item.
+    ///
+    /// The credit is released when the receiver yields the item.
+    pub fn with_credit(mut self, credit: Credit) -> Self {
+        self.credit = Some(credit);
+        self
+    }
+
+    /// Sends the item (`Ok(None)` means the end of the stream) to the receiver.
+    pub fn send(mut self, entry: Result<Option<T>>) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send((self.seqno, entry, self.credit.take()));
+        }
+    }
+}
+impl<T> Drop for ItemSlot<T> {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let e = ErrorKind::Other.cause("An item of the stream has been lost");
+            let _ = tx.send((self.seqno, Err(track!(e).into()), self.credit.take()));
+        }
+    }
+}
+
+/// Receiving half of an item channel.
+///
+/// It yields the items in the order of the slots, and terminates after the end of the stream or an error.
+/// If all the senders are dropped before the end of the stream, it fails.
+#[derive(Debug)]
+pub struct ItemReceiver<T> {
+    rx: mpsc::Receiver<StreamEntry<T>>,
+    items: BTreeMap<u64, (Result<Option<T>>, Option<Credit>)>,
+    next_seqno: u64,
+    is_terminated: bool,
+}
+impl<T> ItemReceiver<T> {
+    /// Makes an `ItemReceiver` instance which fails with `e` immediately.
+    pub fn error(e: Error) -> Self {
+        let (mut tx, rx) = channel();
+        tx.reserve().send(Err(e));
+        rx
+    }
+
+    fn poll_item(&mut self) -> Poll<Option<T>, Error> {
+        let mut is_disconnected = false;
+        while let Async::Ready(entry) = self.rx.poll().expect("Never fails") {
+            if let Some((seqno, item, credit)) = entry {
+                self.items.insert(seqno, (item, credit));
+            } else {
+                is_disconnected = true;
+                break;
+            }
+        }
+
+        if let Some((item, _credit)) = self.items.remove(&self.next_seqno) {
+            // The credit is released here, since the item is consumed
+            self.next_seqno += 1;
+            return track!(item).map(Async::Ready);
+        }
+        track_assert!(
+            !is_disconnected,
+            ErrorKind::Other,
+            "Stream channel disconnected"
+        );
+        Ok(Async::NotReady)
+    }
+}
+impl<T> Stream for ItemReceiver<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.is_terminated {
+            return Ok(Async::Ready(None));
+        }
+        let result = self.poll_item();
+        if let Ok(Async::Ready(None)) | Err(_) = result {
+            self.is_terminated = true;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn item_channel_works() {
+        let (mut tx, mut rx) = channel();
+        let first = tx.reserve();
+        let second = tx.reserve();
+        let end = tx.reserve();
+
+        // Out of order
+        second.send(Ok(Some(2)));
+        end.send(Ok(None));
+        assert_eq!(rx.poll().unwrap(), Async::NotReady);
+
+        first.send(Ok(Some(1)));
+        assert_eq!(rx.poll().unwrap(), Async::Ready(Some(1)));
+        assert_eq!(rx.poll().unwrap(), Async::Ready(Some(2)));
+        assert_eq!(rx.poll().unwrap(), Async::Ready(None));
+        assert_eq!(rx.poll().unwrap(), Async::Ready(None));
+    }
+
+    #[test]
+    fn dropped_slot_fails_stream() {
+        let (mut tx, mut rx) = channel::<u8>();
+        drop(tx.reserve());
+        assert!(rx.poll().is_err());
+        assert_eq!(rx.poll().unwrap(), Async::Ready(None));
+
+        // Disconnected before the end of the stream
+        let (tx, mut rx) = channel::<u8>();
+        drop(tx);
+        assert!(rx.poll().is_err());
+    }
+}
@@ -6,21 +6,28 @@
 //! # Features
 //!
 //! - Asynchronous RPC server/client using [fibers] crate
-//! - Support two type of RPC:
+//! - Support four type of RPC:
 //!   - Request/response model
 //!   - Notification model
+//!   - Server-streaming model (a request and a stream of responses)
+//!   - Bidirectional streaming model (a stream of requests and a stream of responses)
 //! - Strongly typed RPC using [bytecodec] crate
 //!   - You can treat arbitrarily Rust structures that support [serde] as RPC messages
 //!   - It is possible to handle huge structures as RPC messages without
 //!     compromising efficiency and real-time property by implementing your own encoder/decoder
-//! - Multiplexing multiple RPC messages in a single TCP stream
+//! - Multiplexing multiple RPC messages in a single TCP (or Unix domain socket) stream
 //! - Prioritization between messages
+//! - Credit-based flow control (slow receivers apply back-pressure to senders)
+//! - TLS (and mutual TLS) support via [rustls] (requires the `tls` feature)
+//! - Per-message payload compression (requires the `deflate` feature)
+//! - Client-side load balancing across a set of servers
 //! - Expose [Prometheus] metrics
 //!
 //! [fibers]: https://github.com/dwango/fibers-rs
 //! [bytecodec]: https://github.com/sile/bytecodec
 //! [serde]: https://crates.io/crates/serde
 //! [Prometheus]: https://prometheus.io/
+//! [rustls]: https://github.com/rustls/rustls
 //!
 //! # Technical Details
 //!
@@ -86,37 +93,69 @@ extern crate slog;
 #[macro_use]
 extern crate trackable;
 
+pub use compression::Compression;
 pub use error::{Error, ErrorKind};
+pub use message::MessageId;
+pub use transport::TransportAddr;
 
 pub mod client {
     //! RPC client.
 
+    pub use crate::circuit_breaker::{CircuitBreakerPolicy, CircuitState};
     pub use crate::client_service::{ClientService, ClientServiceBuilder, ClientServiceHandle};
-    pub use crate::client_side_handlers::Response;
-    pub use crate::rpc_client::{CallClient, CastClient, Options};
+    pub use crate::client_side_handlers::{Response, ResponseStream};
+    pub use crate::resolver::{FileResolver, Resolver, StaticResolver};
+    pub use crate::rpc_client::{
+        BidiStreamCallClient, CallClient, CastClient, Options, RequestSink, RetryPolicy,
+        StreamCallClient,
+    };
+    pub use crate::server_set::{BalanceStrategy, ServerSet};
 }
 pub mod channel;
 pub mod metrics;
 pub mod server {
     //! RPC server.
 
-    pub use crate::rpc_server::{Server, ServerBuilder};
-    pub use crate::server_side_handlers::{HandleCall, HandleCast, NoReply, Reply};
+    pub use crate::concurrency_limit::ConcurrencyLimit;
+    pub use crate::rpc_server::{Server, ServerBuilder, ServerHandle};
+    pub use crate::server_side_handlers::{
+        HandleBidiStreamCall, HandleBidiStreamCallWithContext, HandleCall, HandleCallWithContext,
+        HandleCast, HandleCastWithContext, HandleStreamCall, HandleStreamCallWithContext, NoReply,
+        Reply, ReplyStream, RequestContext, RequestStream,
+    };
 }
+#[cfg(feature = "tls")]
+pub mod tls;
 
-use crate::client::{CallClient, CastClient, ClientServiceHandle};
+use crate::client::{
+    BidiStreamCallClient, CallClient, CastClient, ClientServiceHandle, StreamCallClient,
+};
 
+mod circuit_breaker;
 mod client_service;
 mod client_side_channel;
 mod client_side_handlers;
+mod compression;
+mod concurrency_limit;
+mod control;
 mod error;
+mod error_reply;
+mod extension;
+mod flow_control;
+mod handshake;
+mod item_stream;
 mod message;
 mod message_stream;
 mod packet;
+mod resolver;
 mod rpc_client;
 mod rpc_server;
+mod server_set;
 mod server_side_channel;
 mod server_side_handlers;
+mod transport;
+#[cfg(unix)]
+mod uds;
 
 /// This crate specific `Result` type.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -160,6 +199,14 @@ pub trait Call: Sized + Send + Sync + 'static {
     /// Response message decoder.
     type ResDecoder: bytecodec::Decode<Item = Self::Res> + Send + 'static;
 
+    /// Whether the procedure is idempotent (i.e., it is safe to handle the same request more than once).
+    ///
+    /// Requests of idempotent procedures can be retried even if they may have been handled
+    /// by the server (see `client::RetryPolicy`).
+    ///
+    /// The default value is `false`.
+    const IDEMPOTENT: bool = false;
+
     /// If it returns `true`, encoding/decoding request messages will be executed asynchronously.
     ///
     /// For large RPC messages, asynchronous encoding/decoding may improve real-time property
@@ -182,20 +229,46 @@ pub trait Call: Sized + Send + Sync + 'static {
         false
     }
 
+    /// Returns the compression algorithm applied to the request message.
+    ///
+    /// Compressed messages are encoded/decoded (and compressed/decompressed) asynchronously.
+    /// If the server does not support the algorithm, the message is sent uncompressed.
+    ///
+    /// The default implementation always return `Compression::None`.
+    #[allow(unused_variables)]
+    fn request_compression(request: &Self::Req) -> Compression {
+        Compression::None
+    }
+
+    /// Returns the compression algorithm applied to the response message.
+    ///
+    /// Compressed messages are encoded/decoded (and compressed/decompressed) asynchronously.
+    /// If the client does not support the algorithm, the message is sent uncompressed.
+    ///
+    /// The default implementation always return `Compression::None`.
+    #[allow(unused_variables)]
+    fn response_compression(response: &Self::Res) -> Compression {
+        Compression::None
+    }
+
     /// Makes a new RPC client.
-    fn client(service: &ClientServiceHandle) -> CallClient<Self>
+    fn client(service: &ClientServiceHandle) -> CallClient<'_, Self>
     where
         Self::ReqEncoder: Default,
         Self::ResDecoder: Default,
     {
-        Self::client_with_codec(service, Default::default(), Default::default())
+        let mut client = Self::client_with_codec(service, Default::default(), Default::default());
+        client.set_decoder_factory(Default::default);
+        client
     }
 
     /// Makes a new RPC client with the given decoder maker.
+    ///
+    /// The returned client never retries requests unless `CallClient::set_decoder_factory` is called.
     fn client_with_decoder(
         service: &ClientServiceHandle,
         decoder: Self::ResDecoder,
-    ) -> CallClient<Self>
+    ) -> CallClient<'_, Self>
     where
         Self::ReqEncoder: Default,
     {
@@ -206,19 +279,23 @@ pub trait Call: Sized + Send + Sync + 'static {
     fn client_with_encoder(
         service: &ClientServiceHandle,
         encoder: Self::ReqEncoder,
-    ) -> CallClient<Self>
+    ) -> CallClient<'_, Self>
     where
         Self::ResDecoder: Default,
     {
-        Self::client_with_codec(service, Default::default(), encoder)
+        let mut client = Self::client_with_codec(service, Default::default(), encoder);
+        client.set_decoder_factory(Default::default);
+        client
     }
 
     /// Makes a new RPC client with the given decoder and encoder makers.
+    ///
+    /// The returned client never retries requests unless `CallClient::set_decoder_factory` is called.
     fn client_with_codec(
         service: &ClientServiceHandle,
         decoder: Self::ResDecoder,
         encoder: Self::ReqEncoder,
-    ) -> CallClient<Self> {
+    ) -> CallClient<'_, Self> {
         CallClient::new(service, decoder, encoder)
     }
 }
@@ -253,8 +330,19 @@ pub trait Cast: Sized + Sync + Send + 'static {
         false
     }
 
+    /// Returns the compression algorithm applied to the notification message.
+    ///
+    /// Compressed messages are encoded/decoded (and compressed/decompressed) asynchronously.
+    /// If the server does not support the algorithm, the message is sent uncompressed.
+    ///
+    /// The default implementation always return `Compression::None`.
+    #[allow(unused_variables)]
+    fn compression(notification: &Self::Notification) -> Compression {
+        Compression::None
+    }
+
     /// Makes a new RPC client.
-    fn client(service: &ClientServiceHandle) -> CastClient<Self>
+    fn client(service: &ClientServiceHandle) -> CastClient<'_, Self>
     where
         Self::Encoder: Default,
     {
@@ -265,19 +353,204 @@ pub trait Cast: Sized + Sync + Send + 'static {
     fn client_with_encoder(
         service: &ClientServiceHandle,
         encoder: Self::Encoder,
-    ) -> CastClient<Self> {
+    ) -> CastClient<'_, Self> {
         CastClient::new(service, encoder)
     }
 }
 
+/// Server-streaming RPC.
+///
+/// The server replies a stream of items to a request.
+/// Each item is sent as an individual message, so the items are received one by one
+/// without waiting for the entire stream.
+pub trait StreamCall: Sized + Send + Sync + 'static {
+    /// The identifier of the procedure.
+    const ID: ProcedureId;
+
+    /// The name of the procedure.
+    ///
+    /// This is only used for debugging purpose.
+    const NAME: &'static str;
+
+    /// Request message.
+    type Req: Send + 'static;
+
+    /// Request message encoder.
+    type ReqEncoder: bytecodec::Encode<Item = Self::Req> + Send + 'static;
+
+    /// Request message decoder.
+    type ReqDecoder: bytecodec::Decode<Item = Self::Req> + Send + 'static;
+
+    /// Item of the response stream.
+    type Item: Send + 'static;
+
+    /// Item encoder.
+    type ItemEncoder: bytecodec::Encode<Item = Self::Item> + Send + 'static;
+
+    /// Item decoder.
+    type ItemDecoder: bytecodec::Decode<Item = Self::Item> + Send + 'static;
+
+    /// If it returns `true`, encoding/decoding request messages will be executed asynchronously.
+    ///
+    /// The default implementation always return `false`.
+    #[allow(unused_variables)]
+    fn enable_async_request(request: &Self::Req) -> bool {
+        false
+    }
+
+    /// If it returns `true`, encoding/decoding the message of the item will be executed asynchronously.
+    ///
+    /// The default implementation always return `false`.
+    #[allow(unused_variables)]
+    fn enable_async_item(item: &Self::Item) -> bool {
+        false
+    }
+
+    /// Returns the compression algorithm applied to the request message.
+    ///
+    /// The default implementation always return `Compression::None`.
+    #[allow(unused_variables)]
+    fn request_compression(request: &Self::Req) -> Compression {
+        Compression::None
+    }
+
+    /// Returns the compression algorithm applied to the message of the item.
+    ///
+    /// The default implementation always return `Compression::None`.
+    #[allow(unused_variables)]
+    fn item_compression(item: &Self::Item) -> Compression {
+        Compression::None
+    }
+
+    /// Makes a new RPC client.
+    fn client(service: &ClientServiceHandle) -> StreamCallClient<'_, Self>
+    where
+        Self::ReqEncoder: Default,
+        Self::ItemDecoder: Default,
+    {
+        Self::client_with_codec(service, Default::default, Default::default())
+    }
+
+    /// Makes a new RPC client with the given item decoder maker and request encoder.
+    fn client_with_codec(
+        service: &ClientServiceHandle,
+        decoder_factory: fn() -> Self::ItemDecoder,
+        encoder: Self::ReqEncoder,
+    ) -> StreamCallClient<'_, Self> {
+        StreamCallClient::new(service, decoder_factory, encoder)
+    }
+}
+
+/// Bidirectional streaming RPC.
+///
+/// The client sends a stream of requests and the server replies a stream of responses.
+/// Both streams are independent of each other (e.g., the server can reply before receiving all the requests),
+/// and each side closes its sending stream when it has no more messages to send.
+///
+/// Client-streaming RPC can be defined as a bidirectional streaming RPC
+/// whose handler replies a single response after receiving all the requests.
+pub trait BidiStreamCall: Sized + Send + Sync + 'static {
+    /// The identifier of the procedure.
+    const ID: ProcedureId;
+
+    /// The name of the procedure.
+    ///
+    /// This is only used for debugging purpose.
+    const NAME: &'static str;
+
+    /// Request message.
+    type Req: Send + 'static;
+
+    /// Request message encoder.
+    type ReqEncoder: bytecodec::Encode<Item = Self::Req> + Send + 'static;
+
+    /// Request message decoder.
+    type ReqDecoder: bytecodec::Decode<Item = Self::Req> + Send + 'static;
+
+    /// Response message.
+    type Res: Send + 'static;
+
+    /// Response message encoder.
+    type ResEncoder: bytecodec::Encode<Item = Self::Res> + Send + 'static;
+
+    /// Response message decoder.
+    type ResDecoder: bytecodec::Decode<Item = Self::Res> + Send + 'static;
+
+    /// If it returns `true`, encoding/decoding request messages will be executed asynchronously.
+    ///
+    /// The default implementation always return `false`.
+    #[allow(unused_variables)]
+    fn enable_async_request(request: &Self::Req) -> bool {
+        false
+    }
+
+    /// If it returns `true`, encoding/decoding response messages will be executed asynchronously.
+    ///
+    /// The default implementation always return `false`.
+    #[allow(unused_variables)]
+    fn enable_async_response(response: &Self::Res) -> bool {
+        false
+    }
+
+    /// Returns the compression algorithm applied to the request message.
+    ///
+    /// The default implementation always return `Compression::None`.
+    #[allow(unused_variables)]
+    fn request_compression(request: &Self::Req) -> Compression {
+        Compression::None
+    }
+
+    /// Returns the compression algorithm applied to the response message.
+    ///
+    /// The default implementation always return `Compression::None`.
+    #[allow(unused_variables)]
+    fn response_compression(response: &Self::Res) -> Compression {
+        Compression::None
+    }
+
+    /// Makes a new RPC client.
+    fn client(service: &ClientServiceHandle) -> BidiStreamCallClient<'_, Self>
+    where
+        Self::ReqEncoder: Default,
+        Self::ResDecoder: Default,
+    {
+        Self::client_with_codec(service, Default::default, Default::default)
+    }
+
+    /// Makes a new RPC client with the given response decoder maker and request encoder maker.
+    fn client_with_codec(
+        service: &ClientServiceHandle,
+        decoder_factory: fn() -> Self::ResDecoder,
+        encoder_factory: fn() -> Self::ReqEncoder,
+    ) -> BidiStreamCallClient<'_, Self> {
+        BidiStreamCallClient::new(service, decoder_factory, encoder_factory)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::client::ClientServiceBuilder;
-    use crate::server::{HandleCall, Reply, ServerBuilder};
-    use crate::{Call, ProcedureId};
-    use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
-    use fibers_global;
-    use futures::Future;
+    use crate::channel::{ChannelOptions, ReconnectPolicy};
+    use crate::client::{
+        BalanceStrategy, CircuitBreakerPolicy, CircuitState, ClientServiceBuilder, FileResolver,
+        RetryPolicy, ServerSet,
+    };
+    use crate::server::{
+        ConcurrencyLimit, HandleBidiStreamCall, HandleCall, HandleCallWithContext, HandleCast,
+        HandleStreamCall, NoReply, Reply, ReplyStream, RequestContext, RequestStream,
+        ServerBuilder,
+    };
+    use crate::{
+        BidiStreamCall, Call, Cast, Compression, ErrorKind, ProcedureId, Result, StreamCall,
+        TransportAddr,
+    };
+    use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder, Utf8Decoder, Utf8Encoder};
+    use bytecodec::marker::Never;
+    use fibers::time::timer;
+    use futures::{Async, Future, Poll, Sink, Stream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
     use trackable::result::TestResult;
 
     // RPC
@@ -301,100 +574,1987 @@ mod tests {
         fn enable_async_response(x: &Self::Res) -> bool {
             x == b"async"
         }
-    }
 
-    // Handler
-    struct EchoHandler;
-    impl HandleCall<EchoRpc> for EchoHandler {
-        fn handle_call(&self, request: <EchoRpc as Call>::Req) -> Reply<EchoRpc> {
-            Reply::done(request)
+        fn request_compression(x: &Self::Req) -> Compression {
+            if x.starts_with(b"compressed") {
+                Compression::Deflate
+            } else {
+                Compression::None
+            }
+        }
+
+        fn response_compression(x: &Self::Res) -> Compression {
+            if x.starts_with(b"compressed") {
+                Compression::Deflate
+            } else {
+                Compression::None
+            }
         }
     }
 
-    #[test]
-    fn it_works() -> TestResult {
-        // Server
-        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
-        builder.add_call_handler(EchoHandler);
-        let server = builder.finish(fibers_global::handle());
-        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
-        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+    // RPC which is not registered in servers
+    struct UnregisteredRpc;
+    impl Call for UnregisteredRpc {
+        const ID: ProcedureId = ProcedureId(1);
+        const NAME: &'static str = "unregistered";
 
-        // Client
-        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
-        let service_handle = service.handle();
-        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+        type Req = Vec<u8>;
+        type ReqEncoder = BytesEncoder<Vec<u8>>;
+        type ReqDecoder = RemainingBytesDecoder;
 
-        let request = Vec::from(&b"hello"[..]);
-        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
-        let response = track_any_err!(fibers_global::execute(response))?;
-        assert_eq!(response, request);
+        type Res = Vec<u8>;
+        type ResEncoder = BytesEncoder<Vec<u8>>;
+        type ResDecoder = RemainingBytesDecoder;
+    }
 
-        let metrics = service_handle
-            .metrics()
-            .channels()
-            .as_map()
-            .load()
-            .get(&server_addr)
-            .cloned()
-            .unwrap();
-        assert_eq!(metrics.async_outgoing_messages(), 0);
-        assert_eq!(metrics.async_incoming_messages(), 0);
-        Ok(())
+    // RPC which only accepts UTF-8 strings
+    struct Utf8EchoRpc;
+    impl Call for Utf8EchoRpc {
+        const ID: ProcedureId = ProcedureId(2);
+        const NAME: &'static str = "utf8_echo";
+
+        type Req = String;
+        type ReqEncoder = Utf8Encoder;
+        type ReqDecoder = Utf8Decoder;
+
+        type Res = String;
+        type ResEncoder = Utf8Encoder;
+        type ResDecoder = Utf8Decoder;
     }
 
-    #[test]
-    fn large_message_works() -> TestResult {
-        // Server
-        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
-        builder.add_call_handler(EchoHandler);
-        let server = builder.finish(fibers_global::handle());
-        // let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
-        let future = server.local_addr();
-        let (server, server_addr) = track!(fibers_global::execute(future))?;
-        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+    // RPC which has the same identifier as `Utf8EchoRpc` but can send arbitrary bytes
+    struct BytesEchoRpc;
+    impl Call for BytesEchoRpc {
+        const ID: ProcedureId = ProcedureId(2);
+        const NAME: &'static str = "bytes_echo";
 
-        // Client
-        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
-        let service_handle = service.handle();
-        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+        type Req = Vec<u8>;
+        type ReqEncoder = BytesEncoder<Vec<u8>>;
+        type ReqDecoder = RemainingBytesDecoder;
 
-        let request = vec![0; 10 * 1024 * 1024];
-        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
-        let response = track!(fibers_global::execute(response))?;
-        assert_eq!(response, request);
-        Ok(())
+        type Res = Vec<u8>;
+        type ResEncoder = BytesEncoder<Vec<u8>>;
+        type ResDecoder = RemainingBytesDecoder;
     }
 
-    #[test]
-    fn async_works() -> TestResult {
-        // Server
-        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
-        builder.add_call_handler(EchoHandler);
-        let server = builder.finish(fibers_global::handle());
-        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
-        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+    // RPC which can be retried safely
+    struct IdempotentEchoRpc;
+    impl Call for IdempotentEchoRpc {
+        const ID: ProcedureId = ProcedureId(3);
+        const NAME: &'static str = "idempotent_echo";
+        const IDEMPOTENT: bool = true;
 
-        // Client
-        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
-        let service_handle = service.handle();
-        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+        type Req = Vec<u8>;
+        type ReqEncoder = BytesEncoder<Vec<u8>>;
+        type ReqDecoder = RemainingBytesDecoder;
 
-        let request = Vec::from(&b"async"[..]);
-        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
-        let response = track!(fibers_global::execute(response))?;
-        assert_eq!(response, request);
+        type Res = Vec<u8>;
+        type ResEncoder = BytesEncoder<Vec<u8>>;
+        type ResDecoder = RemainingBytesDecoder;
+    }
 
-        let metrics = service_handle
-            .metrics()
-            .channels()
-            .as_map()
-            .load()
-            .get(&server_addr)
-            .cloned()
-            .unwrap();
-        assert_eq!(metrics.async_outgoing_messages(), 1);
-        assert_eq!(metrics.async_incoming_messages(), 1);
+    // Server-streaming RPC which replies each byte of the request as an item
+    struct SplitRpc;
+    impl StreamCall for SplitRpc {
+        const ID: ProcedureId = ProcedureId(4);
+        const NAME: &'static str = "split";
+
+        type Req = Vec<u8>;
+        type ReqEncoder = BytesEncoder<Vec<u8>>;
+        type ReqDecoder = RemainingBytesDecoder;
+
+        type Item = Vec<u8>;
+        type ItemEncoder = BytesEncoder<Vec<u8>>;
+        type ItemDecoder = RemainingBytesDecoder;
+
+        fn enable_async_item(x: &Self::Item) -> bool {
+            x == b"a"
+        }
+    }
+
+    // RPC which has the same identifier as `SplitRpc` but only accepts UTF-8 items
+    struct Utf8SplitRpc;
+    impl StreamCall for Utf8SplitRpc {
+        const ID: ProcedureId = ProcedureId(4);
+        const NAME: &'static str = "utf8_split";
+
+        type Req = Vec<u8>;
+        type ReqEncoder = BytesEncoder<Vec<u8>>;
+        type ReqDecoder = RemainingBytesDecoder;
+
+        type Item = String;
+        type ItemEncoder = Utf8Encoder;
+        type ItemDecoder = Utf8Decoder;
+    }
+
+    struct EchoStreamRpc;
+    impl BidiStreamCall for EchoStreamRpc {
+        const ID: ProcedureId = ProcedureId(5);
+        const NAME: &'static str = "echo_stream";
+
+        type Req = Vec<u8>;
+        type ReqEncoder = BytesEncoder<Vec<u8>>;
+        type ReqDecoder = RemainingBytesDecoder;
+
+        type Res = Vec<u8>;
+        type ResEncoder = BytesEncoder<Vec<u8>>;
+        type ResDecoder = RemainingBytesDecoder;
+
+        fn enable_async_request(x: &Self::Req) -> bool {
+            x == b"a"
+        }
+    }
+
+    // Client-streaming RPC
+    struct SumLenRpc;
+    impl BidiStreamCall for SumLenRpc {
+        const ID: ProcedureId = ProcedureId(6);
+        const NAME: &'static str = "sum_len";
+
+        type Req = Vec<u8>;
+        type ReqEncoder = BytesEncoder<Vec<u8>>;
+        type ReqDecoder = RemainingBytesDecoder;
+
+        type Res = String;
+        type ResEncoder = Utf8Encoder;
+        type ResDecoder = Utf8Decoder;
+    }
+
+    // Notification RPC
+    struct NotifyRpc;
+    impl Cast for NotifyRpc {
+        const ID: ProcedureId = ProcedureId(7);
+        const NAME: &'static str = "notify";
+
+        type Notification = Vec<u8>;
+        type Encoder = BytesEncoder<Vec<u8>>;
+        type Decoder = RemainingBytesDecoder;
+    }
+
+    // Handler
+    struct EchoHandler;
+    impl HandleCall<EchoRpc> for EchoHandler {
+        fn handle_call(&self, request: <EchoRpc as Call>::Req) -> Reply<EchoRpc> {
+            Reply::done(request)
+        }
+    }
+
+    struct IdempotentEchoHandler;
+    impl HandleCall<IdempotentEchoRpc> for IdempotentEchoHandler {
+        fn handle_call(
+            &self,
+            request: <IdempotentEchoRpc as Call>::Req,
+        ) -> Reply<IdempotentEchoRpc> {
+            Reply::done(request)
+        }
+    }
+
+    struct Utf8EchoHandler;
+    impl HandleCall<Utf8EchoRpc> for Utf8EchoHandler {
+        fn handle_call(&self, request: <Utf8EchoRpc as Call>::Req) -> Reply<Utf8EchoRpc> {
+            Reply::done(request)
+        }
+    }
+
+    struct ErrorHandler;
+    impl HandleCall<EchoRpc> for ErrorHandler {
+        fn handle_call(&self, request: <EchoRpc as Call>::Req) -> Reply<EchoRpc> {
+            Reply::error(String::from_utf8_lossy(&request))
+        }
+    }
+
+    // Handler which replies the remaining time (in milliseconds) until the deadline
+    struct DeadlineHandler;
+    impl HandleCallWithContext<EchoRpc> for DeadlineHandler {
+        fn handle_call_with_context(
+            &self,
+            context: RequestContext,
+            _request: <EchoRpc as Call>::Req,
+        ) -> Reply<EchoRpc> {
+            let response = context
+                .remaining_time()
+                .map(|d| d.as_millis().to_string())
+                .unwrap_or_default();
+            Reply::done(response.into_bytes())
+        }
+    }
+
+    struct EchoStreamHandler;
+    impl HandleBidiStreamCall<EchoStreamRpc> for EchoStreamHandler {
+        fn handle_bidi_stream_call(
+            &self,
+            requests: RequestStream<<EchoStreamRpc as BidiStreamCall>::Req>,
+        ) -> ReplyStream<<EchoStreamRpc as BidiStreamCall>::Res> {
+            ReplyStream::try_stream(requests)
+        }
+    }
+
+    // Handler which replies the total length of the requests
+    struct SumLenHandler;
+    impl HandleBidiStreamCall<SumLenRpc> for SumLenHandler {
+        fn handle_bidi_stream_call(
+            &self,
+            requests: RequestStream<<SumLenRpc as BidiStreamCall>::Req>,
+        ) -> ReplyStream<<SumLenRpc as BidiStreamCall>::Res> {
+            let sum = requests
+                .fold(0, |sum, x| Ok::<_, crate::Error>(sum + x.len()))
+                .map(|sum| sum.to_string());
+            ReplyStream::try_stream(sum.into_stream())
+        }
+    }
+
+    // Handler which fails the stream at '!' (or never ends the stream if the request is "pending")
+    struct SplitHandler;
+    impl HandleStreamCall<SplitRpc> for SplitHandler {
+        fn handle_stream_call(
+            &self,
+            request: <SplitRpc as StreamCall>::Req,
+        ) -> ReplyStream<<SplitRpc as StreamCall>::Item> {
+            if request == b"pending" {
+                let stream = futures::stream::poll_fn(|| -> Poll<Option<Vec<u8>>, Never> {
+                    Ok(Async::NotReady)
+                });
+                return ReplyStream::stream(stream);
+            }
+            let mut items = Vec::new();
+            for b in request {
+                if b == b'!' {
+                    items.push(Err("unexpected '!'"));
+                    break;
+                }
+                items.push(Ok(vec![b]));
+            }
+            ReplyStream::try_stream(futures::stream::iter_result(items))
+        }
+    }
+
+    // Handler which replies the context of the request
+    struct ContextHandler;
+    impl HandleCallWithContext<EchoRpc> for ContextHandler {
+        fn handle_call_with_context(
+            &self,
+            context: RequestContext,
+            _request: <EchoRpc as Call>::Req,
+        ) -> Reply<EchoRpc> {
+            let response = format!(
+                "{},{:?},{},{:?}",
+                context.peer().as_socket_addr().unwrap().ip(),
+                context.procedure(),
+                context.priority(),
+                context
+                    .metadata()
+                    .get("trace_id")
+                    .map(|v| String::from_utf8_lossy(v))
+            );
+            Reply::done(response.into_bytes())
+        }
+    }
+
+    // Handler which never replies
+    #[derive(Default, Clone)]
+    struct PendingHandler {
+        invoked: Arc<AtomicBool>,
+        dropped: Arc<AtomicBool>,
+    }
+    impl HandleCall<EchoRpc> for PendingHandler {
+        fn handle_call(&self, _request: <EchoRpc as Call>::Req) -> Reply<EchoRpc> {
+            self.invoked.store(true, Ordering::SeqCst);
+            Reply::future(PendingReply {
+                dropped: Arc::clone(&self.dropped),
+            })
+        }
+    }
+
+    // Handler which replies the request after a short delay
+    #[derive(Default, Clone)]
+    struct SlowEchoHandler {
+        invoked: Arc<AtomicBool>,
+    }
+    impl HandleCall<EchoRpc> for SlowEchoHandler {
+        fn handle_call(&self, request: <EchoRpc as Call>::Req) -> Reply<EchoRpc> {
+            self.invoked.store(true, Ordering::SeqCst);
+            let future = timer::timeout(Duration::from_millis(100)).then(|_| Ok(request));
+            Reply::future(future)
+        }
+    }
+
+    // Handler which handles notifications after a short delay
+    #[derive(Default, Clone)]
+    struct SlowNotifyHandler {
+        invoked: Arc<AtomicBool>,
+        handled: Arc<AtomicBool>,
+    }
+    impl HandleCast<NotifyRpc> for SlowNotifyHandler {
+        fn handle_cast(&self, _notification: <NotifyRpc as Cast>::Notification) -> NoReply {
+            self.invoked.store(true, Ordering::SeqCst);
+            let handled = Arc::clone(&self.handled);
+            let future = timer::timeout(Duration::from_millis(100)).then(move |_| {
+                handled.store(true, Ordering::SeqCst);
+                Ok(())
+            });
+            NoReply::future(future)
+        }
+    }
+
+    struct PendingReply {
+        dropped: Arc<AtomicBool>,
+    }
+    impl Future for PendingReply {
+        type Item = Vec<u8>;
+        type Error = Never;
+
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+    impl Drop for PendingReply {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn wait_until(flag: &AtomicBool) -> Result<()> {
+        for _ in 0..500 {
+            if flag.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        track_panic!(ErrorKind::Timeout)
+    }
+
+    #[test]
+    fn it_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track_any_err!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        let metrics = service_handle
+            .metrics()
+            .channels()
+            .as_map()
+            .load()
+            .get(&server_addr)
+            .cloned()
+            .unwrap();
+        assert_eq!(metrics.async_outgoing_messages(), 0);
+        assert_eq!(metrics.async_incoming_messages(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn tiny_write_buffer_works() -> TestResult {
+        // Smaller than the hello message
+        let options = ChannelOptions {
+            write_buffer_size: 1,
+            ..Default::default()
+        };
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.channel_options(options.clone());
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new()
+            .channel_options(options)
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track_any_err!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+        Ok(())
+    }
+
+    #[test]
+    fn large_message_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        // let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        let future = server.local_addr();
+        let (server, server_addr) = track!(fibers_global::execute(future))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = vec![0; 10 * 1024 * 1024];
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+        Ok(())
+    }
+
+    #[test]
+    fn async_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"async"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        let metrics = service_handle
+            .metrics()
+            .channels()
+            .as_map()
+            .load()
+            .get(&server_addr)
+            .cloned()
+            .unwrap();
+        assert_eq!(metrics.async_outgoing_messages(), 1);
+        assert_eq!(metrics.async_incoming_messages(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let mut request = Vec::from(&b"compressed"[..]);
+        request.extend_from_slice(&[b'a'; 10000][..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        let metrics = service_handle
+            .metrics()
+            .channels()
+            .as_map()
+            .load()
+            .get(&server_addr)
+            .cloned()
+            .unwrap();
+        if Compression::Deflate.is_supported() {
+            assert_eq!(metrics.uncompressed_outgoing_bytes(), request.len() as u64);
+            assert!(metrics.compressed_outgoing_bytes() < request.len() as u64);
+            assert_eq!(metrics.uncompressed_incoming_bytes(), response.len() as u64);
+            assert!(metrics.compressed_incoming_bytes() < response.len() as u64);
+        } else {
+            // Sent uncompressed
+            assert_eq!(metrics.uncompressed_outgoing_bytes(), 0);
+            assert_eq!(metrics.uncompressed_incoming_bytes(), 0);
+        }
+        assert_eq!(metrics.async_outgoing_messages(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn stream_call_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_stream_call_handler(SplitHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // Some of the items are decoded asynchronously, but the order is preserved
+        let stream = SplitRpc::client(&service_handle).call(server_addr, b"banana".to_vec());
+        let items = track_any_err!(fibers_global::execute(stream.collect()))?;
+        assert_eq!(
+            items,
+            b"banana".iter().map(|&b| vec![b]).collect::<Vec<_>>()
+        );
+
+        // Empty stream
+        let stream = SplitRpc::client(&service_handle).call(server_addr, Vec::new());
+        let items = track_any_err!(fibers_global::execute(stream.collect()))?;
+        assert!(items.is_empty());
+
+        // The stream fails after replying some items
+        let stream = SplitRpc::client(&service_handle).call(server_addr, b"ab!c".to_vec());
+        let results = stream.then(Ok::<_, crate::Error>).collect();
+        let results = track_any_err!(fibers_global::execute(results))?;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().ok(), Some(&b"a".to_vec()));
+        assert_eq!(results[1].as_ref().ok(), Some(&b"b".to_vec()));
+        let e = results[2].as_ref().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ServerError);
+
+        // The timeout is applied to the entire stream
+        let mut client = SplitRpc::client(&service_handle);
+        client.options_mut().timeout = Some(Duration::from_millis(100));
+        let stream = client.call(server_addr, b"pending".to_vec());
+        let e = fibers_global::execute(stream.collect()).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Timeout);
+        Ok(())
+    }
+
+    #[test]
+    fn stream_item_decode_error_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_stream_call_handler(SplitHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // The second item is not a valid UTF-8 string
+        let stream = Utf8SplitRpc::client(&service_handle).call(server_addr, b"x\xffy".to_vec());
+        let results = stream.then(Ok::<_, crate::Error>).collect();
+        let results = track_any_err!(fibers_global::execute(results))?;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().ok(), Some(&"x".to_owned()));
+        let e = results[1].as_ref().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::DecodeFailed);
+
+        // The channel is still alive
+        let stream = SplitRpc::client(&service_handle).call(server_addr, b"banana".to_vec());
+        let items = track_any_err!(fibers_global::execute(stream.collect()))?;
+        assert_eq!(items.len(), 6);
+        assert_eq!(service_handle.metrics().channels().created_channels(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn bidi_stream_call_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_bidi_stream_call_handler(EchoStreamHandler);
+        builder.add_bidi_stream_call_handler(SumLenHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // The server replies before the client closes the request stream
+        let (sink, stream) = EchoStreamRpc::client(&service_handle).call(server_addr);
+        let mut sink = track!(fibers_global::execute(sink.send(b"foo".to_vec())))?;
+        let (item, stream) = track!(fibers_global::execute(
+            stream.into_future().map_err(|(e, _)| e)
+        ))?;
+        assert_eq!(item, Some(b"foo".to_vec()));
+
+        // Some of the requests are decoded asynchronously, but the order is preserved
+        for x in ["a", "b", "a", "c"] {
+            sink = track!(fibers_global::execute(sink.send(x.as_bytes().to_vec())))?;
+        }
+        track!(sink.close())?;
+        let items = track!(fibers_global::execute(stream.collect()))?;
+        assert_eq!(
+            items,
+            vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec(), b"c".to_vec()]
+        );
+
+        // Client-streaming (dropping the sink closes the request stream)
+        let (sink, stream) = SumLenRpc::client(&service_handle).call(server_addr);
+        let requests =
+            futures::stream::iter_ok::<_, crate::Error>(vec![b"foo".to_vec(), b"ba".to_vec()]);
+        let (sink, _) = track!(fibers_global::execute(sink.send_all(requests)))?;
+        drop(sink);
+        let responses = track!(fibers_global::execute(stream.collect()))?;
+        assert_eq!(responses, vec!["5".to_owned()]);
+
+        // Empty request stream
+        let (_, stream) = SumLenRpc::client(&service_handle).call(server_addr);
+        let responses = track!(fibers_global::execute(stream.collect()))?;
+        assert_eq!(responses, vec!["0".to_owned()]);
+        Ok(())
+    }
+
+    #[test]
+    fn error_reply_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(ErrorHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"something wrong"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request);
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ServerError);
+        assert!(e.to_string().contains("something wrong"));
+        assert_eq!(service_handle.metrics().error_responses(), 1);
+
+        // The channel is still available
+        let request = Vec::from(&b"again"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request);
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ServerError);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_procedure_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = UnregisteredRpc::client(&service_handle).call(server_addr, request);
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::UnknownProcedure);
+
+        // No error is replied to a notification
+        track!(NotifyRpc::client(&service_handle).cast(server_addr, b"hello".to_vec()))?;
+
+        // The channel is still alive
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+        assert_eq!(service_handle.metrics().channels().created_channels(), 1);
+        assert_eq!(service_handle.metrics().unknown_responses(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_response_is_discarded() -> TestResult {
+        use crate::handshake::Hello;
+        use crate::packet::PacketHeader;
+        use byteorder::{BigEndian, ByteOrder};
+        use std::io::{Read, Write};
+
+        // A server which replies a response to an unknown request before the actual response
+        let listener = track_any_err!(std::net::TcpListener::bind("127.0.0.1:0"))?;
+        let server_addr = track_any_err!(listener.local_addr())?;
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&Hello::local().to_bytes()).unwrap();
+            let mut hello = [0; Hello::SIZE];
+            stream.read_exact(&mut hello).unwrap();
+            let (header, payload) = loop {
+                let mut header = [0; PacketHeader::SIZE];
+                stream.read_exact(&mut header).unwrap();
+                let mut payload = vec![0; BigEndian::read_u32(&header[14..]) as usize];
+                stream.read_exact(&mut payload).unwrap();
+                if header[13] & 0b0000_1000 == 0 {
+                    // Not a control message
+                    break (header, payload);
+                }
+            };
+
+            let mut unknown = header;
+            BigEndian::write_u64(&mut unknown[..], BigEndian::read_u64(&header[..]) + 100);
+            for (header, payload) in [(unknown, &b"foo"[..]), (header, &payload[..])] {
+                let mut packet = header.to_vec();
+                packet[13] = 0b0000_0001; // `END_OF_MESSAGE`
+                BigEndian::write_u32(&mut packet[14..], payload.len() as u32);
+                packet.extend_from_slice(payload);
+                stream.write_all(&packet).unwrap();
+            }
+            let _ = stream.read(&mut [0; 1]);
+        });
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let response = EchoRpc::client(&service_handle).call(server_addr, b"hello".to_vec());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, b"hello");
+        assert_eq!(service_handle.metrics().unknown_responses(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_error_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(Utf8EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let handler_metrics = server.metrics().handlers()[&Utf8EchoRpc::ID].clone();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = vec![0xff, 0xfe];
+        let response = BytesEchoRpc::client(&service_handle).call(server_addr, request);
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::DecodeFailed);
+        assert_eq!(handler_metrics.decode_errors(), 1);
+
+        // The channel is still alive
+        let request = "hello".to_owned();
+        let response = Utf8EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+        assert_eq!(handler_metrics.decode_errors(), 1);
+        assert_eq!(service_handle.metrics().channels().created_channels(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_compressed_message_is_rejected() -> TestResult {
+        use crate::handshake::Hello;
+        use std::io::{Read, Write};
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // A peer which sends a message with a broken DEFLATE stream
+        let mut stream = track_any_err!(std::net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(&Hello::local().to_bytes()))?;
+        let mut hello = [0; Hello::SIZE];
+        track_any_err!(stream.read_exact(&mut hello))?;
+
+        fn write_packet(stream: &mut std::net::TcpStream, id: u8, flags: u8, payload: &[u8]) {
+            let mut packet = vec![0, 0, 0, 0, 0, 0, 0, id, 0, 0, 0, 0, 0, flags, 0, 0, 0];
+            packet.push(payload.len() as u8);
+            packet.extend_from_slice(payload);
+            stream.write_all(&packet).unwrap();
+        }
+        fn read_reply(stream: &mut std::net::TcpStream) -> (u8, u8, Vec<u8>) {
+            loop {
+                let mut header = [0; 18];
+                stream.read_exact(&mut header).unwrap();
+                let mut payload = vec![0; header[17] as usize];
+                stream.read_exact(&mut payload).unwrap();
+                let flags = header[13];
+                if flags & 0b0000_1000 == 0 {
+                    // Not a control message
+                    return (header[7], flags, payload);
+                }
+            }
+        }
+
+        // `END_OF_MESSAGE | COMPRESSED`, the DEFLATE code followed by a block of the reserved type
+        write_packet(&mut stream, 1, 0b0010_0001, &[1, 0xff, 0xff, 0xff]);
+        let (id, flags, payload) = read_reply(&mut stream);
+        assert_eq!(id, 1);
+        assert_ne!(flags & 0b0000_0100, 0); // `ERROR`
+        if Compression::Deflate.is_supported() {
+            assert_eq!(payload[0], 6); // `ErrorKind::DecodeFailed`
+        } else {
+            assert_eq!(payload[0], 1); // `ErrorKind::InvalidInput`
+        }
+
+        // The channel is still alive
+        write_packet(&mut stream, 2, 0b0000_0001, b"hello");
+        let (id, flags, payload) = read_reply(&mut stream);
+        assert_eq!(id, 2);
+        assert_eq!(flags & 0b0000_0100, 0);
+        assert_eq!(payload, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_works() -> TestResult {
+        // Server
+        let handler = PendingHandler::default();
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(handler.clone());
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // Dropping the response cancels the request
+        let response = EchoRpc::client(&service_handle).call(server_addr, b"foo".to_vec());
+        track!(wait_until(&handler.invoked))?;
+        assert!(!handler.dropped.load(Ordering::SeqCst));
+        std::mem::drop(response);
+        track!(wait_until(&handler.dropped))?;
+
+        // Timeout also cancels the request
+        let handler = PendingHandler::default();
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(handler.clone());
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        let mut client = EchoRpc::client(&service_handle);
+        client.options_mut().timeout = Some(Duration::from_millis(100));
+        let response = client.call(server_addr, b"foo".to_vec());
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Timeout);
+        track!(wait_until(&handler.dropped))?;
+        Ok(())
+    }
+
+    #[test]
+    fn deadline_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(DeadlineHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // No timeout
+        let response = EchoRpc::client(&service_handle).call(server_addr, Vec::new());
+        let response = track!(fibers_global::execute(response))?;
+        assert!(response.is_empty());
+
+        // With timeout
+        let mut client = EchoRpc::client(&service_handle);
+        client.options_mut().timeout = Some(Duration::from_secs(10));
+        let response = client.call(server_addr, Vec::new());
+        let response = track!(fibers_global::execute(response))?;
+        let remaining: u64 = String::from_utf8(response).unwrap().parse().unwrap();
+        assert!(remaining > 5_000 && remaining <= 10_000, "{}", remaining);
+        Ok(())
+    }
+
+    #[test]
+    fn request_context_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(ContextHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let response = EchoRpc::client(&service_handle).call(server_addr, Vec::new());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, b"127.0.0.1,ProcedureId(0x00000000),128,None");
+
+        let mut client = EchoRpc::client(&service_handle);
+        client.options_mut().priority = 3;
+        client
+            .options_mut()
+            .metadata
+            .insert("trace_id".to_owned(), b"foo".to_vec());
+        let response = client.call(server_addr, Vec::new());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(
+            response,
+            &b"127.0.0.1,ProcedureId(0x00000000),3,Some(\"foo\")"[..]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn graceful_shutdown_works() -> TestResult {
+        // Server
+        let handler = SlowEchoHandler::default();
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(handler.clone());
+        let server = builder.finish(fibers_global::handle());
+        let server_handle = server.handle();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        let server = fibers_global::spawn_monitor(server);
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // The outstanding request is completed even if the server starts shutting down
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        track!(wait_until(&handler.invoked))?;
+        server_handle.shutdown(Duration::from_secs(10));
+
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        // The server stops after the client closes the connection
+        assert!(fibers_global::execute(server).is_ok());
+
+        // New requests are rejected
+        let response = EchoRpc::client(&service_handle).call(server_addr, request);
+        assert!(fibers_global::execute(response).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn graceful_shutdown_waits_for_notifications() -> TestResult {
+        // Server
+        let handler = SlowNotifyHandler::default();
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_cast_handler(handler.clone());
+        let server = builder.finish(fibers_global::handle());
+        let server_handle = server.handle();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        let server = fibers_global::spawn_monitor(server);
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // The server stops after the outstanding notification has been handled
+        track!(NotifyRpc::client(&service_handle).cast(server_addr, b"hello".to_vec()))?;
+        track!(wait_until(&handler.invoked))?;
+        server_handle.shutdown(Duration::from_secs(10));
+        assert!(fibers_global::execute(server).is_ok());
+        assert!(handler.handled.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn graceful_shutdown_with_connected_client_works() -> TestResult {
+        use crate::handshake::Hello;
+        use std::io::{Read, Write};
+        use std::time::Instant;
+
+        // Server
+        let handler = SlowEchoHandler::default();
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(handler.clone());
+        let server = builder.finish(fibers_global::handle());
+        let server_handle = server.handle();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        let server = fibers_global::spawn_monitor(server);
+
+        // A client which never closes the connection by itself
+        let mut stream = track_any_err!(std::net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(&Hello::local().to_bytes()))?;
+        let mut hello = [0; Hello::SIZE];
+        track_any_err!(stream.read_exact(&mut hello))?;
+
+        fn write_request(stream: &mut std::net::TcpStream, id: u8, payload: &[u8]) {
+            // `END_OF_MESSAGE`, procedure=`EchoRpc::ID`
+            let mut packet = vec![0, 0, 0, 0, 0, 0, 0, id, 0, 0, 0, 0, 0, 0b0000_0001, 0, 0, 0];
+            packet.push(payload.len() as u8);
+            packet.extend_from_slice(payload);
+            stream.write_all(&packet).unwrap();
+        }
+        fn read_packet(stream: &mut std::net::TcpStream) -> (u8, u8, Vec<u8>) {
+            let mut header = [0; 18];
+            stream.read_exact(&mut header).unwrap();
+            let mut payload = vec![0; header[17] as usize];
+            stream.read_exact(&mut payload).unwrap();
+            (header[7], header[13], payload)
+        }
+
+        write_request(&mut stream, 1, b"hello");
+        track!(wait_until(&handler.invoked))?;
+        let started_at = Instant::now();
+        server_handle.shutdown(Duration::from_secs(10));
+
+        // GOAWAY (i.e., a control message of type 1)
+        loop {
+            let (_, flags, payload) = read_packet(&mut stream);
+            if flags & 0b0000_1000 != 0 && payload == [1] {
+                break;
+            }
+        }
+
+        // The request sent after GOAWAY is rejected
+        write_request(&mut stream, 2, b"world");
+        let mut replies = Vec::new();
+        while replies.len() < 2 {
+            let (id, flags, payload) = read_packet(&mut stream);
+            if flags & 0b0000_1000 == 0 {
+                replies.push((id, flags, payload));
+            }
+        }
+        replies.sort();
+        assert_eq!(replies[0].0, 1);
+        assert_eq!(replies[0].2, b"hello");
+        assert_eq!(replies[1].0, 2);
+        assert_ne!(replies[1].1 & 0b0000_0100, 0); // `ERROR`
+        assert_eq!(replies[1].2[0], 2); // `ErrorKind::Unavailable`
+
+        // The server stops without waiting for the grace period
+        assert!(fibers_global::execute(server).is_ok());
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn client_service_shutdown_works() -> TestResult {
+        // Server
+        let handler = SlowEchoHandler::default();
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(handler.clone());
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        let service = fibers_global::spawn_monitor(service);
+
+        // The outstanding request is completed even if the service starts shutting down
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        track!(wait_until(&handler.invoked))?;
+        service_handle.shutdown(Duration::from_secs(10));
+
+        // New requests are rejected
+        let rejected = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let e = fibers_global::execute(rejected).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Unavailable);
+
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        // The service stops after all the channels are drained
+        assert!(fibers_global::execute(service).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn incompatible_peer_is_rejected() -> TestResult {
+        use std::io::{Read, Write};
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // A peer which does not speak the protocol
+        let mut stream = track_any_err!(std::net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(b"GET / HTTP/1.1\r\n\r\n"))?;
+
+        // The server sends its hello message and closes the connection
+        let mut buf = Vec::new();
+        track_any_err!(stream.read_to_end(&mut buf))?;
+        assert!(buf.starts_with(b"FRPC"));
+        Ok(())
+    }
+
+    #[test]
+    fn heartbeat_works() -> TestResult {
+        let options = ChannelOptions {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.channel_options(options.clone());
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new()
+            .channel_options(options)
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        // Heartbeat pings are exchanged while the channel is idle
+        thread::sleep(Duration::from_millis(300));
+        let metrics = service_handle
+            .metrics()
+            .channels()
+            .as_map()
+            .load()
+            .get(&server_addr)
+            .cloned()
+            .unwrap();
+        assert!(metrics.round_trip_time().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn idle_channel_with_heartbeat_is_closed() -> TestResult {
+        let options = ChannelOptions {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.channel_options(options.clone());
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new()
+            .channel_options(options)
+            .keep_alive_timeout(Duration::from_millis(500))
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        let channel_metrics = || {
+            service_handle
+                .metrics()
+                .channels()
+                .as_map()
+                .load()
+                .get(&server_addr)
+                .cloned()
+        };
+        thread::sleep(Duration::from_millis(200));
+        assert!(channel_metrics().is_some());
+
+        // Heartbeat pings do not keep the idle channel alive
+        thread::sleep(Duration::from_millis(1000));
+        assert!(channel_metrics().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn flow_control_works() -> TestResult {
+        // The smallest windows
+        let options = ChannelOptions {
+            channel_window_size: 0,
+            stream_window_size: 0,
+            ..Default::default()
+        };
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.add_stream_call_handler(SplitHandler);
+        builder.channel_options(options.clone());
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new()
+            .channel_options(options)
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // Messages larger than the windows
+        let request = vec![1; 1024 * 1024];
+        let responses = (0..4)
+            .map(|_| EchoRpc::client(&service_handle).call(server_addr, request.clone()))
+            .collect::<Vec<_>>();
+        let responses = track!(fibers_global::execute(futures::future::join_all(responses)))?;
+        assert!(responses.iter().all(|r| *r == request));
+
+        // A stream whose items exceed the windows in total
+        let request = vec![2; 100_000];
+        let stream = SplitRpc::client(&service_handle).call(server_addr, request.clone());
+        let items = track_any_err!(fibers_global::execute(stream.collect()))?;
+        assert_eq!(items.concat(), request);
+        Ok(())
+    }
+
+    #[test]
+    fn flow_control_violation_is_detected() -> TestResult {
+        use crate::flow_control::INITIAL_WINDOW_SIZE;
+        use crate::handshake::Hello;
+        use std::io::{self, Read, Write};
+
+        // Server
+        let options = ChannelOptions {
+            channel_window_size: 0,
+            ..Default::default()
+        };
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.channel_options(options);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // A peer which ignores the windows
+        let mut stream = track_any_err!(std::net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(&Hello::local().to_bytes()))?;
+        let mut hello = [0; Hello::SIZE];
+        track_any_err!(stream.read_exact(&mut hello))?;
+
+        fn write_packet(stream: &mut std::net::TcpStream, id: u8, flags: u8, payload: &[u8]) {
+            let mut packet = vec![0, 0, 0, 0, 0, 0, 0, id, 0, 0, 0, 0, 0, flags];
+            packet.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            packet.extend_from_slice(payload);
+            stream.write_all(&packet).unwrap();
+        }
+
+        // The first message (which is not completed yet) consumes the whole channel window
+        write_packet(&mut stream, 1, 0, &vec![0; INITIAL_WINDOW_SIZE as usize]);
+
+        // The second message is started even though the window is exhausted
+        write_packet(&mut stream, 2, 0b0000_0001, b"hello");
+
+        // The server closes the connection
+        let mut buf = Vec::new();
+        match stream.read_to_end(&mut buf) {
+            Ok(_) => {}
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_incoming_message_size_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.procedure_max_incoming_message_size(EchoRpc::ID, 2000);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let options = ChannelOptions {
+            max_incoming_message_size: Some(1000),
+            ..Default::default()
+        };
+        let service = ClientServiceBuilder::new()
+            .channel_options(options)
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // Rejected by the server (a message spanning multiple packets)
+        let request = vec![0; 20 * 1024 * 1024];
+        let response = EchoRpc::client(&service_handle).call(server_addr, request);
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        // Rejected by the client
+        let response = EchoRpc::client(&service_handle).call(server_addr, vec![0; 1500]);
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        // The channel is still available
+        let request = vec![0; 500];
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        let metrics = service_handle
+            .metrics()
+            .channels()
+            .as_map()
+            .load()
+            .get(&server_addr)
+            .cloned()
+            .unwrap();
+        assert_eq!(metrics.rejected_incoming_messages(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn max_incoming_message_size_applies_to_decompressed_payload() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.procedure_max_incoming_message_size(EchoRpc::ID, 64 * 1024);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // A highly compressible payload (it is rejected by the packet level check if not compressed)
+        let mut request = Vec::from(&b"compressed"[..]);
+        request.extend_from_slice(&[0; 8 * 1024 * 1024][..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request);
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        // The channel is still available
+        let request = Vec::from(&b"compressed"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+        assert_eq!(service_handle.metrics().channels().created_channels(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn unresponsive_server_is_detected_by_heartbeat() -> TestResult {
+        use std::io::{Read, Write};
+
+        // A server which completes the handshake but never answers after that
+        let listener = track_any_err!(std::net::TcpListener::bind("127.0.0.1:0"))?;
+        let server_addr = track_any_err!(listener.local_addr())?;
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(&crate::handshake::Hello::local().to_bytes())
+                .unwrap();
+            let mut buf = Vec::new();
+            let _ = stream.read_to_end(&mut buf);
+        });
+
+        // Client
+        let options = ChannelOptions {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            heartbeat_miss_threshold: 2,
+            ..Default::default()
+        };
+        let service = ClientServiceBuilder::new()
+            .channel_options(options)
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let mut client = EchoRpc::client(&service_handle);
+        client.options_mut().timeout = Some(Duration::from_secs(10));
+        let response = client.call(server_addr, Vec::from(&b"hello"[..]));
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionLost { request_sent: true });
+        Ok(())
+    }
+
+    #[test]
+    fn connection_lost_works() -> TestResult {
+        use std::io::{Read, Write};
+
+        // A server which closes the connection after receiving a request
+        let listener = track_any_err!(std::net::TcpListener::bind("127.0.0.1:0"))?;
+        let server_addr = track_any_err!(listener.local_addr())?;
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(&crate::handshake::Hello::local().to_bytes())
+                .unwrap();
+            let mut buf = [0; crate::handshake::Hello::SIZE + crate::packet::PacketHeader::SIZE];
+            stream.read_exact(&mut buf).unwrap();
+        });
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let mut client = EchoRpc::client(&service_handle);
+        client.options_mut().timeout = Some(Duration::from_secs(10));
+        let response = client.call(server_addr, Vec::from(&b"hello"[..]));
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionLost { request_sent: true });
+        Ok(())
+    }
+
+    #[test]
+    fn retry_works() -> TestResult {
+        // Reserves a port on which no server is listening (for now)
+        let server_addr = {
+            let listener = track_any_err!(std::net::TcpListener::bind("127.0.0.1:0"))?;
+            track_any_err!(listener.local_addr())?
+        };
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let retry_policy = RetryPolicy {
+            max_attempts: 100,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        // A request which could not be written is reported as such
+        let response = EchoRpc::client(&service_handle).call(server_addr, Vec::from(&b"hello"[..]));
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(
+            *e.kind(),
+            ErrorKind::ConnectionLost {
+                request_sent: false
+            }
+        );
+        assert_eq!(service_handle.metrics().retries(), 0);
+
+        // Server
+        let mut builder = ServerBuilder::new(server_addr);
+        builder.add_call_handler(IdempotentEchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Idempotent RPC is retried until the client reconnects to the server
+        let mut client = IdempotentEchoRpc::client(&service_handle);
+        client.options_mut().retry_policy = retry_policy;
+        let response = client.call(server_addr, Vec::from(&b"hello"[..]));
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, b"hello");
+        assert_ne!(service_handle.metrics().retries(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn server_set_works() -> TestResult {
+        // Servers
+        let mut servers = Vec::new();
+        let mut server_metrics = Vec::new();
+        for _ in 0..2 {
+            let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+            builder.add_call_handler(EchoHandler);
+            let server = builder.finish(fibers_global::handle());
+            let (server, addr) = track!(fibers_global::execute(server.local_addr()))?;
+            server_metrics.push(server.metrics().clone());
+            fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+            servers.push(addr);
+        }
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let set = ServerSet::new(service_handle.clone(), BalanceStrategy::RoundRobin, servers);
+        for _ in 0..4 {
+            let request = Vec::from(&b"hello"[..]);
+            let response = set.call(EchoRpc::client(&service_handle), request.clone());
+            let response = track!(fibers_global::execute(response))?;
+            assert_eq!(response, request);
+        }
+        for metrics in server_metrics {
+            assert_eq!(metrics.handlers()[&EchoRpc::ID].rpc_count(), 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resolver_works() -> TestResult {
+        // Servers
+        let mut servers = Vec::new();
+        for _ in 0..2 {
+            let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+            builder.add_call_handler(EchoHandler);
+            let server = builder.finish(fibers_global::handle());
+            let (server, addr) = track!(fibers_global::execute(server.local_addr()))?;
+            fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+            servers.push(TransportAddr::Tcp(addr));
+        }
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let path =
+            std::env::temp_dir().join(format!("fibers_rpc_resolver_{}.txt", std::process::id()));
+        track_any_err!(std::fs::write(&path, format!("{}\n", servers[0])))?;
+        let resolver = FileResolver::with_interval(&path, Duration::from_millis(10));
+        service_handle.add_resolver("echo", resolver);
+
+        let set = service_handle.server_set("echo", BalanceStrategy::RoundRobin);
+        let wait_members = |expected: &[TransportAddr]| {
+            for _ in 0..500 {
+                if set.servers() == expected {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(set.servers(), expected);
+        };
+        wait_members(&servers[..1]);
+        let request = Vec::from(&b"hello"[..]);
+        let response = set.call(EchoRpc::client(&service_handle), request.clone());
+        assert_eq!(track!(fibers_global::execute(response))?, request);
+
+        // Membership change
+        track_any_err!(std::fs::write(
+            &path,
+            format!("# comment\n{}\n", servers[1])
+        ))?;
+        wait_members(&servers[1..]);
+        let response = set.call(EchoRpc::client(&service_handle), request.clone());
+        assert_eq!(track!(fibers_global::execute(response))?, request);
+
+        // The channel to the removed server is closed
+        let metrics = service_handle.metrics().channels();
+        for _ in 0..500 {
+            if !metrics.as_transport_map().load().contains_key(&servers[0]) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!metrics.as_transport_map().load().contains_key(&servers[0]));
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn failing_resolver_is_retried_with_delay() -> TestResult {
+        use crate::client::Resolver;
+        use std::sync::atomic::AtomicUsize;
+
+        #[derive(Debug)]
+        struct FailingResolver(Arc<AtomicUsize>);
+        impl Resolver for FailingResolver {
+            fn poll_resolve(&mut self) -> Poll<Option<Vec<TransportAddr>>, crate::Error> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                track_panic!(ErrorKind::Unavailable, "Always fails")
+            }
+        }
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let polls = Arc::new(AtomicUsize::new(0));
+        service_handle.add_resolver("echo", FailingResolver(polls.clone()));
+        thread::sleep(Duration::from_millis(100));
+
+        // The service keeps working without busy-polling the resolver
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        assert_eq!(track!(fibers_global::execute(response))?, request);
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn circuit_breaker_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(ErrorHandler);
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+        let server_addr = TransportAddr::Tcp(server_addr);
+
+        // Client
+        let service = ClientServiceBuilder::new()
+            .circuit_breaker(CircuitBreakerPolicy {
+                consecutive_failures: 2,
+                open_duration: Duration::from_millis(100),
+                ..Default::default()
+            })
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // Two consecutive failures open the breaker
+        for _ in 0..2 {
+            let response = EchoRpc::client(&service_handle).call(&server_addr, b"foo".to_vec());
+            let e = fibers_global::execute(response).err().unwrap();
+            assert_eq!(*e.kind(), ErrorKind::ServerError);
+        }
+        assert_eq!(
+            service_handle.circuit_state(&server_addr),
+            Some(CircuitState::Open)
+        );
+        assert!(service_handle.is_server_down(&server_addr));
+        assert_eq!(service_handle.metrics().open_circuit_breakers(), 1);
+
+        // Requests are rejected while the breaker is open
+        let response = EchoRpc::client(&service_handle).call(&server_addr, b"foo".to_vec());
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Unavailable);
+        assert_eq!(service_handle.metrics().circuit_breaker_rejections(), 1);
+        assert_eq!(service_handle.metrics().requests(), 2);
+
+        // A probing request is sent after the open duration
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(
+            service_handle.circuit_state(&server_addr),
+            Some(CircuitState::HalfOpen)
+        );
+        let response = EchoRpc::client(&service_handle).call(&server_addr, b"foo".to_vec());
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ServerError);
+        assert_eq!(
+            service_handle.circuit_state(&server_addr),
+            Some(CircuitState::Open)
+        );
+        assert_eq!(service_handle.metrics().circuit_breaker_opened(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn concurrency_limit_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(SlowEchoHandler::default());
+        builder.concurrency_limit(ConcurrencyLimit {
+            max_in_flight: 1,
+            max_queued: 1,
+        });
+        let server = builder.finish(fibers_global::handle());
+        let metrics = server.metrics().handlers()[&EchoRpc::ID].clone();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // The first request is handled, the second one is queued and the third one is rejected
+        let responses = (0..3)
+            .map(|_| {
+                EchoRpc::client(&service_handle)
+                    .call(server_addr, b"foo".to_vec())
+                    .then(Ok::<_, crate::Error>)
+            })
+            .collect::<Vec<_>>();
+        let results: Vec<Result<Vec<u8>>> =
+            track!(fibers_global::execute(futures::future::join_all(responses)))?;
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+        let e = results.into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Overloaded);
+
+        assert_eq!(metrics.overloaded(), 1);
+        assert_eq!(metrics.in_flight(), 0);
+        assert_eq!(metrics.queued(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn concurrency_limit_applies_to_notifications() -> TestResult {
+        // Server
+        let handler = SlowNotifyHandler::default();
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(SlowEchoHandler::default());
+        builder.add_cast_handler(handler.clone());
+        builder.concurrency_limit(ConcurrencyLimit {
+            max_in_flight: 1,
+            max_queued: 0,
+        });
+        let server = builder.finish(fibers_global::handle());
+        let metrics = server.metrics().handlers()[&NotifyRpc::ID].clone();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        // The notification being handled holds the permit
+        track!(NotifyRpc::client(&service_handle).cast(server_addr, b"foo".to_vec()))?;
+        track!(wait_until(&handler.invoked))?;
+        assert_eq!(metrics.in_flight(), 1);
+
+        let response = EchoRpc::client(&service_handle).call(server_addr, b"foo".to_vec());
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Overloaded);
+
+        track!(wait_until(&handler.handled))?;
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(metrics.in_flight(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn max_connections_per_ip_works() -> TestResult {
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.max_connections_per_ip(1);
+        let server = builder.finish(fibers_global::handle());
+        let metrics = server.metrics().clone();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // The first client is accepted
+        let service0 = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service0_handle = service0.handle();
+        fibers_global::spawn(service0.map_err(|e| panic!("{}", e)));
+
+        let response = EchoRpc::client(&service0_handle).call(server_addr, b"foo".to_vec());
+        track!(fibers_global::execute(response))?;
+        assert_eq!(metrics.refused_connections(), 0);
+
+        // The second client (from the same IP) is refused
+        let service1 = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service1_handle = service1.handle();
+        fibers_global::spawn(service1.map_err(|e| panic!("{}", e)));
+
+        let response = EchoRpc::client(&service1_handle).call(server_addr, b"foo".to_vec());
+        assert!(fibers_global::execute(response).is_err());
+        assert!(metrics.refused_connections() >= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reconnect_policy_works() -> TestResult {
+        // Reserves a port on which no server is listening
+        let server_addr = {
+            let listener = track_any_err!(std::net::TcpListener::bind("127.0.0.1:0"))?;
+            track_any_err!(listener.local_addr())?
+        };
+
+        // Client
+        let service = ClientServiceBuilder::new()
+            .reconnect_policy(ReconnectPolicy {
+                base_delay: Duration::from_millis(10),
+                max_attempts: Some(3),
+                ..Default::default()
+            })
+            .finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let response = EchoRpc::client(&service_handle).call(server_addr, Vec::from(&b"hello"[..]));
+        let e = fibers_global::execute(response).err().unwrap();
+        assert_eq!(
+            *e.kind(),
+            ErrorKind::ConnectionLost {
+                request_sent: false
+            }
+        );
+
+        // The channel gives up reconnecting after three attempts
+        for _ in 0..100 {
+            if service_handle.metrics().reconnect_give_ups() != 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(service_handle.metrics().reconnect_give_ups(), 1);
+        assert_eq!(service_handle.metrics().reconnects(), 3);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_domain_socket_works() -> TestResult {
+        let path = std::env::temp_dir().join(format!("fibers_rpc_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // Server
+        let mut builder = ServerBuilder::with_transport_addr(path.clone());
+        builder.add_call_handler(EchoHandler);
+        let server = builder.finish(fibers_global::handle());
+        let server_handle = server.handle();
+        let (server, server_addr) = track!(fibers_global::execute(server.local_transport_addr()))?;
+        assert_eq!(server_addr, TransportAddr::Unix(path.clone()));
+        let server = fibers_global::spawn_monitor(server);
+
+        // Client
+        let service = ClientServiceBuilder::new().finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(&server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        let request = vec![1; 10 * 1024 * 1024];
+        let response = EchoRpc::client(&service_handle).call(&server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        // The socket file is removed when the server stops
+        server_handle.shutdown(Duration::from_secs(10));
+        assert!(fibers_global::execute(server).is_ok());
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_testdata(name: &str) -> std::path::PathBuf {
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_works() -> TestResult {
+        use crate::tls::{ClientTlsOptions, ServerTlsOptions};
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        track!(builder.tls(ServerTlsOptions::new(
+            tls_testdata("server.pem"),
+            tls_testdata("server.key")
+        )))?;
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client
+        let mut builder = ClientServiceBuilder::new();
+        track!(builder.tls(ClientTlsOptions::new(tls_testdata("ca.pem"))))?;
+        let service = builder.finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        let request = vec![1; 10 * 1024 * 1024];
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_handshake_timeout_works() -> TestResult {
+        use crate::tls::ServerTlsOptions;
+        use std::io::Read;
+
+        // Server
+        let options = ChannelOptions {
+            tcp_connect_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        builder.channel_options(options);
+        track!(builder.tls(ServerTlsOptions::new(
+            tls_testdata("server.pem"),
+            tls_testdata("server.key")
+        )))?;
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // A client which never starts the handshake is disconnected
+        let mut stream = track_any_err!(std::net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        let mut buf = [0; 1];
+        assert_eq!(track_any_err!(stream.read(&mut buf))?, 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn mutual_tls_works() -> TestResult {
+        use crate::tls::{ClientTlsOptions, ServerTlsOptions};
+
+        // Server
+        let mut builder = ServerBuilder::new("127.0.0.1:0".parse().unwrap());
+        builder.add_call_handler(EchoHandler);
+        let mut options =
+            ServerTlsOptions::new(tls_testdata("server.pem"), tls_testdata("server.key"));
+        options.client_ca_path = Some(tls_testdata("ca.pem"));
+        track!(builder.tls(options))?;
+        let server = builder.finish(fibers_global::handle());
+        let (server, server_addr) = track!(fibers_global::execute(server.local_addr()))?;
+        fibers_global::spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Client (with a certificate)
+        let mut options = ClientTlsOptions::new(tls_testdata("ca.pem"));
+        options.server_name = Some("localhost".to_owned());
+        options.cert_and_key_paths = Some((tls_testdata("client.pem"), tls_testdata("client.key")));
+        let mut builder = ClientServiceBuilder::new();
+        track!(builder.tls(options))?;
+        let service = builder.finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let request = Vec::from(&b"hello"[..]);
+        let response = EchoRpc::client(&service_handle).call(server_addr, request.clone());
+        let response = track!(fibers_global::execute(response))?;
+        assert_eq!(response, request);
+
+        // Client (without certificates)
+        let mut builder = ClientServiceBuilder::new();
+        track!(builder.tls(ClientTlsOptions::new(tls_testdata("ca.pem"))))?;
+        let service = builder.finish(fibers_global::handle());
+        let service_handle = service.handle();
+        fibers_global::spawn(service.map_err(|e| panic!("{}", e)));
+
+        let mut client = EchoRpc::client(&service_handle);
+        client.options_mut().timeout = Some(Duration::from_secs(3));
+        let response = client.call(server_addr, request);
+        assert!(fibers_global::execute(response).is_err());
         Ok(())
     }
 }
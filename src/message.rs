@@ -1,5 +1,10 @@
-use crate::{ProcedureId, Result};
+use crate::compression::Compression;
+use crate::error_reply::{ErrorReply, ErrorReplyEncoder};
+use crate::flow_control::Credit;
+use crate::{Error, ProcedureId, Result};
+use bytecodec::bytes::BytesEncoder;
 use bytecodec::marker::Never;
+use bytecodec::padding::PaddingDecoder;
 use bytecodec::{self, ByteCount, Decode, Encode, EncodeExt, Eos};
 use byteorder::{BigEndian, ByteOrder};
 use std::fmt;
@@ -10,10 +15,20 @@ pub struct MessageHeader {
     pub procedure: ProcedureId,
     pub priority: u8,
     pub is_async: bool,
+    pub is_error: bool,
+    pub is_control: bool,
+    pub has_extension: bool,
+    pub compression: Compression,
+    pub is_stream_item: bool,
+    pub is_notification: bool,
 }
 impl MessageHeader {
     pub const SIZE: usize = 8 + 4 + 1;
 
+    pub fn is_compressed(&self) -> bool {
+        self.compression != Compression::None
+    }
+
     pub fn write(&self, buf: &mut [u8]) {
         BigEndian::write_u64(buf, self.id.0);
         BigEndian::write_u32(&mut buf[8..], self.procedure.0);
@@ -28,14 +43,51 @@ impl MessageHeader {
             id,
             procedure,
             priority,
-            is_async: false, // dummy
+            is_async: false,                // dummy
+            is_error: false,                // dummy
+            is_control: false,              // dummy
+            has_extension: false,           // dummy
+            compression: Compression::None, // dummy
+            is_stream_item: false,          // dummy
+            is_notification: false,         // dummy
         }
     }
 }
 
 pub trait AssignIncomingMessageHandler {
     type Handler: Decode + Send + 'static;
-    fn assign_incoming_message_handler(&mut self, header: &MessageHeader) -> Result<Self::Handler>;
+
+    /// Assigns a handler for the incoming message.
+    ///
+    /// The bytes of the message are not granted to the peer again until `credit` (and its clones) is dropped.
+    fn assign_incoming_message_handler(
+        &mut self,
+        header: &MessageHeader,
+        credit: Credit,
+    ) -> Result<Self::Handler>;
+
+    /// Returns the maximum byte size of the incoming message.
+    ///
+    /// `default` is the limit specified by `ChannelOptions::max_incoming_message_size`.
+    fn max_incoming_message_size(
+        &self,
+        _header: &MessageHeader,
+        default: Option<usize>,
+    ) -> Option<usize> {
+        default
+    }
+
+    /// Rejects the incoming message because it is too large or cannot be decoded.
+    ///
+    /// `handler` is the handler assigned to the message (if any).
+    /// This returns a handler that discards the rest of the message and
+    /// reports `error` to the peer or the application.
+    fn reject_incoming_message(
+        &mut self,
+        header: &MessageHeader,
+        handler: Option<Self::Handler>,
+        error: Error,
+    ) -> Self::Handler;
 }
 
 /// Message identifier.
@@ -46,7 +98,7 @@ pub trait AssignIncomingMessageHandler {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct MessageId(pub u64);
 impl MessageId {
-    pub fn next(&mut self) -> Self {
+    pub(crate) fn next(&mut self) -> Self {
         let n = self.0;
         self.0 += 1;
         MessageId(n)
@@ -58,6 +110,35 @@ pub struct OutgoingMessage {
     pub header: MessageHeader,
     pub payload: OutgoingMessagePayload,
 }
+impl OutgoingMessage {
+    /// Makes a message that replies `error` to the request identified by `header`.
+    pub fn error_reply(mut header: MessageHeader, error: ErrorReply) -> Self {
+        header.is_async = false;
+        header.is_error = true;
+        header.is_control = false;
+        header.has_extension = false;
+        header.compression = Compression::None;
+        header.is_stream_item = false;
+        OutgoingMessage {
+            header,
+            payload: OutgoingMessagePayload::with_item(ErrorReplyEncoder::default(), error),
+        }
+    }
+
+    /// Makes an empty message that ends the stream of the messages identified by `header`.
+    pub fn end_of_stream(mut header: MessageHeader) -> Self {
+        header.is_async = false;
+        header.is_error = false;
+        header.is_control = false;
+        header.has_extension = false;
+        header.compression = Compression::None;
+        header.is_stream_item = false;
+        OutgoingMessage {
+            header,
+            payload: OutgoingMessagePayload::with_item(BytesEncoder::new(), Vec::new()),
+        }
+    }
+}
 
 pub struct OutgoingMessagePayload(Box<dyn Encode<Item = Never> + Send + 'static>);
 impl OutgoingMessagePayload {
@@ -101,6 +182,64 @@ impl Encode for OutgoingMessagePayload {
     }
 }
 
+/// Decoder that isolates decoding errors of a message.
+///
+/// If the inner decoder fails, the error is kept until `finish_decoding` is called,
+/// and the remaining bytes of the message are discarded.
+/// This prevents a malformed message from breaking the other messages sharing the same channel.
+pub struct IsolatedDecoder<D> {
+    inner: D,
+    error: Option<bytecodec::Error>,
+    padding: PaddingDecoder,
+}
+impl<D: Decode> IsolatedDecoder<D> {
+    pub fn new(inner: D) -> Self {
+        IsolatedDecoder {
+            inner,
+            error: None,
+            padding: PaddingDecoder::new(None),
+        }
+    }
+}
+impl<D: Decode> Decode for IsolatedDecoder<D> {
+    type Item = D::Item;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        if self.error.is_none() {
+            match self.inner.decode(buf, eos) {
+                Ok(size) => return Ok(size),
+                Err(e) => self.error = Some(e),
+            }
+        }
+        track!(self.padding.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        if let Some(e) = self.error.take() {
+            track!(self.padding.finish_decoding())?;
+            Err(track!(e))
+        } else {
+            track!(self.inner.finish_decoding())
+        }
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.error.is_some() {
+            self.padding.requiring_bytes()
+        } else {
+            self.inner.requiring_bytes()
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        if self.error.is_some() {
+            self.padding.is_idle()
+        } else {
+            self.inner.is_idle()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
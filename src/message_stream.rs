@@ -1,73 +1,112 @@
 use crate::channel::ChannelOptions;
+use crate::compression::{self, Compression};
+use crate::control::{ControlMessage, ControlMessageDecoder};
+use crate::flow_control::{FlowControl, MessageCredits};
+use crate::handshake::{Capabilities, Hello, HelloDecoder};
 use crate::message::{
-    AssignIncomingMessageHandler, MessageId, OutgoingMessage, OutgoingMessagePayload,
+    AssignIncomingMessageHandler, MessageHeader, MessageId, OutgoingMessage, OutgoingMessagePayload,
 };
 use crate::metrics::ChannelMetrics;
-use crate::packet::{PacketHeaderDecoder, PacketizedMessage, MIN_PACKET_LEN};
+use crate::packet::{PacketHeader, PacketHeaderDecoder, PacketizedMessage, MIN_PACKET_LEN};
+use crate::transport::BoxTransport;
 use crate::{Error, ErrorKind, Result};
 use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
 use bytecodec::combinator::{MaybeEos, Peekable, Slice};
 use bytecodec::io::{IoDecodeExt, IoEncodeExt, ReadBuf, WriteBuf};
 use bytecodec::{Decode, DecodeExt, Encode, EncodeExt, Eos};
-use fibers::net::TcpStream;
 use fibers::sync::mpsc;
 use fibers::time::timer::{self, Timeout};
 use fibers_tasque::DefaultCpuTaskQueue;
 use futures::{Async, Future, Poll, Stream};
 use std::cmp;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::io::Write;
+use std::mem;
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
 
 pub struct MessageStream<A: AssignIncomingMessageHandler> {
-    transport_stream: TcpStream,
+    transport_stream: BoxTransport,
     rbuf: ReadBuf<Vec<u8>>,
     wbuf: WriteBuf<Vec<u8>>,
     assigner: A,
+    hello_decoder: MaybeEos<HelloDecoder>,
+    agreed: Option<Hello>,
     packet_header_decoder: Peekable<MaybeEos<PacketHeaderDecoder>>,
     receiving_messages: HashMap<MessageId, Slice<A::Handler>>,
+    receiving_controls: HashMap<MessageId, Slice<ControlMessageDecoder>>,
+    next_control_message_id: MessageId,
     sending_messages: BinaryHeap<SendingMessage>,
+    async_outgoing_ids: HashSet<MessageId>,
+    handshake_waiting_messages: Vec<OutgoingMessage>,
+    queued_messages: HashMap<MessageId, VecDeque<OutgoingMessage>>,
     async_outgoing_tx: mpsc::Sender<Result<OutgoingMessage>>,
     async_outgoing_rx: mpsc::Receiver<Result<OutgoingMessage>>,
-    async_incoming_tx: mpsc::Sender<Result<<A::Handler as Decode>::Item>>,
-    async_incoming_rx: mpsc::Receiver<Result<<A::Handler as Decode>::Item>>,
+    async_incoming_tx: mpsc::Sender<AsyncIncomingResult<A::Handler>>,
+    async_incoming_rx: mpsc::Receiver<AsyncIncomingResult<A::Handler>>,
     async_incomings: HashMap<MessageId, Slice<RemainingBytesDecoder>>,
+    receiving_credits: HashMap<MessageId, MessageCredits>,
+    incoming_message_sizes: HashMap<MessageId, usize>,
+    rejected_messages: HashSet<MessageId>,
+    flow_control: FlowControl,
     seqno: u64,
     options: ChannelOptions,
     metrics: ChannelMetrics,
     write_timeout: Option<Timeout>,
     is_written: bool,
+    heartbeat: Heartbeat,
 }
 impl<A: AssignIncomingMessageHandler> MessageStream<A>
 where
     <A::Handler as Decode>::Item: Send + 'static,
 {
     pub fn new(
-        transport_stream: TcpStream,
+        transport_stream: BoxTransport,
         assigner: A,
         options: ChannelOptions,
         metrics: ChannelMetrics,
     ) -> Self {
-        let _ = transport_stream.set_nodelay(true);
         let (async_outgoing_tx, async_outgoing_rx) = mpsc::channel();
         let (async_incoming_tx, async_incoming_rx) = mpsc::channel();
+
+        // The hello message is always sent before any packets
+        // (the buffer can hold it because `MIN_PACKET_LEN` is greater than `Hello::SIZE`)
+        let write_buffer_size = cmp::max(options.write_buffer_size, MIN_PACKET_LEN + 1);
+        let mut wbuf = WriteBuf::new(vec![0; write_buffer_size]);
+        wbuf.write_all(&Hello::local().to_bytes())
+            .expect("Write buffer is too small");
         MessageStream {
             transport_stream,
             rbuf: ReadBuf::new(vec![0; options.read_buffer_size]),
-            wbuf: WriteBuf::new(vec![0; options.write_buffer_size]),
+            wbuf,
             sending_messages: BinaryHeap::new(),
+            async_outgoing_ids: HashSet::new(),
+            handshake_waiting_messages: Vec::new(),
+            queued_messages: HashMap::new(),
             async_outgoing_tx,
             async_outgoing_rx,
             async_incoming_tx,
             async_incoming_rx,
             async_incomings: HashMap::new(),
+            receiving_credits: HashMap::new(),
+            incoming_message_sizes: HashMap::new(),
+            rejected_messages: HashSet::new(),
+            flow_control: FlowControl::new(&options),
             assigner,
+            hello_decoder: HelloDecoder::default().maybe_eos(),
+            agreed: None,
             packet_header_decoder: PacketHeaderDecoder::default().maybe_eos().peekable(),
             receiving_messages: HashMap::new(),
+            receiving_controls: HashMap::new(),
+            next_control_message_id: MessageId(0),
             seqno: 0,
             options,
             metrics,
             write_timeout: None,
             is_written: false,
+            heartbeat: Heartbeat::default(),
         }
     }
 
@@ -79,16 +118,79 @@ where
         &self.metrics
     }
 
-    pub fn send_message(&mut self, mut message: OutgoingMessage) {
-        if message.header.is_async {
-            self.metrics.async_outgoing_messages.increment();
+    /// Returns the capabilities agreed with the peer.
+    ///
+    /// This is empty until the handshake is completed.
+    pub fn capabilities(&self) -> Capabilities {
+        self.agreed
+            .as_ref()
+            .map_or_else(Capabilities::default, |h| h.capabilities)
+    }
+
+    /// Sends the message.
+    ///
+    /// If another message with the same identifier is being sent (e.g., the previous item of a stream),
+    /// the message waits for it to be completely sent, so that the packets of the messages are never interleaved.
+    pub fn send_message(&mut self, message: OutgoingMessage) {
+        match self.queued_messages.entry(message.header.id) {
+            Entry::Occupied(mut e) => {
+                e.get_mut().push_back(message);
+                return;
+            }
+            Entry::Vacant(e) => {
+                e.insert(VecDeque::new());
+            }
+        }
+        self.start_message(message);
+    }
+
+    fn start_next_queued_message(&mut self, message_id: MessageId) {
+        let next = match self.queued_messages.get_mut(&message_id) {
+            None => return,
+            Some(queue) => queue.pop_front(),
+        };
+        if let Some(message) = next {
+            self.start_message(message);
+        } else {
+            self.queued_messages.remove(&message_id);
+        }
+    }
+
+    fn start_message(&mut self, mut message: OutgoingMessage) {
+        if message.header.is_compressed() {
+            if self.agreed.is_none() {
+                // Whether the peer supports the compression algorithm is unknown yet
+                self.async_outgoing_ids.insert(message.header.id);
+                self.handshake_waiting_messages.push(message);
+                return;
+            }
+            if !self
+                .capabilities()
+                .contains(message.header.compression.capability())
+            {
+                message.header.compression = Compression::None;
+            }
+        }
+
+        if message.header.is_async || message.header.is_compressed() {
+            if message.header.is_async {
+                self.metrics.async_outgoing_messages.increment();
+            }
+            self.async_outgoing_ids.insert(message.header.id);
             let tx = self.async_outgoing_tx.clone();
+            let uncompressed_bytes = self.metrics.uncompressed_outgoing_bytes.clone();
+            let compressed_bytes = self.metrics.compressed_outgoing_bytes.clone();
             DefaultCpuTaskQueue.with(|tasque| {
                 tasque.enqueue(move || {
                     let f = || {
                         let header = message.header;
                         let mut payload = Vec::new();
                         track!(message.payload.encode_all(&mut payload))?;
+                        if header.is_compressed() {
+                            uncompressed_bytes.add_u64(payload.len() as u64);
+                            payload = track!(compression::compress(header.compression, &payload))?;
+                            compressed_bytes.add_u64(payload.len() as u64);
+                        }
                         let payload =
                             OutgoingMessagePayload::new(BytesEncoder::new().last(payload));
                         Ok(OutgoingMessage { header, payload })
@@ -101,13 +203,130 @@ where
         }
     }
 
+    pub fn assigner(&self) -> &A {
+        &self.assigner
+    }
+
     pub fn assigner_mut(&mut self) -> &mut A {
         &mut self.assigner
     }
 
+    /// Returns the handlers of the messages being received.
+    pub fn receiving_handlers_mut(&mut self) -> impl Iterator<Item = &mut A::Handler> {
+        self.receiving_messages.values_mut().map(|h| h.inner_mut())
+    }
+
+    /// Cancels sending the message.
+    ///
+    /// If the peer may have received (a part of) the message,
+    /// a cancellation request is also sent to the peer.
+    pub fn cancel_message(&mut self, message_id: MessageId) {
+        self.cancel_outgoing_message(message_id, true);
+    }
+
+    fn cancel_outgoing_message(&mut self, message_id: MessageId, notify_if_sent: bool) {
+        self.queued_messages.remove(&message_id);
+        self.flow_control.finish_sending(message_id);
+        if self.async_outgoing_ids.remove(&message_id) {
+            // The message is being encoded and will be discarded when the encoding is completed
+            return;
+        }
+        let notify = match self.remove_sending_message(message_id) {
+            Some(is_started) => is_started,
+            None => notify_if_sent,
+        };
+        if notify {
+            self.send_control_message(ControlMessage::Cancel { message_id });
+        }
+    }
+
+    /// Returns `true` if there are messages that have not been completely sent yet.
+    pub fn is_sending(&self) -> bool {
+        !(self.sending_messages.is_empty()
+            && self.async_outgoing_ids.is_empty()
+            && self.handshake_waiting_messages.is_empty()
+            && self.wbuf.is_empty())
+    }
+
+    pub fn send_control_message(&mut self, message: ControlMessage) {
+        let required = match message {
+            ControlMessage::Cancel { .. } => Capabilities::CANCEL,
+            ControlMessage::GoAway => Capabilities::GO_AWAY,
+            ControlMessage::Ping { .. } | ControlMessage::Pong { .. } => Capabilities::HEARTBEAT,
+            ControlMessage::WindowUpdate { .. } => Capabilities::FLOW_CONTROL,
+        };
+        if self.agreed.is_some() && !self.capabilities().contains(required) {
+            // The peer cannot handle the message
+            return;
+        }
+        let message = message.into_outgoing_message(self.next_control_message_id.next());
+        self.start_sending_message(message);
+    }
+
+    /// Removes the message from the transmit queue.
+    ///
+    /// If the message is found, it returns `Some(true)` if a part of the message has been sent,
+    /// otherwise `Some(false)`.
+    fn remove_sending_message(&mut self, message_id: MessageId) -> Option<bool> {
+        let mut removed = None;
+        self.sending_messages.retain(|m| {
+            let header = m.message.header();
+            if header.id == message_id && !header.is_control {
+                removed = Some(m.is_started);
+                false
+            } else {
+                true
+            }
+        });
+        if removed.is_some() {
+            self.metrics.dequeued_outgoing_messages.increment();
+        }
+        removed
+    }
+
+    fn handle_control_message(
+        &mut self,
+        message: ControlMessage,
+    ) -> Option<MessageEvent<<A::Handler as Decode>::Item>> {
+        match message {
+            ControlMessage::Cancel { message_id } => {
+                self.receiving_messages.remove(&message_id);
+                self.async_incomings.remove(&message_id);
+                self.receiving_credits.remove(&message_id);
+                self.incoming_message_sizes.remove(&message_id);
+                self.rejected_messages.remove(&message_id);
+                self.flow_control.cancel_receiving(message_id);
+                self.cancel_outgoing_message(message_id, false);
+                Some(MessageEvent::Cancelled { message_id })
+            }
+            ControlMessage::GoAway => Some(MessageEvent::GoAway),
+            ControlMessage::Ping { nonce } => {
+                self.send_control_message(ControlMessage::Pong { nonce });
+                None
+            }
+            ControlMessage::Pong { nonce } => {
+                if let Some(rtt) = self.heartbeat.handle_pong(nonce) {
+                    self.metrics
+                        .heartbeat_rtt_seconds
+                        .set(rtt.as_secs_f64().max(f64::MIN_POSITIVE));
+                }
+                None
+            }
+            ControlMessage::WindowUpdate {
+                message_id,
+                increment,
+            } => {
+                self.flow_control
+                    .handle_window_update(message_id, increment);
+                None
+            }
+        }
+    }
+
     fn start_sending_message(&mut self, message: OutgoingMessage) {
         let message = SendingMessage {
             seqno: self.seqno,
+            is_started: false,
             message: PacketizedMessage::new(message),
         };
         self.seqno += 1;
@@ -118,24 +337,70 @@ where
     fn handle_outgoing_messages(
         &mut self,
     ) -> Result<Option<MessageEvent<<A::Handler as Decode>::Item>>> {
+        let mut blocked = Vec::new();
+        let result = self.handle_outgoing_messages_with_flow_control(&mut blocked);
+        self.sending_messages.extend(blocked);
+        result
+    }
+
+    /// Writes the packets of the outgoing messages.
+    ///
+    /// Messages which cannot be started due to the flow control are moved to `blocked`.
+    fn handle_outgoing_messages_with_flow_control(
+        &mut self,
+        blocked: &mut Vec<SendingMessage>,
+    ) -> Result<Option<MessageEvent<<A::Handler as Decode>::Item>>> {
+        let is_flow_controlled = self.capabilities().contains(Capabilities::FLOW_CONTROL);
         while !(self.sending_messages.is_empty() && self.wbuf.is_empty()) {
-            if self.wbuf.room() >= MIN_PACKET_LEN {
+            // A packet needs room for at least one byte of the payload
+            if self.wbuf.room() > MIN_PACKET_LEN && self.agreed.is_some() {
                 if let Some(mut sending) = self.sending_messages.pop() {
+                    if is_flow_controlled
+                        && !sending.is_started
+                        && !self.flow_control.can_start(sending.message.header())
+                    {
+                        blocked.push(sending);
+                        continue;
+                    }
+
+                    let old_len = self.wbuf.len();
                     track!(sending.message.encode_to_write_buf(&mut self.wbuf))?;
+                    if is_flow_controlled {
+                        let payload_len = self.wbuf.len() - old_len - PacketHeader::SIZE;
+                        self.flow_control
+                            .consume(sending.message.header(), payload_len as u64);
+                    }
                     if sending.message.is_idle() {
                         // Completed to write the message to the sending buffer
-                        let event = MessageEvent::Sent;
+                        let header = sending.message.header();
+                        let message_id = if header.is_control {
+                            None
+                        } else {
+                            Some(header.id)
+                        };
                         self.metrics.dequeued_outgoing_messages.increment();
-                        return Ok(Some(event));
+                        if let Some(message_id) = message_id {
+                            if !header.is_stream_item {
+                                // The end of the stream (if any)
+                                self.flow_control.finish_sending(message_id);
+                            }
+                            self.start_next_queued_message(message_id);
+                        }
+                        return Ok(Some(MessageEvent::Sent { message_id }));
                     } else {
                         // A part of the message was written to the sending buffer
                         sending.seqno = self.seqno;
+                        sending.is_started = true;
                         self.seqno += 1;
                         self.sending_messages.push(sending);
                     }
                     continue;
                 }
             }
+            if self.wbuf.is_empty() {
+                // The packets are held until the handshake is completed
+                break;
+            }
 
             let old_len = self.wbuf.len();
             track!(self.wbuf.flush(&mut self.transport_stream))?;
@@ -154,24 +419,83 @@ where
         &mut self,
     ) -> Result<Option<MessageEvent<<A::Handler as Decode>::Item>>> {
         loop {
+            let old_len = self.rbuf.len();
             track!(self.rbuf.fill(&mut self.transport_stream))?;
+            if self.rbuf.len() > old_len {
+                self.heartbeat.is_read = true;
+            }
+
+            if self.agreed.is_none() {
+                track!(self.hello_decoder.decode_from_read_buf(&mut self.rbuf))?;
+                if self.hello_decoder.is_idle() {
+                    let result = track!(self.hello_decoder.finish_decoding())
+                        .map_err(Error::from)
+                        .and_then(|peer| track!(Hello::local().negotiate(&peer)));
+                    let agreed = match result {
+                        Err(e) => {
+                            // Tries to send our hello so that the peer can also detect the incompatibility
+                            let _ = self.wbuf.flush(&mut self.transport_stream);
+                            return Err(e);
+                        }
+                        Ok(agreed) => agreed,
+                    };
+                    self.agreed = Some(agreed.clone());
+                    if let Some(update) = self.flow_control.initial_window_update() {
+                        self.send_control_message(update);
+                    }
+                    for message in mem::take(&mut self.handshake_waiting_messages) {
+                        if self.async_outgoing_ids.remove(&message.header.id) {
+                            self.start_message(message);
+                        }
+                    }
+                    return Ok(Some(MessageEvent::Handshaked { agreed }));
+                }
+                if self.rbuf.is_empty() && !self.rbuf.stream_state().is_normal() {
+                    break;
+                }
+                continue;
+            }
 
             if !self.packet_header_decoder.is_idle() {
                 track!(self
                     .packet_header_decoder
                     .decode_from_read_buf(&mut self.rbuf))?;
                 if let Some(header) = self.packet_header_decoder.peek().cloned() {
-                    if header.is_async() {
+                    if !header.is_control() {
+                        if let Some(update) = self.flow_control.start_receiving(&header.message) {
+                            self.send_control_message(update);
+                        }
+                        if self.capabilities().contains(Capabilities::FLOW_CONTROL) {
+                            let is_first_packet =
+                                !self.receiving_credits.contains_key(&header.message.id);
+                            track!(self.flow_control.receive(
+                                &header.message,
+                                u64::from(header.payload_len),
+                                is_first_packet
+                            ))?;
+                        }
+                        let flow_control = &self.flow_control;
+                        self.receiving_credits
+                            .entry(header.message.id)
+                            .or_insert_with(|| flow_control.credits(header.message.id))
+                            .add(u64::from(header.payload_len));
+                        self.check_incoming_message_size(&header);
+                    }
+                    if header.is_control() {
                         let decoder = self
-                            .async_incomings
+                            .receiving_controls
                             .entry(header.message.id)
-                            .or_insert_with(Default::default);
+                            .or_insert_with(|| ControlMessageDecoder::default().slice());
+                        decoder.set_consumable_bytes(u64::from(header.payload_len));
+                    } else if self.is_buffered(&header) {
+                        let decoder = self.async_incomings.entry(header.message.id).or_default();
                         decoder.set_consumable_bytes(u64::from(header.payload_len));
                     } else {
                         if !self.receiving_messages.contains_key(&header.message.id) {
+                            let credit = self.receiving_credits[&header.message.id].stream.clone();
                             let handler = track!(self
                                 .assigner
-                                .assign_incoming_message_handler(&header.message))?;
+                                .assign_incoming_message_handler(&header.message, credit))?;
                             self.receiving_messages
                                 .insert(header.message.id, handler.slice());
                         }
@@ -185,7 +509,27 @@ where
             }
 
             if let Some(header) = self.packet_header_decoder.peek().cloned() {
-                if header.is_async() {
+                if header.is_control() {
+                    let mut decoder = self
+                        .receiving_controls
+                        .remove(&header.message.id)
+                        .expect("Never fails");
+
+                    track!(decoder.decode_from_read_buf(&mut self.rbuf))?;
+                    if decoder.is_suspended() {
+                        let _ = track!(self.packet_header_decoder.finish_decoding())?;
+                    }
+                    if decoder.is_suspended() && header.is_end_of_message() {
+                        track!(decoder.decode(&[][..], Eos::new(true)))?;
+                        let message = track!(decoder.finish_decoding())?;
+                        if let Some(event) = self.handle_control_message(message) {
+                            return Ok(Some(event));
+                        }
+                    } else {
+                        self.receiving_controls.insert(header.message.id, decoder);
+                    }
+                } else if self.is_buffered(&header) {
+                    // Compressed messages are also decoded asynchronously (after being decompressed)
                     let mut decoder = self
                         .async_incomings
                         .remove(&header.message.id)
@@ -200,21 +544,61 @@ where
                     if decoder.is_suspended() && header.is_end_of_message() {
                         track!(decoder.decode(&[][..], Eos::new(true)))?;
                         let buf = track!(decoder.finish_decoding())?;
-                        self.metrics.async_incoming_messages.increment();
+                        if header.is_async() {
+                            self.metrics.async_incoming_messages.increment();
+                        }
 
                         let tx = self.async_incoming_tx.clone();
-                        let mut handler = track!(self
-                            .assigner
-                            .assign_incoming_message_handler(&header.message))?;
+                        let credits = self
+                            .receiving_credits
+                            .remove(&header.message.id)
+                            .expect("Never fails");
+                        let mut handler = track!(self.assigner.assign_incoming_message_handler(
+                            &header.message,
+                            credits.stream.clone()
+                        ))?;
+                        let message_header = header.message.clone();
+                        let is_compressed = header.is_compressed();
+                        let limit = self.assigner.max_incoming_message_size(
+                            &header.message,
+                            self.options.max_incoming_message_size,
+                        );
+                        let compressed_bytes = self.metrics.compressed_incoming_bytes.clone();
+                        let uncompressed_bytes = self.metrics.uncompressed_incoming_bytes.clone();
                         DefaultCpuTaskQueue.with(|tasque| {
                             tasque.enqueue(move || {
-                                let mut f = || {
-                                    let size = track!(handler.decode(&buf[..], Eos::new(true)))?;
-                                    track_assert_eq!(size, buf.len(), ErrorKind::Other);
-                                    let action = track!(handler.finish_decoding())?;
+                                let f = || -> Result<_> {
+                                    let buf = if is_compressed {
+                                        compressed_bytes.add_u64(buf.len() as u64);
+                                        let buf = track!(
+                                            compression::decompress(&buf, limit);
+                                            message_header.procedure
+                                        )?;
+                                        uncompressed_bytes.add_u64(buf.len() as u64);
+                                        buf
+                                    } else {
+                                        buf
+                                    };
+                                    let size = track!(handler
+                                        .decode(&buf[..], Eos::new(true))
+                                        .map_err(|e| ErrorKind::DecodeFailed.takes_over(e)))?;
+                                    track_assert_eq!(size, buf.len(), ErrorKind::DecodeFailed);
+                                    let action = track!(handler
+                                        .finish_decoding()
+                                        .map_err(|e| ErrorKind::DecodeFailed.takes_over(e)))?;
                                     Ok(action)
                                 };
-                                let _ = tx.send(f());
+
+                                // A failure only affects the message itself
+                                let result = f().map_err(|error| FailedMessage {
+                                    header: message_header,
+                                    handler,
+                                    error,
+                                });
+                                let _ = tx.send(result);
+
+                                // The bytes are released after the message is decoded
+                                drop(credits);
                             })
                         })
                     } else {
@@ -237,6 +621,7 @@ where
                         let next_action = track!(handler.finish_decoding(); header)?;
                         track_assert_eq!(handler.consumable_bytes(), 0, ErrorKind::Other; header);
                         track_assert!(header.is_end_of_message(), ErrorKind::Other; header);
+                        self.receiving_credits.remove(&header.message.id);
 
                         let event = MessageEvent::Received { next_action };
                         return Ok(Some(event));
@@ -253,6 +638,86 @@ where
         Ok(None)
     }
 
+    /// Returns `true` if the payload of the packet is buffered until the end of the message,
+    /// so that it is decoded asynchronously (after being decompressed if needed).
+    ///
+    /// Rejected messages are always discarded synchronously.
+    fn is_buffered(&self, header: &PacketHeader) -> bool {
+        (header.is_async() || header.is_compressed())
+            && !self.receiving_messages.contains_key(&header.message.id)
+    }
+
+    /// Rejects the incoming message if its size exceeds the limit.
+    ///
+    /// The rest of a rejected message is discarded by the handler assigned by `A::reject_incoming_message`.
+    fn check_incoming_message_size(&mut self, header: &PacketHeader) {
+        let message_id = header.message.id;
+        let size = self.incoming_message_sizes.entry(message_id).or_insert(0);
+        *size += header.payload_len as usize;
+        let size = *size;
+        let is_rejected = if header.is_end_of_message() {
+            self.incoming_message_sizes.remove(&message_id);
+            self.rejected_messages.remove(&message_id)
+        } else {
+            self.rejected_messages.contains(&message_id)
+        };
+        if is_rejected {
+            return;
+        }
+
+        let limit = self
+            .assigner
+            .max_incoming_message_size(&header.message, self.options.max_incoming_message_size);
+        let limit = match limit {
+            Some(limit) if size > limit => limit,
+            _ => return,
+        };
+        if !header.is_end_of_message() {
+            self.rejected_messages.insert(message_id);
+        }
+        self.metrics.rejected_incoming_messages.increment();
+
+        // Discards the buffered (or partially decoded) payload
+        self.async_incomings.remove(&message_id);
+        let handler = self
+            .receiving_messages
+            .remove(&message_id)
+            .map(Slice::into_inner);
+        let e = ErrorKind::InvalidInput.cause(format!(
+            "Too large message: procedure={:?}, limit={} bytes",
+            header.message.procedure, limit
+        ));
+        let handler =
+            self.assigner
+                .reject_incoming_message(&header.message, handler, track!(e).into());
+        self.receiving_messages.insert(message_id, handler.slice());
+    }
+
+    /// Handles the failure of an asynchronously decoded (or decompressed) message.
+    ///
+    /// The error is reported to the peer or the application via `A::reject_incoming_message`,
+    /// instead of aborting the entire channel.
+    fn handle_failed_message(
+        &mut self,
+        failed: FailedMessage<A::Handler>,
+    ) -> Result<<A::Handler as Decode>::Item> {
+        let FailedMessage {
+            header,
+            handler,
+            error,
+        } = failed;
+        if *error.kind() == ErrorKind::InvalidInput {
+            // e.g., The decompressed payload exceeds the size limit
+            self.metrics.rejected_incoming_messages.increment();
+        }
+        let mut handler = self
+            .assigner
+            .reject_incoming_message(&header, Some(handler), error);
+        track!(handler.decode(&[][..], Eos::new(true)); header)?;
+        let next_action = track!(handler.finish_decoding(); header)?;
+        Ok(next_action)
+    }
+
     fn check_write_timeout(&mut self) -> Result<()> {
         loop {
             if self.write_timeout.is_none() && !self.wbuf.is_empty() {
@@ -264,7 +729,7 @@ where
                 track_assert!(
                     self.is_written,
                     ErrorKind::Timeout,
-                    "TCP socket buffer (send) is full for {:?}",
+                    "Socket buffer (send) is full for {:?}",
                     self.options.tcp_write_timeout
                 );
                 continue;
@@ -273,6 +738,42 @@ where
         }
         Ok(())
     }
+
+    fn check_heartbeat(&mut self) -> Result<()> {
+        let interval = match self.options.heartbeat_interval {
+            Some(interval) if self.capabilities().contains(Capabilities::HEARTBEAT) => interval,
+            _ => return Ok(()),
+        };
+        loop {
+            if self.heartbeat.timeout.is_none() {
+                self.heartbeat.timeout = Some(timer::timeout(interval));
+                self.heartbeat.is_read = false;
+            }
+            if let Ok(Async::Ready(Some(()))) = self.heartbeat.timeout.poll() {
+                self.heartbeat.timeout = None;
+                if self.heartbeat.is_read {
+                    // The peer is alive (and the channel is not idle)
+                    self.heartbeat.missed = 0;
+                    self.heartbeat.ping = None;
+                    continue;
+                }
+                if self.heartbeat.ping.is_some() {
+                    self.heartbeat.missed += 1;
+                    track_assert!(
+                        self.heartbeat.missed < self.options.heartbeat_miss_threshold,
+                        ErrorKind::Timeout,
+                        "The peer did not answer {} heartbeat pings",
+                        self.heartbeat.missed
+                    );
+                }
+                let nonce = self.heartbeat.next_ping();
+                self.send_control_message(ControlMessage::Ping { nonce });
+                continue;
+            }
+            break;
+        }
+        Ok(())
+    }
 }
 impl<A: AssignIncomingMessageHandler> Stream for MessageStream<A>
 where
@@ -283,13 +784,23 @@ where
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         track!(self.check_write_timeout())?;
+        track!(self.check_heartbeat())?;
+        for update in self.flow_control.poll_window_updates() {
+            self.send_control_message(update);
+        }
 
         while let Async::Ready(Some(message)) = self.async_outgoing_rx.poll().expect("Never fails")
         {
-            self.start_sending_message(track!(message)?);
+            let message = track!(message)?;
+            if self.async_outgoing_ids.remove(&message.header.id) {
+                self.start_sending_message(message);
+            }
         }
         if let Async::Ready(Some(next)) = self.async_incoming_rx.poll().expect("Never fails") {
-            let next_action = track!(next)?;
+            let next_action = match next {
+                Ok(next_action) => next_action,
+                Err(failed) => track!(self.handle_failed_message(failed))?,
+            };
             let event = MessageEvent::Received { next_action };
             return Ok(Async::Ready(Some(event)));
         }
@@ -322,13 +833,68 @@ impl<A: AssignIncomingMessageHandler> fmt::Debug for MessageStream<A> {
 
 #[derive(Debug)]
 pub enum MessageEvent<T> {
-    Sent,
-    Received { next_action: T },
+    Handshaked {
+        agreed: Hello,
+    },
+    /// A message has been written to the write buffer.
+    ///
+    /// `message_id` is `None` if the message is a control message.
+    Sent {
+        message_id: Option<MessageId>,
+    },
+    Received {
+        next_action: T,
+    },
+    Cancelled {
+        message_id: MessageId,
+    },
+    GoAway,
+}
+
+/// The result of decoding a message asynchronously.
+type AsyncIncomingResult<H> = std::result::Result<<H as Decode>::Item, FailedMessage<H>>;
+
+/// A message which could not be decoded (or decompressed).
+struct FailedMessage<H> {
+    header: MessageHeader,
+    handler: H,
+    error: Error,
+}
+
+/// The state of heartbeat pings.
+#[derive(Debug, Default)]
+struct Heartbeat {
+    timeout: Option<Timeout>,
+    is_read: bool,
+    missed: usize,
+    next_nonce: u64,
+    ping: Option<(u64, Instant)>,
+}
+impl Heartbeat {
+    fn next_ping(&mut self) -> u64 {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.ping = Some((nonce, Instant::now()));
+        nonce
+    }
+
+    /// Returns the round-trip time if `nonce` is the one of the last ping.
+    fn handle_pong(&mut self, nonce: u64) -> Option<Duration> {
+        match self.ping {
+            Some((n, sent_at)) if n == nonce => {
+                self.ping = None;
+                self.missed = 0;
+                Some(sent_at.elapsed())
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 struct SendingMessage {
     seqno: u64,
+    is_started: bool,
     message: PacketizedMessage,
 }
 impl PartialEq for SendingMessage {
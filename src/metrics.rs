@@ -1,14 +1,15 @@
 //! [Prometheus][prometheus] metrics.
 //!
 //! [prometheus]: https://prometheus.io/
-use crate::ProcedureId;
+use crate::{ProcedureId, TransportAddr};
 use atomic_immut::AtomicImmut;
-use prometrics::metrics::{Counter, MetricBuilder};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 /// Client side metrics.
 #[derive(Debug, Clone)]
@@ -17,7 +18,14 @@ pub struct ClientMetrics {
     pub(crate) requests: Counter,
     pub(crate) ok_responses: Counter,
     pub(crate) error_responses: Counter,
+    pub(crate) unknown_responses: Counter,
     pub(crate) discarded_outgoing_messages: Counter,
+    pub(crate) retries: Counter,
+    pub(crate) reconnects: Counter,
+    pub(crate) reconnect_give_ups: Counter,
+    pub(crate) open_circuit_breakers: Gauge,
+    pub(crate) circuit_breaker_opened: Counter,
+    pub(crate) circuit_breaker_rejections: Counter,
     channels: ChannelsMetrics,
 }
 impl ClientMetrics {
@@ -41,11 +49,51 @@ impl ClientMetrics {
         self.error_responses.value() as u64
     }
 
+    /// Metric: `fibers_rpc_client_unknown_responses_total <COUNTER>`.
+    ///
+    /// This is the number of the response messages that no request was waiting for
+    /// (e.g., responses to timed out or cancelled requests).
+    pub fn unknown_responses(&self) -> u64 {
+        self.unknown_responses.value() as u64
+    }
+
     /// Metric: `fibers_rpc_client_discarded_outgoing_messages_total <COUNTER>`.
     pub fn discarded_outgoing_messages(&self) -> u64 {
         self.discarded_outgoing_messages.value() as u64
     }
 
+    /// Metric: `fibers_rpc_client_retries_total <COUNTER>`.
+    pub fn retries(&self) -> u64 {
+        self.retries.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_client_reconnects_total <COUNTER>`.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_client_reconnect_give_ups_total <COUNTER>`.
+    pub fn reconnect_give_ups(&self) -> u64 {
+        self.reconnect_give_ups.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_client_open_circuit_breakers <GAUGE>`.
+    ///
+    /// This is the number of the circuit breakers which are open or half-open.
+    pub fn open_circuit_breakers(&self) -> u64 {
+        self.open_circuit_breakers.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_client_circuit_breaker_opened_total <COUNTER>`.
+    pub fn circuit_breaker_opened(&self) -> u64 {
+        self.circuit_breaker_opened.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_client_circuit_breaker_rejected_requests_total <COUNTER>`.
+    pub fn circuit_breaker_rejections(&self) -> u64 {
+        self.circuit_breaker_rejections.value() as u64
+    }
+
     /// Returns the metrics of the channels associated with the client service.
     pub fn channels(&self) -> &ChannelsMetrics {
         &self.channels
@@ -78,11 +126,46 @@ impl ClientMetrics {
                 .label("result", "error")
                 .finish()
                 .expect("Never fails"),
+            unknown_responses: builder
+                .counter("unknown_responses_total")
+                .help("Number of discarded response messages that no request was waiting for")
+                .finish()
+                .expect("Never fails"),
             discarded_outgoing_messages: builder
                 .counter("discarded_outgoing_messages_total")
                 .help("Number of discarded messages before sending")
                 .finish()
                 .expect("Never fails"),
+            retries: builder
+                .counter("retries_total")
+                .help("Number of retried requests")
+                .finish()
+                .expect("Never fails"),
+            reconnects: builder
+                .counter("reconnects_total")
+                .help("Number of reconnection attempts of channels")
+                .finish()
+                .expect("Never fails"),
+            reconnect_give_ups: builder
+                .counter("reconnect_give_ups_total")
+                .help("Number of channels that gave up reconnecting")
+                .finish()
+                .expect("Never fails"),
+            open_circuit_breakers: builder
+                .gauge("open_circuit_breakers")
+                .help("Number of open (or half-open) circuit breakers")
+                .finish()
+                .expect("Never fails"),
+            circuit_breaker_opened: builder
+                .counter("circuit_breaker_opened_total")
+                .help("Number of times circuit breakers have been opened")
+                .finish()
+                .expect("Never fails"),
+            circuit_breaker_rejections: builder
+                .counter("circuit_breaker_rejected_requests_total")
+                .help("Number of requests rejected by open circuit breakers")
+                .finish()
+                .expect("Never fails"),
             channels: ChannelsMetrics::new(&builder, "client"),
         }
     }
@@ -93,8 +176,14 @@ impl ClientMetrics {
 pub struct ServerMetrics {
     channels: ChannelsMetrics,
     handlers: HashMap<ProcedureId, HandlerMetrics>,
+    pub(crate) refused_connections: Counter,
 }
 impl ServerMetrics {
+    /// Metric: `fibers_rpc_server_refused_connections_total <COUNTER>`.
+    pub fn refused_connections(&self) -> u64 {
+        self.refused_connections.value() as u64
+    }
+
     /// Returns the metrics of the channels associated with the server.
     pub fn channels(&self) -> &ChannelsMetrics {
         &self.channels
@@ -113,6 +202,11 @@ impl ServerMetrics {
         ServerMetrics {
             handlers,
             channels: ChannelsMetrics::new(&builder, "server"),
+            refused_connections: builder
+                .counter("refused_connections_total")
+                .help("Number of incoming connections refused due to connection limits")
+                .finish()
+                .expect("Never fails"),
         }
     }
 }
@@ -121,13 +215,37 @@ impl ServerMetrics {
 #[derive(Debug, Clone)]
 pub struct HandlerMetrics {
     pub(crate) rpc_count: Counter,
+    pub(crate) decode_errors: Counter,
+    pub(crate) in_flight: Gauge,
+    pub(crate) queued: Gauge,
+    pub(crate) overloaded: Counter,
 }
 impl HandlerMetrics {
-    /// Metric: `fibers_rpc_handler_rpc_tocal { type="call|cast", procedure="${ID}@${NAME}" } <COUNTER>`.
+    /// Metric: `fibers_rpc_handler_rpc_tocal { type="call|cast|stream_call|bidi_stream_call", procedure="${ID}@${NAME}" } <COUNTER>`.
     pub fn rpc_count(&self) -> u64 {
         self.rpc_count.value() as u64
     }
 
+    /// Metric: `fibers_rpc_handler_decode_errors_total { type="call|cast|stream_call|bidi_stream_call", procedure="${ID}@${NAME}" } <COUNTER>`.
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_handler_in_flight_requests { type="call|cast|stream_call|bidi_stream_call", procedure="${ID}@${NAME}" } <GAUGE>`.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_handler_queued_requests { type="call|cast|stream_call|bidi_stream_call", procedure="${ID}@${NAME}" } <GAUGE>`.
+    pub fn queued(&self) -> u64 {
+        self.queued.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_handler_overloaded_requests_total { type="call|cast|stream_call|bidi_stream_call", procedure="${ID}@${NAME}" } <COUNTER>`.
+    pub fn overloaded(&self) -> u64 {
+        self.overloaded.value() as u64
+    }
+
     pub(crate) fn new(
         mut builder: MetricBuilder,
         id: ProcedureId,
@@ -145,6 +263,34 @@ impl HandlerMetrics {
                 .label("type", rpc_type)
                 .finish()
                 .expect("Never fails"),
+            decode_errors: builder
+                .counter("decode_errors_total")
+                .help("Number of incoming messages that could not be decoded")
+                .label("procedure", &procedure)
+                .label("type", rpc_type)
+                .finish()
+                .expect("Never fails"),
+            in_flight: builder
+                .gauge("in_flight_requests")
+                .help("Number of requests being handled")
+                .label("procedure", &procedure)
+                .label("type", rpc_type)
+                .finish()
+                .expect("Never fails"),
+            queued: builder
+                .gauge("queued_requests")
+                .help("Number of requests waiting for being handled due to concurrency limits")
+                .label("procedure", &procedure)
+                .label("type", rpc_type)
+                .finish()
+                .expect("Never fails"),
+            overloaded: builder
+                .counter("overloaded_requests_total")
+                .help("Number of requests rejected due to concurrency limits")
+                .label("procedure", &procedure)
+                .label("type", rpc_type)
+                .finish()
+                .expect("Never fails"),
         }
     }
 }
@@ -153,6 +299,7 @@ impl HandlerMetrics {
 #[derive(Debug, Clone)]
 pub struct ChannelsMetrics {
     channels: Arc<AtomicImmut<HashMap<SocketAddr, ChannelMetrics>>>,
+    transport_channels: Arc<AtomicImmut<HashMap<TransportAddr, ChannelMetrics>>>,
     builder: Arc<Mutex<MetricBuilder>>,
     created_channels: Counter,
     removed_channels: Counter,
@@ -169,38 +316,110 @@ impl ChannelsMetrics {
         self.removed_channels.value() as u64
     }
 
-    /// Returns a reference to the internal address-to-metrics map.
+    /// Returns a reference to the internal address-to-metrics map of the TCP channels.
     pub fn as_map(&self) -> &Arc<AtomicImmut<HashMap<SocketAddr, ChannelMetrics>>> {
         &self.channels
     }
 
-    pub(crate) fn create_channel_metrics(&self, server: SocketAddr) -> ChannelMetrics {
-        if let Some(metrics) = self.channels.load().get(&server).cloned() {
+    /// Returns a reference to the internal address-to-metrics map of the channels of all the transports.
+    ///
+    /// Server side channels of Unix domain socket clients are not contained in the map,
+    /// because their addresses cannot distinguish the connections (i.e., unnamed sockets have empty paths).
+    pub fn as_transport_map(&self) -> &Arc<AtomicImmut<HashMap<TransportAddr, ChannelMetrics>>> {
+        &self.transport_channels
+    }
+
+    /// Returns the metrics of the client side channel connected to `server`.
+    ///
+    /// A new one is created if there is no such channel.
+    pub(crate) fn create_channel_metrics(&self, server: &TransportAddr) -> ChannelMetrics {
+        if let Some(metrics) = self.transport_channels.load().get(server).cloned() {
             return metrics;
         }
+        let metrics = self.new_channel_metrics();
+        self.insert(server, &metrics);
+        metrics
+    }
+
+    pub(crate) fn remove_channel_metrics(&self, server: &TransportAddr) {
+        if !self.transport_channels.load().contains_key(server) {
+            return;
+        }
+        self.remove(server, |_| true);
+    }
+
+    /// Creates the metrics of a server side channel (i.e., an accepted connection from `client`).
+    pub(crate) fn create_connection_metrics(&self, client: &TransportAddr) -> ChannelMetrics {
+        let metrics = self.new_channel_metrics();
+        if client.as_socket_addr().is_some() {
+            self.insert(client, &metrics);
+        }
+        metrics
+    }
+
+    /// Removes the metrics created by `create_connection_metrics`.
+    ///
+    /// The entry of `client` is left if it has been replaced by a newer connection from the same address.
+    pub(crate) fn remove_connection_metrics(
+        &self,
+        client: &TransportAddr,
+        metrics: &ChannelMetrics,
+    ) {
+        let is_same = |m: &ChannelMetrics| m.is_same(metrics);
+        if !self
+            .transport_channels
+            .load()
+            .get(client)
+            .is_some_and(is_same)
+        {
+            return;
+        }
+        self.remove(client, is_same);
+    }
 
+    fn new_channel_metrics(&self) -> ChannelMetrics {
         self.created_channels.increment();
         let correction = Arc::clone(&self.correction);
-        let metrics = if let Ok(builder) = self.builder.lock() {
+        if let Ok(builder) = self.builder.lock() {
             ChannelMetrics::new(&builder, Some(correction))
         } else {
             ChannelMetrics::new(&MetricBuilder::without_registry(), Some(correction))
-        };
-        self.channels.update(|channels| {
+        }
+    }
+
+    fn insert(&self, addr: &TransportAddr, metrics: &ChannelMetrics) {
+        if let Some(addr) = addr.as_socket_addr() {
+            self.channels.update(|channels| {
+                let mut channels = channels.clone();
+                channels.insert(addr, metrics.clone());
+                channels
+            });
+        }
+        self.transport_channels.update(|channels| {
             let mut channels = channels.clone();
-            channels.insert(server, metrics.clone());
+            channels.insert(addr.clone(), metrics.clone());
             channels
         });
-        metrics
     }
 
-    pub(crate) fn remove_channel_metrics(&self, server: SocketAddr) {
-        if !self.channels.load().contains_key(&server) {
-            return;
+    fn remove<F>(&self, addr: &TransportAddr, f: F)
+    where
+        F: Fn(&ChannelMetrics) -> bool,
+    {
+        if let Some(addr) = addr.as_socket_addr() {
+            self.channels.update(|channels| {
+                let mut channels = channels.clone();
+                if channels.get(&addr).is_some_and(&f) {
+                    channels.remove(&addr);
+                }
+                channels
+            });
         }
-        self.channels.update(|channels| {
+        self.transport_channels.update(|channels| {
             let mut channels = channels.clone();
-            channels.remove(&server);
+            if channels.get(addr).is_some_and(&f) {
+                channels.remove(addr);
+            }
             channels
         });
     }
@@ -221,6 +440,7 @@ impl ChannelsMetrics {
                 .finish()
                 .expect("Never fails"),
             channels: Arc::new(AtomicImmut::new(HashMap::new())),
+            transport_channels: Arc::new(AtomicImmut::new(HashMap::new())),
             builder: Arc::new(Mutex::new(builder)),
             correction,
         }
@@ -235,6 +455,12 @@ pub struct ChannelMetrics {
     pub(crate) async_incoming_messages: Counter,
     pub(crate) enqueued_outgoing_messages: Counter,
     pub(crate) dequeued_outgoing_messages: Counter,
+    pub(crate) heartbeat_rtt_seconds: Gauge,
+    pub(crate) compressed_outgoing_bytes: Counter,
+    pub(crate) uncompressed_outgoing_bytes: Counter,
+    pub(crate) compressed_incoming_bytes: Counter,
+    pub(crate) uncompressed_incoming_bytes: Counter,
+    pub(crate) rejected_incoming_messages: Counter,
     correction: Option<Arc<ChannelMetrics>>,
     last_one: LastOne,
 }
@@ -273,6 +499,48 @@ impl ChannelMetrics {
         enqueued_messages.saturating_sub(dequeued_messages)
     }
 
+    /// Metric: `fibers_rpc_channel_heartbeat_rtt_seconds { role="server|client" } <GAUGE>`.
+    pub fn heartbeat_rtt_seconds(&self) -> f64 {
+        self.heartbeat_rtt_seconds.value()
+    }
+
+    /// Metric: `fibers_rpc_channel_compressed_outgoing_bytes_total { role="server|client" } <COUNTER>`.
+    pub fn compressed_outgoing_bytes(&self) -> u64 {
+        self.compressed_outgoing_bytes.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_channel_uncompressed_outgoing_bytes_total { role="server|client" } <COUNTER>`.
+    pub fn uncompressed_outgoing_bytes(&self) -> u64 {
+        self.uncompressed_outgoing_bytes.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_channel_compressed_incoming_bytes_total { role="server|client" } <COUNTER>`.
+    pub fn compressed_incoming_bytes(&self) -> u64 {
+        self.compressed_incoming_bytes.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_channel_uncompressed_incoming_bytes_total { role="server|client" } <COUNTER>`.
+    pub fn uncompressed_incoming_bytes(&self) -> u64 {
+        self.uncompressed_incoming_bytes.value() as u64
+    }
+
+    /// Metric: `fibers_rpc_channel_rejected_incoming_messages_total { role="server|client" } <COUNTER>`.
+    pub fn rejected_incoming_messages(&self) -> u64 {
+        self.rejected_incoming_messages.value() as u64
+    }
+
+    /// Returns the round-trip time measured by the last heartbeat ping.
+    ///
+    /// If no heartbeat ping has been answered yet, this returns `None`.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        let seconds = self.heartbeat_rtt_seconds();
+        if seconds > 0.0 {
+            Some(Duration::from_secs_f64(seconds))
+        } else {
+            None
+        }
+    }
+
     fn new(builder: &MetricBuilder, correction: Option<Arc<Self>>) -> Self {
         ChannelMetrics {
             fiber_yielded: builder
@@ -300,11 +568,47 @@ impl ChannelMetrics {
                 .help("Number of dequeued outgoing messages")
                 .finish()
                 .expect("Never fails"),
+            heartbeat_rtt_seconds: builder
+                .gauge("heartbeat_rtt_seconds")
+                .help("Round-trip time measured by the last heartbeat ping")
+                .finish()
+                .expect("Never fails"),
+            compressed_outgoing_bytes: builder
+                .counter("compressed_outgoing_bytes_total")
+                .help("Number of bytes of outgoing payloads after compression")
+                .finish()
+                .expect("Never fails"),
+            uncompressed_outgoing_bytes: builder
+                .counter("uncompressed_outgoing_bytes_total")
+                .help("Number of bytes of outgoing payloads before compression")
+                .finish()
+                .expect("Never fails"),
+            compressed_incoming_bytes: builder
+                .counter("compressed_incoming_bytes_total")
+                .help("Number of bytes of incoming payloads before decompression")
+                .finish()
+                .expect("Never fails"),
+            uncompressed_incoming_bytes: builder
+                .counter("uncompressed_incoming_bytes_total")
+                .help("Number of bytes of incoming payloads after decompression")
+                .finish()
+                .expect("Never fails"),
+            rejected_incoming_messages: builder
+                .counter("rejected_incoming_messages_total")
+                .help("Number of incoming messages rejected because they are too large")
+                .finish()
+                .expect("Never fails"),
             correction,
             last_one: LastOne::default(),
         }
     }
 }
+impl ChannelMetrics {
+    /// Returns `true` if `self` and `other` are the metrics of the same channel.
+    fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.last_one.0, &other.last_one.0)
+    }
+}
 impl Drop for ChannelMetrics {
     fn drop(&mut self) {
         if let Some(ref c) = self.correction {
@@ -320,6 +624,16 @@ impl Drop for ChannelMetrics {
                 .add_u64(self.enqueued_outgoing_messages());
             c.dequeued_outgoing_messages
                 .add_u64(self.enqueued_outgoing_messages()); // Considers all messages dequeued
+            c.compressed_outgoing_bytes
+                .add_u64(self.compressed_outgoing_bytes());
+            c.uncompressed_outgoing_bytes
+                .add_u64(self.uncompressed_outgoing_bytes());
+            c.compressed_incoming_bytes
+                .add_u64(self.compressed_incoming_bytes());
+            c.uncompressed_incoming_bytes
+                .add_u64(self.uncompressed_incoming_bytes());
+            c.rejected_incoming_messages
+                .add_u64(self.rejected_incoming_messages());
         }
     }
 }
@@ -355,7 +669,8 @@ mod tests {
         let channels = ChannelsMetrics::new(&builder, "server");
         assert_eq!(channels.correction.enqueued_outgoing_messages(), 0);
         {
-            let channel0 = channels.create_channel_metrics("127.0.0.1:80".parse().unwrap());
+            let addr = "127.0.0.1:80".parse().unwrap();
+            let channel0 = channels.create_channel_metrics(&addr);
             channel0.enqueued_outgoing_messages.increment();
 
             let channel1 = channel0.clone();
@@ -365,8 +680,36 @@ mod tests {
             assert_eq!(channel0.enqueued_outgoing_messages(), 2);
             assert_eq!(channel1.enqueued_outgoing_messages(), 2);
 
-            channels.remove_channel_metrics("127.0.0.1:80".parse().unwrap());
+            channels.remove_channel_metrics(&addr);
         }
         assert_eq!(channels.correction.enqueued_outgoing_messages(), 2);
     }
+
+    #[test]
+    fn connection_metrics_works() {
+        let builder = MetricBuilder::new();
+        let channels = ChannelsMetrics::new(&builder, "server");
+
+        // Connections from the same address
+        let addr: TransportAddr = "127.0.0.1:80".parse().unwrap();
+        let channel0 = channels.create_connection_metrics(&addr);
+        let channel1 = channels.create_connection_metrics(&addr);
+        channels.remove_connection_metrics(&addr, &channel0);
+        assert!(channels
+            .as_map()
+            .load()
+            .contains_key(&"127.0.0.1:80".parse().unwrap()));
+        channels.remove_connection_metrics(&addr, &channel1);
+        assert!(channels.as_map().load().is_empty());
+        assert!(channels.as_transport_map().load().is_empty());
+
+        // Unix domain socket clients are indistinguishable by their addresses
+        let addr = TransportAddr::Unix(Default::default());
+        let channel0 = channels.create_connection_metrics(&addr);
+        let channel1 = channels.create_connection_metrics(&addr);
+        assert!(channels.as_transport_map().load().is_empty());
+        channels.remove_connection_metrics(&addr, &channel0);
+        channels.remove_connection_metrics(&addr, &channel1);
+        assert_eq!(channels.created_channels(), 4);
+    }
 }
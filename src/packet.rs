@@ -11,6 +11,12 @@ pub const MAX_PAYLOAD_LEN: usize = 16777215;
 
 const FLAG_END_OF_MESSAGE: u8 = 0b0000_0001;
 const FLAG_ASYNC: u8 = 0b0000_0010;
+const FLAG_ERROR: u8 = 0b0000_0100;
+const FLAG_CONTROL: u8 = 0b0000_1000;
+const FLAG_EXTENSION: u8 = 0b0001_0000;
+const FLAG_COMPRESSED: u8 = 0b0010_0000;
+const FLAG_STREAM_ITEM: u8 = 0b0100_0000;
+const FLAG_NOTIFICATION: u8 = 0b1000_0000;
 
 #[derive(Debug, Clone)]
 pub struct PacketHeader {
@@ -31,6 +37,11 @@ impl PacketHeader {
         let mut message = MessageHeader::read(buf);
         let flags = buf[MessageHeader::SIZE];
         message.is_async = (flags & FLAG_ASYNC) != 0;
+        message.is_error = (flags & FLAG_ERROR) != 0;
+        message.is_control = (flags & FLAG_CONTROL) != 0;
+        message.has_extension = (flags & FLAG_EXTENSION) != 0;
+        message.is_stream_item = (flags & FLAG_STREAM_ITEM) != 0;
+        message.is_notification = (flags & FLAG_NOTIFICATION) != 0;
         let payload_len = BigEndian::read_u32(&buf[MessageHeader::SIZE + 1..]);
         PacketHeader {
             message,
@@ -46,6 +57,14 @@ impl PacketHeader {
     pub fn is_async(&self) -> bool {
         (self.flags & FLAG_ASYNC) != 0
     }
+
+    pub fn is_control(&self) -> bool {
+        (self.flags & FLAG_CONTROL) != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        (self.flags & FLAG_COMPRESSED) != 0
+    }
 }
 
 #[derive(Debug, Default)]
@@ -100,7 +119,13 @@ impl Encode for PacketizedMessage {
             .encode(&mut buf[PacketHeader::SIZE..][..limit], eos))?;
 
         let flags = (self.message.payload.is_idle() as u8 * FLAG_END_OF_MESSAGE)
-            | (self.message.header.is_async as u8 * FLAG_ASYNC);
+            | (self.message.header.is_async as u8 * FLAG_ASYNC)
+            | (self.message.header.is_error as u8 * FLAG_ERROR)
+            | (self.message.header.is_control as u8 * FLAG_CONTROL)
+            | (self.message.header.has_extension as u8 * FLAG_EXTENSION)
+            | (self.message.header.is_compressed() as u8 * FLAG_COMPRESSED)
+            | (self.message.header.is_stream_item as u8 * FLAG_STREAM_ITEM)
+            | (self.message.header.is_notification as u8 * FLAG_NOTIFICATION);
         let packet_header = PacketHeader {
             message: self.message.header.clone(),
             flags,
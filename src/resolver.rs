@@ -0,0 +1,205 @@
+use crate::{Error, ErrorKind, Result, TransportAddr};
+use fibers::time::timer::{self, Timeout};
+use fibers_tasque::{AsyncCall, DefaultIoTaskQueue, TaskQueueExt};
+use futures::{Async, Future, Poll};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+/// This trait allows for resolving the addresses of the servers that provide a logical service.
+///
+/// Resolvers are registered to a client service by `ClientServiceHandle::add_resolver` and
+/// polled by the service (i.e., in the fiber of `ClientService`).
+pub trait Resolver: fmt::Debug + Send + 'static {
+    /// Polls the latest set of the server addresses.
+    ///
+    /// This should return `Async::Ready(Some(addrs))` only when the set has changed
+    /// (or when it is resolved for the first time).
+    /// `Async::Ready(None)` means that the set will never change.
+    ///
+    /// If this returns an error, the service logs it and polls the resolver again after a short delay.
+    fn poll_resolve(&mut self) -> Poll<Option<Vec<TransportAddr>>, Error>;
+}
+impl<R: Resolver + ?Sized> Resolver for Box<R> {
+    fn poll_resolve(&mut self) -> Poll<Option<Vec<TransportAddr>>, Error> {
+        (**self).poll_resolve()
+    }
+}
+
+/// A `Resolver` implementation that always resolves to the fixed set of addresses.
+#[derive(Debug)]
+pub struct StaticResolver {
+    addrs: Option<Vec<TransportAddr>>,
+}
+impl StaticResolver {
+    /// Makes a new `StaticResolver` instance.
+    pub fn new<I, A>(addrs: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<TransportAddr>,
+    {
+        StaticResolver {
+            addrs: Some(addrs.into_iter().map(Into::into).collect()),
+        }
+    }
+}
+impl Resolver for StaticResolver {
+    fn poll_resolve(&mut self) -> Poll<Option<Vec<TransportAddr>>, Error> {
+        Ok(Async::Ready(self.addrs.take()))
+    }
+}
+
+/// A `Resolver` implementation that periodically reads the addresses from a local file.
+///
+/// The file contains one address per line (e.g., `127.0.0.1:3000` or `unix:/tmp/rpc.sock`).
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// If the file cannot be read or parsed, the last resolved addresses are retained
+/// and the file is read again after the interval.
+pub struct FileResolver {
+    path: PathBuf,
+    interval: Duration,
+    last: Option<Vec<TransportAddr>>,
+    reading: Option<AsyncCall<io::Result<String>>>,
+    timeout: Option<Timeout>,
+}
+impl FileResolver {
+    /// The default value of the reading interval.
+    pub const DEFAULT_INTERVAL_SECONDS: u64 = 5;
+
+    /// Makes a new `FileResolver` instance which reads `path` every `DEFAULT_INTERVAL_SECONDS` seconds.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self::with_interval(path, Duration::from_secs(Self::DEFAULT_INTERVAL_SECONDS))
+    }
+
+    /// Makes a new `FileResolver` instance which reads `path` every `interval`.
+    pub fn with_interval<P: AsRef<Path>>(path: P, interval: Duration) -> Self {
+        FileResolver {
+            path: path.as_ref().to_path_buf(),
+            interval,
+            last: None,
+            reading: None,
+            timeout: None,
+        }
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn start_reading(&mut self) {
+        let path = self.path.clone();
+        let future =
+            DefaultIoTaskQueue.with(|tasque| tasque.async_call(move || fs::read_to_string(path)));
+        self.reading = Some(future);
+    }
+
+    fn handle_content(
+        &mut self,
+        content: io::Result<String>,
+    ) -> Result<Option<Vec<TransportAddr>>> {
+        let content = track!(content.map_err(Error::from), "path={:?}", self.path)?;
+        let mut addrs = track!(parse_addrs(&content), "path={:?}", self.path)?;
+        addrs.sort();
+        addrs.dedup();
+        if self.last.as_ref() == Some(&addrs) {
+            Ok(None)
+        } else {
+            self.last = Some(addrs.clone());
+            Ok(Some(addrs))
+        }
+    }
+}
+impl Resolver for FileResolver {
+    fn poll_resolve(&mut self) -> Poll<Option<Vec<TransportAddr>>, Error> {
+        loop {
+            if let Some(mut future) = self.reading.take() {
+                match future.poll() {
+                    Err(_) => {
+                        self.timeout = Some(timer::timeout(self.interval));
+                        track_panic!(ErrorKind::Other, "I/O worker thread aborted");
+                    }
+                    Ok(Async::NotReady) => {
+                        self.reading = Some(future);
+                        return Ok(Async::NotReady);
+                    }
+                    Ok(Async::Ready(content)) => {
+                        self.timeout = Some(timer::timeout(self.interval));
+                        if let Some(addrs) = track!(self.handle_content(content))? {
+                            return Ok(Async::Ready(Some(addrs)));
+                        }
+                    }
+                }
+            } else if let Some(mut timeout) = self.timeout.take() {
+                let expired = track!(timeout
+                    .poll()
+                    .map_err(|_| Error::from(ErrorKind::Other.cause("Broken timer"))))?;
+                if expired.is_ready() {
+                    self.start_reading();
+                } else {
+                    self.timeout = Some(timeout);
+                    return Ok(Async::NotReady);
+                }
+            } else {
+                self.start_reading();
+            }
+        }
+    }
+}
+impl fmt::Debug for FileResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "FileResolver {{ path: {:?}, interval: {:?}, last: {:?}, .. }}",
+            self.path, self.interval, self.last
+        )
+    }
+}
+
+fn parse_addrs(content: &str) -> Result<Vec<TransportAddr>> {
+    let mut addrs = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let addr = track!(line.parse(), "line={}", i + 1)?;
+        addrs.push(addr);
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_addrs_works() {
+        let content = "# comment\n127.0.0.1:3000\n\n  unix:/tmp/rpc.sock  \n";
+        let addrs = parse_addrs(content).unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                TransportAddr::Tcp("127.0.0.1:3000".parse().unwrap()),
+                TransportAddr::Unix("/tmp/rpc.sock".into()),
+            ]
+        );
+
+        assert!(parse_addrs("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn static_resolver_works() {
+        let addr: std::net::SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut resolver = StaticResolver::new(vec![addr]);
+        assert_eq!(
+            resolver.poll_resolve().unwrap(),
+            Async::Ready(Some(vec![TransportAddr::Tcp(addr)]))
+        );
+        assert_eq!(resolver.poll_resolve().unwrap(), Async::Ready(None));
+    }
+}
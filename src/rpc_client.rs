@@ -1,12 +1,24 @@
+use crate::circuit_breaker::{CircuitBreaker, Permit};
 use crate::client_service::{ClientServiceHandle, Message};
-use crate::client_side_handlers::{Response, ResponseHandler};
-use crate::message::{MessageHeader, MessageId, OutgoingMessage, OutgoingMessagePayload};
+use crate::client_side_handlers::{
+    Canceller, Response, ResponseHandler, ResponseStream, Retry, StreamResponseHandler,
+};
+use crate::extension::{ExtendedEncoder, HeaderExtension};
+use crate::message::{MessageHeader, OutgoingMessage, OutgoingMessagePayload};
 use crate::metrics::ClientMetrics;
-use crate::{Call, Cast, ErrorKind, Result};
+use crate::{
+    BidiStreamCall, Call, Cast, Compression, Error, ErrorKind, Result, StreamCall, TransportAddr,
+};
+use bytecodec::bytes::BytesEncoder;
+use bytecodec::io::IoEncodeExt;
+use bytecodec::Encode;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend};
+use rand::Rng;
+use std::cmp;
+use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
 /// Client for notification RPC.
@@ -31,27 +43,48 @@ where
     }
 
     /// Sends the notification message to the RPC server.
-    pub fn cast(self, server: SocketAddr, notification: T::Notification) -> Result<()> {
+    pub fn cast<A>(self, server: A, notification: T::Notification) -> Result<()>
+    where
+        A: Into<TransportAddr>,
+    {
+        let server = server.into();
         if !self
             .options
-            .is_allowable_queue_len(&self.service.metrics, server)
+            .is_allowable_queue_len(&self.service.metrics, &server)
         {
             self.service.metrics.discarded_outgoing_messages.increment();
             let e = track!(ErrorKind::Unavailable.cause("too long transmit queue"));
             return Err(e.into());
         }
 
+        let extension = HeaderExtension {
+            deadline: None,
+            metadata: self.options.metadata,
+        };
+        if let Err(e) = track!(extension.validate()) {
+            self.service.metrics.discarded_outgoing_messages.increment();
+            return Err(e);
+        }
         let header = MessageHeader {
-            id: MessageId(0), // dummy
+            id: self.service.next_message_id(),
             procedure: T::ID,
             priority: self.options.priority,
             is_async: T::enable_async(&notification),
+            is_error: false,
+            is_control: false,
+            has_extension: !extension.is_empty(),
+            compression: T::compression(&notification),
+            is_stream_item: false,
+            is_notification: true,
+        };
+        let payload = if header.has_extension {
+            let encoder = ExtendedEncoder::new(self.encoder);
+            OutgoingMessagePayload::with_item(encoder, (extension, notification))
+        } else {
+            OutgoingMessagePayload::with_item(self.encoder, notification)
         };
         let message = Message {
-            message: OutgoingMessage {
-                header,
-                payload: OutgoingMessagePayload::with_item(self.encoder, notification),
-            },
+            message: OutgoingMessage { header, payload },
             response_handler: None,
             force_wakeup: self.options.force_wakeup,
         };
@@ -93,6 +126,7 @@ pub struct CallClient<'a, T: Call> {
     service: &'a ClientServiceHandle,
     decoder: T::ResDecoder,
     encoder: T::ReqEncoder,
+    decoder_factory: Option<fn() -> T::ResDecoder>,
     options: Options,
     _call: PhantomData<T>,
 }
@@ -106,6 +140,7 @@ impl<'a, T: Call> CallClient<'a, T> {
             service,
             decoder,
             encoder,
+            decoder_factory: None,
             options: Options::default(),
             _call: PhantomData,
         }
@@ -113,46 +148,80 @@ impl<'a, T: Call> CallClient<'a, T> {
 
     /// Sends the request message to the RPC server,
     /// and returns a future that represents the response from the server.
-    pub fn call(self, server: SocketAddr, request: T::Req) -> Response<T::Res> {
-        if !self
-            .options
-            .is_allowable_queue_len(&self.service.metrics, server)
-        {
-            self.service.metrics.discarded_outgoing_messages.increment();
-            let e = track!(ErrorKind::Unavailable.cause("too long transmit queue"));
-            return Response::error(e.into());
-        }
-
-        let header = MessageHeader {
-            id: MessageId(0), // dummy
-            procedure: T::ID,
-            priority: self.options.priority,
+    ///
+    /// If `Options::retry_policy` allows and this client has a decoder factory,
+    /// the request is transparently retried on failure (see `RetryPolicy` for the details).
+    pub fn call<A>(self, server: A, request: T::Req) -> Response<T::Res>
+    where
+        A: Into<TransportAddr>,
+    {
+        let server = server.into();
+        let flags = RequestFlags {
             is_async: T::enable_async_request(&request),
+            compression: T::request_compression(&request),
+            deadline: self.options.deadline(),
         };
-
-        let (handler, response) = ResponseHandler::new(
-            self.decoder,
-            self.options.timeout,
-            Arc::clone(&self.service.metrics),
-            T::NAME,
-        );
-
-        let message = Message {
-            message: OutgoingMessage {
-                header,
-                payload: OutgoingMessagePayload::with_item(self.encoder, request),
-            },
-            response_handler: Some(Box::new(handler)),
-            force_wakeup: self.options.force_wakeup,
+        let decoder_factory = match self.decoder_factory {
+            Some(f) if self.options.retry_policy.max_attempts > 1 => f,
+            _ => {
+                return start_call::<T, _>(
+                    self.service,
+                    &server,
+                    &self.options,
+                    flags,
+                    self.encoder,
+                    request,
+                    self.decoder,
+                );
+            }
         };
 
-        if !self.service.send_message(server, message) {
+        // Encodes the request in advance so that it can be resent
+        let mut encoder = self.encoder;
+        let mut buf = Vec::new();
+        let result = track!(encoder.start_encoding(request))
+            .and_then(|()| track!(encoder.encode_all(&mut buf)));
+        if let Err(e) = result {
             self.service.metrics.discarded_outgoing_messages.increment();
-            let e = track!(ErrorKind::Unavailable.cause("client service or server is unavailable"));
-            return Response::error(e.into());
+            return Response::error(track!(Error::from(e)));
         }
-        self.service.metrics.requests.increment();
-        response
+
+        let response = start_call::<T, _>(
+            self.service,
+            &server,
+            &self.options,
+            flags,
+            BytesEncoder::new(),
+            buf.clone(),
+            self.decoder,
+        );
+        let service = self.service.clone();
+        let options = self.options;
+        let retry = Retry::new(
+            options.retry_policy.clone(),
+            T::IDEMPOTENT,
+            Box::new(move || {
+                service.metrics.retries.increment();
+                start_call::<T, _>(
+                    &service,
+                    &server,
+                    &options,
+                    flags,
+                    BytesEncoder::new(),
+                    buf.clone(),
+                    decoder_factory(),
+                )
+            }),
+        );
+        response.with_retry(retry)
+    }
+
+    /// Sets the function used for making the response decoder of each retry attempt.
+    ///
+    /// Clients made by `Call::client` or `Call::client_with_encoder` have `Default::default` as the factory.
+    /// If a client has no factory, its requests are never retried.
+    pub fn set_decoder_factory(&mut self, f: fn() -> T::ResDecoder) {
+        self.decoder_factory = Some(f);
     }
 }
 impl<'a, T: Call> CallClient<'a, T> {
@@ -187,11 +256,293 @@ impl<'a, T: Call> CallClient<'a, T> {
     }
 }
 
+/// Client for server-streaming RPC.
+#[derive(Debug)]
+pub struct StreamCallClient<'a, T: StreamCall> {
+    service: &'a ClientServiceHandle,
+    decoder_factory: fn() -> T::ItemDecoder,
+    encoder: T::ReqEncoder,
+    options: Options,
+    _call: PhantomData<T>,
+}
+impl<'a, T: StreamCall> StreamCallClient<'a, T> {
+    pub(crate) fn new(
+        service: &'a ClientServiceHandle,
+        decoder_factory: fn() -> T::ItemDecoder,
+        encoder: T::ReqEncoder,
+    ) -> Self {
+        StreamCallClient {
+            service,
+            decoder_factory,
+            encoder,
+            options: Options::default(),
+            _call: PhantomData,
+        }
+    }
+
+    /// Sends the request message to the RPC server,
+    /// and returns a stream that represents the items replied from the server.
+    ///
+    /// The timeout specified by `Options::timeout` is applied to the entire stream,
+    /// and `Options::retry_policy` is ignored (i.e., streaming requests are never retried).
+    pub fn call<A>(self, server: A, request: T::Req) -> ResponseStream<T::Item>
+    where
+        A: Into<TransportAddr>,
+    {
+        let server = server.into();
+        let service = self.service;
+        let options = &self.options;
+        let deadline = options.deadline();
+        let (extension, permit) = match track!(prepare_request(service, &server, options, deadline))
+        {
+            Err(e) => return ResponseStream::error(e),
+            Ok(v) => v,
+        };
+
+        let header = MessageHeader {
+            id: service.next_message_id(),
+            procedure: T::ID,
+            priority: options.priority,
+            is_async: T::enable_async_request(&request),
+            is_error: false,
+            is_control: false,
+            has_extension: !extension.is_empty(),
+            compression: T::request_compression(&request),
+            is_stream_item: false,
+            is_notification: false,
+        };
+
+        let canceller = Canceller::new(service.clone(), server.clone(), header.id);
+        let (handler, stream) = StreamResponseHandler::new(
+            self.decoder_factory,
+            remaining_time(deadline),
+            canceller,
+            Arc::clone(&service.metrics),
+            T::NAME,
+        );
+
+        let payload = request_payload(&header, extension, self.encoder, request);
+        let message = Message {
+            message: OutgoingMessage { header, payload },
+            response_handler: Some(Box::new(handler)),
+            force_wakeup: options.force_wakeup,
+        };
+
+        if !service.send_message(server, message) {
+            service.metrics.discarded_outgoing_messages.increment();
+            let e = track!(ErrorKind::Unavailable.cause("client service or server is unavailable"));
+            return ResponseStream::error(e.into()).with_permit(permit);
+        }
+        service.metrics.requests.increment();
+        stream.with_permit(permit)
+    }
+
+    /// Returns a reference to the RPC options of this client.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Returns a mutable reference to the RPC options of this client.
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    /// Returns a reference to the encoder of this client.
+    pub fn encoder(&self) -> &T::ReqEncoder {
+        &self.encoder
+    }
+
+    /// Returns a mutable reference to the encoder of this client.
+    pub fn encoder_mut(&mut self) -> &mut T::ReqEncoder {
+        &mut self.encoder
+    }
+}
+
+/// Client for bidirectional streaming RPC.
+#[derive(Debug)]
+pub struct BidiStreamCallClient<'a, T: BidiStreamCall> {
+    service: &'a ClientServiceHandle,
+    decoder_factory: fn() -> T::ResDecoder,
+    encoder_factory: fn() -> T::ReqEncoder,
+    options: Options,
+    _call: PhantomData<T>,
+}
+impl<'a, T: BidiStreamCall> BidiStreamCallClient<'a, T> {
+    pub(crate) fn new(
+        service: &'a ClientServiceHandle,
+        decoder_factory: fn() -> T::ResDecoder,
+        encoder_factory: fn() -> T::ReqEncoder,
+    ) -> Self {
+        BidiStreamCallClient {
+            service,
+            decoder_factory,
+            encoder_factory,
+            options: Options::default(),
+            _call: PhantomData,
+        }
+    }
+
+    /// Opens a stream to the RPC server.
+    ///
+    /// The requests are sent via the returned sink, and the responses are received via the returned stream.
+    /// Closing (or dropping) the sink notifies the server of the end of the requests.
+    ///
+    /// The timeout specified by `Options::timeout` is applied to the entire response stream,
+    /// and `Options::retry_policy` is ignored (i.e., streaming requests are never retried).
+    pub fn call<A>(self, server: A) -> (RequestSink<T>, ResponseStream<T::Res>)
+    where
+        A: Into<TransportAddr>,
+    {
+        let server = server.into();
+        let service = self.service;
+        let options = &self.options;
+        let header = MessageHeader {
+            id: service.next_message_id(),
+            procedure: T::ID,
+            priority: options.priority,
+            is_async: false,
+            is_error: false,
+            is_control: false,
+            has_extension: false,
+            compression: Compression::None,
+            is_stream_item: false,
+            is_notification: false,
+        };
+        let mut sink = RequestSink {
+            service: service.clone(),
+            server: server.clone(),
+            header: header.clone(),
+            encoder_factory: self.encoder_factory,
+            force_wakeup: options.force_wakeup,
+            is_closed: true,
+        };
+
+        let deadline = options.deadline();
+        let (extension, permit) = match track!(prepare_request(service, &server, options, deadline))
+        {
+            Err(e) => return (sink, ResponseStream::error(e)),
+            Ok(v) => v,
+        };
+
+        let canceller = Canceller::new(service.clone(), server.clone(), header.id);
+        let (handler, stream) = StreamResponseHandler::new(
+            self.decoder_factory,
+            remaining_time(deadline),
+            canceller,
+            Arc::clone(&service.metrics),
+            T::NAME,
+        );
+
+        // The message that opens the stream has no payload other than the header extension
+        let mut header = header;
+        header.has_extension = !extension.is_empty();
+        let payload = request_payload(&header, extension, BytesEncoder::new(), Vec::new());
+        let message = Message {
+            message: OutgoingMessage { header, payload },
+            response_handler: Some(Box::new(handler)),
+            force_wakeup: options.force_wakeup,
+        };
+
+        if !service.send_message(server, message) {
+            service.metrics.discarded_outgoing_messages.increment();
+            let e = track!(ErrorKind::Unavailable.cause("client service or server is unavailable"));
+            return (sink, ResponseStream::error(e.into()).with_permit(permit));
+        }
+        service.metrics.requests.increment();
+        sink.is_closed = false;
+        (sink, stream.with_permit(permit))
+    }
+
+    /// Returns a reference to the RPC options of this client.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Returns a mutable reference to the RPC options of this client.
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+}
+
+/// `Sink` for sending the requests of bidirectional streaming RPC.
+///
+/// Each request is sent as an individual message
+/// (i.e., it is interleaved with the other messages in accordance with the priority).
+///
+/// If this is dropped without closing, the end of the requests is notified to the server at that time.
+#[derive(Debug)]
+pub struct RequestSink<T: BidiStreamCall> {
+    service: ClientServiceHandle,
+    server: TransportAddr,
+    header: MessageHeader,
+    encoder_factory: fn() -> T::ReqEncoder,
+    force_wakeup: bool,
+    is_closed: bool,
+}
+impl<T: BidiStreamCall> RequestSink<T> {
+    fn send_message(&self, message: OutgoingMessage) -> bool {
+        let message = Message {
+            message,
+            response_handler: None,
+            force_wakeup: self.force_wakeup,
+        };
+        self.service.send_message(self.server.clone(), message)
+    }
+}
+impl<T: BidiStreamCall> Sink for RequestSink<T> {
+    type SinkItem = T::Req;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        track_assert!(
+            !self.is_closed,
+            ErrorKind::InvalidInput,
+            "The request stream has been closed"
+        );
+
+        let mut header = self.header.clone();
+        header.is_async = T::enable_async_request(&item);
+        header.compression = T::request_compression(&item);
+        header.is_stream_item = true;
+        let payload = OutgoingMessagePayload::with_item((self.encoder_factory)(), item);
+        if !self.send_message(OutgoingMessage { header, payload }) {
+            self.service.metrics.discarded_outgoing_messages.increment();
+            track_panic!(
+                ErrorKind::Unavailable,
+                "client service or server is unavailable"
+            );
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        if !self.is_closed {
+            self.is_closed = true;
+            let message = OutgoingMessage::end_of_stream(self.header.clone());
+            self.send_message(message);
+        }
+        Ok(Async::Ready(()))
+    }
+}
+impl<T: BidiStreamCall> Drop for RequestSink<T> {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
 /// Options for RPC.
 #[derive(Debug, Clone)]
 pub struct Options {
     /// The timeout of a RPC request.
     ///
+    /// The timeout covers all the attempts of the request (see `RetryPolicy`).
+    /// It is also notified to the server as the deadline of the request
+    /// (i.e., the remaining time when the request is sent; see `server::RequestContext::deadline`).
+    ///
     /// The default value is `None` and it means there is no timeout.
     ///
     /// This is no effect on notification RPC.
@@ -215,22 +566,45 @@ pub struct Options {
     ///
     /// The default value is `false`.
     pub force_wakeup: bool,
+
+    /// Arbitrary key/value pairs attached to the RPC message (e.g., trace IDs or auth tokens).
+    ///
+    /// The server can refer them via `server::RequestContext::metadata`.
+    ///
+    /// The default value is empty.
+    pub metadata: HashMap<String, Vec<u8>>,
+
+    /// The policy for retrying failed requests.
+    ///
+    /// The default value is `RetryPolicy::default()` and it means requests are never retried.
+    ///
+    /// This is no effect on notification and streaming RPCs,
+    /// nor on `CallClient`s without a decoder factory (see `RetryPolicy` for the details).
+    pub retry_policy: RetryPolicy,
 }
 impl Options {
     /// The default priority.
     pub const DEFAULT_PRIORITY: u8 = 128;
 
-    fn is_allowable_queue_len(&self, metrics: &ClientMetrics, server: SocketAddr) -> bool {
+    fn is_allowable_queue_len(&self, metrics: &ClientMetrics, server: &TransportAddr) -> bool {
         self.max_queue_len.map_or(true, |max| {
             let queue_len = metrics
                 .channels()
-                .as_map()
+                .as_transport_map()
                 .load()
-                .get(&server)
+                .get(server)
                 .map_or(0, |channel| channel.queue_len());
             queue_len <= max
         })
     }
+
+    /// Returns the deadline of a request started now.
+    ///
+    /// Too long timeouts which cannot be represented as an instant are regarded as no timeout.
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout))
+    }
 }
 impl Default for Options {
     fn default() -> Self {
@@ -239,6 +613,288 @@ impl Default for Options {
             max_queue_len: None,
             priority: Self::DEFAULT_PRIORITY,
             force_wakeup: false,
+            metadata: HashMap::new(),
+            retry_policy: RetryPolicy::default(),
         }
     }
 }
+
+/// Policy for retrying failed requests.
+///
+/// A failed request is retried if all of the following conditions are satisfied:
+/// - The number of attempts is less than `max_attempts`
+/// - The kind of the error is contained in `retryable_errors`
+/// - The RPC is idempotent (i.e., `Call::IDEMPOTENT` is `true`) or the request has never been handled
+///   by the server (i.e., the error is `ErrorKind::ConnectionLost { request_sent: false }` or
+///   `ErrorKind::Overloaded`)
+///
+/// Retrying is only supported by `CallClient`s that have a decoder factory
+/// (see `CallClient::set_decoder_factory`), because each attempt needs a fresh response decoder.
+/// Clients made by `Call::client` and `Call::client_with_encoder` have it,
+/// but clients made by `Call::client_with_decoder` and `Call::client_with_codec` do not,
+/// so their requests are never retried unless a factory is set explicitly.
+/// Notification and streaming requests are never retried either.
+///
+/// Note that retrying requires the request to be encoded in advance (in the caller's context),
+/// so a request is encoded synchronously whenever `max_attempts` is greater than `1`
+/// (even if it would be retried only by a later attempt),
+/// and the timeout specified by `Options::timeout` is shared by all the attempts.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts (including the first one).
+    ///
+    /// The default value is `1` and it means requests are never retried.
+    pub max_attempts: usize,
+
+    /// The backoff duration before the first retry.
+    ///
+    /// The duration is doubled for each subsequent retry.
+    ///
+    /// The default value is `100ms`.
+    pub initial_backoff: Duration,
+
+    /// The maximum backoff duration.
+    ///
+    /// The default value is `10s`.
+    pub max_backoff: Duration,
+
+    /// The ratio of randomization applied to backoff durations.
+    ///
+    /// Each backoff duration is chosen uniformly from `[backoff * (1 - jitter), backoff * (1 + jitter)]`.
+    /// The value is clamped to `[0.0, 1.0]` (a non-finite value is regarded as `0.0`).
+    ///
+    /// The default value is `0.2`.
+    pub jitter: f64,
+
+    /// The kinds of errors to be retried.
+    ///
+    /// The default value contains `ErrorKind::Unavailable`, `ErrorKind::ConnectionLost`
+    /// and `ErrorKind::Overloaded`.
+    pub retryable_errors: Vec<ErrorKind>,
+}
+impl RetryPolicy {
+    /// Returns `true` if a request which failed with `kind` can be retried.
+    pub fn is_retryable(&self, kind: &ErrorKind, idempotent: bool) -> bool {
+        self.retryable_errors.contains(kind)
+            && (idempotent
+                || *kind
+                    == ErrorKind::ConnectionLost {
+                        request_sent: false,
+                    }
+                || *kind == ErrorKind::Overloaded)
+    }
+
+    /// Returns the backoff duration before the `retry`-th retry (1-origin).
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exp = cmp::min(retry.saturating_sub(1), 31) as u32;
+        let backoff = self
+            .initial_backoff
+            .checked_mul(1 << exp)
+            .map_or(self.max_backoff, |d| cmp::min(d, self.max_backoff));
+        let jitter = if self.jitter.is_finite() {
+            self.jitter.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        if jitter == 0.0 {
+            return backoff;
+        }
+        let ratio = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+
+        // The backoff may exceed the range of `Duration` if `max_backoff` is close to `Duration::MAX`
+        Duration::try_from_secs_f64(backoff.as_secs_f64() * ratio).unwrap_or(Duration::MAX)
+    }
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.2,
+            retryable_errors: vec![
+                ErrorKind::Unavailable,
+                ErrorKind::ConnectionLost {
+                    request_sent: false,
+                },
+                ErrorKind::ConnectionLost { request_sent: true },
+                ErrorKind::Overloaded,
+            ],
+        }
+    }
+}
+
+/// Returns the time remaining until `deadline`.
+fn remaining_time(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|d| d.saturating_duration_since(Instant::now()))
+}
+
+/// Flags of a request message decided by `Call` methods.
+#[derive(Debug, Clone, Copy)]
+struct RequestFlags {
+    is_async: bool,
+    compression: Compression,
+
+    /// The deadline shared by all the attempts of the request.
+    deadline: Option<Instant>,
+}
+
+/// Checks whether a request can be sent to `server` in accordance with `options`,
+/// and acquires a permit from the circuit breaker of the server (if any).
+fn prepare_request(
+    service: &ClientServiceHandle,
+    server: &TransportAddr,
+    options: &Options,
+    deadline: Option<Instant>,
+) -> Result<(HeaderExtension, Option<Permit>)> {
+    if !options.is_allowable_queue_len(&service.metrics, server) {
+        service.metrics.discarded_outgoing_messages.increment();
+        track_panic!(ErrorKind::Unavailable, "too long transmit queue");
+    }
+
+    let extension = HeaderExtension {
+        deadline,
+        metadata: options.metadata.clone(),
+    };
+    if let Err(e) = track!(extension.validate()) {
+        service.metrics.discarded_outgoing_messages.increment();
+        return Err(e);
+    }
+    let permit = if let Some(breaker) = service.circuit_breaker(server) {
+        if let Some(permit) = CircuitBreaker::try_acquire(&breaker) {
+            Some(permit)
+        } else {
+            service.metrics.circuit_breaker_rejections.increment();
+            track_panic!(ErrorKind::Unavailable, "Circuit breaker is open");
+        }
+    } else {
+        None
+    };
+    Ok((extension, permit))
+}
+
+/// Makes the payload of a request message.
+fn request_payload<E>(
+    header: &MessageHeader,
+    extension: HeaderExtension,
+    encoder: E,
+    request: E::Item,
+) -> OutgoingMessagePayload
+where
+    E: Encode + Send + 'static,
+    E::Item: Send + 'static,
+{
+    if header.has_extension {
+        let encoder = ExtendedEncoder::new(encoder);
+        OutgoingMessagePayload::with_item(encoder, (extension, request))
+    } else {
+        OutgoingMessagePayload::with_item(encoder, request)
+    }
+}
+
+/// Starts an attempt of the request/response RPC.
+fn start_call<T, E>(
+    service: &ClientServiceHandle,
+    server: &TransportAddr,
+    options: &Options,
+    flags: RequestFlags,
+    encoder: E,
+    request: E::Item,
+    decoder: T::ResDecoder,
+) -> Response<T::Res>
+where
+    T: Call,
+    E: Encode + Send + 'static,
+    E::Item: Send + 'static,
+{
+    let (extension, permit) =
+        match track!(prepare_request(service, server, options, flags.deadline)) {
+            Err(e) => return Response::error(e),
+            Ok(v) => v,
+        };
+
+    let header = MessageHeader {
+        id: service.next_message_id(),
+        procedure: T::ID,
+        priority: options.priority,
+        is_async: flags.is_async,
+        is_error: false,
+        is_control: false,
+        has_extension: !extension.is_empty(),
+        compression: flags.compression,
+        is_stream_item: false,
+        is_notification: false,
+    };
+
+    let canceller = Canceller::new(service.clone(), server.clone(), header.id);
+    let (handler, response) = ResponseHandler::new(
+        decoder,
+        remaining_time(flags.deadline),
+        canceller,
+        Arc::clone(&service.metrics),
+        T::NAME,
+    );
+
+    let payload = request_payload(&header, extension, encoder, request);
+    let message = Message {
+        message: OutgoingMessage { header, payload },
+        response_handler: Some(Box::new(handler)),
+        force_wakeup: options.force_wakeup,
+    };
+
+    if !service.send_message(server.clone(), message) {
+        service.metrics.discarded_outgoing_messages.increment();
+        let e = track!(ErrorKind::Unavailable.cause("client service or server is unavailable"));
+        return Response::error(e.into()).with_permit(permit);
+    }
+    service.metrics.requests.increment();
+    response.with_permit(permit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_works() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(usize::MAX), Duration::from_secs(10));
+
+        // Extreme values
+        let policy = RetryPolicy {
+            initial_backoff: Duration::MAX,
+            max_backoff: Duration::MAX,
+            ..Default::default()
+        };
+        assert!(policy.backoff(usize::MAX) > Duration::from_secs(10));
+
+        let policy = RetryPolicy {
+            jitter: f64::NAN,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn is_retryable_works() {
+        let policy = RetryPolicy::default();
+        let not_sent = ErrorKind::ConnectionLost {
+            request_sent: false,
+        };
+        let sent = ErrorKind::ConnectionLost { request_sent: true };
+
+        assert!(policy.is_retryable(&not_sent, false));
+        assert!(policy.is_retryable(&ErrorKind::Overloaded, false));
+        assert!(!policy.is_retryable(&sent, false));
+        assert!(!policy.is_retryable(&ErrorKind::Unavailable, false));
+
+        assert!(policy.is_retryable(&sent, true));
+        assert!(policy.is_retryable(&ErrorKind::Unavailable, true));
+        assert!(!policy.is_retryable(&ErrorKind::Timeout, true));
+    }
+}
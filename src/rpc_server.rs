@@ -1,47 +1,73 @@
 use crate::channel::ChannelOptions;
-use crate::message::OutgoingMessage;
+use crate::concurrency_limit::{ConcurrencyLimit, Limiters};
+use crate::error_reply::ErrorReply;
+use crate::message::{MessageId, OutgoingMessage};
 use crate::metrics::{HandlerMetrics, ServerMetrics};
 use crate::server_side_channel::ServerSideChannel;
 use crate::server_side_handlers::{
-    Action, Assigner, CallHandlerFactory, CastHandlerFactory, HandleCall, HandleCast,
-    MessageHandlers,
+    Action, Assigner, BidiStreamCallHandlerFactory, BoxNoReply, BoxReply, BoxReplyStream,
+    CallHandlerFactory, CastHandlerFactory, HandleBidiStreamCallWithContext, HandleCallWithContext,
+    HandleCastWithContext, HandleStreamCallWithContext, MessageHandlers, StreamCallHandlerFactory,
+};
+use crate::transport::{self, Acceptor, Bind, Connect, Incoming};
+use crate::{
+    BidiStreamCall, Call, Cast, Error, ErrorKind, ProcedureId, Result, StreamCall, TransportAddr,
 };
-use crate::{Call, Cast, Error, ProcedureId};
-use bytecodec::marker::Never;
 use factory::{DefaultFactory, Factory};
-use fibers::net::futures::{Connected, TcpListenerBind};
-use fibers::net::streams::Incoming;
-use fibers::net::TcpListener;
-use fibers::sync::mpsc;
+use fibers::sync::{mpsc, oneshot};
+use fibers::time::timer::{self, Timeout};
 use fibers::{self, BoxSpawn, Spawn};
 use futures::future::{loop_fn, Either, Loop};
 use futures::{self, Async, Future, Poll, Stream};
 use prometrics::metrics::MetricBuilder;
 use slog::{Discard, Logger};
 use std::collections::HashMap;
+use std::fmt;
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
 
 /// RPC server builder.
 #[derive(Debug)]
 pub struct ServerBuilder {
-    bind_addr: SocketAddr,
+    bind_addr: TransportAddr,
     logger: Logger,
     handlers: MessageHandlers,
     channel_options: ChannelOptions,
+    acceptor: Acceptor,
     metrics: MetricBuilder,
     handlers_metrics: HashMap<ProcedureId, HandlerMetrics>,
+    concurrency_limit: Option<ConcurrencyLimit>,
+    procedure_concurrency_limits: HashMap<ProcedureId, ConcurrencyLimit>,
+    procedure_max_message_sizes: HashMap<ProcedureId, usize>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
 }
 impl ServerBuilder {
     /// Makes a new `ServerBuilder` instance.
     pub fn new(bind_addr: SocketAddr) -> Self {
+        Self::with_transport_addr(bind_addr)
+    }
+
+    /// Makes a new `ServerBuilder` instance which binds the server to the given address.
+    ///
+    /// `bind_addr` can be either a TCP socket address or a filesystem path of a Unix domain socket.
+    pub fn with_transport_addr<A: Into<TransportAddr>>(bind_addr: A) -> Self {
         ServerBuilder {
-            bind_addr,
+            bind_addr: bind_addr.into(),
             logger: Logger::root(Discard, o!()),
             handlers: MessageHandlers(HashMap::new()),
             channel_options: ChannelOptions::default(),
+            acceptor: Acceptor::default(),
             metrics: MetricBuilder::new(),
             handlers_metrics: HashMap::new(),
+            concurrency_limit: None,
+            procedure_concurrency_limits: HashMap::new(),
+            procedure_max_message_sizes: HashMap::new(),
+            max_connections: None,
+            max_connections_per_ip: None,
         }
     }
 
@@ -69,6 +95,71 @@ impl ServerBuilder {
         self
     }
 
+    /// Limits the number of requests handled concurrently by the server (i.e., across all the channels).
+    ///
+    /// By default, the number is unlimited.
+    pub fn concurrency_limit(&mut self, limit: ConcurrencyLimit) -> &mut Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Limits the number of requests of `procedure` handled concurrently by the server.
+    ///
+    /// This is applied in addition to the server-wide limit (if any).
+    ///
+    /// By default, the number is unlimited.
+    pub fn procedure_concurrency_limit(
+        &mut self,
+        procedure: ProcedureId,
+        limit: ConcurrencyLimit,
+    ) -> &mut Self {
+        self.procedure_concurrency_limits.insert(procedure, limit);
+        self
+    }
+
+    /// Sets the maximum byte size of the messages of `procedure` received by the server.
+    ///
+    /// This overrides `ChannelOptions::max_incoming_message_size` for the procedure.
+    /// Messages exceeding the limit are discarded, and an `ErrorKind::InvalidInput` error is replied.
+    pub fn procedure_max_incoming_message_size(
+        &mut self,
+        procedure: ProcedureId,
+        max: usize,
+    ) -> &mut Self {
+        self.procedure_max_message_sizes.insert(procedure, max);
+        self
+    }
+
+    /// Sets the maximum number of connections that the server can have at the same time.
+    ///
+    /// New connections beyond the limit are closed immediately after being accepted.
+    ///
+    /// By default, the number is unlimited.
+    pub fn max_connections(&mut self, max: usize) -> &mut Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of connections from the same IP address.
+    ///
+    /// New connections beyond the limit are closed immediately after being accepted.
+    /// This limit is not applied to the connections via Unix domain sockets.
+    ///
+    /// By default, the number is unlimited.
+    pub fn max_connections_per_ip(&mut self, max: usize) -> &mut Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Enables TLS on the connections from clients.
+    ///
+    /// This fails if the certificates or the private key specified by `options` cannot be loaded.
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, options: crate::tls::ServerTlsOptions) -> Result<&mut Self> {
+        self.acceptor.tls = Some(track!(crate::tls::TlsAcceptor::new(&options))?);
+        Ok(self)
+    }
+
     /// Registers a handler for the request/response RPC.
     ///
     /// This equivalent to
@@ -80,7 +171,7 @@ impl ServerBuilder {
     pub fn add_call_handler<T, H>(&mut self, handler: H) -> &mut Self
     where
         T: Call,
-        H: HandleCall<T>,
+        H: HandleCallWithContext<T>,
         T::ReqDecoder: Default,
         T::ResEncoder: Default,
     {
@@ -101,7 +192,7 @@ impl ServerBuilder {
     ) -> &mut Self
     where
         T: Call,
-        H: HandleCall<T>,
+        H: HandleCallWithContext<T>,
         D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
         T::ResEncoder: Default,
     {
@@ -124,7 +215,7 @@ impl ServerBuilder {
     ) -> &mut Self
     where
         T: Call,
-        H: HandleCall<T>,
+        H: HandleCallWithContext<T>,
         E: Factory<Item = T::ResEncoder> + Send + Sync + 'static,
         T::ReqDecoder: Default,
     {
@@ -144,7 +235,7 @@ impl ServerBuilder {
     ) -> &mut Self
     where
         T: Call,
-        H: HandleCall<T>,
+        H: HandleCallWithContext<T>,
         D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
         E: Factory<Item = T::ResEncoder> + Send + Sync + 'static,
     {
@@ -173,7 +264,7 @@ impl ServerBuilder {
     pub fn add_cast_handler<T, H>(&mut self, handler: H) -> &mut Self
     where
         T: Cast,
-        H: HandleCast<T>,
+        H: HandleCastWithContext<T>,
         T::Decoder: Default,
     {
         self.add_cast_handler_with_decoder(handler, DefaultFactory::new())
@@ -191,7 +282,7 @@ impl ServerBuilder {
     ) -> &mut Self
     where
         T: Cast,
-        H: HandleCast<T>,
+        H: HandleCastWithContext<T>,
         D: Factory<Item = T::Decoder> + Send + Sync + 'static,
     {
         assert!(
@@ -209,6 +300,116 @@ impl ServerBuilder {
         self
     }
 
+    /// Registers a handler for the server-streaming RPC.
+    ///
+    /// This equivalent to
+    /// `add_stream_call_handler_with_codec(handler, DefaultFactory::new(), DefaultFactory::new())`.
+    ///
+    /// # Panices
+    ///
+    /// If a procedure which has `T::ID` already have been registered, the calling thread will panic.
+    pub fn add_stream_call_handler<T, H>(&mut self, handler: H) -> &mut Self
+    where
+        T: StreamCall,
+        H: HandleStreamCallWithContext<T>,
+        T::ReqDecoder: Default,
+        T::ItemEncoder: Default,
+    {
+        self.add_stream_call_handler_with_codec(
+            handler,
+            DefaultFactory::new(),
+            DefaultFactory::new(),
+        )
+    }
+
+    /// Registers a handler (with the given request decoder/item encoder makers) for the server-streaming RPC.
+    ///
+    /// # Panices
+    ///
+    /// If a procedure which has `T::ID` already have been registered, the calling thread will panic.
+    pub fn add_stream_call_handler_with_codec<T, H, D, E>(
+        &mut self,
+        handler: H,
+        decoder_factory: D,
+        encoder_factory: E,
+    ) -> &mut Self
+    where
+        T: StreamCall,
+        H: HandleStreamCallWithContext<T>,
+        D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
+        E: Factory<Item = T::ItemEncoder> + Send + Sync + 'static,
+    {
+        assert!(
+            !self.handlers.0.contains_key(&T::ID),
+            "RPC registration conflicts: procedure={:?}, name={:?}",
+            T::ID,
+            T::NAME
+        );
+
+        let metrics = HandlerMetrics::new(self.metrics.clone(), T::ID, T::NAME, "stream_call");
+        self.handlers_metrics.insert(T::ID, metrics.clone());
+
+        let handler =
+            StreamCallHandlerFactory::new(handler, decoder_factory, encoder_factory, metrics);
+        self.handlers.0.insert(T::ID, Box::new(handler));
+        self
+    }
+
+    /// Registers a handler for the bidirectional streaming RPC.
+    ///
+    /// This equivalent to
+    /// `add_bidi_stream_call_handler_with_codec(handler, DefaultFactory::new(), DefaultFactory::new())`.
+    ///
+    /// # Panices
+    ///
+    /// If a procedure which has `T::ID` already have been registered, the calling thread will panic.
+    pub fn add_bidi_stream_call_handler<T, H>(&mut self, handler: H) -> &mut Self
+    where
+        T: BidiStreamCall,
+        H: HandleBidiStreamCallWithContext<T>,
+        T::ReqDecoder: Default,
+        T::ResEncoder: Default,
+    {
+        self.add_bidi_stream_call_handler_with_codec(
+            handler,
+            DefaultFactory::new(),
+            DefaultFactory::new(),
+        )
+    }
+
+    /// Registers a handler (with the given request decoder/response encoder makers) for the bidirectional streaming RPC.
+    ///
+    /// # Panices
+    ///
+    /// If a procedure which has `T::ID` already have been registered, the calling thread will panic.
+    pub fn add_bidi_stream_call_handler_with_codec<T, H, D, E>(
+        &mut self,
+        handler: H,
+        decoder_factory: D,
+        encoder_factory: E,
+    ) -> &mut Self
+    where
+        T: BidiStreamCall,
+        H: HandleBidiStreamCallWithContext<T>,
+        D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
+        E: Factory<Item = T::ResEncoder> + Send + Sync + 'static,
+    {
+        assert!(
+            !self.handlers.0.contains_key(&T::ID),
+            "RPC registration conflicts: procedure={:?}, name={:?}",
+            T::ID,
+            T::NAME
+        );
+
+        let metrics = HandlerMetrics::new(self.metrics.clone(), T::ID, T::NAME, "bidi_stream_call");
+        self.handlers_metrics.insert(T::ID, metrics.clone());
+
+        let handler =
+            BidiStreamCallHandlerFactory::new(handler, decoder_factory, encoder_factory, metrics);
+        self.handlers.0.insert(T::ID, Box::new(handler));
+        self
+    }
+
     /// Returns the resulting RPC server.
     pub fn finish<S>(mut self, spawner: S) -> Server<S>
     where
@@ -216,13 +417,30 @@ impl ServerBuilder {
     {
         let logger = self.logger.new(o!("server" => self.bind_addr.to_string()));
         info!(logger, "Starts RPC server");
-        let handlers = mem::replace(&mut self.handlers, MessageHandlers(HashMap::new()));
+        let mut handlers = mem::replace(&mut self.handlers, MessageHandlers(HashMap::new()));
+        handlers.0.shrink_to_fit();
+        let (command_tx, command_rx) = mpsc::channel();
         Server {
-            listener: Listener::Binding(TcpListener::bind(self.bind_addr)),
+            listener: Listener::Binding(transport::bind(&self.bind_addr)),
             logger,
             spawner,
-            assigner: Assigner::new(handlers),
+            handlers: Arc::new(handlers),
+            limiters: Arc::new(Limiters::new(
+                self.concurrency_limit,
+                &self.procedure_concurrency_limits,
+            )),
+            max_message_sizes: Arc::new(self.procedure_max_message_sizes.clone()),
+            command_tx,
+            command_rx,
+            channels: HashMap::new(),
+            connections_per_ip: HashMap::new(),
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            next_channel_id: 0,
+            shutdown_deadline: None,
+            shutdown_timeout: None,
             channel_options: self.channel_options.clone(),
+            acceptor: self.acceptor.clone(),
             metrics: ServerMetrics::new(self.metrics.clone(), self.handlers_metrics.clone()),
         }
     }
@@ -235,15 +453,51 @@ pub struct Server<S> {
     listener: Listener,
     logger: Logger,
     spawner: S,
-    assigner: Assigner,
+    handlers: Arc<MessageHandlers>,
+    limiters: Arc<Limiters>,
+    max_message_sizes: Arc<HashMap<ProcedureId, usize>>,
+    command_tx: mpsc::Sender<Command>,
+    command_rx: mpsc::Receiver<Command>,
+    channels: HashMap<u64, ChannelEntry>,
+    connections_per_ip: HashMap<IpAddr, usize>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    next_channel_id: u64,
+    shutdown_deadline: Option<Instant>,
+    shutdown_timeout: Option<Timeout>,
     channel_options: ChannelOptions,
+    acceptor: Acceptor,
     metrics: ServerMetrics,
 }
 impl<S> Server<S> {
-    /// Returns a future that retrieves the address to which the server is bound.
+    /// Returns a handle of the server.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            command_tx: self.command_tx.clone(),
+        }
+    }
+
+    /// Returns a future that retrieves the TCP address to which the server is bound.
+    ///
+    /// If the server is bound to a Unix domain socket, the future will fail with `ErrorKind::InvalidInput`.
     pub fn local_addr(self) -> impl Future<Item = (Self, SocketAddr), Error = Error> {
+        self.local_transport_addr().and_then(|(this, addr)| {
+            let addr = track!(to_socket_addr(addr))?;
+            Ok((this, addr))
+        })
+    }
+
+    /// Returns a future that retrieves the address to which the server is bound.
+    pub fn local_transport_addr(self) -> impl Future<Item = (Self, TransportAddr), Error = Error> {
         match self.listener {
-            Listener::Listening(_, addr) => Either::A(futures::finished((self, addr))),
+            Listener::Listening(_, ref addr) => {
+                let addr = addr.clone();
+                Either::A(futures::finished((self, addr)))
+            }
+            Listener::Closed => {
+                let e = ErrorKind::Unavailable.cause("The server has been shut down");
+                Either::A(futures::failed(track!(Error::from(e))))
+            }
             Listener::Binding(_) => {
                 let future = loop_fn(self, |mut this| {
                     if fibers::fiber::with_current_context(|_| ()).is_none() {
@@ -251,7 +505,8 @@ impl<S> Server<S> {
                     }
 
                     track!(this.listener.poll())?;
-                    if let Listener::Listening(_, addr) = this.listener {
+                    if let Listener::Listening(_, ref addr) = this.listener {
+                        let addr = addr.clone();
                         Ok(Loop::Break((this, addr)))
                     } else {
                         Ok(Loop::Continue(this))
@@ -262,18 +517,32 @@ impl<S> Server<S> {
         }
     }
 
-    /// Polls the address to which the server is bound.
+    /// Polls the TCP address to which the server is bound.
+    ///
+    /// If the server is bound to a Unix domain socket, this will return an `ErrorKind::InvalidInput` error.
     pub fn poll_local_addr(&mut self) -> Poll<SocketAddr, Error> {
+        if let Async::Ready(addr) = track!(self.poll_local_transport_addr())? {
+            Ok(Async::Ready(track!(to_socket_addr(addr))?))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Polls the address to which the server is bound.
+    pub fn poll_local_transport_addr(&mut self) -> Poll<TransportAddr, Error> {
         match self.listener {
-            Listener::Listening(_, addr) => Ok(Async::Ready(addr)),
+            Listener::Listening(_, ref addr) => Ok(Async::Ready(addr.clone())),
             Listener::Binding(_) => {
                 track!(self.listener.poll())?;
-                if let Listener::Listening(_, addr) = self.listener {
-                    Ok(Async::Ready(addr))
+                if let Listener::Listening(_, ref addr) = self.listener {
+                    Ok(Async::Ready(addr.clone()))
                 } else {
                     Ok(Async::NotReady)
                 }
             }
+            Listener::Closed => {
+                track_panic!(ErrorKind::Unavailable, "The server has been shut down")
+            }
         }
     }
 
@@ -282,6 +551,120 @@ impl<S> Server<S> {
         &self.metrics
     }
 }
+impl<S> Server<S>
+where
+    S: Clone + Spawn + Send + 'static,
+{
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Shutdown { grace_period } => {
+                if self.shutdown_deadline.is_some() {
+                    return;
+                }
+                info!(
+                    self.logger,
+                    "Starts graceful shutdown: grace_period={:?}, channels={}",
+                    grace_period,
+                    self.channels.len()
+                );
+                let deadline = Instant::now() + grace_period;
+                self.shutdown_deadline = Some(deadline);
+                self.shutdown_timeout = Some(timer::timeout(grace_period));
+                self.listener = Listener::Closed;
+                for channel in self.channels.values_mut() {
+                    if let Some(tx) = channel.shutdown_tx.take() {
+                        let _ = tx.send(deadline);
+                    }
+                }
+            }
+            Command::RemoveChannel { channel_id } => {
+                let ip = self.channels.remove(&channel_id).and_then(|c| c.ip);
+                if let Some(ip) = ip {
+                    if let Some(count) = self.connections_per_ip.get_mut(&ip) {
+                        *count -= 1;
+                        if *count == 0 {
+                            self.connections_per_ip.remove(&ip);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns `false` if accepting a connection from `addr` would exceed the connection limits.
+    fn is_acceptable(&self, addr: &TransportAddr) -> bool {
+        if let Some(max) = self.max_connections {
+            if self.channels.len() >= max {
+                warn!(
+                    self.logger,
+                    "Refused a connection from {}: too many connections (max={})", addr, max
+                );
+                return false;
+            }
+        }
+        if let (Some(max), Some(ip)) = (self.max_connections_per_ip, addr_to_ip(addr)) {
+            if self.connections_per_ip.get(&ip).map_or(0, |&n| n) >= max {
+                warn!(
+                    self.logger,
+                    "Refused a connection from {}: too many connections from the same IP (max={})",
+                    addr,
+                    max
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    fn spawn_channel_handler(&mut self, client: Connect, addr: TransportAddr) {
+        let logger = self.logger.new(o!("client" => addr.to_string()));
+        info!(logger, "New client");
+
+        let channel_id = self.next_channel_id;
+        self.next_channel_id += 1;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let ip = addr_to_ip(&addr);
+        if let Some(ip) = ip {
+            *self.connections_per_ip.entry(ip).or_default() += 1;
+        }
+        self.channels.insert(
+            channel_id,
+            ChannelEntry {
+                shutdown_tx: Some(shutdown_tx),
+                ip,
+            },
+        );
+
+        let options = self.channel_options.clone();
+        let metrics = self.metrics.channels().create_connection_metrics(&addr);
+        let exit_metrics = metrics.clone();
+        let channels = self.metrics.channels().clone();
+        let exit_logger = logger.clone();
+        let spawner = self.spawner.clone().boxed();
+        let assigner = Assigner::new(
+            Arc::clone(&self.handlers),
+            Arc::clone(&self.max_message_sizes),
+            addr.clone(),
+        );
+        let limiters = Arc::clone(&self.limiters);
+        let command_tx = self.command_tx.clone();
+        let client = self.acceptor.accept(client, &options);
+        let future = track_err!(client).and_then(move |stream| {
+            let channel = ServerSideChannel::new(logger, stream, assigner, options, metrics);
+            ChannelHandler::new(spawner, channel, limiters, shutdown_rx)
+        });
+        self.spawner.spawn(future.then(move |result| {
+            channels.remove_connection_metrics(&addr, &exit_metrics);
+            if let Err(e) = result {
+                error!(exit_logger, "Connection aborted: {}", e);
+            } else {
+                info!(exit_logger, "Connection was closed");
+            }
+            let _ = command_tx.send(Command::RemoveChannel { channel_id });
+            Ok(())
+        }));
+    }
+}
 impl<S> Future for Server<S>
 where
     S: Clone + Spawn + Send + 'static,
@@ -290,38 +673,43 @@ where
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(command) = self.command_rx.poll().expect("Never fails") {
+            let command = command.expect("Never fails");
+            self.handle_command(command);
+        }
+
+        if self.shutdown_deadline.is_some() {
+            if self.channels.is_empty() {
+                info!(
+                    self.logger,
+                    "RPC server stopped (graceful shutdown completed)"
+                );
+                return Ok(Async::Ready(()));
+            }
+            let is_expired = track!(self
+                .shutdown_timeout
+                .poll()
+                .map_err(|_| Error::from(ErrorKind::Other.cause("Broken timer"))))?;
+            if let Async::Ready(Some(())) = is_expired {
+                // The remaining channels abort by themselves (or when the accepting times out)
+                warn!(
+                    self.logger,
+                    "RPC server stopped (grace period expired): channels={}",
+                    self.channels.len()
+                );
+                return Ok(Async::Ready(()));
+            }
+            return Ok(Async::NotReady);
+        }
+
         while let Async::Ready(item) = track!(self.listener.poll())? {
             if let Some((client, addr)) = item {
-                let logger = self.logger.new(o!("client" => addr.to_string()));
-                info!(logger, "New TCP client");
-
-                let options = self.channel_options.clone();
-                let metrics = self.metrics.channels().create_channel_metrics(addr);
-                let channels = self.metrics.channels().clone();
-                let exit_logger = logger.clone();
-                let spawner = self.spawner.clone().boxed();
-                let assigner = self.assigner.clone();
-                let future = client
-                    .map_err(|e| track!(Error::from(e)))
-                    .and_then(move |stream| {
-                        let channel = ServerSideChannel::new(
-                            logger,
-                            stream,
-                            assigner.clone(),
-                            options,
-                            metrics,
-                        );
-                        ChannelHandler::new(spawner, channel)
-                    });
-                self.spawner.spawn(future.then(move |result| {
-                    channels.remove_channel_metrics(addr);
-                    if let Err(e) = result {
-                        error!(exit_logger, "TCP connection aborted: {}", e);
-                    } else {
-                        info!(exit_logger, "TCP connection was closed");
-                    }
-                    Ok(())
-                }));
+                if self.is_acceptable(&addr) {
+                    self.spawn_channel_handler(client, addr);
+                } else {
+                    // The connection is closed by dropping `client`
+                    self.metrics.refused_connections.increment();
+                }
             } else {
                 info!(self.logger, "RPC server stopped");
                 return Ok(Async::Ready(()));
@@ -331,57 +719,273 @@ where
     }
 }
 
+/// Handle of `Server`.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    command_tx: mpsc::Sender<Command>,
+}
+impl ServerHandle {
+    /// Starts the graceful shutdown of the server.
+    ///
+    /// The server stops accepting new connections and notifies the connected clients that
+    /// they should not send new requests (i.e., sends GOAWAY).
+    /// Requests arriving after that are rejected with an `ErrorKind::Unavailable` error,
+    /// and notifications arriving after that are discarded.
+    /// Each connection is closed once all the outstanding responses have been sent and
+    /// all the outstanding notifications have been handled, or when `grace_period` elapses.
+    ///
+    /// The `Server` future completes once all the connections have been closed
+    /// (or when `grace_period` elapses).
+    pub fn shutdown(&self, grace_period: Duration) {
+        let _ = self.command_tx.send(Command::Shutdown { grace_period });
+    }
+}
+
+#[derive(Debug)]
+struct ChannelEntry {
+    shutdown_tx: Option<oneshot::Sender<Instant>>,
+    ip: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Shutdown { grace_period: Duration },
+    RemoveChannel { channel_id: u64 },
+}
+
 struct ChannelHandler {
     spawner: BoxSpawn,
     channel: ServerSideChannel,
+    limiters: Arc<Limiters>,
     reply_tx: mpsc::Sender<OutgoingMessage>,
     reply_rx: mpsc::Receiver<OutgoingMessage>,
+    pending_replies: HashMap<MessageId, oneshot::Sender<()>>,
+    cast_done_tx: mpsc::Sender<u64>,
+    cast_done_rx: mpsc::Receiver<u64>,
+    pending_casts: HashMap<u64, oneshot::Sender<()>>,
+    next_cast_id: u64,
+    is_closed: bool,
+    shutdown_rx: Option<oneshot::Receiver<Instant>>,
+    shutdown_timeout: Option<Timeout>,
 }
 impl ChannelHandler {
-    fn new(spawner: BoxSpawn, channel: ServerSideChannel) -> Self {
+    fn new(
+        spawner: BoxSpawn,
+        channel: ServerSideChannel,
+        limiters: Arc<Limiters>,
+        shutdown_rx: oneshot::Receiver<Instant>,
+    ) -> Self {
         let (reply_tx, reply_rx) = mpsc::channel();
+        let (cast_done_tx, cast_done_rx) = mpsc::channel();
         ChannelHandler {
             spawner,
             channel,
+            limiters,
             reply_tx,
             reply_rx,
+            pending_replies: HashMap::new(),
+            cast_done_tx,
+            cast_done_rx,
+            pending_casts: HashMap::new(),
+            next_cast_id: 0,
+            is_closed: false,
+            shutdown_rx: Some(shutdown_rx),
+            shutdown_timeout: None,
+        }
+    }
+
+    fn poll_shutdown(&mut self) -> Result<bool> {
+        let deadline = match self.shutdown_rx.as_mut().map(Future::poll) {
+            None | Some(Ok(Async::NotReady)) => None,
+            Some(Ok(Async::Ready(deadline))) => Some(deadline),
+            Some(Err(_)) => {
+                // The server has been dropped
+                self.shutdown_rx = None;
+                None
+            }
+        };
+        if let Some(deadline) = deadline {
+            self.shutdown_rx = None;
+            self.channel.go_away();
+            let grace_period = deadline.saturating_duration_since(Instant::now());
+            self.shutdown_timeout = Some(timer::timeout(grace_period));
+        }
+
+        let is_expired = track!(self
+            .shutdown_timeout
+            .poll()
+            .map_err(|_| Error::from(ErrorKind::Other.cause("Broken timer"))))?;
+        Ok(matches!(is_expired, Async::Ready(Some(()))))
+    }
+
+    fn poll_channel(&mut self) -> Poll<Option<Action>, Error> {
+        if self.is_closed {
+            Ok(Async::NotReady)
+        } else {
+            track!(self.channel.poll())
         }
     }
+
+    /// Returns `true` if GOAWAY has been sent to the client.
+    fn is_going_away(&self) -> bool {
+        self.shutdown_timeout.is_some()
+    }
+
+    fn going_away_error() -> ErrorReply {
+        ErrorReply::new(ErrorKind::Unavailable, "The server is shutting down")
+    }
+
+    fn spawn_reply(&mut self, reply: BoxReply) {
+        let metrics = reply.metrics().cloned().expect("Never fails");
+        let mut acquire = self.limiters.acquire(reply.procedure(), metrics);
+        let acquire = match acquire.poll() {
+            Err(e) => {
+                // Rejects the request without spawning a fiber
+                self.channel.reply(reply.into_error_reply(e));
+                return;
+            }
+            Ok(Async::Ready(permits)) => Either::A(futures::finished(permits)),
+            Ok(Async::NotReady) => Either::B(acquire),
+        };
+
+        // The reply future will be dropped if `cancel_tx` is dropped
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.pending_replies.insert(reply.message_id(), cancel_tx);
+
+        let reply_tx = self.reply_tx.clone();
+        let future = acquire.then(move |result| match result {
+            Ok(permits) => Either::A(reply.map(move |message| {
+                drop(permits);
+                message
+            })),
+            Err(e) => Either::B(futures::finished(reply.into_error_reply(e))),
+        });
+        let future = future.select2(cancel_rx).then(move |result| {
+            if let Ok(Either::A((message, _))) = result {
+                let _ = reply_tx.send(message);
+            }
+            Ok(())
+        });
+        self.spawner.spawn(future);
+    }
+
+    fn spawn_cast(&mut self, task: BoxNoReply) {
+        let metrics = task.metrics().clone();
+        let acquire = self.limiters.acquire(task.procedure(), metrics);
+
+        // The task will be dropped if `cancel_tx` is dropped (i.e., the grace period expired)
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let cast_id = self.next_cast_id;
+        self.next_cast_id += 1;
+        self.pending_casts.insert(cast_id, cancel_tx);
+
+        // Notifications rejected by the limiter are discarded (there is no way to tell the client)
+        let future = acquire.then(move |result| match result {
+            Ok(permits) => Either::A(task.map(move |()| drop(permits))),
+            Err(_) => Either::B(futures::finished(())),
+        });
+        let done_tx = self.cast_done_tx.clone();
+        let future = future.select2(cancel_rx).then(move |_| {
+            let _ = done_tx.send(cast_id);
+            Ok(())
+        });
+        self.spawner.spawn(future);
+    }
+
+    fn spawn_reply_stream(&mut self, stream: BoxReplyStream) {
+        let metrics = stream.metrics().clone();
+        let mut acquire = self.limiters.acquire(stream.procedure(), metrics);
+        let acquire = match acquire.poll() {
+            Err(e) => {
+                self.channel.reply(stream.into_error_reply(e));
+                return;
+            }
+            Ok(Async::Ready(permits)) => Either::A(futures::finished(permits)),
+            Ok(Async::NotReady) => Either::B(acquire),
+        };
+
+        // The reply stream will be dropped if `cancel_tx` is dropped
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.pending_replies.insert(stream.message_id(), cancel_tx);
+
+        let reply_tx = self.reply_tx.clone();
+        let future = acquire.then(move |result| match result {
+            Ok(permits) => Either::A(
+                stream
+                    .for_each(move |message| {
+                        let _ = reply_tx.send(message);
+                        Ok(())
+                    })
+                    .map(move |()| drop(permits)),
+            ),
+            Err(e) => {
+                let _ = reply_tx.send(stream.into_error_reply(e));
+                Either::B(futures::finished(()))
+            }
+        });
+        self.spawner
+            .spawn(future.select2(cancel_rx).then(|_| Ok(())));
+    }
 }
 impl Future for ChannelHandler {
     type Item = ();
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if track!(self.poll_shutdown())? {
+            // The grace period has expired (outstanding replies are aborted)
+            return Ok(Async::Ready(()));
+        }
         loop {
-            while let Async::Ready(action) = track!(self.channel.poll())? {
+            while let Async::Ready(action) = track!(self.poll_channel())? {
                 if let Some(action) = action {
                     match action {
-                        Action::NoReply(noreply) => {
-                            if let Some(future) = noreply.into_future() {
-                                self.spawner.spawn(future.map_err(|_: Never| ()));
-                            }
+                        Action::NoReply | Action::DecodeFailed { .. } => {}
+                        Action::Cast(_) if self.is_going_away() => {
+                            // New notifications are discarded after GOAWAY
+                        }
+                        Action::Cast(task) => {
+                            self.spawn_cast(task);
+                        }
+                        Action::Reply(reply) if self.is_going_away() => {
+                            // New requests are not accepted after GOAWAY
+                            let message = reply.into_error_reply(Self::going_away_error());
+                            self.channel.reply(message);
+                        }
+                        Action::ReplyStream(stream) if self.is_going_away() => {
+                            let message = stream.into_error_reply(Self::going_away_error());
+                            self.channel.reply(message);
                         }
                         Action::Reply(mut reply) => {
                             if let Some(message) = reply.try_take() {
                                 self.channel.reply(message);
                             } else {
-                                let reply_tx = self.reply_tx.clone();
-                                let future = reply.map(move |message| {
-                                    let _ = reply_tx.send(message);
-                                });
-                                self.spawner.spawn(future.map_err(|_: Never| ()));
+                                self.spawn_reply(reply);
                             }
                         }
+                        Action::ReplyStream(stream) => {
+                            self.spawn_reply_stream(stream);
+                        }
+                        Action::Cancel(message_id) => {
+                            self.pending_replies.remove(&message_id);
+                        }
                     }
-                } else {
+                } else if self.pending_casts.is_empty() {
                     return Ok(Async::Ready(()));
+                } else {
+                    // The connection has been closed, but the outstanding notifications are still handled
+                    self.is_closed = true;
+                    self.pending_replies.clear();
                 }
             }
 
             let mut do_break = true;
             while let Async::Ready(item) = self.reply_rx.poll().expect("Never fails") {
                 let message = item.expect("Never fails");
+                if !message.header.is_stream_item {
+                    // The last message of the reply
+                    self.pending_replies.remove(&message.header.id);
+                }
                 self.channel.reply(message);
                 do_break = false;
             }
@@ -389,34 +993,72 @@ impl Future for ChannelHandler {
                 break;
             }
         }
+        while let Async::Ready(item) = self.cast_done_rx.poll().expect("Never fails") {
+            let cast_id = item.expect("Never fails");
+            self.pending_casts.remove(&cast_id);
+        }
+        if self.is_closed && self.pending_casts.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        if self.is_going_away()
+            && self.pending_replies.is_empty()
+            && self.pending_casts.is_empty()
+            && !self.channel.is_sending()
+        {
+            // All the notifications have been handled and all the replies (and the GOAWAY) have been sent
+            return Ok(Async::Ready(()));
+        }
         Ok(Async::NotReady)
     }
 }
 
-#[derive(Debug)]
+fn addr_to_ip(addr: &TransportAddr) -> Option<IpAddr> {
+    addr.as_socket_addr().map(|a| a.ip())
+}
+
+fn to_socket_addr(addr: TransportAddr) -> Result<SocketAddr> {
+    let socket_addr = track_assert_some!(
+        addr.as_socket_addr(),
+        ErrorKind::InvalidInput,
+        "Not a TCP server: {}",
+        addr
+    );
+    Ok(socket_addr)
+}
+
 enum Listener {
-    Binding(TcpListenerBind),
-    Listening(Incoming, SocketAddr),
+    Binding(Bind),
+    Listening(Incoming, TransportAddr),
+    Closed,
 }
 impl Stream for Listener {
-    type Item = (Connected, SocketAddr);
+    type Item = (Connect, TransportAddr);
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         loop {
             let next = match self {
                 Listener::Binding(f) => {
-                    if let Async::Ready(listener) = track!(f.poll().map_err(Error::from))? {
-                        let addr = track!(listener.local_addr().map_err(Error::from))?;
-                        Listener::Listening(listener.incoming(), addr)
+                    if let Async::Ready((incoming, addr)) = track!(f.poll())? {
+                        Listener::Listening(incoming, addr)
                     } else {
                         break;
                     }
                 }
-                Listener::Listening(s, _) => return track!(s.poll().map_err(Error::from)),
+                Listener::Listening(s, _) => return track!(s.poll()),
+                Listener::Closed => return Ok(Async::Ready(None)),
             };
             *self = next;
         }
         Ok(Async::NotReady)
     }
 }
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Binding(_) => write!(f, "Binding(_)"),
+            Listener::Listening(_, addr) => write!(f, "Listening(_, {:?})", addr),
+            Listener::Closed => write!(f, "Closed"),
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use crate::client_service::ClientServiceHandle;
+use crate::client_side_handlers::Response;
+use crate::rpc_client::{CallClient, CastClient};
+use crate::{Call, Cast, ErrorKind, Result, TransportAddr};
+use atomic_immut::AtomicImmut;
+use rand::Rng;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+/// The number of points placed on the hash ring for each server.
+const VIRTUAL_NODES_PER_SERVER: usize = 100;
+
+/// Strategy for choosing a server from a `ServerSet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BalanceStrategy {
+    /// Chooses the servers in turn.
+    RoundRobin,
+
+    /// Chooses a server at random.
+    Random,
+
+    /// Chooses the server which has the shortest transmit queue
+    /// (i.e., `ChannelMetrics::queue_len`).
+    ///
+    /// Ties are broken in round robin order.
+    LeastOutstandingRequests,
+
+    /// Chooses a server by the hash value of a key given by the caller,
+    /// so that the same key is mapped to the same server as long as the server set is unchanged.
+    ///
+    /// Keys and servers are hashed by FNV-1a (64 bits), so the mapping is the same in every process.
+    /// Note that it still depends on the bytes that the `Hash` implementation of the key writes
+    /// (e.g., integers are written in the native byte order).
+    ///
+    /// If a key is not given, this behaves like `RoundRobin`.
+    ConsistentHashing,
+}
+
+/// A set of servers that provide the same RPC service.
+///
+/// `ServerSet` chooses a server for each RPC invocation in accordance with its `BalanceStrategy`.
+/// Servers whose channels are waiting for reconnecting (i.e., considered to be down) are skipped,
+/// unless all of the servers are down.
+///
+/// The members of a set made by `ClientServiceHandle::server_set` are kept up to date
+/// by the resolver associated with the service name.
+#[derive(Debug, Clone)]
+pub struct ServerSet {
+    service: ClientServiceHandle,
+    strategy: BalanceStrategy,
+    members: Arc<AtomicImmut<Members>>,
+    next: Arc<AtomicUsize>,
+}
+impl ServerSet {
+    /// Makes a new `ServerSet` instance which has the fixed members.
+    pub fn new<I, A>(service: ClientServiceHandle, strategy: BalanceStrategy, servers: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<TransportAddr>,
+    {
+        let members = Members::new(servers.into_iter().map(Into::into).collect());
+        Self::with_members(service, strategy, Arc::new(AtomicImmut::new(members)))
+    }
+
+    pub(crate) fn with_members(
+        service: ClientServiceHandle,
+        strategy: BalanceStrategy,
+        members: Arc<AtomicImmut<Members>>,
+    ) -> Self {
+        ServerSet {
+            service,
+            strategy,
+            members,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the strategy of the set.
+    pub fn strategy(&self) -> BalanceStrategy {
+        self.strategy
+    }
+
+    /// Returns the current servers in the set.
+    pub fn servers(&self) -> Vec<TransportAddr> {
+        self.members.load().servers.clone()
+    }
+
+    /// Chooses a server.
+    ///
+    /// If the set is empty, this returns `None`.
+    pub fn select(&self) -> Option<TransportAddr> {
+        match self.strategy {
+            BalanceStrategy::RoundRobin | BalanceStrategy::ConsistentHashing => {
+                self.select_round_robin()
+            }
+            BalanceStrategy::Random => self.select_random(),
+            BalanceStrategy::LeastOutstandingRequests => self.select_least_outstanding_requests(),
+        }
+    }
+
+    /// Chooses a server by using `key`.
+    ///
+    /// `key` is ignored unless the strategy is `BalanceStrategy::ConsistentHashing`.
+    ///
+    /// If the set is empty, this returns `None`.
+    pub fn select_by_key<K: Hash + ?Sized>(&self, key: &K) -> Option<TransportAddr> {
+        if self.strategy == BalanceStrategy::ConsistentHashing {
+            self.select_consistent_hashing(hash(key))
+        } else {
+            self.select()
+        }
+    }
+
+    /// Sends the request message to a server chosen by `select` method.
+    pub fn call<T: Call>(&self, client: CallClient<T>, request: T::Req) -> Response<T::Res> {
+        if let Some(server) = self.select() {
+            client.call(server, request)
+        } else {
+            Response::error(track!(ErrorKind::Unavailable.cause("Empty server set")).into())
+        }
+    }
+
+    /// Sends the request message to a server chosen by `select_by_key` method.
+    pub fn call_by_key<T, K>(
+        &self,
+        client: CallClient<T>,
+        key: &K,
+        request: T::Req,
+    ) -> Response<T::Res>
+    where
+        T: Call,
+        K: Hash + ?Sized,
+    {
+        if let Some(server) = self.select_by_key(key) {
+            client.call(server, request)
+        } else {
+            Response::error(track!(ErrorKind::Unavailable.cause("Empty server set")).into())
+        }
+    }
+
+    /// Sends the notification message to a server chosen by `select` method.
+    pub fn cast<T: Cast>(
+        &self,
+        client: CastClient<T>,
+        notification: T::Notification,
+    ) -> Result<()> {
+        let server = track_assert_some!(self.select(), ErrorKind::Unavailable, "Empty server set");
+        track!(client.cast(server, notification))
+    }
+
+    /// Returns the servers which are not considered to be down.
+    ///
+    /// If all of the servers are down, this returns all of them.
+    fn candidates<'a>(&self, members: &'a Members) -> Vec<&'a TransportAddr> {
+        let candidates = members
+            .servers
+            .iter()
+            .filter(|s| !self.service.is_server_down(s))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            members.servers.iter().collect()
+        } else {
+            candidates
+        }
+    }
+
+    fn select_round_robin(&self) -> Option<TransportAddr> {
+        let members = self.members.load();
+        let candidates = self.candidates(&members);
+        if candidates.is_empty() {
+            return None;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(candidates[i % candidates.len()].clone())
+    }
+
+    fn select_random(&self) -> Option<TransportAddr> {
+        let members = self.members.load();
+        let candidates = self.candidates(&members);
+        if candidates.is_empty() {
+            return None;
+        }
+        let i = rand::thread_rng().gen_range(0..candidates.len());
+        Some(candidates[i].clone())
+    }
+
+    fn select_least_outstanding_requests(&self) -> Option<TransportAddr> {
+        let members = self.members.load();
+        let candidates = self.candidates(&members);
+        if candidates.is_empty() {
+            return None;
+        }
+        let channels = self.service.metrics().channels().as_transport_map().load();
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..candidates.len())
+            .map(|i| candidates[(offset + i) % candidates.len()])
+            .min_by_key(|s| channels.get(s).map_or(0, |m| m.queue_len()))
+            .cloned()
+    }
+
+    fn select_consistent_hashing(&self, key_hash: u64) -> Option<TransportAddr> {
+        let members = self.members.load();
+        let ring = &members.ring;
+        if ring.is_empty() {
+            return None;
+        }
+        let start = ring.partition_point(|&(h, _)| h < key_hash) % ring.len();
+        let first = &members.servers[ring[start].1];
+        let server = ring[start..]
+            .iter()
+            .chain(&ring[..start])
+            .map(|&(_, i)| &members.servers[i])
+            .find(|s| !self.service.is_server_down(s))
+            .unwrap_or(first);
+        Some(server.clone())
+    }
+}
+
+/// The members of a `ServerSet`.
+#[derive(Debug, Default)]
+pub(crate) struct Members {
+    servers: Vec<TransportAddr>,
+    ring: Vec<(u64, usize)>,
+}
+impl Members {
+    pub fn new(mut servers: Vec<TransportAddr>) -> Self {
+        servers.sort();
+        servers.dedup();
+
+        let mut ring = Vec::with_capacity(servers.len() * VIRTUAL_NODES_PER_SERVER);
+        for (i, server) in servers.iter().enumerate() {
+            let server = server.to_string();
+            for vnode in 0..VIRTUAL_NODES_PER_SERVER as u32 {
+                let mut hasher = Fnv1aHasher::new();
+                hasher.write(server.as_bytes());
+                hasher.write(&vnode.to_be_bytes());
+                ring.push((hasher.finish(), i));
+            }
+        }
+        ring.sort_unstable();
+        Members { servers, ring }
+    }
+
+    pub fn servers(&self) -> &[TransportAddr] {
+        &self.servers
+    }
+}
+
+fn hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = Fnv1aHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a hasher.
+///
+/// Unlike `DefaultHasher`, its algorithm is fixed, so consistent hashing gives the same results
+/// in every process (see <http://www.isthe.com/chongo/tech/comp/fnv/>).
+#[derive(Debug)]
+struct Fnv1aHasher(u64);
+impl Fnv1aHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> Self {
+        Fnv1aHasher(Self::OFFSET_BASIS)
+    }
+}
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientServiceBuilder;
+    use std::collections::HashSet;
+
+    fn addrs(ports: &[u16]) -> Vec<TransportAddr> {
+        ports
+            .iter()
+            .map(|p| TransportAddr::Tcp(([127, 0, 0, 1], *p).into()))
+            .collect()
+    }
+
+    fn service_handle() -> ClientServiceHandle {
+        ClientServiceBuilder::new()
+            .finish(fibers_global::handle())
+            .handle()
+    }
+
+    #[test]
+    fn round_robin_works() {
+        let servers = addrs(&[3000, 3001, 3002]);
+        let set = ServerSet::new(
+            service_handle(),
+            BalanceStrategy::RoundRobin,
+            servers.clone(),
+        );
+        let selected = (0..6).map(|_| set.select().unwrap()).collect::<Vec<_>>();
+        assert_eq!(&selected[..3], &servers[..]);
+        assert_eq!(&selected[3..], &servers[..]);
+
+        let set = ServerSet::new(service_handle(), BalanceStrategy::RoundRobin, addrs(&[]));
+        assert_eq!(set.select(), None);
+    }
+
+    #[test]
+    fn random_and_least_outstanding_requests_work() {
+        let servers = addrs(&[3000, 3001, 3002]);
+        for strategy in [
+            BalanceStrategy::Random,
+            BalanceStrategy::LeastOutstandingRequests,
+        ] {
+            let set = ServerSet::new(service_handle(), strategy, servers.clone());
+            let selected = (0..100)
+                .map(|_| set.select().unwrap())
+                .collect::<HashSet<_>>();
+            assert!(selected.iter().all(|s| servers.contains(s)));
+            assert!(selected.len() > 1);
+        }
+    }
+
+    #[test]
+    fn fnv1a_hasher_works() {
+        // Test vectors from the reference implementation
+        let digest = |bytes: &[u8]| {
+            let mut hasher = Fnv1aHasher::new();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(digest(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(digest(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(digest(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn consistent_hashing_works() {
+        let service = service_handle();
+        let servers = addrs(&[3000, 3001, 3002, 3003]);
+        let set = ServerSet::new(
+            service.clone(),
+            BalanceStrategy::ConsistentHashing,
+            servers.clone(),
+        );
+        for key in 0..100 {
+            assert_eq!(set.select_by_key(&key), set.select_by_key(&key));
+        }
+
+        // Removing a server only remaps the keys that were mapped to it
+        let removed = servers[0].clone();
+        let smaller_set = ServerSet::new(
+            service,
+            BalanceStrategy::ConsistentHashing,
+            servers[1..].to_vec(),
+        );
+        for key in 0..1000 {
+            let before = set.select_by_key(&key).unwrap();
+            let after = smaller_set.select_by_key(&key).unwrap();
+            assert!(before == removed || before == after);
+        }
+    }
+}
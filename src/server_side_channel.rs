@@ -1,11 +1,11 @@
 use crate::channel::ChannelOptions;
+use crate::control::ControlMessage;
 use crate::message::OutgoingMessage;
 use crate::message_stream::{MessageEvent, MessageStream};
 use crate::metrics::ChannelMetrics;
 use crate::server_side_handlers::{Action, Assigner};
+use crate::transport::BoxTransport;
 use crate::Error;
-use fibers;
-use fibers::net::TcpStream;
 use futures::{Async, Poll, Stream};
 use slog::Logger;
 
@@ -17,7 +17,7 @@ pub struct ServerSideChannel {
 impl ServerSideChannel {
     pub fn new(
         logger: Logger,
-        transport_stream: TcpStream,
+        transport_stream: BoxTransport,
         assigner: Assigner,
         options: ChannelOptions,
         metrics: ChannelMetrics,
@@ -32,6 +32,18 @@ impl ServerSideChannel {
     pub fn reply(&mut self, message: OutgoingMessage) {
         self.message_stream.send_message(message);
     }
+
+    /// Returns `true` if there are messages that have not been completely sent yet.
+    pub fn is_sending(&self) -> bool {
+        self.message_stream.is_sending()
+    }
+
+    /// Notifies the client that the server is shutting down.
+    pub fn go_away(&mut self) {
+        info!(self.logger, "Sends GOAWAY to the client");
+        self.message_stream
+            .send_control_message(ControlMessage::GoAway);
+    }
 }
 impl Stream for ServerSideChannel {
     type Item = Action;
@@ -42,13 +54,36 @@ impl Stream for ServerSideChannel {
         while let Async::Ready(item) = track!(self.message_stream.poll())? {
             if let Some(event) = item {
                 match event {
-                    MessageEvent::Sent => {
+                    MessageEvent::Handshaked { agreed } => {
+                        debug!(self.logger, "Handshake completed: {:?}", agreed);
+                    }
+                    MessageEvent::Sent { .. } => {
                         trace!(self.logger, "Completed to send a message");
                     }
+                    MessageEvent::Received {
+                        next_action: Action::DecodeFailed { procedure, error },
+                    } => {
+                        warn!(
+                            self.logger,
+                            "Failed to decode a notification: procedure={:?}, error={}",
+                            procedure,
+                            error
+                        );
+                    }
                     MessageEvent::Received { next_action } => {
                         trace!(self.logger, "Completed to receive a message");
                         return Ok(Async::Ready(Some(next_action)));
                     }
+                    MessageEvent::Cancelled { message_id } => {
+                        trace!(self.logger, "Cancellation requested: {:?}", message_id);
+                        self.message_stream
+                            .assigner_mut()
+                            .cancel_request_stream(message_id);
+                        return Ok(Async::Ready(Some(Action::Cancel(message_id))));
+                    }
+                    MessageEvent::GoAway => {
+                        debug!(self.logger, "The client is going away");
+                    }
                 }
 
                 count += 1;
@@ -1,17 +1,29 @@
+use crate::error_reply::ErrorReply;
+use crate::extension::{ExtendedDecoder, HeaderExtension};
+use crate::flow_control::Credit;
+use crate::item_stream::{self, ItemReceiver, ItemSender, ItemSlot};
 use crate::message::{
-    AssignIncomingMessageHandler, MessageHeader, OutgoingMessage, OutgoingMessagePayload,
+    AssignIncomingMessageHandler, IsolatedDecoder, MessageHeader, MessageId, OutgoingMessage,
+    OutgoingMessagePayload,
 };
 use crate::metrics::HandlerMetrics;
-use crate::{Call, Cast, ErrorKind, ProcedureId, Result};
+use crate::{
+    BidiStreamCall, Call, Cast, Compression, Error, ErrorKind, ProcedureId, Result, StreamCall,
+    TransportAddr,
+};
 use bytecodec::marker::Never;
-use bytecodec::{self, ByteCount, Decode, Eos};
+use bytecodec::padding::PaddingDecoder;
+use bytecodec::{self, ByteCount, Decode, Encode, Eos};
 use factory::Factory;
+use fibers::time::timer::{self, Timeout};
 use futures::future::Either;
-use futures::{Async, Future, Poll};
+use futures::{Async, Future, Poll, Stream};
 use std::collections::HashMap;
 use std::fmt;
 use std::marker::PhantomData;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
 
 pub struct MessageHandlers(pub HashMap<ProcedureId, Box<dyn MessageHandlerFactory>>);
 impl fmt::Debug for MessageHandlers {
@@ -32,11 +44,179 @@ pub trait HandleCall<T: Call>: Send + Sync + 'static {
     fn handle_call(&self, request: T::Req) -> Reply<T>;
 }
 
-type BoxResponseFuture<T> = Box<dyn Future<Item = T, Error = Never> + Send + 'static>;
+/// This trait allows for handling notification RPC with the context of the notification.
+///
+/// This is automatically implemented for all `HandleCast` implementations.
+pub trait HandleCastWithContext<T: Cast>: Send + Sync + 'static {
+    /// Handles a notification.
+    fn handle_cast_with_context(
+        &self,
+        context: RequestContext,
+        notification: T::Notification,
+    ) -> NoReply;
+}
+impl<T: Cast, H: HandleCast<T>> HandleCastWithContext<T> for H {
+    fn handle_cast_with_context(
+        &self,
+        _context: RequestContext,
+        notification: T::Notification,
+    ) -> NoReply {
+        self.handle_cast(notification)
+    }
+}
+
+/// This trait allows for handling request/response RPC with the context of the request.
+///
+/// This is automatically implemented for all `HandleCall` implementations.
+pub trait HandleCallWithContext<T: Call>: Send + Sync + 'static {
+    /// Handles a request.
+    fn handle_call_with_context(&self, context: RequestContext, request: T::Req) -> Reply<T>;
+}
+impl<T: Call, H: HandleCall<T>> HandleCallWithContext<T> for H {
+    fn handle_call_with_context(&self, _context: RequestContext, request: T::Req) -> Reply<T> {
+        self.handle_call(request)
+    }
+}
+
+/// This trait allows for handling server-streaming RPC.
+pub trait HandleStreamCall<T: StreamCall>: Send + Sync + 'static {
+    /// Handles a request.
+    fn handle_stream_call(&self, request: T::Req) -> ReplyStream<T::Item>;
+}
+
+/// This trait allows for handling server-streaming RPC with the context of the request.
+///
+/// This is automatically implemented for all `HandleStreamCall` implementations.
+pub trait HandleStreamCallWithContext<T: StreamCall>: Send + Sync + 'static {
+    /// Handles a request.
+    fn handle_stream_call_with_context(
+        &self,
+        context: RequestContext,
+        request: T::Req,
+    ) -> ReplyStream<T::Item>;
+}
+impl<T: StreamCall, H: HandleStreamCall<T>> HandleStreamCallWithContext<T> for H {
+    fn handle_stream_call_with_context(
+        &self,
+        _context: RequestContext,
+        request: T::Req,
+    ) -> ReplyStream<T::Item> {
+        self.handle_stream_call(request)
+    }
+}
+
+/// This trait allows for handling bidirectional streaming RPC.
+pub trait HandleBidiStreamCall<T: BidiStreamCall>: Send + Sync + 'static {
+    /// Handles a stream of requests.
+    fn handle_bidi_stream_call(&self, requests: RequestStream<T::Req>) -> ReplyStream<T::Res>;
+}
+
+/// This trait allows for handling bidirectional streaming RPC with the context of the request stream.
+///
+/// This is automatically implemented for all `HandleBidiStreamCall` implementations.
+pub trait HandleBidiStreamCallWithContext<T: BidiStreamCall>: Send + Sync + 'static {
+    /// Handles a stream of requests.
+    fn handle_bidi_stream_call_with_context(
+        &self,
+        context: RequestContext,
+        requests: RequestStream<T::Req>,
+    ) -> ReplyStream<T::Res>;
+}
+impl<T: BidiStreamCall, H: HandleBidiStreamCall<T>> HandleBidiStreamCallWithContext<T> for H {
+    fn handle_bidi_stream_call_with_context(
+        &self,
+        _context: RequestContext,
+        requests: RequestStream<T::Req>,
+    ) -> ReplyStream<T::Res> {
+        self.handle_bidi_stream_call(requests)
+    }
+}
+
+/// Context of an incoming RPC request or notification.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    peer: TransportAddr,
+    message_id: MessageId,
+    procedure: ProcedureId,
+    priority: u8,
+    deadline: Option<Instant>,
+    metadata: HashMap<String, Vec<u8>>,
+}
+impl RequestContext {
+    fn new(header: &MessageHeader, peer: TransportAddr, extension: HeaderExtension) -> Self {
+        RequestContext {
+            peer,
+            message_id: header.id,
+            procedure: header.procedure,
+            priority: header.priority,
+            deadline: extension.deadline,
+            metadata: extension.metadata,
+        }
+    }
+
+    /// Returns the address of the client that sent the request.
+    ///
+    /// If the client connected via an unnamed Unix domain socket, the path of the address is empty.
+    pub fn peer(&self) -> &TransportAddr {
+        &self.peer
+    }
+
+    /// Returns the identifier of the request message.
+    ///
+    /// This is unique within the channel (i.e., TCP connection) between the client and the server.
+    pub fn message_id(&self) -> MessageId {
+        self.message_id
+    }
+
+    /// Returns the identifier of the procedure.
+    pub fn procedure(&self) -> ProcedureId {
+        self.procedure
+    }
+
+    /// Returns the priority of the request message.
+    ///
+    /// See also: `client::Options::priority`.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Returns the metadata attached to the request by the client.
+    ///
+    /// See also: `client::Options::metadata`.
+    pub fn metadata(&self) -> &HashMap<String, Vec<u8>> {
+        &self.metadata
+    }
+
+    /// Returns the deadline of the request.
+    ///
+    /// It is derived from the timeout specified by the client (i.e., `client::Options::timeout`).
+    /// If the client has no timeout, this returns `None`.
+    ///
+    /// When the deadline passes, the future of the reply (if any) will be aborted
+    /// and an `ErrorKind::Timeout` error will be replied to the client.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the remaining time until the deadline.
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns `true` if the deadline has already passed, otherwise `false`.
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+type BoxResponseFuture<T> = Box<dyn Future<Item = T, Error = ErrorReply> + Send + 'static>;
+type ReplyResult<T> = std::result::Result<T, ErrorReply>;
 
 /// This represents a reply from a RPC server.
 pub struct Reply<T: Call> {
-    either: Either<BoxResponseFuture<T::Res>, Option<T::Res>>,
+    either: Either<BoxResponseFuture<T::Res>, Option<ReplyResult<T::Res>>>,
 }
 impl<T: Call> Reply<T> {
     /// Makes a `Reply` instance which will execute `future`
@@ -45,6 +225,22 @@ impl<T: Call> Reply<T> {
     where
         F: Future<Item = T::Res, Error = Never> + Send + 'static,
     {
+        Reply {
+            either: Either::A(Box::new(future.map_err(|_| unreachable!()))),
+        }
+    }
+
+    /// Makes a `Reply` instance which will execute `future`
+    /// then reply the resulting item as the response.
+    ///
+    /// If the future fails, the error is replied to the client instead
+    /// and the client will receive it as an `ErrorKind::ServerError` error.
+    pub fn try_future<F>(future: F) -> Self
+    where
+        F: Future<Item = T::Res> + Send + 'static,
+        F::Error: fmt::Display,
+    {
+        let future = future.map_err(|e| ErrorReply::new(ErrorKind::ServerError, e.to_string()));
         Reply {
             either: Either::A(Box::new(future)),
         }
@@ -53,24 +249,70 @@ impl<T: Call> Reply<T> {
     /// Makes a `Reply` instance which replies the response immediately.
     pub fn done(response: T::Res) -> Self {
         Reply {
-            either: Either::B(Some(response)),
+            either: Either::B(Some(Ok(response))),
+        }
+    }
+
+    /// Makes a `Reply` instance which replies the given error immediately.
+    ///
+    /// The client will receive it as an `ErrorKind::ServerError` error.
+    pub fn error<E: fmt::Display>(error: E) -> Self {
+        let error = ErrorReply::new(ErrorKind::ServerError, error.to_string());
+        Reply {
+            either: Either::B(Some(Err(error))),
         }
     }
 
-    fn boxed<F>(self, f: F) -> BoxReply
+    fn boxed<F>(
+        self,
+        header: MessageHeader,
+        deadline: Option<Instant>,
+        metrics: HandlerMetrics,
+        f: F,
+    ) -> BoxReply
     where
-        F: FnOnce(T::Res) -> OutgoingMessage + Send + 'static,
+        F: FnOnce(ReplyResult<T::Res>) -> OutgoingMessage + Send + 'static,
     {
         match self.either {
-            Either::A(v) => BoxReply {
-                either: Either::A(Box::new(v.map(f))),
-            },
+            Either::A(v) => {
+                let v = if let Some(deadline) = deadline {
+                    with_deadline(v, deadline)
+                } else {
+                    v
+                };
+                BoxReply {
+                    header,
+                    metrics: Some(metrics),
+                    either: Either::A(Box::new(v.then(|r| Ok(f(r))))),
+                }
+            }
             Either::B(v) => BoxReply {
+                header,
+                metrics: None,
                 either: Either::B(v.map(f)),
             },
         }
     }
 }
+
+/// Aborts `future` if it has not been completed by `deadline`.
+fn with_deadline<T>(future: BoxResponseFuture<T>, deadline: Instant) -> BoxResponseFuture<T>
+where
+    T: Send + 'static,
+{
+    let timeout = timer::timeout(deadline.saturating_duration_since(Instant::now()));
+    let future = future.select2(timeout).then(|result| match result {
+        Ok(Either::A((v, _))) => Ok(v),
+        Err(Either::A((e, _))) => Err(e),
+        Ok(Either::B(_)) => Err(ErrorReply::new(
+            ErrorKind::Timeout,
+            "The deadline of the request has expired",
+        )),
+        Err(Either::B(_)) => Err(ErrorReply::new(ErrorKind::Other, "Broken timer")),
+    });
+    Box::new(future)
+}
+
 impl<T: Call> fmt::Debug for Reply<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Reply {{ .. }}")
@@ -78,15 +320,43 @@ impl<T: Call> fmt::Debug for Reply<T> {
 }
 
 pub struct BoxReply {
+    header: MessageHeader,
+    metrics: Option<HandlerMetrics>,
     either: Either<
         Box<dyn Future<Item = OutgoingMessage, Error = Never> + Send + 'static>,
         Option<OutgoingMessage>,
     >,
 }
 impl BoxReply {
+    pub fn done(message: OutgoingMessage) -> Self {
+        BoxReply {
+            header: message.header.clone(),
+            metrics: None,
+            either: Either::B(Some(message)),
+        }
+    }
+
+    pub fn message_id(&self) -> MessageId {
+        self.header.id
+    }
+
+    pub fn procedure(&self) -> ProcedureId {
+        self.header.procedure
+    }
+
+    /// Returns the metrics of the handler if the reply is a future.
+    pub fn metrics(&self) -> Option<&HandlerMetrics> {
+        self.metrics.as_ref()
+    }
+
+    /// Discards the reply and makes an error reply instead.
+    pub fn into_error_reply(self, error: ErrorReply) -> OutgoingMessage {
+        OutgoingMessage::error_reply(self.header, error)
+    }
+
     pub fn try_take(&mut self) -> Option<OutgoingMessage> {
         if let Either::B(ref mut v) = self.either {
-            v.take().map(|v| v)
+            v.take()
         } else {
             None
         }
@@ -109,6 +379,210 @@ impl fmt::Debug for BoxReply {
     }
 }
 
+type BoxItemStream<T> = Box<dyn Stream<Item = T, Error = ErrorReply> + Send + 'static>;
+
+/// This represents a stream of items replied from a RPC server.
+///
+/// Each item is sent to the client as soon as it is yielded,
+/// and the end of the stream is notified to the client after the last item.
+pub struct ReplyStream<T> {
+    items: BoxItemStream<T>,
+}
+impl<T: Send + 'static> ReplyStream<T> {
+    /// Makes a `ReplyStream` instance which replies the items yielded by `stream`.
+    pub fn stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = T, Error = Never> + Send + 'static,
+    {
+        ReplyStream {
+            items: Box::new(stream.map_err(|_| unreachable!())),
+        }
+    }
+
+    /// Makes a `ReplyStream` instance which replies the items yielded by `stream`.
+    ///
+    /// If the stream fails, the error is replied to the client (after the items yielded so far)
+    /// and the client will receive it as an `ErrorKind::ServerError` error.
+    pub fn try_stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = T> + Send + 'static,
+        S::Error: fmt::Display,
+    {
+        let stream = stream.map_err(|e| ErrorReply::new(ErrorKind::ServerError, e.to_string()));
+        ReplyStream {
+            items: Box::new(stream),
+        }
+    }
+
+    /// Makes a `ReplyStream` instance which replies the given items.
+    pub fn iter<I>(items: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        ReplyStream {
+            items: Box::new(futures::stream::iter_ok(items)),
+        }
+    }
+
+    /// Makes a `ReplyStream` instance which replies the given error without any items.
+    ///
+    /// The client will receive it as an `ErrorKind::ServerError` error.
+    pub fn error<E: fmt::Display>(error: E) -> Self {
+        let error = ErrorReply::new(ErrorKind::ServerError, error.to_string());
+        ReplyStream {
+            items: Box::new(futures::stream::once(Err(error))),
+        }
+    }
+
+    fn boxed<E>(
+        self,
+        header: MessageHeader,
+        deadline: Option<Instant>,
+        metrics: HandlerMetrics,
+        encoder_maker: Arc<E>,
+        hooks: ItemHooks<T>,
+    ) -> BoxReplyStream
+    where
+        E: Factory + Send + Sync + 'static,
+        E::Item: Encode<Item = T> + Send + 'static,
+    {
+        let timeout = deadline
+            .map(|deadline| timer::timeout(deadline.saturating_duration_since(Instant::now())));
+        let messages = ReplyMessages {
+            items: Some(self.items),
+            header: header.clone(),
+            encoder_maker,
+            hooks,
+            timeout,
+        };
+        BoxReplyStream {
+            header,
+            metrics,
+            messages: Box::new(messages),
+        }
+    }
+}
+impl<T> fmt::Debug for ReplyStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReplyStream {{ .. }}")
+    }
+}
+
+/// Stream of the messages that reply the items of a `ReplyStream`.
+///
+/// The last message is either an empty message that ends the stream or an error reply.
+/// If the deadline of the request passes before that, the stream is aborted with an `ErrorKind::Timeout` error.
+struct ReplyMessages<T, E> {
+    items: Option<BoxItemStream<T>>,
+    header: MessageHeader,
+    encoder_maker: Arc<E>,
+    hooks: ItemHooks<T>,
+    timeout: Option<Timeout>,
+}
+impl<T, E> Stream for ReplyMessages<T, E>
+where
+    T: Send + 'static,
+    E: Factory,
+    E::Item: Encode<Item = T> + Send + 'static,
+{
+    type Item = OutgoingMessage;
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = match self.items {
+            None => return Ok(Async::Ready(None)),
+            Some(ref mut items) => items.poll(),
+        };
+        let result = match result {
+            Ok(Async::NotReady) => match self.timeout.poll() {
+                Ok(Async::Ready(Some(()))) => Err(ErrorReply::new(
+                    ErrorKind::Timeout,
+                    "The deadline of the request has expired",
+                )),
+                Err(_) => Err(ErrorReply::new(ErrorKind::Other, "Broken timer")),
+                Ok(_) => return Ok(Async::NotReady),
+            },
+            Ok(Async::Ready(item)) => Ok(item),
+            Err(e) => Err(e),
+        };
+        let message = match result {
+            Ok(Some(item)) => {
+                let mut header = self.header.clone();
+                header.is_async = (self.hooks.enable_async)(&item);
+                header.compression = (self.hooks.compression)(&item);
+                header.is_stream_item = true;
+                let payload = OutgoingMessagePayload::with_item(self.encoder_maker.create(), item);
+                return Ok(Async::Ready(Some(OutgoingMessage { header, payload })));
+            }
+            Ok(None) => OutgoingMessage::end_of_stream(self.header.clone()),
+            Err(e) => OutgoingMessage::error_reply(self.header.clone(), e),
+        };
+        self.items = None;
+        Ok(Async::Ready(Some(message)))
+    }
+}
+
+/// The hooks of a RPC definition applied to each item of a stream.
+struct ItemHooks<T> {
+    enable_async: fn(&T) -> bool,
+    compression: fn(&T) -> Compression,
+}
+
+pub struct BoxReplyStream {
+    header: MessageHeader,
+    metrics: HandlerMetrics,
+    messages: Box<dyn Stream<Item = OutgoingMessage, Error = Never> + Send + 'static>,
+}
+impl BoxReplyStream {
+    pub fn message_id(&self) -> MessageId {
+        self.header.id
+    }
+
+    pub fn procedure(&self) -> ProcedureId {
+        self.header.procedure
+    }
+
+    pub fn metrics(&self) -> &HandlerMetrics {
+        &self.metrics
+    }
+
+    /// Discards the stream and makes an error reply instead.
+    pub fn into_error_reply(self, error: ErrorReply) -> OutgoingMessage {
+        OutgoingMessage::error_reply(self.header, error)
+    }
+}
+impl Stream for BoxReplyStream {
+    type Item = OutgoingMessage;
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.messages.poll()
+    }
+}
+impl fmt::Debug for BoxReplyStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BoxReplyStream {{ .. }}")
+    }
+}
+
+/// `Stream` that represents the requests sent from a RPC client.
+///
+/// It terminates when the client closes the sending side of the stream.
+/// If the client is disconnected or cancels the RPC before that, it fails.
+#[derive(Debug)]
+pub struct RequestStream<T> {
+    items: ItemReceiver<T>,
+}
+impl<T> Stream for RequestStream<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        track!(self.items.poll())
+    }
+}
+
 /// This represents a task for handling an RPC notification.
 pub struct NoReply {
     future: Option<Box<dyn Future<Item = (), Error = Never> + Send + 'static>>,
@@ -129,10 +603,12 @@ impl NoReply {
         NoReply { future: None }
     }
 
-    pub(crate) fn into_future(
-        self,
-    ) -> Option<Box<dyn Future<Item = (), Error = Never> + Send + 'static>> {
-        self.future
+    fn boxed(self, procedure: ProcedureId, metrics: HandlerMetrics) -> Option<BoxNoReply> {
+        self.future.map(|future| BoxNoReply {
+            procedure,
+            metrics,
+            future,
+        })
     }
 }
 impl fmt::Debug for NoReply {
@@ -145,54 +621,280 @@ impl fmt::Debug for NoReply {
     }
 }
 
+/// The task of a notification handler.
+pub struct BoxNoReply {
+    procedure: ProcedureId,
+    metrics: HandlerMetrics,
+    future: Box<dyn Future<Item = (), Error = Never> + Send + 'static>,
+}
+impl BoxNoReply {
+    pub fn procedure(&self) -> ProcedureId {
+        self.procedure
+    }
+
+    pub fn metrics(&self) -> &HandlerMetrics {
+        &self.metrics
+    }
+}
+impl Future for BoxNoReply {
+    type Item = ();
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.future.poll()
+    }
+}
+impl fmt::Debug for BoxNoReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BoxNoReply {{ procedure: {:?}, .. }}", self.procedure)
+    }
+}
+
 #[derive(Debug)]
 pub enum Action {
     Reply(BoxReply),
-    NoReply(NoReply),
+    ReplyStream(BoxReplyStream),
+    NoReply,
+    Cast(BoxNoReply),
+    Cancel(MessageId),
+
+    /// A notification message could not be decoded (the channel logs and discards it).
+    DecodeFailed {
+        procedure: ProcedureId,
+        error: bytecodec::Error,
+    },
 }
 
 pub struct Assigner {
     handlers: Arc<MessageHandlers>,
+    max_message_sizes: Arc<HashMap<ProcedureId, usize>>,
+    peer: TransportAddr,
+    request_streams: RequestStreams,
 }
 impl Assigner {
-    pub fn new(mut handlers: MessageHandlers) -> Self {
-        handlers.0.shrink_to_fit();
+    pub fn new(
+        handlers: Arc<MessageHandlers>,
+        max_message_sizes: Arc<HashMap<ProcedureId, usize>>,
+        peer: TransportAddr,
+    ) -> Self {
         Assigner {
-            handlers: Arc::new(handlers),
+            handlers,
+            max_message_sizes,
+            peer,
+            request_streams: HashMap::new(),
         }
     }
+
+    /// Stops receiving the request stream identified by `message_id` (if any).
+    pub fn cancel_request_stream(&mut self, message_id: MessageId) {
+        self.request_streams.remove(&message_id);
+    }
 }
 impl AssignIncomingMessageHandler for Assigner {
     type Handler = Box<dyn Decode<Item = Action> + Send + 'static>;
 
-    fn assign_incoming_message_handler(&mut self, header: &MessageHeader) -> Result<Self::Handler> {
-        let factory = track_assert_some!(
-            self.handlers.0.get(&header.procedure),
-            ErrorKind::InvalidInput,
-            "Unregistered RPC: {:?}",
-            header.procedure,
-        );
-        let handler = factory.create_message_handler(header);
-        Ok(handler)
+    fn assign_incoming_message_handler(
+        &mut self,
+        header: &MessageHeader,
+        credit: Credit,
+    ) -> Result<Self::Handler> {
+        if header.is_stream_item {
+            if let Some(stream) = self.request_streams.get_mut(&header.id) {
+                return Ok(stream.item_handler(credit));
+            }
+            // e.g., an item of a cancelled stream
+            return Ok(Box::new(DiscardMessageHandler::default()));
+        }
+        if let Some(stream) = self.request_streams.remove(&header.id) {
+            return Ok(stream.end_handler());
+        }
+
+        if let Some(factory) = self.handlers.0.get(&header.procedure) {
+            Ok(
+                factory.create_message_handler(
+                    header,
+                    self.peer.clone(),
+                    &mut self.request_streams,
+                ),
+            )
+        } else {
+            Ok(Box::new(UnknownProcedureHandler::new(header)))
+        }
+    }
+
+    fn max_incoming_message_size(
+        &self,
+        header: &MessageHeader,
+        default: Option<usize>,
+    ) -> Option<usize> {
+        match self.max_message_sizes.get(&header.procedure) {
+            Some(&max) => Some(max),
+            None => default,
+        }
+    }
+
+    fn reject_incoming_message(
+        &mut self,
+        header: &MessageHeader,
+        _handler: Option<Self::Handler>,
+        error: Error,
+    ) -> Self::Handler {
+        // The request stream (if any) fails because its items are lost
+        self.request_streams.remove(&header.id);
+        Box::new(RejectedMessageHandler::new(header, error))
     }
 }
 impl fmt::Debug for Assigner {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Assigner {{ handlers.len: {} }}", self.handlers.0.len(),)
+        write!(
+            f,
+            "Assigner {{ handlers.len: {}, peer: {}, request_streams.len: {} }}",
+            self.handlers.0.len(),
+            self.peer,
+            self.request_streams.len()
+        )
     }
 }
-impl Clone for Assigner {
-    fn clone(&self) -> Self {
-        Assigner {
-            handlers: Arc::clone(&self.handlers),
+
+/// The request streams being received by a channel.
+pub type RequestStreams = HashMap<MessageId, Box<dyn HandleRequestStream>>;
+
+/// This trait allows for receiving the messages of a request stream.
+///
+/// The stream starts with the message that invokes the procedure,
+/// continues with the messages of the items, and ends with an empty message.
+pub trait HandleRequestStream: Send + 'static {
+    /// Returns a handler for decoding an item of the stream.
+    ///
+    /// `credit` should be held until the item is consumed by the application.
+    fn item_handler(&mut self, credit: Credit) -> Box<dyn Decode<Item = Action> + Send + 'static>;
+
+    /// Returns a handler for decoding the message that ends the stream.
+    fn end_handler(self: Box<Self>) -> Box<dyn Decode<Item = Action> + Send + 'static>;
+}
+
+/// Handler for messages that no one is waiting for.
+#[derive(Debug, Default)]
+struct DiscardMessageHandler {
+    padding: PaddingDecoder,
+}
+impl Decode for DiscardMessageHandler {
+    type Item = Action;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.padding.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.padding.finish_decoding())?;
+        Ok(Action::NoReply)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.padding.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.padding.is_idle()
+    }
+}
+
+/// Handler for messages of unregistered procedures.
+///
+/// It discards the payload of the message and replies an `ErrorKind::UnknownProcedure` error
+/// (if the message is not a notification).
+struct UnknownProcedureHandler {
+    header: MessageHeader,
+    padding: PaddingDecoder,
+}
+impl UnknownProcedureHandler {
+    fn new(header: &MessageHeader) -> Self {
+        UnknownProcedureHandler {
+            header: header.clone(),
+            padding: PaddingDecoder::new(None),
         }
     }
 }
+impl Decode for UnknownProcedureHandler {
+    type Item = Action;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.padding.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.padding.finish_decoding())?;
+        if self.header.is_notification {
+            return Ok(Action::NoReply);
+        }
+        let error = ErrorReply::new(
+            ErrorKind::UnknownProcedure,
+            format!("Unregistered RPC: {:?}", self.header.procedure),
+        );
+        let message = OutgoingMessage::error_reply(self.header.clone(), error);
+        Ok(Action::Reply(BoxReply::done(message)))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.padding.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.padding.is_idle()
+    }
+}
+
+/// Handler for discarding a message which is too large (or cannot be decoded),
+/// and replying the error (if the message is not a notification).
+struct RejectedMessageHandler {
+    header: MessageHeader,
+    error: Option<ErrorReply>,
+    padding: PaddingDecoder,
+}
+impl RejectedMessageHandler {
+    fn new(header: &MessageHeader, error: Error) -> Self {
+        RejectedMessageHandler {
+            header: header.clone(),
+            error: Some(ErrorReply::new(*error.kind(), error.to_string())),
+            padding: PaddingDecoder::new(None),
+        }
+    }
+}
+impl Decode for RejectedMessageHandler {
+    type Item = Action;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.padding.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.padding.finish_decoding())?;
+        let error = track_assert_some!(self.error.take(), bytecodec::ErrorKind::InconsistentState);
+        if self.header.is_notification {
+            return Ok(Action::NoReply);
+        }
+        let message = OutgoingMessage::error_reply(self.header.clone(), error);
+        Ok(Action::Reply(BoxReply::done(message)))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.padding.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.padding.is_idle()
+    }
+}
 
 pub trait MessageHandlerFactory: Send + Sync + 'static {
+    /// Makes a handler for the message that invokes the procedure.
+    ///
+    /// If the procedure receives a request stream, the receiver of the stream is registered to `request_streams`.
     fn create_message_handler(
         &self,
         header: &MessageHeader,
+        peer: TransportAddr,
+        request_streams: &mut RequestStreams,
     ) -> Box<dyn Decode<Item = Action> + Send + 'static>;
 }
 
@@ -205,7 +907,7 @@ pub struct CastHandlerFactory<T, H, D> {
 impl<T, H, D> CastHandlerFactory<T, H, D>
 where
     T: Cast,
-    H: HandleCast<T>,
+    H: HandleCastWithContext<T>,
     D: Factory<Item = T::Decoder>,
 {
     pub fn new(handler: H, decoder_maker: D, metrics: HandlerMetrics) -> Self {
@@ -220,18 +922,23 @@ where
 impl<T, H, D> MessageHandlerFactory for CastHandlerFactory<T, H, D>
 where
     T: Cast,
-    H: HandleCast<T>,
+    H: HandleCastWithContext<T>,
     D: Factory<Item = T::Decoder> + Send + Sync + 'static,
 {
     fn create_message_handler(
         &self,
-        _header: &MessageHeader,
+        header: &MessageHeader,
+        peer: TransportAddr,
+        _request_streams: &mut RequestStreams,
     ) -> Box<dyn Decode<Item = Action> + Send + 'static> {
-        let decoder = self.decoder_maker.create();
+        let decoder = ExtendedDecoder::new(self.decoder_maker.create(), header.has_extension);
         let handler = CastHandler {
             _rpc: PhantomData,
             handler: Arc::clone(&self.handler),
-            decoder,
+            decoder: IsolatedDecoder::new(decoder),
+            header: header.clone(),
+            peer,
+            metrics: self.metrics.clone(),
         };
         self.metrics.rpc_count.increment();
         Box::new(handler)
@@ -241,12 +948,15 @@ where
 struct CastHandler<T: Cast, H, D> {
     _rpc: PhantomData<T>,
     handler: Arc<H>,
-    decoder: D,
+    decoder: IsolatedDecoder<ExtendedDecoder<D>>,
+    header: MessageHeader,
+    peer: TransportAddr,
+    metrics: HandlerMetrics,
 }
 impl<T, H> Decode for CastHandler<T, H, T::Decoder>
 where
     T: Cast,
-    H: HandleCast<T>,
+    H: HandleCastWithContext<T>,
 {
     type Item = Action;
 
@@ -255,9 +965,23 @@ where
     }
 
     fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
-        let notification = track!(self.decoder.finish_decoding(); T::NAME)?;
-        let noreply = self.handler.handle_cast(notification);
-        Ok(Action::NoReply(noreply))
+        let (extension, notification) = match self.decoder.finish_decoding() {
+            Err(error) => {
+                self.metrics.decode_errors.increment();
+                return Ok(Action::DecodeFailed {
+                    procedure: self.header.procedure,
+                    error,
+                });
+            }
+            Ok(item) => item,
+        };
+        let context = RequestContext::new(&self.header, self.peer.clone(), extension);
+        let noreply = self.handler.handle_cast_with_context(context, notification);
+        if let Some(task) = noreply.boxed(self.header.procedure, self.metrics.clone()) {
+            Ok(Action::Cast(task))
+        } else {
+            Ok(Action::NoReply)
+        }
     }
 
     fn requiring_bytes(&self) -> ByteCount {
@@ -279,7 +1003,7 @@ pub struct CallHandlerFactory<T, H, D, E> {
 impl<T, H, D, E> CallHandlerFactory<T, H, D, E>
 where
     T: Call,
-    H: HandleCall<T>,
+    H: HandleCallWithContext<T>,
     D: Factory<Item = T::ReqDecoder>,
     E: Factory<Item = T::ResEncoder>,
 {
@@ -296,21 +1020,25 @@ where
 impl<T, H, D, E> MessageHandlerFactory for CallHandlerFactory<T, H, D, E>
 where
     T: Call,
-    H: HandleCall<T>,
+    H: HandleCallWithContext<T>,
     D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
     E: Factory<Item = T::ResEncoder> + Send + Sync + 'static,
 {
     fn create_message_handler(
         &self,
         header: &MessageHeader,
+        peer: TransportAddr,
+        _request_streams: &mut RequestStreams,
     ) -> Box<dyn Decode<Item = Action> + Send + 'static> {
-        let decoder = self.decoder_maker.create();
+        let decoder = ExtendedDecoder::new(self.decoder_maker.create(), header.has_extension);
         let handler = CallHandler {
             _rpc: PhantomData,
             handler: Arc::clone(&self.handler),
-            decoder,
+            decoder: IsolatedDecoder::new(decoder),
             encoder: Some(self.encoder_maker.create()),
             header: header.clone(),
+            peer,
+            metrics: self.metrics.clone(),
         };
         self.metrics.rpc_count.increment();
         Box::new(handler)
@@ -320,14 +1048,16 @@ where
 struct CallHandler<T: Call, H, D, E> {
     _rpc: PhantomData<T>,
     handler: Arc<H>,
-    decoder: D,
+    decoder: IsolatedDecoder<ExtendedDecoder<D>>,
     encoder: Option<E>,
     header: MessageHeader,
+    peer: TransportAddr,
+    metrics: HandlerMetrics,
 }
 impl<T, H> Decode for CallHandler<T, H, T::ReqDecoder, T::ResEncoder>
 where
     T: Call,
-    H: HandleCall<T>,
+    H: HandleCallWithContext<T>,
 {
     type Item = Action;
 
@@ -336,17 +1066,51 @@ where
     }
 
     fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
-        let request = track!(self.decoder.finish_decoding(); T::NAME)?;
         let encoder = track_assert_some!(self.encoder.take(), bytecodec::ErrorKind::DecoderTerminated;
                                          T::NAME);
         let mut header = self.header.clone();
-        let reply = self.handler.handle_call(request).boxed(move |v| {
-            header.is_async = T::enable_async_response(&v);
-            OutgoingMessage {
-                header,
-                payload: OutgoingMessagePayload::with_item(encoder, v),
+        header.has_extension = false;
+        let (extension, request) = match self.decoder.finish_decoding() {
+            Err(e) => {
+                self.metrics.decode_errors.increment();
+                let error = ErrorReply::new(
+                    ErrorKind::DecodeFailed,
+                    format!("Cannot decode the request of {:?}: {}", T::NAME, e),
+                );
+                let message = OutgoingMessage::error_reply(header, error);
+                return Ok(Action::Reply(BoxReply::done(message)));
             }
-        });
+            Ok(item) => item,
+        };
+        let context = RequestContext::new(&self.header, self.peer.clone(), extension);
+        if context.is_expired() {
+            let error = ErrorReply::new(
+                ErrorKind::Timeout,
+                format!("The deadline of the request of {:?} has expired", T::NAME),
+            );
+            let message = OutgoingMessage::error_reply(header, error);
+            return Ok(Action::Reply(BoxReply::done(message)));
+        }
+        let deadline = context.deadline;
+        let reply = self
+            .handler
+            .handle_call_with_context(context, request)
+            .boxed(
+                header.clone(),
+                deadline,
+                self.metrics.clone(),
+                move |result| match result {
+                    Ok(v) => {
+                        header.is_async = T::enable_async_response(&v);
+                        header.compression = T::response_compression(&v);
+                        OutgoingMessage {
+                            header,
+                            payload: OutgoingMessagePayload::with_item(encoder, v),
+                        }
+                    }
+                    Err(e) => OutgoingMessage::error_reply(header, e),
+                },
+            );
         Ok(Action::Reply(reply))
     }
 
@@ -362,3 +1126,381 @@ where
         self.encoder.is_none() || self.decoder.is_idle()
     }
 }
+
+pub struct StreamCallHandlerFactory<T, H, D, E> {
+    _rpc: PhantomData<T>,
+    handler: Arc<H>,
+    decoder_maker: D,
+    encoder_maker: Arc<E>,
+    metrics: HandlerMetrics,
+}
+impl<T, H, D, E> StreamCallHandlerFactory<T, H, D, E>
+where
+    T: StreamCall,
+    H: HandleStreamCallWithContext<T>,
+    D: Factory<Item = T::ReqDecoder>,
+    E: Factory<Item = T::ItemEncoder>,
+{
+    pub fn new(handler: H, decoder_maker: D, encoder_maker: E, metrics: HandlerMetrics) -> Self {
+        StreamCallHandlerFactory {
+            _rpc: PhantomData,
+            handler: Arc::new(handler),
+            decoder_maker,
+            encoder_maker: Arc::new(encoder_maker),
+            metrics,
+        }
+    }
+}
+impl<T, H, D, E> MessageHandlerFactory for StreamCallHandlerFactory<T, H, D, E>
+where
+    T: StreamCall,
+    H: HandleStreamCallWithContext<T>,
+    D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
+    E: Factory<Item = T::ItemEncoder> + Send + Sync + 'static,
+{
+    fn create_message_handler(
+        &self,
+        header: &MessageHeader,
+        peer: TransportAddr,
+        _request_streams: &mut RequestStreams,
+    ) -> Box<dyn Decode<Item = Action> + Send + 'static> {
+        let decoder = ExtendedDecoder::new(self.decoder_maker.create(), header.has_extension);
+        let handler = StreamCallHandler {
+            _rpc: PhantomData,
+            handler: Arc::clone(&self.handler),
+            decoder: IsolatedDecoder::new(decoder),
+            encoder_maker: Arc::clone(&self.encoder_maker),
+            header: header.clone(),
+            peer,
+            metrics: self.metrics.clone(),
+            is_finished: false,
+        };
+        self.metrics.rpc_count.increment();
+        Box::new(handler)
+    }
+}
+
+struct StreamCallHandler<T: StreamCall, H, D, E> {
+    _rpc: PhantomData<T>,
+    handler: Arc<H>,
+    decoder: IsolatedDecoder<ExtendedDecoder<D>>,
+    encoder_maker: Arc<E>,
+    header: MessageHeader,
+    peer: TransportAddr,
+    metrics: HandlerMetrics,
+    is_finished: bool,
+}
+impl<T, H, E> Decode for StreamCallHandler<T, H, T::ReqDecoder, E>
+where
+    T: StreamCall,
+    H: HandleStreamCallWithContext<T>,
+    E: Factory<Item = T::ItemEncoder> + Send + Sync + 'static,
+{
+    type Item = Action;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.decoder.decode(buf, eos); T::NAME)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track_assert!(!self.is_finished, bytecodec::ErrorKind::DecoderTerminated; T::NAME);
+        self.is_finished = true;
+
+        let mut header = self.header.clone();
+        header.has_extension = false;
+        let (extension, request) = match self.decoder.finish_decoding() {
+            Err(e) => {
+                self.metrics.decode_errors.increment();
+                let error = ErrorReply::new(
+                    ErrorKind::DecodeFailed,
+                    format!("Cannot decode the request of {:?}: {}", T::NAME, e),
+                );
+                let message = OutgoingMessage::error_reply(header, error);
+                return Ok(Action::Reply(BoxReply::done(message)));
+            }
+            Ok(item) => item,
+        };
+        let context = RequestContext::new(&self.header, self.peer.clone(), extension);
+        if context.is_expired() {
+            let error = ErrorReply::new(
+                ErrorKind::Timeout,
+                format!("The deadline of the request of {:?} has expired", T::NAME),
+            );
+            let message = OutgoingMessage::error_reply(header, error);
+            return Ok(Action::Reply(BoxReply::done(message)));
+        }
+        let deadline = context.deadline;
+        let stream = self
+            .handler
+            .handle_stream_call_with_context(context, request)
+            .boxed(
+                header,
+                deadline,
+                self.metrics.clone(),
+                Arc::clone(&self.encoder_maker),
+                ItemHooks {
+                    enable_async: T::enable_async_item,
+                    compression: T::item_compression,
+                },
+            );
+        Ok(Action::ReplyStream(stream))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.is_finished {
+            ByteCount::Finite(0)
+        } else {
+            self.decoder.requiring_bytes()
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.is_finished || self.decoder.is_idle()
+    }
+}
+
+pub struct BidiStreamCallHandlerFactory<T, H, D, E> {
+    _rpc: PhantomData<T>,
+    handler: Arc<H>,
+    decoder_maker: Arc<D>,
+    encoder_maker: Arc<E>,
+    metrics: HandlerMetrics,
+}
+impl<T, H, D, E> BidiStreamCallHandlerFactory<T, H, D, E>
+where
+    T: BidiStreamCall,
+    H: HandleBidiStreamCallWithContext<T>,
+    D: Factory<Item = T::ReqDecoder>,
+    E: Factory<Item = T::ResEncoder>,
+{
+    pub fn new(handler: H, decoder_maker: D, encoder_maker: E, metrics: HandlerMetrics) -> Self {
+        BidiStreamCallHandlerFactory {
+            _rpc: PhantomData,
+            handler: Arc::new(handler),
+            decoder_maker: Arc::new(decoder_maker),
+            encoder_maker: Arc::new(encoder_maker),
+            metrics,
+        }
+    }
+}
+impl<T, H, D, E> MessageHandlerFactory for BidiStreamCallHandlerFactory<T, H, D, E>
+where
+    T: BidiStreamCall,
+    H: HandleBidiStreamCallWithContext<T>,
+    D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
+    E: Factory<Item = T::ResEncoder> + Send + Sync + 'static,
+{
+    fn create_message_handler(
+        &self,
+        header: &MessageHeader,
+        peer: TransportAddr,
+        request_streams: &mut RequestStreams,
+    ) -> Box<dyn Decode<Item = Action> + Send + 'static> {
+        let (items, item_rx) = item_stream::channel();
+        let stream = RequestItems::<T, D> {
+            decoder_maker: Arc::clone(&self.decoder_maker),
+            items,
+            metrics: self.metrics.clone(),
+        };
+        request_streams.insert(header.id, Box::new(stream));
+
+        let decoder = ExtendedDecoder::new(PaddingDecoder::new(None), header.has_extension);
+        let handler = BidiStreamCallHandler {
+            _rpc: PhantomData,
+            handler: Arc::clone(&self.handler),
+            decoder: IsolatedDecoder::new(decoder),
+            requests: Some(RequestStream { items: item_rx }),
+            encoder_maker: Arc::clone(&self.encoder_maker),
+            header: header.clone(),
+            peer,
+            metrics: self.metrics.clone(),
+        };
+        self.metrics.rpc_count.increment();
+        Box::new(handler)
+    }
+}
+
+/// Handler for the message that opens a request stream.
+///
+/// The message has no payload other than the header extension.
+struct BidiStreamCallHandler<T: BidiStreamCall, H, E> {
+    _rpc: PhantomData<T>,
+    handler: Arc<H>,
+    decoder: IsolatedDecoder<ExtendedDecoder<PaddingDecoder>>,
+    requests: Option<RequestStream<T::Req>>,
+    encoder_maker: Arc<E>,
+    header: MessageHeader,
+    peer: TransportAddr,
+    metrics: HandlerMetrics,
+}
+impl<T, H, E> Decode for BidiStreamCallHandler<T, H, E>
+where
+    T: BidiStreamCall,
+    H: HandleBidiStreamCallWithContext<T>,
+    E: Factory<Item = T::ResEncoder> + Send + Sync + 'static,
+{
+    type Item = Action;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.decoder.decode(buf, eos); T::NAME)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let requests = track_assert_some!(self.requests.take(), bytecodec::ErrorKind::DecoderTerminated;
+                                          T::NAME);
+        let mut header = self.header.clone();
+        header.has_extension = false;
+        let (extension, ()) = match self.decoder.finish_decoding() {
+            Err(e) => {
+                self.metrics.decode_errors.increment();
+                let error = ErrorReply::new(
+                    ErrorKind::DecodeFailed,
+                    format!("Cannot decode the request of {:?}: {}", T::NAME, e),
+                );
+                let message = OutgoingMessage::error_reply(header, error);
+                return Ok(Action::Reply(BoxReply::done(message)));
+            }
+            Ok(item) => item,
+        };
+        let context = RequestContext::new(&self.header, self.peer.clone(), extension);
+        if context.is_expired() {
+            let error = ErrorReply::new(
+                ErrorKind::Timeout,
+                format!("The deadline of the request of {:?} has expired", T::NAME),
+            );
+            let message = OutgoingMessage::error_reply(header, error);
+            return Ok(Action::Reply(BoxReply::done(message)));
+        }
+        let deadline = context.deadline;
+        let stream = self
+            .handler
+            .handle_bidi_stream_call_with_context(context, requests)
+            .boxed(
+                header,
+                deadline,
+                self.metrics.clone(),
+                Arc::clone(&self.encoder_maker),
+                ItemHooks {
+                    enable_async: T::enable_async_response,
+                    compression: T::response_compression,
+                },
+            );
+        Ok(Action::ReplyStream(stream))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.requests.is_none() {
+            ByteCount::Finite(0)
+        } else {
+            self.decoder.requiring_bytes()
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.requests.is_none() || self.decoder.is_idle()
+    }
+}
+
+/// Receiver of a request stream of bidirectional streaming RPC.
+struct RequestItems<T: BidiStreamCall, D> {
+    decoder_maker: Arc<D>,
+    items: ItemSender<T::Req>,
+    metrics: HandlerMetrics,
+}
+impl<T, D> HandleRequestStream for RequestItems<T, D>
+where
+    T: BidiStreamCall,
+    D: Factory<Item = T::ReqDecoder> + Send + Sync + 'static,
+{
+    fn item_handler(&mut self, credit: Credit) -> Box<dyn Decode<Item = Action> + Send + 'static> {
+        let handler = RequestItemHandler::<T> {
+            decoder: IsolatedDecoder::new(self.decoder_maker.create()),
+            slot: Some(self.items.reserve().with_credit(credit)),
+            metrics: self.metrics.clone(),
+        };
+        Box::new(handler)
+    }
+
+    fn end_handler(mut self: Box<Self>) -> Box<dyn Decode<Item = Action> + Send + 'static> {
+        let handler = RequestEndHandler {
+            padding: PaddingDecoder::new(None),
+            slot: Some(self.items.reserve()),
+        };
+        Box::new(handler)
+    }
+}
+
+/// Handler for decoding an item of a request stream.
+///
+/// If decoding fails, the request stream fails with an `ErrorKind::DecodeFailed` error.
+struct RequestItemHandler<T: BidiStreamCall> {
+    decoder: IsolatedDecoder<T::ReqDecoder>,
+    slot: Option<ItemSlot<T::Req>>,
+    metrics: HandlerMetrics,
+}
+impl<T: BidiStreamCall> Decode for RequestItemHandler<T> {
+    type Item = Action;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.decoder.decode(buf, eos); T::NAME)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        let slot = track_assert_some!(self.slot.take(), bytecodec::ErrorKind::DecoderTerminated;
+                                      T::NAME);
+        match self.decoder.finish_decoding() {
+            Err(e) => {
+                self.metrics.decode_errors.increment();
+                let e = ErrorKind::DecodeFailed.cause(format!(
+                    "Cannot decode the request of {:?}: {}",
+                    T::NAME,
+                    e
+                ));
+                slot.send(Err(track!(e).into()));
+            }
+            Ok(item) => slot.send(Ok(Some(item))),
+        }
+        Ok(Action::NoReply)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.slot.is_none() {
+            ByteCount::Finite(0)
+        } else {
+            self.decoder.requiring_bytes()
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.slot.is_none() || self.decoder.is_idle()
+    }
+}
+
+/// Handler for the (empty) message that ends a request stream.
+struct RequestEndHandler<T> {
+    padding: PaddingDecoder,
+    slot: Option<ItemSlot<T>>,
+}
+impl<T> Decode for RequestEndHandler<T> {
+    type Item = Action;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.padding.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.padding.finish_decoding())?;
+        if let Some(slot) = self.slot.take() {
+            slot.send(Ok(None));
+        }
+        Ok(Action::NoReply)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.padding.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.padding.is_idle()
+    }
+}
@@ -0,0 +1,353 @@
+//! TLS support (requires the `tls` feature).
+use crate::transport::{BoxTransport, Connect, Transport, TransportAddr};
+use crate::{Error, ErrorKind, Result};
+use futures::{Async, Future, Poll};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, RootCertStore,
+    ServerConfig, ServerConnection, ServerName,
+};
+use rustls_pemfile::Item;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+/// TLS options of an RPC server.
+#[derive(Debug, Clone)]
+pub struct ServerTlsOptions {
+    /// The path of the PEM file containing the certificate chain of the server.
+    pub cert_path: PathBuf,
+
+    /// The path of the PEM file containing the private key of the server.
+    pub key_path: PathBuf,
+
+    /// The path of the PEM file containing the CA certificates used for verifying clients.
+    ///
+    /// If `Some`, clients are required to present valid certificates (i.e., mutual TLS).
+    ///
+    /// The default value is `None`.
+    pub client_ca_path: Option<PathBuf>,
+}
+impl ServerTlsOptions {
+    /// Makes a new `ServerTlsOptions` instance.
+    pub fn new<P: Into<PathBuf>, Q: Into<PathBuf>>(cert_path: P, key_path: Q) -> Self {
+        ServerTlsOptions {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+        }
+    }
+}
+
+/// TLS options of an RPC client service.
+#[derive(Debug, Clone)]
+pub struct ClientTlsOptions {
+    /// The path of the PEM file containing the CA certificates used for verifying servers.
+    pub ca_path: PathBuf,
+
+    /// The name used for verifying the certificates of servers.
+    ///
+    /// If `None`, the IP address of the server is used.
+    ///
+    /// The default value is `None`.
+    pub server_name: Option<String>,
+
+    /// The paths of the PEM files containing the certificate chain and the private key of the client.
+    ///
+    /// This is required if servers verify clients (i.e., mutual TLS).
+    ///
+    /// The default value is `None`.
+    pub cert_and_key_paths: Option<(PathBuf, PathBuf)>,
+}
+impl ClientTlsOptions {
+    /// Makes a new `ClientTlsOptions` instance.
+    pub fn new<P: Into<PathBuf>>(ca_path: P) -> Self {
+        ClientTlsOptions {
+            ca_path: ca_path.into(),
+            server_name: None,
+            cert_and_key_paths: None,
+        }
+    }
+}
+
+/// Wraps streams accepted by a server in TLS.
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+impl TlsAcceptor {
+    pub fn new(options: &ServerTlsOptions) -> Result<Self> {
+        let certs = track!(load_certs(&options.cert_path))?;
+        let key = track!(load_private_key(&options.key_path))?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = if let Some(ref path) = options.client_ca_path {
+            let roots = track!(load_root_cert_store(path))?;
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = track!(builder
+            .with_single_cert(certs, key)
+            .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn accept(&self, stream: Connect) -> Connect {
+        let config = Arc::clone(&self.config);
+        let future = stream.and_then(move |stream| {
+            let connection =
+                track!(ServerConnection::new(config)
+                    .map_err(|e| Error::from(ErrorKind::Other.cause(e))))?;
+            Ok(Handshake(Some(TlsStream::new(stream, connection.into()))))
+        });
+        Box::new(future.flatten())
+    }
+}
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsAcceptor {{ .. }}")
+    }
+}
+
+/// Wraps streams connected to servers in TLS.
+#[derive(Clone)]
+pub(crate) struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName>,
+}
+impl TlsConnector {
+    pub fn new(options: &ClientTlsOptions) -> Result<Self> {
+        let roots = track!(load_root_cert_store(&options.ca_path))?;
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = if let Some((ref cert_path, ref key_path)) = options.cert_and_key_paths {
+            let certs = track!(load_certs(cert_path))?;
+            let key = track!(load_private_key(key_path))?;
+            track!(builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| ErrorKind::InvalidInput.cause(e)))?
+        } else {
+            builder.with_no_client_auth()
+        };
+        let server_name = if let Some(ref name) = options.server_name {
+            let name =
+                track!(ServerName::try_from(name.as_str())
+                    .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+            Some(name)
+        } else {
+            None
+        };
+        Ok(TlsConnector {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    pub fn connect(&self, server: &TransportAddr, stream: Connect) -> Connect {
+        let server_name = match (&self.server_name, server) {
+            (Some(name), _) => name.clone(),
+            (None, TransportAddr::Tcp(addr)) => ServerName::IpAddress(addr.ip()),
+            (None, TransportAddr::Unix(_)) => {
+                let e = ErrorKind::InvalidInput
+                    .cause("`ClientTlsOptions::server_name` is required for Unix domain sockets");
+                return Box::new(futures::failed(track!(Error::from(e))));
+            }
+        };
+        let config = Arc::clone(&self.config);
+        let future = stream.and_then(move |stream| {
+            let connection = track!(ClientConnection::new(config, server_name)
+                .map_err(|e| Error::from(ErrorKind::Other.cause(e))))?;
+            Ok(Handshake(Some(TlsStream::new(stream, connection.into()))))
+        });
+        Box::new(future.flatten())
+    }
+}
+impl fmt::Debug for TlsConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TlsConnector {{ server_name: {:?}, .. }}",
+            self.server_name
+        )
+    }
+}
+
+/// A future that completes the TLS handshake.
+struct Handshake(Option<TlsStream>);
+impl Future for Handshake {
+    type Item = BoxTransport;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut stream = self.0.take().expect("Cannot poll Handshake twice");
+        while stream.connection.is_handshaking() {
+            match stream.complete_io() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.0 = Some(stream);
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(track!(Error::from(e))),
+                Ok(false) => {
+                    track_panic!(
+                        ErrorKind::Unavailable,
+                        "Connection closed during TLS handshake"
+                    );
+                }
+                Ok(true) => {}
+            }
+        }
+
+        // The last handshake message may still remain in the buffer (it will be written later)
+        let _ = stream.write_tls();
+        Ok(Async::Ready(Box::new(stream)))
+    }
+}
+
+/// TLS stream over an arbitrary transport stream.
+pub(crate) struct TlsStream {
+    inner: BoxTransport,
+    connection: Connection,
+}
+impl TlsStream {
+    fn new(inner: BoxTransport, connection: Connection) -> Self {
+        TlsStream { inner, connection }
+    }
+
+    /// Writes the pending TLS records to the underlying stream.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut self.inner)?;
+        }
+        Ok(())
+    }
+
+    /// Reads and processes TLS records from the underlying stream.
+    ///
+    /// Returns `false` if the underlying stream reached EOF.
+    fn read_tls(&mut self) -> io::Result<bool> {
+        if self.connection.read_tls(&mut self.inner)? == 0 {
+            return Ok(false);
+        }
+        if let Err(e) = self.connection.process_new_packets() {
+            // Tries to notify the peer of the error (e.g., a bad certificate alert)
+            let _ = self.write_tls();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(true)
+    }
+
+    fn complete_io(&mut self) -> io::Result<bool> {
+        self.write_tls()?;
+        if self.connection.wants_read() {
+            return self.read_tls();
+        }
+        Ok(true)
+    }
+}
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.connection.reader().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            // The result of this is ignored because a `WouldBlock` error on writing
+            // should not prevent reading (the stream will be waked up when it becomes writable).
+            let _ = self.write_tls();
+            if !self.read_tls()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.connection.writer().write(buf)?;
+        match self.write_tls() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && size != 0 => Ok(size),
+            Err(e) => Err(e),
+            Ok(()) if size == 0 && !buf.is_empty() => {
+                // The plaintext buffer was full (but now it has been flushed)
+                self.connection.writer().write(buf)
+            }
+            Ok(()) => Ok(size),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.writer().flush()?;
+        self.write_tls()?;
+        self.inner.flush()
+    }
+}
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsStream {{ inner: {:?}, .. }}", self.inner)
+    }
+}
+impl Transport for TlsStream {}
+
+fn load_pem_items(path: &Path) -> Result<Vec<Item>> {
+    let file = track!(File::open(path).map_err(Error::from), "path={:?}", path)?;
+    let mut reader = BufReader::new(file);
+    let mut items = Vec::new();
+    while let Some(item) = track!(
+        rustls_pemfile::read_one(&mut reader).map_err(Error::from),
+        "path={:?}",
+        path
+    )? {
+        items.push(item);
+    }
+    Ok(items)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = track!(load_pem_items(path))?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    track_assert!(
+        !certs.is_empty(),
+        ErrorKind::InvalidInput,
+        "No certificates: path={:?}",
+        path
+    );
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    for item in track!(load_pem_items(path))? {
+        match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => {
+                return Ok(PrivateKey(der));
+            }
+            _ => {}
+        }
+    }
+    track_panic!(ErrorKind::InvalidInput, "No private key: path={:?}", path);
+}
+
+fn load_root_cert_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in track!(load_certs(path))? {
+        track!(
+            roots
+                .add(&cert)
+                .map_err(|e| ErrorKind::InvalidInput.cause(e)),
+            "path={:?}",
+            path
+        )?;
+    }
+    Ok(roots)
+}
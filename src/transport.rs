@@ -0,0 +1,237 @@
+use crate::channel::ChannelOptions;
+use crate::{Error, ErrorKind};
+use fibers::net::{TcpListener, TcpStream};
+use fibers::time::timer::TimerExt;
+use futures::{Future, Stream};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use trackable::error::ErrorKindExt;
+
+/// The address of an RPC server or client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TransportAddr {
+    /// TCP socket address.
+    Tcp(SocketAddr),
+
+    /// Filesystem path of a Unix domain socket.
+    ///
+    /// Note that the path of an unnamed client socket is empty.
+    Unix(PathBuf),
+}
+impl TransportAddr {
+    /// Returns the TCP socket address if this is a `TransportAddr::Tcp`.
+    pub fn as_socket_addr(&self) -> Option<SocketAddr> {
+        if let TransportAddr::Tcp(addr) = *self {
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the filesystem path if this is a `TransportAddr::Unix`.
+    pub fn as_path(&self) -> Option<&Path> {
+        if let TransportAddr::Unix(ref path) = *self {
+            Some(path)
+        } else {
+            None
+        }
+    }
+}
+impl From<SocketAddr> for TransportAddr {
+    fn from(f: SocketAddr) -> Self {
+        TransportAddr::Tcp(f)
+    }
+}
+impl From<PathBuf> for TransportAddr {
+    fn from(f: PathBuf) -> Self {
+        TransportAddr::Unix(f)
+    }
+}
+impl<'a> From<&'a Path> for TransportAddr {
+    fn from(f: &'a Path) -> Self {
+        TransportAddr::Unix(f.to_path_buf())
+    }
+}
+impl<'a> From<&'a TransportAddr> for TransportAddr {
+    fn from(f: &'a TransportAddr) -> Self {
+        f.clone()
+    }
+}
+impl FromStr for TransportAddr {
+    type Err = Error;
+
+    /// Parses a TCP socket address (e.g., `127.0.0.1:3000`) or
+    /// a Unix domain socket path prefixed by `unix:` (e.g., `unix:/tmp/rpc.sock`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(TransportAddr::Unix(PathBuf::from(path)))
+        } else {
+            let addr = track!(s.parse().map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+            Ok(TransportAddr::Tcp(addr))
+        }
+    }
+}
+impl fmt::Display for TransportAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransportAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            TransportAddr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A byte stream over which RPC messages are transmitted.
+///
+/// Read and write operations must not block.
+/// If an operation cannot be completed immediately, it has to return a `WouldBlock` error
+/// and arrange for the current fiber to be woken up once the stream becomes ready.
+pub trait Transport: Read + Write + fmt::Debug + Send + 'static {}
+impl Transport for TcpStream {}
+#[cfg(unix)]
+impl Transport for crate::uds::UnixStream {}
+
+pub type BoxTransport = Box<dyn Transport>;
+
+/// A future that establishes a transport stream.
+pub type Connect = Box<dyn Future<Item = BoxTransport, Error = Error> + Send + 'static>;
+
+/// An infinite stream of the transport streams accepted by a listener.
+pub type Incoming =
+    Box<dyn Stream<Item = (Connect, TransportAddr), Error = Error> + Send + 'static>;
+
+/// A future that binds a listener to an address.
+///
+/// The resulting item is the pair of the incoming stream and the actual bound address.
+pub type Bind = Box<dyn Future<Item = (Incoming, TransportAddr), Error = Error> + Send + 'static>;
+
+/// Connector of transport streams (used by clients).
+#[derive(Debug, Clone, Default)]
+pub struct Connector {
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsConnector>,
+}
+impl Connector {
+    /// Opens a transport stream to the given server.
+    pub fn connect(&self, server: &TransportAddr, options: &ChannelOptions) -> Connect {
+        #[allow(unused_mut)]
+        let mut future = connect(server);
+        #[cfg(feature = "tls")]
+        {
+            if let Some(ref tls) = self.tls {
+                future = tls.connect(server, future);
+            }
+        }
+        let future = future
+            .timeout_after(options.tcp_connect_timeout)
+            .map_err(|e| e.unwrap_or_else(|| ErrorKind::Timeout.error().into()));
+        Box::new(track_err!(future))
+    }
+}
+
+/// Acceptor of transport streams (used by servers).
+#[derive(Debug, Clone, Default)]
+pub struct Acceptor {
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsAcceptor>,
+}
+impl Acceptor {
+    /// Wraps the stream accepted by a listener (e.g., in TLS).
+    ///
+    /// The resulting future fails with `ErrorKind::Timeout` if it does not complete
+    /// within `options.tcp_connect_timeout`.
+    pub fn accept(&self, client: Connect, options: &ChannelOptions) -> Connect {
+        #[allow(unused_mut)]
+        let mut future = client;
+        #[cfg(feature = "tls")]
+        {
+            if let Some(ref tls) = self.tls {
+                future = tls.accept(future);
+            }
+        }
+        let future = future
+            .timeout_after(options.tcp_connect_timeout)
+            .map_err(|e| e.unwrap_or_else(|| ErrorKind::Timeout.error().into()));
+        Box::new(track_err!(future))
+    }
+}
+
+fn connect(server: &TransportAddr) -> Connect {
+    match *server {
+        TransportAddr::Tcp(addr) => {
+            let future = TcpStream::connect(addr).map(|stream| {
+                let _ = stream.set_nodelay(true);
+                Box::new(stream) as BoxTransport
+            });
+            Box::new(future.map_err(Error::from))
+        }
+        TransportAddr::Unix(ref path) => unix_connect(path),
+    }
+}
+
+/// Makes a listener bound to the given address.
+pub fn bind(addr: &TransportAddr) -> Bind {
+    match *addr {
+        TransportAddr::Tcp(addr) => {
+            let future = TcpListener::bind(addr)
+                .map_err(Error::from)
+                .and_then(|listener| {
+                    let addr = track!(listener.local_addr().map_err(Error::from))?;
+                    let incoming =
+                        listener
+                            .incoming()
+                            .map_err(Error::from)
+                            .map(|(client, addr)| {
+                                let client = client.map_err(Error::from).map(|stream| {
+                                    let _ = stream.set_nodelay(true);
+                                    Box::new(stream) as BoxTransport
+                                });
+                                (Box::new(client) as Connect, TransportAddr::Tcp(addr))
+                            });
+                    Ok((Box::new(incoming) as Incoming, TransportAddr::Tcp(addr)))
+                });
+            Box::new(track_err!(future))
+        }
+        TransportAddr::Unix(ref path) => unix_bind(path),
+    }
+}
+
+#[cfg(unix)]
+fn unix_connect(path: &Path) -> Connect {
+    let future = crate::uds::UnixStream::connect(path.to_path_buf())
+        .map(|stream| Box::new(stream) as BoxTransport);
+    Box::new(future)
+}
+
+#[cfg(not(unix))]
+fn unix_connect(path: &Path) -> Connect {
+    let e = ErrorKind::InvalidInput.cause(format!(
+        "Unix domain sockets are not supported on this platform: {:?}",
+        path
+    ));
+    Box::new(futures::failed(track!(Error::from(e))))
+}
+
+#[cfg(unix)]
+fn unix_bind(path: &Path) -> Bind {
+    let future = crate::uds::UnixListener::bind(path.to_path_buf()).map(|listener| {
+        let addr = TransportAddr::Unix(listener.path().to_path_buf());
+        let incoming = listener.incoming().map(|(client, addr)| {
+            let client = client.map(|stream| Box::new(stream) as BoxTransport);
+            (Box::new(client) as Connect, addr)
+        });
+        (Box::new(incoming) as Incoming, addr)
+    });
+    Box::new(future)
+}
+
+#[cfg(not(unix))]
+fn unix_bind(path: &Path) -> Bind {
+    let e = ErrorKind::InvalidInput.cause(format!(
+        "Unix domain sockets are not supported on this platform: {:?}",
+        path
+    ));
+    Box::new(futures::failed(track!(Error::from(e))))
+}
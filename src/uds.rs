@@ -0,0 +1,264 @@
+//! Unix domain socket support built on top of the fibers' poller.
+use crate::transport::TransportAddr;
+use crate::{Error, ErrorKind, Result};
+use fibers::fiber;
+use fibers::io::poll::{EventedHandle, Interest, Register};
+use fibers::sync::oneshot::{Monitor, MonitorError};
+use futures::{Async, Future, Poll, Stream};
+use mio::unix::EventedFd;
+use mio::{Evented, PollOpt, Ready, Token};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+#[derive(Debug)]
+pub struct MioUnixStream(net::UnixStream);
+impl Evented for MioUnixStream {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+#[derive(Debug)]
+pub struct MioUnixListener(net::UnixListener);
+impl Evented for MioUnixListener {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+/// Unix domain socket stream.
+///
+/// Like `fibers::net::TcpStream`, non blocking mode is always enabled on this socket.
+pub struct UnixStream {
+    handle: Arc<EventedHandle<MioUnixStream>>,
+    read_monitor: Option<Monitor<(), io::Error>>,
+    write_monitor: Option<Monitor<(), io::Error>>,
+}
+impl UnixStream {
+    /// Makes a future to connect to the server listening on `path`.
+    pub fn connect(path: PathBuf) -> impl Future<Item = Self, Error = Error> + Send + 'static {
+        futures::lazy(move || {
+            // NOTE: Connecting to a local socket completes (or fails) immediately
+            // unless the backlog of the listener is full.
+            let stream = track!(
+                net::UnixStream::connect(&path).map_err(Error::from),
+                "path={:?}",
+                path
+            )?;
+            track!(stream.set_nonblocking(true).map_err(Error::from))?;
+            track!(register(MioUnixStream(stream)))
+        })
+        .flatten()
+        .map(UnixStream::new)
+    }
+
+    fn new(handle: Arc<EventedHandle<MioUnixStream>>) -> Self {
+        UnixStream {
+            handle,
+            read_monitor: None,
+            write_monitor: None,
+        }
+    }
+
+    fn operate<F, T>(&mut self, interest: Interest, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&mut net::UnixStream) -> io::Result<T>,
+    {
+        loop {
+            let monitor = if interest == Interest::Read {
+                &mut self.read_monitor
+            } else {
+                &mut self.write_monitor
+            };
+            if let Some(mut m) = monitor.take() {
+                if let Async::NotReady = m.poll().map_err(from_monitor_error)? {
+                    *monitor = Some(m);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+            } else {
+                match f(&mut self.handle.inner().0) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        *monitor = Some(self.handle.monitor(interest));
+                    }
+                    result => return result,
+                }
+            }
+        }
+    }
+}
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.operate(Interest::Read, |inner| inner.read(buf))
+    }
+}
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.operate(Interest::Write, |inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.operate(Interest::Write, |inner| inner.flush())
+    }
+}
+impl fmt::Debug for UnixStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.handle.inner();
+        write!(f, "UnixStream {{ ")?;
+        if let Ok(addr) = inner.0.local_addr() {
+            write!(f, "local_addr:{:?}, ", addr)?;
+        }
+        if let Ok(addr) = inner.0.peer_addr() {
+            write!(f, "peer_addr:{:?}, ", addr)?;
+        }
+        write!(f, ".. }}")
+    }
+}
+
+/// Unix domain socket listener.
+///
+/// The socket file is removed when the listener is dropped.
+#[derive(Debug)]
+pub struct UnixListener {
+    handle: Arc<EventedHandle<MioUnixListener>>,
+    monitor: Option<Monitor<(), io::Error>>,
+    path: PathBuf,
+}
+impl UnixListener {
+    /// Makes a future to create a listener bound to `path`.
+    pub fn bind(path: PathBuf) -> impl Future<Item = Self, Error = Error> + Send + 'static {
+        futures::lazy(move || {
+            let listener = track!(
+                net::UnixListener::bind(&path).map_err(Error::from),
+                "path={:?}",
+                path
+            )?;
+            track!(listener.set_nonblocking(true).map_err(Error::from))?;
+            let future = track!(register(MioUnixListener(listener)))?;
+            Ok::<_, Error>(future.map(move |handle| UnixListener {
+                handle,
+                monitor: None,
+                path,
+            }))
+        })
+        .flatten()
+    }
+
+    /// Returns the path to which the listener is bound.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the stream of the connections accepted by the listener.
+    pub fn incoming(self) -> Incoming {
+        Incoming(self)
+    }
+}
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// An infinite stream of the connections accepted by `UnixListener`.
+#[derive(Debug)]
+pub struct Incoming(UnixListener);
+impl Stream for Incoming {
+    type Item = (
+        Box<dyn Future<Item = UnixStream, Error = Error> + Send>,
+        TransportAddr,
+    );
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(mut monitor) = self.0.monitor.take() {
+                if let Async::NotReady = track!(monitor
+                    .poll()
+                    .map_err(|e| Error::from(from_monitor_error(e))))?
+                {
+                    self.0.monitor = Some(monitor);
+                    return Ok(Async::NotReady);
+                }
+            } else {
+                let accepted = self.0.handle.inner().0.accept();
+                match accepted {
+                    Ok((stream, addr)) => {
+                        track!(stream.set_nonblocking(true).map_err(Error::from))?;
+                        let future = track!(register(MioUnixStream(stream)))?;
+                        let addr = addr
+                            .as_pathname()
+                            .map(Path::to_path_buf)
+                            .unwrap_or_default();
+                        let client = Box::new(future.map(UnixStream::new));
+                        return Ok(Async::Ready(Some((client, TransportAddr::Unix(addr)))));
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.0.monitor = Some(self.0.handle.monitor(Interest::Read));
+                    }
+                    Err(e) => return Err(track!(Error::from(e))),
+                }
+            }
+        }
+    }
+}
+
+/// Registers `evented` to the poller of the current fiber.
+fn register<T>(evented: T) -> Result<impl Future<Item = Arc<EventedHandle<T>>, Error = Error>>
+where
+    T: Evented + Send + 'static,
+{
+    let future: Option<Register<T>> =
+        fiber::with_current_context(|mut c| c.poller().register(evented));
+    let future = track_assert_some!(future, ErrorKind::Other, "Not in a fiber");
+    Ok(future.map_err(|_| Error::from(ErrorKind::Other.cause("The poller has been dropped"))))
+}
+
+fn from_monitor_error(e: MonitorError<io::Error>) -> io::Error {
+    e.unwrap_or_else(|| io::Error::other("Monitor channel disconnected"))
+}